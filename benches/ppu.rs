@@ -0,0 +1,37 @@
+use cerboy::lcd::ppu_decode_tile_line;
+use cerboy::types::Byte;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+// the per-bit loop ppu_decode_tile_line used to be, for comparison
+fn decode_tile_line_loop(low: Byte, high: Byte) -> [Byte; 8] {
+    let mut result = [0; 8];
+    for (i, px) in result.iter_mut().enumerate() {
+        *px = ((high >> i) & 1) << 1 | ((low >> i) & 1);
+    }
+    result
+}
+
+fn bench_decode_tile_line(c: &mut Criterion) {
+    // a tile's worth of bitplanes
+    let lines: Vec<(Byte, Byte)> = (0..8).map(|i| (0x3C ^ (i * 29), 0x7E ^ (i * 53))).collect();
+
+    let mut group = c.benchmark_group("decode_tile_line");
+    group.bench_function("spread table", |b| {
+        b.iter(|| {
+            for &(low, high) in &lines {
+                black_box(ppu_decode_tile_line(black_box(low), black_box(high)));
+            }
+        })
+    });
+    group.bench_function("per-bit loop", |b| {
+        b.iter(|| {
+            for &(low, high) in &lines {
+                black_box(decode_tile_line_loop(black_box(low), black_box(high)));
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_decode_tile_line);
+criterion_main!(benches);
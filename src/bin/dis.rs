@@ -16,19 +16,11 @@ fn main() {
 
     // print rom
     // ------------
-    let cart = Cartridge::new(rom_path);
+    let cart = Cartridge::new(rom_path).unwrap_or_else(|e| panic!("{}: {}", rom_path, e));
     let mut mem: Memory = Memory::new();
     mem.load_rom(&cart);
 
-    println!(
-        "{} | size: {} | banks: {} | ram: {} | hw: {} | dst: {}",
-        cart.title(),
-        cart.size(),
-        cart.num_banks(),
-        cart.size_ram(),
-        cart.hardware_type(),
-        cart.destination_code()
-    );
+    println!("{}", cart.summary());
 
     // hex
     // ------------
@@ -0,0 +1,133 @@
+//! Pretty-prints a mem.bin written by dbg::dump, for pasting into bug reports
+//!
+//! usage: memdump <mem.bin> [io] [oam] [maps]  (everything when no sections are given)
+use cerboy::dbg::{io_bits, io_name};
+use cerboy::lcd::*;
+use cerboy::memory::*;
+use cerboy::types::{Byte, Word};
+
+const SECTIONS: [&str; 3] = ["io", "oam", "maps"];
+
+fn print_io(mem: &[Byte]) {
+    println!("io registers");
+    println!("------------");
+    for addr in (MEM_IO_PORTS..MEM_HRAM).chain(std::iter::once(IE)) {
+        let Some(name) = io_name(addr) else {
+            continue;
+        };
+        let val = mem[addr as usize];
+        match io_bits(addr, val) {
+            Some(bits) => println!("{addr:04X} {name:<5} {val:02X}  {bits}"),
+            None => println!("{addr:04X} {name:<5} {val:02X}"),
+        }
+    }
+    println!();
+}
+
+fn print_oam(mem: &[Byte]) {
+    let tall = mem[LCDC as usize] & LCDC_BIT_OBJ_SIZE != 0;
+    println!("oam (8x{})", if tall { 16 } else { 8 });
+    println!("------------");
+    println!(" #    y    x tile flags");
+    for (i, obj) in mem[MEM_OAM as usize..MEM_NOT_USABLE as usize]
+        .chunks(OBJ_ATTR_SIZE as usize)
+        .enumerate()
+    {
+        let (y, x, tile, attr) = (obj[0], obj[1], obj[2], obj[3]);
+        let mut flags = vec![];
+        if attr & OAM_BIT_PRIORITY != 0 {
+            flags.push("behind");
+        }
+        if attr & OAM_BIT_FLIP_Y != 0 {
+            flags.push("flip_y");
+        }
+        if attr & OAM_BIT_FLIP_X != 0 {
+            flags.push("flip_x");
+        }
+        flags.push(if attr & OAM_BIT_DMG_PAL != 0 {
+            "OBP1"
+        } else {
+            "OBP0"
+        });
+        // positions are stored offset so objects can sit partly off screen
+        let hidden = y == 0 || y >= 160 || x == 0 || x >= 168;
+        println!(
+            "{i:2} {:4} {:4}   {tile:02X} {}{}",
+            y as i16 - 16,
+            x as i16 - 8,
+            flags.join(" "),
+            if hidden { " (off screen)" } else { "" }
+        );
+    }
+    println!();
+}
+
+fn print_map(mem: &[Byte], base: Word, users: &str) {
+    println!("tile map {base:04X}{users}");
+    println!("------------");
+    for row in mem[base as usize..base as usize + 0x400].chunks(32) {
+        let line: Vec<String> = row.iter().map(|t| format!("{t:02X}")).collect();
+        println!("{}", line.join(" "));
+    }
+    println!();
+}
+
+fn print_maps(mem: &[Byte]) {
+    let lcdc = mem[LCDC as usize];
+    let map = |bit: Byte| if lcdc & bit != 0 { 0x9C00 } else { 0x9800 };
+    for base in [0x9800, 0x9C00] {
+        let mut users = vec![];
+        if map(LCDC_BIT_BG_TILE_MAP_SELECT) == base {
+            users.push("bg");
+        }
+        if lcdc & LCDC_BIT_WINDOW_ENABLE != 0 && map(LCDC_BIT_WINDOW_TILE_MAP_SELECT) == base {
+            users.push("window");
+        }
+        let users = if users.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", users.join(", "))
+        };
+        print_map(mem, base, &users);
+    }
+    println!(
+        "tile data at {:04X}, scroll ({}, {}), window at ({}, {})",
+        if lcdc & LCDC_BIT_BG_WINDOW_TILE_DATA_SELECT != 0 {
+            0x8000
+        } else {
+            0x8800
+        },
+        mem[SCX as usize],
+        mem[SCY as usize],
+        mem[WX as usize] as i16 - 7,
+        mem[WY as usize]
+    );
+}
+
+fn main() {
+    // arg processing
+    // ---------
+    let args: Vec<String> = std::env::args().collect();
+    assert!(
+        args.len() >= 2,
+        "unexpected number of args (must pass in path to a memory dump)"
+    );
+    let mut sections: Vec<&str> = args[2..].iter().map(|s| s.as_str()).collect();
+    if let Some(bad) = sections.iter().find(|s| !SECTIONS.contains(s)) {
+        panic!("unknown section {} (expected one of {:?})", bad, SECTIONS);
+    }
+    if sections.is_empty() {
+        sections = SECTIONS.to_vec();
+    }
+
+    let mem = std::fs::read(&args[1]).expect("couldn't read the dump");
+    assert_eq!(mem.len(), MEM_SIZE, "a dump covers the whole address space");
+
+    for section in sections {
+        match section {
+            "io" => print_io(&mem),
+            "oam" => print_oam(&mem),
+            _ => print_maps(&mem),
+        }
+    }
+}
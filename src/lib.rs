@@ -3,6 +3,7 @@
 
 pub mod cpu {
     use crate::bits::*;
+    use crate::dbg::StateHash;
     use crate::decode::*;
     use crate::memory::*;
     use crate::types::*;
@@ -61,10 +62,15 @@ pub mod cpu {
         lut[idx as usize]
     }
 
+    /// The shade (0-3) a palette register gives a color index
+    pub fn shade(color: Byte, plt: Byte) -> Byte {
+        (plt >> ((color & 0b11) * 2)) & 0b11
+    }
+
     // https://gbdev.gg8.se/files/docs/mirrors/pandocs.html#lcdstatusregister
     pub const TICKS_PER_OAM_SEARCH: u64 = 80;
-    pub const TICKS_PER_VRAM_IO: u64 = 168; // roughly
-    pub const TICKS_PER_HBLANK: u64 = 208; // roughly
+    pub const TICKS_PER_VRAM_IO: u64 = 172; // at least, scrolling and sprites make it longer
+    pub const TICKS_PER_HBLANK: u64 = 204; // at most, whatever mode 3 leaves of the line
     pub const TICKS_PER_SCANLINE: u64 = TICKS_PER_OAM_SEARCH + TICKS_PER_VRAM_IO + TICKS_PER_HBLANK;
     pub const TICKS_PER_VBLANK: u64 = TICKS_PER_SCANLINE * 10; // 144 on screen + 10 additional lines
     pub const TICKS_PER_FRAME: u64 =
@@ -88,6 +94,15 @@ pub mod cpu {
         op: Byte,
     }
 
+    impl UnknownInstructionError {
+        fn new(op: Byte) -> UnknownInstructionError {
+            UnknownInstructionError {
+                op,
+                mnm: crate::decode::decode(op).mnm,
+            }
+        }
+    }
+
     impl std::fmt::Display for UnknownInstructionError {
         fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
             write!(f, "unknown instruction 0x{:X} ({})", self.op, self.mnm)
@@ -97,7 +112,7 @@ pub mod cpu {
     #[derive(Copy, Clone, Debug)]
     pub struct CPUState {
         // ------------ meta, not part of actual gb hardware but useful
-        pub tsc: u64,        // counting cycles since reset
+        pub tsc: Cycles,     // counting cycles since reset
         pub inst_count: u64, // counting instructions since reset
         pub inst_ei: u64, // timestamp when ei was set, used to keep track of the two-instruction-delay
         // ------------ hardware
@@ -115,7 +130,7 @@ pub mod cpu {
         /// https://gbdev.gg8.se/files/docs/mirrors/pandocs.html#powerupsequence
         pub const fn new() -> CPUState {
             CPUState {
-                tsc: 0,
+                tsc: Cycles::ZERO,
                 inst_count: 0,
                 inst_ei: 0,
                 //    B     C     D     E     H     L     fl    A
@@ -127,6 +142,16 @@ pub mod cpu {
             }
         }
 
+        /// What the cgb boot rom leaves behind instead, A = 0x11 is how games tell they're
+        /// on a cgb
+        pub const fn new_cgb() -> CPUState {
+            CPUState {
+                //    B     C     D     E     H     L     fl    A
+                reg: [0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D, 0x80, 0x11],
+                ..CPUState::new()
+            }
+        }
+
         /// Commonly used for addresses
         ///
         /// Combines the H and L registers into a usize for mem indexing
@@ -159,14 +184,15 @@ pub mod cpu {
         /// Add time to the time stamp counter (tsc)
         ///
         /// Adds some number of cycles to the tsc and return a new state
-        const fn tick(&self, t: u64) -> CPUState {
+        const fn tick(&self, t: Cycles) -> CPUState {
             CPUState {
-                tsc: self.tsc + t,
+                tsc: Cycles::t(self.tsc.as_t() + t.as_t()),
                 ..*self
             }
         }
     }
 
+    #[derive(Copy, Clone, Debug)]
     pub struct HardwareTimers {
         timer: u64,
         divider: u64,
@@ -181,7 +207,27 @@ pub mod cpu {
         }
     }
 
-    pub fn update_clocks(state: HardwareTimers, mem: &mut Memory, cycles: u64) -> HardwareTimers {
+    impl HardwareTimers {
+        /// Cycles until DIV or TIMA next ticks over
+        pub fn cycles_until_event(&self, mem: &Memory) -> Cycles {
+            let div = TICKS_PER_DIV_INC.saturating_sub(self.divider);
+            Cycles::t(match tac_cycles_per_inc(mem) {
+                Ok(cpi) if tac_enabled(mem) => div.min(cpi.saturating_sub(self.timer)),
+                _ => div,
+            })
+        }
+
+        pub fn hash_state(&self, h: &mut StateHash) {
+            h.u64(self.timer);
+            h.u64(self.divider);
+        }
+    }
+
+    pub fn update_clocks(
+        state: HardwareTimers,
+        mem: &mut Memory,
+        cycles: Cycles,
+    ) -> HardwareTimers {
         // todo: If a TMA write is executed on the same cycle as the content
         // of TMA is transferred to TIMA due to a timer overflow,
         // the old value is transferred to TIMA.
@@ -189,8 +235,8 @@ pub mod cpu {
         // note: this implies you should save this value before executing the instruction
         // todo:
         let mut result = HardwareTimers {
-            timer: state.timer + cycles,
-            divider: state.divider + cycles,
+            timer: state.timer + cycles.as_t(),
+            divider: state.divider + cycles.as_t(),
         };
 
         while result.divider >= TICKS_PER_DIV_INC {
@@ -220,119 +266,123 @@ pub mod cpu {
     }
 
     pub fn next(cpu: CPUState, mem: &mut Memory) -> Result<CPUState, UnknownInstructionError> {
-        // fetch and execute
+        // fetch
         // -----------------
         let pc = cpu.pc;
+        let op = mem.read_fetch(pc);
+        let args = [
+            mem.read_fetch(pc.wrapping_add(1)),
+            mem.read_fetch(pc.wrapping_add(2)),
+        ];
+        // cerboy::decode::print_op(op);
+        next_fetched(cpu, mem, op, args)
+    }
+
+    /// next() with the opcode and the two bytes after it already read from pc
+    ///
+    /// The cached interpreter (see emu::BlockCache) fetches ahead of time and comes in here.
+    pub fn next_fetched(
+        cpu: CPUState,
+        mem: &mut Memory,
+        op: Byte,
+        args: [Byte; 2],
+    ) -> Result<CPUState, UnknownInstructionError> {
+        // check interrupts
+        // -----------------
+        if interrupt_pending(&cpu, mem) {
+            // nothing else is running alongside, so the vector's picked from IF as it is now
+            return Ok(dispatch_interrupt_end(
+                dispatch_interrupt_begin(cpu, mem),
+                mem,
+            ));
+        }
+
+        // execute
+        // -----------------
         let cpu = CPUState {
             inst_count: cpu.inst_count + 1,
             ..cpu
         }; // referenced by interrupt enabling instructions
-        let op = mem.read(pc);
-        // cerboy::decode::print_op(op);
 
         // todo; inst count is not the same as tick, halt state makes this above incorrect
 
-        // check interrupts
-        // -----------------
-        // https://gbdev.io/pandocs/single.html#ime-interrupt-master-enable-flag-write-only
-        // The effect of EI is delayed by one instruction.
-        // This means that EI followed immediately by DI does not
-        // allow interrupts between the EI and the DI.
-        let ei_valid_delay = (cpu.inst_count - cpu.inst_ei) > 1;
-        let enabled_flags = mem.read(IE) & mem.read(IF);
-
         // possibly unhalt the cpu
-        let cpu = if enabled_flags != 0 {
+        let cpu = if mem.enabled_interrupts() != 0 {
             CPUState { halt: false, ..cpu }
         } else {
             cpu
         };
 
-        if cpu.ime && ei_valid_delay && enabled_flags != 0 {
-            if (enabled_flags & FL_INT_VBLANK) != 0 {
-                Ok(jump_to_int_vec(cpu, mem, FL_INT_VBLANK, VEC_INT_VBLANK))
-            } else if (enabled_flags & FL_INT_STAT) != 0 {
-                Ok(jump_to_int_vec(cpu, mem, FL_INT_STAT, VEC_INT_STAT))
-            } else if (enabled_flags & FL_INT_TIMER) != 0 {
-                Ok(jump_to_int_vec(cpu, mem, FL_INT_TIMER, VEC_INT_TIMER))
-            } else if (enabled_flags & FL_INT_SERIAL) != 0 {
-                Ok(jump_to_int_vec(cpu, mem, FL_INT_SERIAL, VEC_INT_SERIAL))
-            } else if (enabled_flags & FL_INT_JOYPAD) != 0 {
-                Ok(jump_to_int_vec(cpu, mem, FL_INT_JOYPAD, VEC_INT_JOYPAD))
-            } else {
-                panic!("interrupt enabled but unknown flag?")
-            }
-        } else if cpu.halt {
+        if cpu.halt {
             // halted, just pass the time
-            Ok(cpu.tick(4))
+            Ok(cpu.tick(Cycles::t(4)))
         } else {
             // todo: is this correct? I'm assuming it can't handle an interrupt
             // and then go right into the next instruction, it's one or the other
-            let inst = crate::decode::decode(op);
             match op {
                 0x00 => Ok(nop(cpu)),
-                0x01 => Ok(ld_bc_d16(cpu, mem.read(pc + 1), mem.read(pc + 2))),
+                0x01 => Ok(ld_bc_d16(cpu, args[0], args[1])),
                 0x02 => Ok(ld_BC_a(cpu, mem)),
                 0x03 => Ok(inc_bc(cpu)),
                 0x04 => Ok(inc_b(cpu)),
                 0x05 => Ok(dec_b(cpu)),
-                0x06 => Ok(ld_b_d8(cpu, mem.read(pc + 1))),
+                0x06 => Ok(ld_b_d8(cpu, args[0])),
                 0x07 => Ok(rlca(cpu)),
-                0x08 => Ok(ld_A16_sp(mem.read(pc + 1), mem.read(pc + 2), cpu, mem)),
+                0x08 => Ok(ld_A16_sp(args[0], args[1], cpu, mem)),
                 0x09 => Ok(add_hl_bc(cpu)),
                 0x0A => Ok(ld_a_BC(cpu, &mem)),
                 0x0B => Ok(dec_bc(cpu)),
                 0x0C => Ok(inc_c(cpu)),
                 0x0D => Ok(dec_c(cpu)),
-                0x0E => Ok(ld_c_d8(cpu, mem.read(pc + 1))),
+                0x0E => Ok(ld_c_d8(cpu, args[0])),
                 0x0F => Ok(rrca(cpu)),
                 0x10 => Ok(stop(cpu)),
-                0x11 => Ok(ld_de_d16(cpu, mem.read(pc + 1), mem.read(pc + 2))),
+                0x11 => Ok(ld_de_d16(cpu, args[0], args[1])),
                 0x12 => Ok(ld_DE_a(cpu, mem)),
                 0x13 => Ok(inc_de(cpu)),
                 0x14 => Ok(inc_d(cpu)),
                 0x15 => Ok(dec_d(cpu)),
-                0x16 => Ok(ld_d_d8(cpu, mem.read(pc + 1))),
+                0x16 => Ok(ld_d_d8(cpu, args[0])),
                 0x17 => Ok(rla(cpu)),
-                0x18 => Ok(jr_r8(cpu, signed(mem.read(pc + 1)))),
+                0x18 => Ok(jr_r8(cpu, signed(args[0]))),
                 0x19 => Ok(add_hl_de(cpu)),
                 0x1A => Ok(ld_a_DE(cpu, &mem)),
                 0x1B => Ok(dec_de(cpu)),
                 0x1C => Ok(inc_e(cpu)),
                 0x1D => Ok(dec_e(cpu)),
-                0x1E => Ok(ld_e_d8(cpu, mem.read(pc + 1))),
+                0x1E => Ok(ld_e_d8(cpu, args[0])),
                 0x1F => Ok(rra(cpu)),
-                0x20 => Ok(jr_nz_r8(cpu, signed(mem.read(pc + 1)))),
-                0x21 => Ok(ld_hl_d16(cpu, mem.read(pc + 1), mem.read(pc + 2))),
+                0x20 => Ok(jr_nz_r8(cpu, signed(args[0]))),
+                0x21 => Ok(ld_hl_d16(cpu, args[0], args[1])),
                 0x22 => Ok(ldi_HL_a(cpu, mem)),
                 0x23 => Ok(inc_hl(cpu)),
                 0x24 => Ok(inc_h(cpu)),
                 0x25 => Ok(dec_h(cpu)),
-                0x26 => Ok(ld_h_d8(cpu, mem.read(pc + 1))),
+                0x26 => Ok(ld_h_d8(cpu, args[0])),
                 0x27 => Ok(daa(cpu)),
-                0x28 => Ok(jr_z_r8(cpu, signed(mem.read(pc + 1)))),
+                0x28 => Ok(jr_z_r8(cpu, signed(args[0]))),
                 0x29 => Ok(add_hl_hl(cpu)),
                 0x2A => Ok(ldi_a_HL(cpu, mem)),
                 0x2B => Ok(dec_hl(cpu)),
                 0x2C => Ok(inc_l(cpu)),
                 0x2D => Ok(dec_l(cpu)),
-                0x2E => Ok(ld_l_d8(cpu, mem.read(pc + 1))),
+                0x2E => Ok(ld_l_d8(cpu, args[0])),
                 0x2F => Ok(cpl(cpu)),
-                0x30 => Ok(jr_nc_r8(cpu, signed(mem.read(pc + 1)))),
-                0x31 => Ok(ld_sp_d16(cpu, mem.read(pc + 1), mem.read(pc + 2))),
+                0x30 => Ok(jr_nc_r8(cpu, signed(args[0]))),
+                0x31 => Ok(ld_sp_d16(cpu, args[0], args[1])),
                 0x32 => Ok(ldd_HL_a(cpu, mem)),
                 0x33 => Ok(inc_sp(cpu)),
                 0x34 => Ok(inc_HL(cpu, mem)),
                 0x35 => Ok(dec_HL(cpu, mem)),
-                0x36 => Ok(ld_HL_d8(cpu, mem.read(pc + 1), mem)),
+                0x36 => Ok(ld_HL_d8(cpu, args[0], mem)),
                 0x37 => Ok(scf(cpu)),
-                0x38 => Ok(jr_c_r8(cpu, signed(mem.read(pc + 1)))),
+                0x38 => Ok(jr_c_r8(cpu, signed(args[0]))),
                 0x39 => Ok(add_hl_sp(cpu)),
                 0x3A => Ok(ldd_a_HL(cpu, mem)),
                 0x3B => Ok(dec_sp(cpu)),
                 0x3C => Ok(inc_a(cpu)),
                 0x3D => Ok(dec_a(cpu)),
-                0x3E => Ok(ld_a_d8(cpu, mem.read(pc + 1))),
+                0x3E => Ok(ld_a_d8(cpu, args[0])),
                 0x3F => Ok(ccf(cpu)),
                 0x40..=0x7F => match op {
                     0x46 => Ok(ld_b_HL(cpu, &mem)),
@@ -368,23 +418,17 @@ pub mod cpu {
                 }
                 0xC0 => Ok(ret_nz(cpu, &mem)),
                 0xC1 => Ok(pop_bc(cpu, &mem)),
-                0xC2 => Ok(jp_f_d16(cpu, mem.read(pc + 1), mem.read(pc + 2), 0xC2)),
-                0xC3 => Ok(jp_d16(cpu, mem.read(pc + 1), mem.read(pc + 2))),
-                0xC4 => Ok(call_f_d16(
-                    mem.read(pc + 1),
-                    mem.read(pc + 2),
-                    cpu,
-                    mem,
-                    0xC4,
-                )),
+                0xC2 => Ok(jp_f_d16(cpu, args[0], args[1], 0xC2)),
+                0xC3 => Ok(jp_d16(cpu, args[0], args[1])),
+                0xC4 => Ok(call_f_d16(args[0], args[1], cpu, mem, 0xC4)),
                 0xC5 => Ok(push_bc(cpu, mem)),
-                0xC6 => Ok(add_d8(cpu, mem.read(pc + 1))),
+                0xC6 => Ok(add_d8(cpu, args[0])),
                 0xC7 => Ok(rst_n(cpu, mem, 0xC7)),
                 0xC8 => Ok(ret_z(cpu, &mem)),
                 0xC9 => Ok(ret(cpu, &mem)),
-                0xCA => Ok(jp_f_d16(cpu, mem.read(pc + 1), mem.read(pc + 2), 0xCA)),
+                0xCA => Ok(jp_f_d16(cpu, args[0], args[1], 0xCA)),
                 0xCB => {
-                    let op_cb = mem.read(pc + 1);
+                    let op_cb = args[0];
                     let icb = decodeCB(op_cb);
                     if icb.reg == ADR_HL {
                         match icb.opcode {
@@ -418,76 +462,51 @@ pub mod cpu {
                         }
                     }
                 }
-                0xCC => Ok(call_f_d16(
-                    mem.read(pc + 1),
-                    mem.read(pc + 2),
-                    cpu,
-                    mem,
-                    0xCC,
-                )),
-                0xCD => Ok(call_d16(mem.read(pc + 1), mem.read(pc + 2), cpu, mem)),
-                0xCE => Ok(adc_d8(cpu, mem.read(pc + 1))),
+                0xCC => Ok(call_f_d16(args[0], args[1], cpu, mem, 0xCC)),
+                0xCD => Ok(call_d16(args[0], args[1], cpu, mem)),
+                0xCE => Ok(adc_d8(cpu, args[0])),
                 0xCF => Ok(rst_n(cpu, mem, 0xCF)),
                 0xD0 => Ok(ret_nc(cpu, &mem)),
                 0xD1 => Ok(pop_de(cpu, &mem)),
-                0xD2 => Ok(jp_f_d16(cpu, mem.read(pc + 1), mem.read(pc + 2), 0xD2)),
-                0xD3 => Err(UnknownInstructionError { op, mnm: inst.mnm }),
-                0xD4 => Ok(call_f_d16(
-                    mem.read(pc + 1),
-                    mem.read(pc + 2),
-                    cpu,
-                    mem,
-                    0xD4,
-                )),
+                0xD2 => Ok(jp_f_d16(cpu, args[0], args[1], 0xD2)),
+                0xD4 => Ok(call_f_d16(args[0], args[1], cpu, mem, 0xD4)),
                 0xD5 => Ok(push_de(cpu, mem)),
-                0xD6 => Ok(sub_d8(cpu, mem.read(pc + 1))),
+                0xD6 => Ok(sub_d8(cpu, args[0])),
                 0xD7 => Ok(rst_n(cpu, mem, 0xD7)),
                 0xD8 => Ok(ret_c(cpu, &mem)),
                 0xD9 => Ok(reti(cpu, &mem)),
-                0xDA => Ok(jp_f_d16(cpu, mem.read(pc + 1), mem.read(pc + 2), 0xDA)),
-                0xDB => Err(UnknownInstructionError { op, mnm: inst.mnm }),
-                0xDC => Ok(call_f_d16(
-                    mem.read(pc + 1),
-                    mem.read(pc + 2),
-                    cpu,
-                    mem,
-                    0xDC,
-                )),
-                0xDD => Err(UnknownInstructionError { op, mnm: inst.mnm }),
-                0xDE => Ok(sbc_d8(cpu, mem.read(pc + 1))),
+                0xDA => Ok(jp_f_d16(cpu, args[0], args[1], 0xDA)),
+                0xDC => Ok(call_f_d16(args[0], args[1], cpu, mem, 0xDC)),
+                0xDE => Ok(sbc_d8(cpu, args[0])),
                 0xDF => Ok(rst_n(cpu, mem, 0xDF)),
-                0xE0 => Ok(ld_FF00_A8_a(mem.read(pc + 1), cpu, mem)),
+                0xE0 => Ok(ld_FF00_A8_a(args[0], cpu, mem)),
                 0xE1 => Ok(pop_hl(cpu, &mem)),
                 0xE2 => Ok(ld_FF00_C_a(cpu, mem)),
-                0xE3 => Err(UnknownInstructionError { op, mnm: inst.mnm }),
-                0xE4 => Err(UnknownInstructionError { op, mnm: inst.mnm }),
                 0xE5 => Ok(push_hl(cpu, mem)),
-                0xE6 => Ok(and_d8(cpu, mem.read(pc + 1))),
+                0xE6 => Ok(and_d8(cpu, args[0])),
                 0xE7 => Ok(rst_n(cpu, mem, 0xE7)),
-                0xE8 => Ok(add_sp_r8(cpu, signed(mem.read(pc + 1)))),
+                0xE8 => Ok(add_sp_r8(cpu, signed(args[0]))),
                 0xE9 => Ok(jp_hl(cpu)),
-                0xEA => Ok(ld_A16_a(mem.read(pc + 1), mem.read(pc + 2), cpu, mem)),
-                0xEB => Err(UnknownInstructionError { op, mnm: inst.mnm }),
-                0xEC => Err(UnknownInstructionError { op, mnm: inst.mnm }),
-                0xED => Err(UnknownInstructionError { op, mnm: inst.mnm }),
-                0xEE => Ok(xor_d8(cpu, mem.read(pc + 1))),
+                0xEA => Ok(ld_A16_a(args[0], args[1], cpu, mem)),
+                0xEE => Ok(xor_d8(cpu, args[0])),
                 0xEF => Ok(rst_n(cpu, mem, 0xEF)),
-                0xF0 => Ok(ld_a_FF00_A8(cpu, &mem, mem.read(pc + 1))),
+                0xF0 => Ok(ld_a_FF00_A8(cpu, &mem, args[0])),
                 0xF1 => Ok(pop_af(cpu, &mem)),
                 0xF2 => Ok(ld_a_FF00_C(cpu, &mem)),
                 0xF3 => Ok(di(cpu)),
-                0xF4 => Err(UnknownInstructionError { op, mnm: inst.mnm }),
                 0xF5 => Ok(push_af(cpu, mem)),
-                0xF6 => Ok(or_d8(cpu, mem.read(pc + 1))),
+                0xF6 => Ok(or_d8(cpu, args[0])),
                 0xF7 => Ok(rst_n(cpu, mem, 0xF7)),
-                0xF8 => Ok(ld_hl_sp_r8(cpu, signed(mem.read(pc + 1)))),
+                0xF8 => Ok(ld_hl_sp_r8(cpu, signed(args[0]))),
                 0xF9 => Ok(ld_sp_hl(cpu)),
-                0xFA => Ok(ld_a_A16(mem.read(pc + 1), mem.read(pc + 2), cpu, &mem)),
+                0xFA => Ok(ld_a_A16(args[0], args[1], cpu, &mem)),
                 0xFB => Ok(ei(cpu)),
-                0xFC => Err(UnknownInstructionError { op, mnm: inst.mnm }),
-                0xFD => Err(UnknownInstructionError { op, mnm: inst.mnm }),
-                0xFE => Ok(cp_d8(cpu, mem.read(pc + 1))),
+                0xFE => Ok(cp_d8(cpu, args[0])),
                 0xFF => Ok(rst_n(cpu, mem, 0xFF)),
+                // the holes in the opcode table
+                0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => {
+                    Err(UnknownInstructionError::new(op))
+                }
             }
         }
     }
@@ -511,31 +530,31 @@ pub mod cpu {
         let src_idx = opcode % 0x08;
         impl_ld_r_d8(cpu, R_ID[dst_idx as usize], cpu.reg[R_ID[src_idx as usize]])
             .adv_pc(1)
-            .tick(4)
+            .tick(Cycles::t(4))
     }
 
     //   ld   r,n         xx nn      8 ---- r=n
     // ----------------------------------------------------------------------------
     const fn ld_b_d8(cpu: CPUState, d8: Byte) -> CPUState {
-        impl_ld_r_d8(cpu, REG_B, d8).adv_pc(2).tick(8)
+        impl_ld_r_d8(cpu, REG_B, d8).adv_pc(2).tick(Cycles::t(8))
     }
     const fn ld_c_d8(cpu: CPUState, d8: Byte) -> CPUState {
-        impl_ld_r_d8(cpu, REG_C, d8).adv_pc(2).tick(8)
+        impl_ld_r_d8(cpu, REG_C, d8).adv_pc(2).tick(Cycles::t(8))
     }
     const fn ld_d_d8(cpu: CPUState, d8: Byte) -> CPUState {
-        impl_ld_r_d8(cpu, REG_D, d8).adv_pc(2).tick(8)
+        impl_ld_r_d8(cpu, REG_D, d8).adv_pc(2).tick(Cycles::t(8))
     }
     const fn ld_e_d8(cpu: CPUState, d8: Byte) -> CPUState {
-        impl_ld_r_d8(cpu, REG_E, d8).adv_pc(2).tick(8)
+        impl_ld_r_d8(cpu, REG_E, d8).adv_pc(2).tick(Cycles::t(8))
     }
     const fn ld_h_d8(cpu: CPUState, d8: Byte) -> CPUState {
-        impl_ld_r_d8(cpu, REG_H, d8).adv_pc(2).tick(8)
+        impl_ld_r_d8(cpu, REG_H, d8).adv_pc(2).tick(Cycles::t(8))
     }
     const fn ld_l_d8(cpu: CPUState, d8: Byte) -> CPUState {
-        impl_ld_r_d8(cpu, REG_L, d8).adv_pc(2).tick(8)
+        impl_ld_r_d8(cpu, REG_L, d8).adv_pc(2).tick(Cycles::t(8))
     }
     const fn ld_a_d8(cpu: CPUState, d8: Byte) -> CPUState {
-        impl_ld_r_d8(cpu, REG_A, d8).adv_pc(2).tick(8)
+        impl_ld_r_d8(cpu, REG_A, d8).adv_pc(2).tick(Cycles::t(8))
     }
 
     //   ld   r,(HL)      xx         8 ---- r=(HL)
@@ -543,67 +562,81 @@ pub mod cpu {
     fn ld_b_HL(cpu: CPUState, mem: &Memory) -> CPUState {
         impl_ld_r_d8(cpu, REG_B, mem.read(cpu.HL()))
             .adv_pc(1)
-            .tick(8)
+            .tick(Cycles::t(8))
     }
     fn ld_c_HL(cpu: CPUState, mem: &Memory) -> CPUState {
         impl_ld_r_d8(cpu, REG_C, mem.read(cpu.HL()))
             .adv_pc(1)
-            .tick(8)
+            .tick(Cycles::t(8))
     }
     fn ld_d_HL(cpu: CPUState, mem: &Memory) -> CPUState {
         impl_ld_r_d8(cpu, REG_D, mem.read(cpu.HL()))
             .adv_pc(1)
-            .tick(8)
+            .tick(Cycles::t(8))
     }
     fn ld_e_HL(cpu: CPUState, mem: &Memory) -> CPUState {
         impl_ld_r_d8(cpu, REG_E, mem.read(cpu.HL()))
             .adv_pc(1)
-            .tick(8)
+            .tick(Cycles::t(8))
     }
     fn ld_h_HL(cpu: CPUState, mem: &Memory) -> CPUState {
         impl_ld_r_d8(cpu, REG_H, mem.read(cpu.HL()))
             .adv_pc(1)
-            .tick(8)
+            .tick(Cycles::t(8))
     }
     fn ld_l_HL(cpu: CPUState, mem: &Memory) -> CPUState {
         impl_ld_r_d8(cpu, REG_L, mem.read(cpu.HL()))
             .adv_pc(1)
-            .tick(8)
+            .tick(Cycles::t(8))
     }
     fn ld_a_HL(cpu: CPUState, mem: &Memory) -> CPUState {
         impl_ld_r_d8(cpu, REG_A, mem.read(cpu.HL()))
             .adv_pc(1)
-            .tick(8)
+            .tick(Cycles::t(8))
     }
 
     //   ld   (HL),r      7x         8 ---- (HL)=r
     // ----------------------------------------------------------------------------
     fn ld_HL_b(cpu: CPUState, mem: &mut Memory) -> CPUState {
-        impl_ld_HL_d8(cpu, mem, cpu.reg[REG_B]).adv_pc(1).tick(8)
+        impl_ld_HL_d8(cpu, mem, cpu.reg[REG_B])
+            .adv_pc(1)
+            .tick(Cycles::t(8))
     }
     fn ld_HL_c(cpu: CPUState, mem: &mut Memory) -> CPUState {
-        impl_ld_HL_d8(cpu, mem, cpu.reg[REG_C]).adv_pc(1).tick(8)
+        impl_ld_HL_d8(cpu, mem, cpu.reg[REG_C])
+            .adv_pc(1)
+            .tick(Cycles::t(8))
     }
     fn ld_HL_d(cpu: CPUState, mem: &mut Memory) -> CPUState {
-        impl_ld_HL_d8(cpu, mem, cpu.reg[REG_D]).adv_pc(1).tick(8)
+        impl_ld_HL_d8(cpu, mem, cpu.reg[REG_D])
+            .adv_pc(1)
+            .tick(Cycles::t(8))
     }
     fn ld_HL_e(cpu: CPUState, mem: &mut Memory) -> CPUState {
-        impl_ld_HL_d8(cpu, mem, cpu.reg[REG_E]).adv_pc(1).tick(8)
+        impl_ld_HL_d8(cpu, mem, cpu.reg[REG_E])
+            .adv_pc(1)
+            .tick(Cycles::t(8))
     }
     fn ld_HL_h(cpu: CPUState, mem: &mut Memory) -> CPUState {
-        impl_ld_HL_d8(cpu, mem, cpu.reg[REG_H]).adv_pc(1).tick(8)
+        impl_ld_HL_d8(cpu, mem, cpu.reg[REG_H])
+            .adv_pc(1)
+            .tick(Cycles::t(8))
     }
     fn ld_HL_l(cpu: CPUState, mem: &mut Memory) -> CPUState {
-        impl_ld_HL_d8(cpu, mem, cpu.reg[REG_L]).adv_pc(1).tick(8)
+        impl_ld_HL_d8(cpu, mem, cpu.reg[REG_L])
+            .adv_pc(1)
+            .tick(Cycles::t(8))
     }
     fn ld_HL_a(cpu: CPUState, mem: &mut Memory) -> CPUState {
-        impl_ld_HL_d8(cpu, mem, cpu.reg[REG_A]).adv_pc(1).tick(8)
+        impl_ld_HL_d8(cpu, mem, cpu.reg[REG_A])
+            .adv_pc(1)
+            .tick(Cycles::t(8))
     }
 
     //   ld   (HL),n      36 nn     12 ----
     // ----------------------------------------------------------------------------
     fn ld_HL_d8(cpu: CPUState, val: Byte, mem: &mut Memory) -> CPUState {
-        impl_ld_HL_d8(cpu, mem, val).adv_pc(2).tick(12)
+        impl_ld_HL_d8(cpu, mem, val).adv_pc(2).tick(Cycles::t(12))
     }
 
     //   ld   A,(BC)      0A         8 ----
@@ -613,10 +646,10 @@ pub mod cpu {
         reg[REG_A] = mem.read(cpu.BC());
         CPUState {
             pc: cpu.pc + 1,
-            tsc: cpu.tsc + 8,
             reg,
             ..cpu
         }
+        .tick(Cycles::t(8))
     }
 
     //   ld   A,(DE)      1A         8 ----
@@ -626,10 +659,10 @@ pub mod cpu {
         reg[REG_A] = mem.read(cpu.DE());
         CPUState {
             pc: cpu.pc + 1,
-            tsc: cpu.tsc + 8,
             reg,
             ..cpu
         }
+        .tick(Cycles::t(8))
     }
 
     //   ld   A,(nn)      FA nn nn        16 ----
@@ -638,7 +671,7 @@ pub mod cpu {
         let mut reg = cpu.reg;
         reg[REG_A] = mem.read(combine(high, low));
 
-        CPUState { reg, ..cpu }.tick(16).adv_pc(3)
+        CPUState { reg, ..cpu }.tick(Cycles::t(16)).adv_pc(3)
     }
 
     //   ld   (BC),A      02         8 ----
@@ -647,9 +680,9 @@ pub mod cpu {
         mem.write(cpu.BC(), cpu.reg[REG_A]);
         CPUState {
             pc: cpu.pc + 1,
-            tsc: cpu.tsc + 8,
             ..cpu
         }
+        .tick(Cycles::t(8))
     }
 
     //   ld   (DE),A      12         8 ----
@@ -658,9 +691,9 @@ pub mod cpu {
         mem.write(cpu.DE(), cpu.reg[REG_A]);
         CPUState {
             pc: cpu.pc + 1,
-            tsc: cpu.tsc + 8,
             ..cpu
         }
+        .tick(Cycles::t(8))
     }
 
     //   ld   (nn),A      EA nn nn        16 ----
@@ -669,7 +702,7 @@ pub mod cpu {
         let addr = combine(high, low);
         mem.write(addr, cpu.reg[REG_A]);
 
-        cpu.tick(16).adv_pc(3)
+        cpu.tick(Cycles::t(16)).adv_pc(3)
     }
 
     //   ld   (nn),SP      08 nn nn        20 ----
@@ -679,7 +712,7 @@ pub mod cpu {
         mem.write(addr + 1, hi(cpu.sp));
         mem.write(addr + 0, lo(cpu.sp));
 
-        cpu.tick(20).adv_pc(3)
+        cpu.tick(Cycles::t(20)).adv_pc(3)
     }
 
     //   ld   A,(FF00+n)  F0 nn     12 ---- read from io-port n (memory FF00+n)
@@ -689,10 +722,10 @@ pub mod cpu {
         reg[REG_A] = mem.read(MEM_IO_PORTS + off as Word);
         CPUState {
             pc: cpu.pc + 2,
-            tsc: cpu.tsc + 12,
             reg,
             ..cpu
         }
+        .tick(Cycles::t(12))
     }
 
     //   ld   (FF00+n),A  E0 nn     12 ---- write to io-port n (memory FF00+n)
@@ -701,9 +734,9 @@ pub mod cpu {
         mem.write(MEM_IO_PORTS + off as Word, cpu.reg[REG_A]);
         CPUState {
             pc: cpu.pc + 2,
-            tsc: cpu.tsc + 12,
             ..cpu
         }
+        .tick(Cycles::t(12))
     }
 
     //   ld   A,(FF00+C)  F2         8 ---- read from io-port C (memory FF00+C)
@@ -713,10 +746,10 @@ pub mod cpu {
         reg[REG_A] = mem.read(MEM_IO_PORTS + reg[REG_C] as Word);
         CPUState {
             pc: cpu.pc + 1,
-            tsc: cpu.tsc + 8,
             reg,
             ..cpu
         }
+        .tick(Cycles::t(8))
     }
 
     //   ld   (FF00+C),A  E2         8 ---- write to io-port C (memory FF00+C)
@@ -725,9 +758,9 @@ pub mod cpu {
         mem.write(MEM_IO_PORTS + cpu.reg[REG_C] as Word, cpu.reg[REG_A]);
         CPUState {
             pc: cpu.pc + 1,
-            tsc: cpu.tsc + 8,
             ..cpu
         }
+        .tick(Cycles::t(8))
     }
 
     //   ldi  (HL),A      22         8 ---- (HL)=A, HL=HL+1
@@ -742,10 +775,10 @@ pub mod cpu {
 
         CPUState {
             pc: cpu.pc + 1,
-            tsc: cpu.tsc + 8,
             reg,
             ..cpu
         }
+        .tick(Cycles::t(8))
     }
 
     //   ldi  A,(HL)      2A         8 ---- A=(HL), HL=HL+1
@@ -760,10 +793,10 @@ pub mod cpu {
 
         CPUState {
             pc: cpu.pc + 1,
-            tsc: cpu.tsc + 8,
             reg,
             ..cpu
         }
+        .tick(Cycles::t(8))
     }
 
     //   ldd  (HL),A      32         8 ---- (HL)=A, HL=HL-1
@@ -778,10 +811,10 @@ pub mod cpu {
 
         CPUState {
             pc: cpu.pc + 1,
-            tsc: cpu.tsc + 8,
             reg,
             ..cpu
         }
+        .tick(Cycles::t(8))
     }
 
     //   ldd  A,(HL)      3A         8 ---- A=(HL), HL=HL-1
@@ -796,10 +829,10 @@ pub mod cpu {
 
         CPUState {
             pc: cpu.pc + 1,
-            tsc: cpu.tsc + 8,
             reg,
             ..cpu
         }
+        .tick(Cycles::t(8))
     }
 
     // GMB 16bit-Loadcommands
@@ -822,9 +855,9 @@ pub mod cpu {
         let cpu_pushed = push_d16(cpu, mem, val);
         CPUState {
             pc: cpu.pc + 1,
-            tsc: cpu.tsc + 16,
             ..cpu_pushed
         }
+        .tick(Cycles::t(16))
     }
 
     fn impl_pop_rr(cpu: CPUState, mem: &Memory, reg_hi: usize, reg_lo: usize) -> CPUState {
@@ -840,10 +873,10 @@ pub mod cpu {
 
         CPUState {
             pc: cpu.pc + 1,
-            tsc: cpu.tsc + 12,
             reg,
             ..cpu_popped
         }
+        .tick(Cycles::t(12))
     }
 
     //   ld   rr,nn       x1 nn nn  12 ---- rr=nn (rr may be BC,DE,HL or SP)
@@ -851,25 +884,25 @@ pub mod cpu {
     const fn ld_bc_d16(cpu: CPUState, low: Byte, high: Byte) -> CPUState {
         impl_ld_rr_d16(cpu, REG_B, REG_C, high, low)
             .adv_pc(3)
-            .tick(12)
+            .tick(Cycles::t(12))
     }
     const fn ld_de_d16(cpu: CPUState, low: Byte, high: Byte) -> CPUState {
         impl_ld_rr_d16(cpu, REG_D, REG_E, high, low)
             .adv_pc(3)
-            .tick(12)
+            .tick(Cycles::t(12))
     }
     const fn ld_hl_d16(cpu: CPUState, low: Byte, high: Byte) -> CPUState {
         impl_ld_rr_d16(cpu, REG_H, REG_L, high, low)
             .adv_pc(3)
-            .tick(12)
+            .tick(Cycles::t(12))
     }
     const fn ld_sp_d16(cpu: CPUState, low: Byte, high: Byte) -> CPUState {
         CPUState {
             pc: cpu.pc + 3,
-            tsc: cpu.tsc + 12,
             sp: combine(high, low),
             ..cpu
         }
+        .tick(Cycles::t(12))
     }
 
     //   ld   SP,HL       F9         8 ---- SP=HL
@@ -879,7 +912,7 @@ pub mod cpu {
             sp: cpu.HL(),
             ..cpu
         }
-        .tick(8)
+        .tick(Cycles::t(8))
         .adv_pc(1)
     }
 
@@ -1096,49 +1129,59 @@ pub mod cpu {
     //   add  A,r         8x         4 z0hc A=A+r
     // ----------------------------------------------------------------------------
     const fn add_r(cpu: CPUState, src: usize) -> CPUState {
-        impl_add_sub(cpu, cpu.reg[src], 0).adv_pc(1).tick(4)
+        impl_add_sub(cpu, cpu.reg[src], 0)
+            .adv_pc(1)
+            .tick(Cycles::t(4))
     }
 
     //   add  A,n         C6 nn      8 z0hc A=A+n
     // ----------------------------------------------------------------------------
     const fn add_d8(cpu: CPUState, d8: Byte) -> CPUState {
-        impl_add_sub(cpu, d8, 0).adv_pc(2).tick(8)
+        impl_add_sub(cpu, d8, 0).adv_pc(2).tick(Cycles::t(8))
     }
 
     //   add  A,(HL)      86         8 z0hc A=A+(HL)
     // ----------------------------------------------------------------------------
     fn add_HL(cpu: CPUState, mem: &Memory) -> CPUState {
-        impl_add_sub(cpu, mem.read(cpu.HL()), 0).adv_pc(1).tick(8)
+        impl_add_sub(cpu, mem.read(cpu.HL()), 0)
+            .adv_pc(1)
+            .tick(Cycles::t(8))
     }
 
     //   adc  A,r         8x         4 z0hc A=A+r+cy
     // ----------------------------------------------------------------------------
     const fn adc_r(cpu: CPUState, src: usize) -> CPUState {
-        impl_adc_sbc(cpu, cpu.reg[src], 0).adv_pc(1).tick(4)
+        impl_adc_sbc(cpu, cpu.reg[src], 0)
+            .adv_pc(1)
+            .tick(Cycles::t(4))
     }
 
     //   adc  A,n         CE nn      8 z0hc A=A+n+cy
     // ----------------------------------------------------------------------------
     const fn adc_d8(cpu: CPUState, d8: Byte) -> CPUState {
-        impl_adc_sbc(cpu, d8, 0).adv_pc(2).tick(8)
+        impl_adc_sbc(cpu, d8, 0).adv_pc(2).tick(Cycles::t(8))
     }
 
     //   adc  A,(HL)      8E         8 z0hc A=A+(HL)+cy
     // ----------------------------------------------------------------------------
     fn adc_HL(cpu: CPUState, mem: &Memory) -> CPUState {
-        impl_adc_sbc(cpu, mem.read(cpu.HL()), 0).adv_pc(1).tick(8)
+        impl_adc_sbc(cpu, mem.read(cpu.HL()), 0)
+            .adv_pc(1)
+            .tick(Cycles::t(8))
     }
 
     //   sub  r           9x         4 z1hc A=A-r
     // ----------------------------------------------------------------------------
     const fn sub_r(cpu: CPUState, src: usize) -> CPUState {
-        impl_add_sub(cpu, cpu.reg[src], FL_N).adv_pc(1).tick(4)
+        impl_add_sub(cpu, cpu.reg[src], FL_N)
+            .adv_pc(1)
+            .tick(Cycles::t(4))
     }
 
     //   sub  n           D6 nn      8 z1hc A=A-n
     // ----------------------------------------------------------------------------
     const fn sub_d8(cpu: CPUState, d8: Byte) -> CPUState {
-        impl_add_sub(cpu, d8, FL_N).adv_pc(2).tick(8)
+        impl_add_sub(cpu, d8, FL_N).adv_pc(2).tick(Cycles::t(8))
     }
 
     //   sub  (HL)        96         8 z1hc A=A-(HL)
@@ -1146,121 +1189,131 @@ pub mod cpu {
     fn sub_HL(cpu: CPUState, mem: &Memory) -> CPUState {
         impl_add_sub(cpu, mem.read(cpu.HL()), FL_N)
             .adv_pc(1)
-            .tick(8)
+            .tick(Cycles::t(8))
     }
 
     //   sbc  A,r         9x         4 z1hc A=A-r-cy
     // ----------------------------------------------------------------------------
     const fn sbc_r(cpu: CPUState, src: usize) -> CPUState {
-        impl_adc_sbc(cpu, cpu.reg[src], FL_N).adv_pc(1).tick(4)
+        impl_adc_sbc(cpu, cpu.reg[src], FL_N)
+            .adv_pc(1)
+            .tick(Cycles::t(4))
     }
     //   sbc  A,n         DE nn      8 z1hc A=A-n-cy
     // ----------------------------------------------------------------------------
     const fn sbc_d8(cpu: CPUState, d8: Byte) -> CPUState {
-        impl_adc_sbc(cpu, d8, FL_N).adv_pc(2).tick(8)
+        impl_adc_sbc(cpu, d8, FL_N).adv_pc(2).tick(Cycles::t(8))
     }
     //   sbc  A,(HL)      9E         8 z1hc A=A-(HL)-cy
     // ----------------------------------------------------------------------------
     fn sbc_HL(cpu: CPUState, mem: &Memory) -> CPUState {
         impl_adc_sbc(cpu, mem.read(cpu.HL()), FL_N)
             .adv_pc(1)
-            .tick(8)
+            .tick(Cycles::t(8))
     }
 
     //   and  r           Ax         4 z010 A=A & r
     // ----------------------------------------------------------------------------
     const fn and_r(cpu: CPUState, src: usize) -> CPUState {
-        impl_and(cpu, cpu.reg[src]).adv_pc(1).tick(4)
+        impl_and(cpu, cpu.reg[src]).adv_pc(1).tick(Cycles::t(4))
     }
 
     //   and  n           E6 nn      8 z010 A=A & n
     // ----------------------------------------------------------------------------
     const fn and_d8(cpu: CPUState, d8: Byte) -> CPUState {
-        impl_and(cpu, d8).adv_pc(2).tick(8)
+        impl_and(cpu, d8).adv_pc(2).tick(Cycles::t(8))
     }
 
     //   and  (HL)        A6         8 z010 A=A & (HL)
     // ----------------------------------------------------------------------------
     fn and_HL(cpu: CPUState, mem: &Memory) -> CPUState {
-        impl_and(cpu, mem.read(cpu.HL())).adv_pc(1).tick(8)
+        impl_and(cpu, mem.read(cpu.HL()))
+            .adv_pc(1)
+            .tick(Cycles::t(8))
     }
 
     //   xor  r           Ax         4 z000
     // ----------------------------------------------------------------------------
     const fn xor_r(cpu: CPUState, src: usize) -> CPUState {
-        impl_xor(cpu, cpu.reg[src]).adv_pc(1).tick(4)
+        impl_xor(cpu, cpu.reg[src]).adv_pc(1).tick(Cycles::t(4))
     }
 
     //   xor  n           EE nn      8 z000
     // ----------------------------------------------------------------------------
     const fn xor_d8(cpu: CPUState, d8: Byte) -> CPUState {
-        impl_xor(cpu, d8).adv_pc(2).tick(8)
+        impl_xor(cpu, d8).adv_pc(2).tick(Cycles::t(8))
     }
 
     //   xor  (HL)        AE         8 z000
     // ----------------------------------------------------------------------------
     fn xor_HL(cpu: CPUState, mem: &Memory) -> CPUState {
-        impl_xor(cpu, mem.read(cpu.HL())).adv_pc(1).tick(8)
+        impl_xor(cpu, mem.read(cpu.HL()))
+            .adv_pc(1)
+            .tick(Cycles::t(8))
     }
 
     //   or   r           Bx         4 z000 A=A | r
     // ----------------------------------------------------------------------------
     const fn or_r(cpu: CPUState, src: usize) -> CPUState {
-        impl_or(cpu, cpu.reg[src]).adv_pc(1).tick(4)
+        impl_or(cpu, cpu.reg[src]).adv_pc(1).tick(Cycles::t(4))
     }
 
     //   or   n           F6 nn      8 z000 A=A | n
     // ----------------------------------------------------------------------------
     const fn or_d8(cpu: CPUState, d8: Byte) -> CPUState {
-        impl_or(cpu, d8).adv_pc(2).tick(8)
+        impl_or(cpu, d8).adv_pc(2).tick(Cycles::t(8))
     }
 
     //   or   (HL)        B6         8 z000 A=A | (HL)
     // ----------------------------------------------------------------------------
     fn or_HL(cpu: CPUState, mem: &Memory) -> CPUState {
-        impl_or(cpu, mem.read(cpu.HL())).adv_pc(1).tick(8)
+        impl_or(cpu, mem.read(cpu.HL()))
+            .adv_pc(1)
+            .tick(Cycles::t(8))
     }
 
     //   cp   r           Bx         4 z1hc compare A-r
     // ----------------------------------------------------------------------------
     const fn cp_r(cpu: CPUState, src: usize) -> CPUState {
-        impl_cp(cpu, cpu.reg[src]).adv_pc(1).tick(4)
+        impl_cp(cpu, cpu.reg[src]).adv_pc(1).tick(Cycles::t(4))
     }
 
     //   cp   n           FE nn      8 z1hc compare A-n
     // ----------------------------------------------------------------------------
     const fn cp_d8(cpu: CPUState, d8: Byte) -> CPUState {
-        impl_cp(cpu, d8).adv_pc(2).tick(8)
+        impl_cp(cpu, d8).adv_pc(2).tick(Cycles::t(8))
     }
 
     //   cp   (HL)        BE         8 z1hc compare A-(HL)
     // ----------------------------------------------------------------------------
     fn cp_HL(cpu: CPUState, mem: &Memory) -> CPUState {
-        impl_cp(cpu, mem.read(cpu.HL())).adv_pc(1).tick(8)
+        impl_cp(cpu, mem.read(cpu.HL()))
+            .adv_pc(1)
+            .tick(Cycles::t(8))
     }
 
     //   inc  r           xx         4 z0h- r=r+1
     // ----------------------------------------------------------------------------
     const fn inc_b(cpu: CPUState) -> CPUState {
-        impl_inc_dec(cpu, REG_B, 0).adv_pc(1).tick(4)
+        impl_inc_dec(cpu, REG_B, 0).adv_pc(1).tick(Cycles::t(4))
     }
     const fn inc_c(cpu: CPUState) -> CPUState {
-        impl_inc_dec(cpu, REG_C, 0).adv_pc(1).tick(4)
+        impl_inc_dec(cpu, REG_C, 0).adv_pc(1).tick(Cycles::t(4))
     }
     const fn inc_d(cpu: CPUState) -> CPUState {
-        impl_inc_dec(cpu, REG_D, 0).adv_pc(1).tick(4)
+        impl_inc_dec(cpu, REG_D, 0).adv_pc(1).tick(Cycles::t(4))
     }
     const fn inc_e(cpu: CPUState) -> CPUState {
-        impl_inc_dec(cpu, REG_E, 0).adv_pc(1).tick(4)
+        impl_inc_dec(cpu, REG_E, 0).adv_pc(1).tick(Cycles::t(4))
     }
     const fn inc_h(cpu: CPUState) -> CPUState {
-        impl_inc_dec(cpu, REG_H, 0).adv_pc(1).tick(4)
+        impl_inc_dec(cpu, REG_H, 0).adv_pc(1).tick(Cycles::t(4))
     }
     const fn inc_l(cpu: CPUState) -> CPUState {
-        impl_inc_dec(cpu, REG_L, 0).adv_pc(1).tick(4)
+        impl_inc_dec(cpu, REG_L, 0).adv_pc(1).tick(Cycles::t(4))
     }
     const fn inc_a(cpu: CPUState) -> CPUState {
-        impl_inc_dec(cpu, REG_A, 0).adv_pc(1).tick(4)
+        impl_inc_dec(cpu, REG_A, 0).adv_pc(1).tick(Cycles::t(4))
     }
 
     //   inc  (HL)        34        12 z0h- (HL)=(HL)+1
@@ -1281,31 +1334,31 @@ pub mod cpu {
 
         mem.write(cpu.HL(), res);
 
-        CPUState { reg, ..cpu }.adv_pc(1).tick(12)
+        CPUState { reg, ..cpu }.adv_pc(1).tick(Cycles::t(12))
     }
 
     //   dec  r           xx         4 z1h- r=r-1
     // ----------------------------------------------------------------------------
     const fn dec_b(cpu: CPUState) -> CPUState {
-        impl_inc_dec(cpu, REG_B, FL_N).adv_pc(1).tick(4)
+        impl_inc_dec(cpu, REG_B, FL_N).adv_pc(1).tick(Cycles::t(4))
     }
     const fn dec_c(cpu: CPUState) -> CPUState {
-        impl_inc_dec(cpu, REG_C, FL_N).adv_pc(1).tick(4)
+        impl_inc_dec(cpu, REG_C, FL_N).adv_pc(1).tick(Cycles::t(4))
     }
     const fn dec_d(cpu: CPUState) -> CPUState {
-        impl_inc_dec(cpu, REG_D, FL_N).adv_pc(1).tick(4)
+        impl_inc_dec(cpu, REG_D, FL_N).adv_pc(1).tick(Cycles::t(4))
     }
     const fn dec_e(cpu: CPUState) -> CPUState {
-        impl_inc_dec(cpu, REG_E, FL_N).adv_pc(1).tick(4)
+        impl_inc_dec(cpu, REG_E, FL_N).adv_pc(1).tick(Cycles::t(4))
     }
     const fn dec_h(cpu: CPUState) -> CPUState {
-        impl_inc_dec(cpu, REG_H, FL_N).adv_pc(1).tick(4)
+        impl_inc_dec(cpu, REG_H, FL_N).adv_pc(1).tick(Cycles::t(4))
     }
     const fn dec_l(cpu: CPUState) -> CPUState {
-        impl_inc_dec(cpu, REG_L, FL_N).adv_pc(1).tick(4)
+        impl_inc_dec(cpu, REG_L, FL_N).adv_pc(1).tick(Cycles::t(4))
     }
     const fn dec_a(cpu: CPUState) -> CPUState {
-        impl_inc_dec(cpu, REG_A, FL_N).adv_pc(1).tick(4)
+        impl_inc_dec(cpu, REG_A, FL_N).adv_pc(1).tick(Cycles::t(4))
     }
 
     //   dec  (HL)        35        12 z1h- (HL)=(HL)-1
@@ -1325,7 +1378,7 @@ pub mod cpu {
 
         mem.write(cpu.HL(), res);
 
-        CPUState { reg, ..cpu }.adv_pc(1).tick(12)
+        CPUState { reg, ..cpu }.adv_pc(1).tick(Cycles::t(12))
     }
 
     //   daa              27         4 z-0x decimal adjust akku
@@ -1358,7 +1411,7 @@ pub mod cpu {
         };
         reg[FLAGS] |= fl_z(reg[REG_A]);
 
-        CPUState { reg, ..cpu }.adv_pc(1).tick(4)
+        CPUState { reg, ..cpu }.adv_pc(1).tick(Cycles::t(4))
     }
 
     //   cpl              2F         4 -11- A = A xor FF
@@ -1367,7 +1420,7 @@ pub mod cpu {
         let mut reg = cpu.reg;
         reg[REG_A] = reg[REG_A] ^ 0xFF;
         reg[FLAGS] = (reg[FLAGS] & FL_Z) | FL_N | FL_H | (reg[FLAGS] & FL_C);
-        CPUState { reg, ..cpu }.adv_pc(1).tick(4)
+        CPUState { reg, ..cpu }.adv_pc(1).tick(Cycles::t(4))
     }
 
     // GMB 16bit-Arithmetic/logical Commands
@@ -1389,7 +1442,7 @@ pub mod cpu {
         reg[REG_H] = hi(result);
         reg[REG_L] = lo(result);
 
-        CPUState { reg, ..cpu }.adv_pc(1).tick(8)
+        CPUState { reg, ..cpu }.adv_pc(1).tick(Cycles::t(8))
     }
 
     const fn add_hl_bc(cpu: CPUState) -> CPUState {
@@ -1408,43 +1461,43 @@ pub mod cpu {
     //   inc  rr        x3           8 ---- rr = rr+1      ;rr may be BC,DE,HL,SP
     // ----------------------------------------------------------------------------
     const fn inc_bc(cpu: CPUState) -> CPUState {
-        impl_inc16(cpu, REG_B, REG_C).adv_pc(1).tick(8)
+        impl_inc16(cpu, REG_B, REG_C).adv_pc(1).tick(Cycles::t(8))
     }
     const fn inc_de(cpu: CPUState) -> CPUState {
-        impl_inc16(cpu, REG_D, REG_E).adv_pc(1).tick(8)
+        impl_inc16(cpu, REG_D, REG_E).adv_pc(1).tick(Cycles::t(8))
     }
     const fn inc_hl(cpu: CPUState) -> CPUState {
-        impl_inc16(cpu, REG_H, REG_L).adv_pc(1).tick(8)
+        impl_inc16(cpu, REG_H, REG_L).adv_pc(1).tick(Cycles::t(8))
     }
     const fn inc_sp(cpu: CPUState) -> CPUState {
         let (res, _) = cpu.sp.overflowing_add(1);
         CPUState {
             pc: cpu.pc + 1,
-            tsc: cpu.tsc + 8,
             sp: res,
             ..cpu
         }
+        .tick(Cycles::t(8))
     }
 
     //   dec  rr        xB           8 ---- rr = rr-1      ;rr may be BC,DE,HL,SP
     // ----------------------------------------------------------------------------
     const fn dec_bc(cpu: CPUState) -> CPUState {
-        impl_dec16(cpu, REG_B, REG_C).adv_pc(1).tick(8)
+        impl_dec16(cpu, REG_B, REG_C).adv_pc(1).tick(Cycles::t(8))
     }
     const fn dec_de(cpu: CPUState) -> CPUState {
-        impl_dec16(cpu, REG_D, REG_E).adv_pc(1).tick(8)
+        impl_dec16(cpu, REG_D, REG_E).adv_pc(1).tick(Cycles::t(8))
     }
     const fn dec_hl(cpu: CPUState) -> CPUState {
-        impl_dec16(cpu, REG_H, REG_L).adv_pc(1).tick(8)
+        impl_dec16(cpu, REG_H, REG_L).adv_pc(1).tick(Cycles::t(8))
     }
     const fn dec_sp(cpu: CPUState) -> CPUState {
         let (res, _) = cpu.sp.overflowing_sub(1);
         CPUState {
             pc: cpu.pc + 1,
-            tsc: cpu.tsc + 8,
             sp: res,
             ..cpu
         }
+        .tick(Cycles::t(8))
     }
 
     //   add  SP,dd     E8          16 00hc SP = SP +/- dd ;dd is 8bit signed number
@@ -1463,7 +1516,7 @@ pub mod cpu {
             reg,
             ..cpu
         }
-        .tick(16)
+        .tick(Cycles::t(16))
         .adv_pc(2)
     }
 
@@ -1479,7 +1532,7 @@ pub mod cpu {
         reg[REG_H] = hi(hl);
         reg[REG_L] = lo(hl);
 
-        CPUState { reg, ..cpu }.tick(12).adv_pc(2)
+        CPUState { reg, ..cpu }.tick(Cycles::t(12)).adv_pc(2)
     }
 
     // GMB Rotate- und Shift-Commands
@@ -1493,10 +1546,10 @@ pub mod cpu {
         reg[REG_A] = cpu.reg[REG_A].rotate_left(1);
         CPUState {
             pc: cpu.pc + 1,
-            tsc: cpu.tsc + 4,
             reg,
             ..cpu
         }
+        .tick(Cycles::t(4))
     }
 
     //   rla            17           4 000c rotate akku left through carry
@@ -1507,10 +1560,10 @@ pub mod cpu {
         reg[REG_A] = (cpu.reg[REG_A].rotate_left(1) & 0xFE) | ((cpu.reg[FLAGS] & FL_C) >> 4);
         CPUState {
             pc: cpu.pc + 1,
-            tsc: cpu.tsc + 4,
             reg,
             ..cpu
         }
+        .tick(Cycles::t(4))
     }
 
     //   rrca           0F           4 000c rotate akku right
@@ -1521,10 +1574,10 @@ pub mod cpu {
         reg[REG_A] = cpu.reg[REG_A].rotate_right(1);
         CPUState {
             pc: cpu.pc + 1,
-            tsc: cpu.tsc + 4,
             reg,
             ..cpu
         }
+        .tick(Cycles::t(4))
     }
 
     //   rra            1F           4 000c rotate akku right through carry
@@ -1535,10 +1588,10 @@ pub mod cpu {
         reg[REG_A] = (cpu.reg[REG_A].rotate_right(1) & 0x7F) | ((cpu.reg[FLAGS] & FL_C) << 3);
         CPUState {
             pc: cpu.pc + 1,
-            tsc: cpu.tsc + 4,
             reg,
             ..cpu
         }
+        .tick(Cycles::t(4))
     }
 
     //   rlc  r         CB 0x        8 z00c rotate left
@@ -1551,7 +1604,7 @@ pub mod cpu {
         reg[dst] = result;
         reg[FLAGS] = fl_z(result) | fl_set(FL_C, (result & 1) != 0);
 
-        CPUState { reg, ..cpu }.adv_pc(2).tick(8)
+        CPUState { reg, ..cpu }.adv_pc(2).tick(Cycles::t(8))
     }
 
     //   rlc  (HL)      CB 06       16 z00c rotate left
@@ -1566,7 +1619,7 @@ pub mod cpu {
         mem.write(addr, result);
         reg[FLAGS] = fl_z(result) | fl_set(FL_C, (result & 1) != 0);
 
-        CPUState { reg, ..cpu }.adv_pc(2).tick(16)
+        CPUState { reg, ..cpu }.adv_pc(2).tick(Cycles::t(16))
     }
 
     //   rl   r         CB 1x        8 z00c rotate left through carry
@@ -1577,7 +1630,7 @@ pub mod cpu {
         reg[dst] = (cpu.reg[dst].rotate_left(1) & 0xFE) | ((cpu.reg[FLAGS] & FL_C) >> 4);
         reg[FLAGS] = (cpu.reg[dst] & 0x80) >> 3 | fl_z(reg[dst]);
 
-        CPUState { reg, ..cpu }.adv_pc(2).tick(8)
+        CPUState { reg, ..cpu }.adv_pc(2).tick(Cycles::t(8))
     }
 
     //   rl   (HL)      CB 16       16 z00c rotate left through carry
@@ -1593,7 +1646,7 @@ pub mod cpu {
         );
         reg[FLAGS] = (cur & 0x80) >> 3 | fl_z(mem.read(addr));
 
-        CPUState { reg, ..cpu }.adv_pc(2).tick(16)
+        CPUState { reg, ..cpu }.adv_pc(2).tick(Cycles::t(16))
     }
 
     //   rrc  r         CB 0x        8 z00c rotate right
@@ -1607,7 +1660,7 @@ pub mod cpu {
         reg[dst] = result;
         reg[FLAGS] = fl_z(result) | fl_c;
 
-        CPUState { reg, ..cpu }.adv_pc(2).tick(8)
+        CPUState { reg, ..cpu }.adv_pc(2).tick(Cycles::t(8))
     }
 
     //   rrc  (HL)      CB 0E       16 z00c rotate right
@@ -1622,7 +1675,7 @@ pub mod cpu {
         mem.write(addr, result);
         reg[FLAGS] = fl_z(result) | fl_set(FL_C, (cur & 1) != 0);
 
-        CPUState { reg, ..cpu }.adv_pc(2).tick(16)
+        CPUState { reg, ..cpu }.adv_pc(2).tick(Cycles::t(16))
     }
 
     //   rr   r         CB 1x        8 z00c rotate right through carry
@@ -1635,7 +1688,7 @@ pub mod cpu {
         reg[dst] = (cpu.reg[dst].rotate_right(1) & 0x7F) | ((cpu.reg[FLAGS] & FL_C) << 3);
         reg[FLAGS] = fl_c | fl_z(reg[dst]);
 
-        CPUState { reg, ..cpu }.adv_pc(2).tick(8)
+        CPUState { reg, ..cpu }.adv_pc(2).tick(Cycles::t(8))
     }
 
     //   rr   (HL)      CB 1E       16 z00c rotate right through carry
@@ -1650,7 +1703,7 @@ pub mod cpu {
         mem.write(addr, result);
         reg[FLAGS] = fl_z(result) | fl_set(FL_C, cur & 1 != 0);
 
-        CPUState { reg, ..cpu }.adv_pc(2).tick(16)
+        CPUState { reg, ..cpu }.adv_pc(2).tick(Cycles::t(16))
     }
 
     //   sla  r         CB 2x        8 z00c shift left arithmetic (b0=0)
@@ -1661,7 +1714,7 @@ pub mod cpu {
         reg[dst] = reg[dst] << 1;
         reg[FLAGS] = fl_z(reg[dst]) | fl_set(FL_C, cpu.reg[dst] & 0x80 != 0);
 
-        CPUState { reg, ..cpu }.adv_pc(2).tick(8)
+        CPUState { reg, ..cpu }.adv_pc(2).tick(Cycles::t(8))
     }
 
     //   sla  (HL)      CB 26       16 z00c shift left arithmetic (b0=0)
@@ -1676,7 +1729,7 @@ pub mod cpu {
         mem.write(addr, result);
         reg[FLAGS] = fl_z(result) | fl_set(FL_C, cur & 0x80 != 0);
 
-        CPUState { reg, ..cpu }.adv_pc(2).tick(16)
+        CPUState { reg, ..cpu }.adv_pc(2).tick(Cycles::t(16))
     }
 
     //   swap r         CB 3x        8 z000 exchange low/hi-nibble
@@ -1687,7 +1740,7 @@ pub mod cpu {
         reg[dst] = (reg[dst] >> 4) | (reg[dst] << 4);
         reg[FLAGS] = fl_z(reg[dst]);
 
-        CPUState { reg, ..cpu }.adv_pc(2).tick(8)
+        CPUState { reg, ..cpu }.adv_pc(2).tick(Cycles::t(8))
     }
 
     //   swap (HL)      CB 36       16 z000 exchange low/hi-nibble
@@ -1702,7 +1755,7 @@ pub mod cpu {
         mem.write(addr, result);
         reg[FLAGS] = fl_z(result);
 
-        CPUState { reg, ..cpu }.adv_pc(2).tick(16)
+        CPUState { reg, ..cpu }.adv_pc(2).tick(Cycles::t(16))
     }
 
     //   sra  r         CB 2x        8 z00c shift right arithmetic (b7=b7)
@@ -1713,7 +1766,7 @@ pub mod cpu {
         reg[dst] = (cpu.reg[dst] & 0x80) | reg[dst] >> 1;
         reg[FLAGS] = fl_z(reg[dst]) | fl_set(FL_C, cpu.reg[dst] & 1 != 0);
 
-        CPUState { reg, ..cpu }.adv_pc(2).tick(8)
+        CPUState { reg, ..cpu }.adv_pc(2).tick(Cycles::t(8))
     }
 
     //   sra  (HL)      CB 2E       16 z00c shift right arithmetic (b7=b7)
@@ -1728,7 +1781,7 @@ pub mod cpu {
         mem.write(addr, result);
         reg[FLAGS] = fl_z(result) | fl_set(FL_C, cur & 1 != 0);
 
-        CPUState { reg, ..cpu }.adv_pc(2).tick(16)
+        CPUState { reg, ..cpu }.adv_pc(2).tick(Cycles::t(16))
     }
 
     //   srl  r         CB 3x        8 z00c shift right logical (b7=0)
@@ -1739,7 +1792,7 @@ pub mod cpu {
         reg[dst] = reg[dst] >> 1;
         reg[FLAGS] = fl_z(reg[dst]) | fl_set(FL_C, cpu.reg[dst] & 1 != 0);
 
-        CPUState { reg, ..cpu }.adv_pc(2).tick(8)
+        CPUState { reg, ..cpu }.adv_pc(2).tick(Cycles::t(8))
     }
 
     //   srl  (HL)      CB 3E       16 z00c shift right logical (b7=0)
@@ -1754,7 +1807,7 @@ pub mod cpu {
         mem.write(addr, result);
         reg[FLAGS] = fl_z(result) | fl_set(FL_C, cur & 1 != 0);
 
-        CPUState { reg, ..cpu }.adv_pc(2).tick(16)
+        CPUState { reg, ..cpu }.adv_pc(2).tick(Cycles::t(16))
     }
 
     // GMB Singlebit Operation Commands
@@ -1767,7 +1820,7 @@ pub mod cpu {
         let mask = 1 << bit;
         reg[FLAGS] = fl_z(cpu.reg[dst] & mask) | FL_H | cpu.reg[FLAGS] & FL_C;
 
-        CPUState { reg, ..cpu }.adv_pc(2).tick(8)
+        CPUState { reg, ..cpu }.adv_pc(2).tick(Cycles::t(8))
     }
 
     //   bit  n,(HL)    CB xx       12 z01- test bit n
@@ -1780,7 +1833,7 @@ pub mod cpu {
         let mask = 1 << bit;
         reg[FLAGS] = fl_z(cur & mask) | FL_H | (cpu.reg[FLAGS] & FL_C);
 
-        CPUState { reg, ..cpu }.adv_pc(2).tick(12)
+        CPUState { reg, ..cpu }.adv_pc(2).tick(Cycles::t(12))
     }
 
     //   set  n,r       CB xx        8 ---- set bit n
@@ -1791,7 +1844,7 @@ pub mod cpu {
         let mask = 1 << bit;
         reg[dst] |= mask;
 
-        CPUState { reg, ..cpu }.adv_pc(2).tick(8)
+        CPUState { reg, ..cpu }.adv_pc(2).tick(Cycles::t(8))
     }
 
     //   set  n,(HL)    CB xx       16 ---- set bit n
@@ -1803,7 +1856,7 @@ pub mod cpu {
         let mask = 1 << bit;
         mem.write(addr, mem.read(addr) | mask);
 
-        CPUState { reg, ..cpu }.adv_pc(2).tick(16)
+        CPUState { reg, ..cpu }.adv_pc(2).tick(Cycles::t(16))
     }
 
     //   res  n,r       CB xx        8 ---- reset bit n
@@ -1814,7 +1867,7 @@ pub mod cpu {
         let mask = 1 << n;
         reg[r] &= !mask;
 
-        CPUState { reg, ..cpu }.adv_pc(2).tick(8)
+        CPUState { reg, ..cpu }.adv_pc(2).tick(Cycles::t(8))
     }
 
     //   res  n,(HL)    CB xx       16 ---- reset bit n
@@ -1826,7 +1879,7 @@ pub mod cpu {
         let mask = 1 << n;
         mem.write(addr, mem.read(addr) & !mask);
 
-        CPUState { reg, ..cpu }.adv_pc(2).tick(16)
+        CPUState { reg, ..cpu }.adv_pc(2).tick(Cycles::t(16))
     }
 
     #[test]
@@ -1852,7 +1905,7 @@ pub mod cpu {
         let mut reg = cpu.reg;
         reg[FLAGS] = reg[FLAGS] & FL_Z | 0 | 0 | (reg[FLAGS] ^ FL_C) & FL_C;
 
-        CPUState { reg, ..cpu }.adv_pc(1).tick(4)
+        CPUState { reg, ..cpu }.adv_pc(1).tick(Cycles::t(4))
     }
 
     //   scf            37           4 -001 cy=1
@@ -1860,7 +1913,7 @@ pub mod cpu {
         let mut reg = cpu.reg;
         reg[FLAGS] = reg[FLAGS] & FL_Z | 0 | 0 | FL_C;
 
-        CPUState { reg, ..cpu }.adv_pc(1).tick(4)
+        CPUState { reg, ..cpu }.adv_pc(1).tick(Cycles::t(4))
     }
 
     #[test]
@@ -1883,19 +1936,19 @@ pub mod cpu {
     //   nop            00           4 ---- no operation
     // ----------------------------------------------------------------------------
     const fn nop(cpu: CPUState) -> CPUState {
-        cpu.adv_pc(1).tick(4)
+        cpu.adv_pc(1).tick(Cycles::t(4))
     }
 
     //   halt           76         N*4 ---- halt until interrupt occurs (low power)
     const fn halt(cpu: CPUState) -> CPUState {
-        CPUState { halt: true, ..cpu }.adv_pc(1).tick(4)
+        CPUState { halt: true, ..cpu }.adv_pc(1).tick(Cycles::t(4))
     }
 
     //   stop           10 00        ? ---- low power standby mode (VERY low power)
     // ----------------------------------------------------------------------------
     const fn stop(cpu: CPUState) -> CPUState {
         // todo: not sure what to do here
-        cpu.adv_pc(2).tick(0)
+        cpu.adv_pc(2).tick(Cycles::t(0))
     }
 
     //   di             F3           4 ---- disable interrupts, IME=0
@@ -1903,7 +1956,7 @@ pub mod cpu {
     const fn di(cpu: CPUState) -> CPUState {
         CPUState {
             ime: false,
-            ..cpu.adv_pc(1).tick(4)
+            ..cpu.adv_pc(1).tick(Cycles::t(4))
         }
     }
 
@@ -1913,7 +1966,7 @@ pub mod cpu {
         CPUState {
             ime: true,
             inst_ei: cpu.inst_count,
-            ..cpu.adv_pc(1).tick(4)
+            ..cpu.adv_pc(1).tick(Cycles::t(4))
         }
     }
 
@@ -1933,13 +1986,13 @@ pub mod cpu {
     //   jp   nn        C3 nn nn    16 ---- jump to nn, PC=nn
     // ----------------------------------------------------------------------------
     const fn jp_d16(cpu: CPUState, low: Byte, high: Byte) -> CPUState {
-        impl_jp(cpu, combine(high, low)).tick(16)
+        impl_jp(cpu, combine(high, low)).tick(Cycles::t(16))
     }
 
     //   jp   HL        E9           4 ---- jump to HL, PC=HL
     // ----------------------------------------------------------------------------
     const fn jp_hl(cpu: CPUState) -> CPUState {
-        impl_jp(cpu, cpu.HL()).tick(4)
+        impl_jp(cpu, cpu.HL()).tick(Cycles::t(4))
     }
 
     #[test]
@@ -1960,49 +2013,49 @@ pub mod cpu {
             _ => panic!("jp_f_d16 unreachable"),
         };
         if do_jump {
-            impl_jp(cpu, combine(high, low)).tick(16)
+            impl_jp(cpu, combine(high, low)).tick(Cycles::t(16))
         } else {
-            cpu.adv_pc(3).tick(12)
+            cpu.adv_pc(3).tick(Cycles::t(12))
         }
     }
 
     //   jr   PC+dd     18 dd       12 ---- relative jump to nn (PC=PC+/-7bit)
     // ----------------------------------------------------------------------------
     const fn jr_r8(cpu: CPUState, r8: SByte) -> CPUState {
-        impl_jr(cpu.adv_pc(2), r8).tick(12)
+        impl_jr(cpu.adv_pc(2), r8).tick(Cycles::t(12))
     }
 
     //   jr   f,PC+dd   xx dd     12;8 ---- conditional relative jump if nz,z,nc,c
     // ----------------------------------------------------------------------------
     const fn jr_nz_r8(cpu: CPUState, r8: SByte) -> CPUState {
         let (time, offset) = if cpu.reg[FLAGS] & FL_Z == 0 {
-            (12, r8)
+            (Cycles::t(12), r8)
         } else {
-            (8, 0)
+            (Cycles::t(8), 0)
         };
         impl_jr(cpu.adv_pc(2), offset).tick(time)
     }
     const fn jr_nc_r8(cpu: CPUState, r8: SByte) -> CPUState {
         let (time, offset) = if cpu.reg[FLAGS] & FL_C == 0 {
-            (12, r8)
+            (Cycles::t(12), r8)
         } else {
-            (8, 0)
+            (Cycles::t(8), 0)
         };
         impl_jr(cpu.adv_pc(2), offset).tick(time)
     }
     const fn jr_z_r8(cpu: CPUState, r8: SByte) -> CPUState {
         let (time, offset) = if cpu.reg[FLAGS] & FL_Z != 0 {
-            (12, r8)
+            (Cycles::t(12), r8)
         } else {
-            (8, 0)
+            (Cycles::t(8), 0)
         };
         impl_jr(cpu.adv_pc(2), offset).tick(time)
     }
     const fn jr_c_r8(cpu: CPUState, r8: SByte) -> CPUState {
         let (time, offset) = if cpu.reg[FLAGS] & FL_C != 0 {
-            (12, r8)
+            (Cycles::t(12), r8)
         } else {
-            (8, 0)
+            (Cycles::t(8), 0)
         };
         impl_jr(cpu.adv_pc(2), offset).tick(time)
     }
@@ -2010,7 +2063,7 @@ pub mod cpu {
     //   call nn        CD nn nn    24 ---- call to nn, SP=SP-2, (SP)=PC, PC=nn
     // ----------------------------------------------------------------------------
     fn call_d16(low: Byte, high: Byte, cpu: CPUState, mem: &mut Memory) -> CPUState {
-        let cpu = cpu.adv_pc(3).tick(24);
+        let cpu = cpu.adv_pc(3).tick(Cycles::t(24));
         let cpu_pushed = push_d16(cpu, mem, cpu.pc);
         CPUState {
             pc: combine(high, low),
@@ -2032,7 +2085,7 @@ pub mod cpu {
         if do_call {
             call_d16(low, high, cpu, mem)
         } else {
-            cpu.adv_pc(3).tick(12)
+            cpu.adv_pc(3).tick(Cycles::t(12))
         }
     }
 
@@ -2042,7 +2095,7 @@ pub mod cpu {
         let (cpu_popped, pval) = pop_d16(cpu, mem);
         CPUState {
             pc: pval,
-            tsc: cpu.tsc + 16,
+            tsc: cpu.tsc + Cycles::t(16),
             ..cpu_popped
         }
     }
@@ -2051,11 +2104,11 @@ pub mod cpu {
     // ----------------------------------------------------------------------------
     fn impl_ret_conditional(condition: bool, cpu: CPUState, mem: &Memory) -> CPUState {
         if condition {
-            ret(cpu, mem).tick(4)
+            ret(cpu, mem).tick(Cycles::t(4))
         } else {
             CPUState {
                 pc: cpu.pc + 1,
-                tsc: cpu.tsc + 8,
+                tsc: cpu.tsc + Cycles::t(8),
                 ..cpu
             }
         }
@@ -2087,7 +2140,7 @@ pub mod cpu {
     //   rst  n         xx          16 ---- call to 00,08,10,18,20,28,30,38
     // ----------------------------------------------------------------------------
     fn rst_n(cpu: CPUState, mem: &mut Memory, opcode: Byte) -> CPUState {
-        let cpu = cpu.adv_pc(1).tick(16);
+        let cpu = cpu.adv_pc(1).tick(Cycles::t(16));
         let rst_hi = (opcode & HIGH_MASK_NIB) - 0xC0;
         let rst_lo = opcode & 0x08;
         let rst_addr = rst_hi | rst_lo;
@@ -2119,24 +2172,110 @@ pub mod cpu {
     // ============================================================================
     // interrupts
     // ============================================================================
-    fn jump_to_int_vec(cpu: CPUState, mem: &mut Memory, fl_int: Byte, vec_int: Word) -> CPUState {
-        let flags = mem.read(IF);
-        mem.write(IF, flags & !fl_int); // acknowledge the request flag (set to 0)
-                                        // push current position to stack to prepare for jump
-
-        let cpu_pushed = push_d16(cpu, mem, cpu.pc);
 
+    /// Whether the cpu dispatches an interrupt instead of running the instruction at pc
+    ///
+    /// Sampled at the boundary, so with IF as the end of the previous instruction left it.
+    /// The effect of EI is delayed by one instruction: EI followed immediately by DI doesn't
+    /// let any interrupts in between.
+    /// https://gbdev.io/pandocs/single.html#ime-interrupt-master-enable-flag-write-only
+    pub fn interrupt_pending(cpu: &CPUState, mem: &Memory) -> bool {
+        let ei_valid_delay = cpu.inst_count > cpu.inst_ei;
+        cpu.ime && ei_valid_delay && mem.enabled_interrupts() != 0
+    }
+
+    /// First 3 M-cycles of interrupt dispatch: two idle, then the high byte of pc is pushed
+    ///
+    /// Coming out of halt takes an extra M-cycle. Which interrupt is being dispatched isn't
+    /// decided yet, see dispatch_interrupt_end. The rest of the hardware should run for the
+    /// cycles this took before carrying on.
+    /// https://gbdev.io/pandocs/Interrupts.html#interrupt-handling
+    pub fn dispatch_interrupt_begin(cpu: CPUState, mem: &mut Memory) -> CPUState {
+        let wake = if cpu.halt { 1 } else { 0 };
+        let sp = cpu.sp.wrapping_sub(1);
+        mem.write(sp, hi(cpu.pc));
         CPUState {
-            ime: mem.read(IF) != 0, // only lock the ime if we're handling the final request
-            // todo: acc: this behavior is incorrect, the ime should remain locked while handling the
-            // SET OF interrupt requests that were enabled at the time of the handler invocation
-            // e.g. if FL_INT_VSYNC and FL_INT_JOYPAD are requested then the interrupt handler
-            // should execute both (in order of priority) but NOT execute any newly requested
-            // interrupts until those are handled.
-            pc: vec_int,
-            ..cpu_pushed
+            inst_count: cpu.inst_count + 1,
+            ime: false,
+            halt: false,
+            sp,
+            ..cpu
         }
-        .tick(20) // https://gbdev.io/pandocs/Interrupts.html#interrupt-handling
+        .tick(Cycles::m(wake + 3))
+    }
+
+    /// Last 2 M-cycles of interrupt dispatch: picks the highest priority interrupt that's
+    /// requested and enabled by now, pushes the low byte of pc and jumps to its vector
+    ///
+    /// An interrupt raised during the first 3 M-cycles can still win. If pushing the high
+    /// byte overwrote IE (sp was 0000) and nothing's enabled anymore, the dispatch is
+    /// cancelled and jumps to 0000 instead, acknowledging nothing.
+    pub fn dispatch_interrupt_end(cpu: CPUState, mem: &mut Memory) -> CPUState {
+        let enabled_flags = mem.enabled_interrupts();
+        let sp = cpu.sp.wrapping_sub(1);
+        mem.write(sp, lo(cpu.pc));
+        let pc = if enabled_flags != 0 {
+            let fl_int = enabled_flags & enabled_flags.wrapping_neg(); // lowest bit goes first
+            mem.write(IF, mem.read(IF) & !fl_int); // acknowledge the request flag (set to 0)
+            VEC_INT_VBLANK + 8 * fl_int.trailing_zeros() as Word
+        } else {
+            0x0000
+        };
+        CPUState { sp, pc, ..cpu }.tick(Cycles::m(2))
+    }
+
+    #[test]
+    fn test_interrupt_dispatch() {
+        let mut mem = Memory::new();
+        let cpu = CPUState {
+            ime: true,
+            inst_count: 1,
+            pc: 0x0150,
+            ..CPUState::new()
+        };
+        mem.write(IE, FL_INT_VBLANK | FL_INT_TIMER);
+        mem.write(IF, FL_INT_TIMER);
+        assert!(interrupt_pending(&cpu, &mem));
+        assert!(!interrupt_pending(&CPUState { inst_ei: 1, ..cpu }, &mem)); // right after ei
+
+        // vblank comes in while the high byte is being pushed and goes first
+        let mid = dispatch_interrupt_begin(cpu, &mut mem);
+        assert_eq!(mid.tsc, Cycles::m(3));
+        request_interrupt(&mut mem, FL_INT_VBLANK);
+        let done = dispatch_interrupt_end(mid, &mut mem);
+        assert_eq!(
+            (done.pc, done.tsc, done.ime),
+            (VEC_INT_VBLANK, Cycles::m(5), false)
+        );
+        assert_eq!(mem.read(IF), FL_INT_TIMER);
+        assert_eq!(pop_d16(done, &mem).1, 0x0150);
+
+        // with sp at 0000 the high byte lands in IE, disabling the timer interrupt
+        let cpu = CPUState { sp: 0x0000, ..cpu };
+        mem.write(IE, FL_INT_TIMER);
+        let done = dispatch_interrupt_end(dispatch_interrupt_begin(cpu, &mut mem), &mut mem);
+        assert_eq!(done.pc, 0x0000);
+        assert_eq!(mem.read(IF), FL_INT_TIMER);
+
+        // waking from halt takes an extra M-cycle
+        let cpu = CPUState {
+            halt: true,
+            sp: 0xFFFE,
+            ..cpu
+        };
+        mem.write(IE, FL_INT_TIMER);
+        let done = next(cpu, &mut mem).unwrap();
+        assert_eq!(
+            (done.pc, done.tsc, done.halt),
+            (VEC_INT_TIMER, Cycles::m(6), false)
+        );
+
+        // a masked interrupt neither dispatches nor wakes the cpu, but stays requested
+        mem.int_mask = FL_INT_TIMER;
+        request_interrupt(&mut mem, FL_INT_TIMER);
+        let done = next(cpu, &mut mem).unwrap();
+        assert_eq!((done.pc, done.halt), (cpu.pc, true));
+        assert_eq!(mem.read(IF) & FL_INT_TIMER, FL_INT_TIMER);
     }
 
     // ============================================================================
@@ -2174,6 +2313,7 @@ pub mod cpu {
     mod tests_cpu {
         use super::*;
         use crate::dbg::*;
+        use crate::lcd::{lcd_mode, set_lcd_mode};
 
         // tsc: 0,
         // //    B     C     D     E     H     L     fl    A
@@ -2197,9 +2337,13 @@ pub mod cpu {
 
         #[test]
         fn test_impl_xor_r() {
-            let result = impl_xor(INITIAL, 0x13).adv_pc(1).tick(4);
+            let result = impl_xor(INITIAL, 0x13).adv_pc(1).tick(Cycles::t(4));
             assert_eq!(result.pc, INITIAL.pc + 1, "incorrect program counter");
-            assert_eq!(result.tsc, INITIAL.tsc + 4, "incorrect time stamp counter");
+            assert_eq!(
+                result.tsc,
+                INITIAL.tsc + Cycles::t(4),
+                "incorrect time stamp counter"
+            );
             assert_eq!(
                 result.reg[REG_A], 0x12,
                 "incorrect value in reg_a (expected 0x{:X} got 0x{:X})",
@@ -2233,7 +2377,11 @@ pub mod cpu {
         fn test_xor_d8() {
             let result = xor_d8(INITIAL, 0xFF);
             assert_eq!(result.pc, INITIAL.pc + 2, "incorrect program counter");
-            assert_eq!(result.tsc, INITIAL.tsc + 8, "incorrect time stamp counter");
+            assert_eq!(
+                result.tsc,
+                INITIAL.tsc + Cycles::t(8),
+                "incorrect time stamp counter"
+            );
             assert_eq!(result.reg[REG_A], 0xFE, "incorrect xor value in reg a");
         }
 
@@ -2349,7 +2497,7 @@ pub mod cpu {
         fn test_add_HL() {
             let mut mem = Memory::new();
             let cpu = CPUState {
-                reg: [0, 0, 0, 0, 0, 0x01, 0, 0x01],
+                reg: [0, 0, 0, 0, 0xC0, 0x01, 0, 0x01],
                 ..INITIAL
             };
             mem.write(cpu.HL(), 0x0F);
@@ -2361,7 +2509,7 @@ pub mod cpu {
         fn test_inc_HL() {
             let mut mem = Memory::new();
             let mut cpu = CPUState {
-                reg: [0, 0, 0, 0, 0, 0x01, FL_Z | FL_N | FL_H | FL_C, 0x01],
+                reg: [0, 0, 0, 0, 0xC0, 0x01, FL_Z | FL_N | FL_H | FL_C, 0x01],
                 ..INITIAL
             };
 
@@ -2445,8 +2593,14 @@ pub mod cpu {
                 reg: [0x00, 0x01, 0x02, 0x03, 0x11, 0x12, FL_C, 0x11],
                 ..INITIAL
             };
+            // same as above but with (HL) in work ram, rom isn't writable
+            let cpu_hl = CPUState {
+                //    B     C     D     E     H     L     fl    A
+                reg: [0x00, 0x01, 0x02, 0x03, 0xC1, 0x12, FL_C, 0x11],
+                ..INITIAL
+            };
             let mut mem = Memory::new();
-            mem.write(cpu.HL(), cpu.reg[REG_L]);
+            mem.write(cpu_hl.HL(), cpu.reg[REG_L]);
 
             assert_eq_flags!(cp_r(cpu, REG_B).reg[FLAGS], FL_N);
             assert_eq_flags!(cp_r(cpu, REG_C).reg[FLAGS], FL_N);
@@ -2457,7 +2611,7 @@ pub mod cpu {
             assert_eq_flags!(cp_r(cpu, REG_A).reg[FLAGS], FL_Z | FL_N);
 
             assert_eq_flags!(cp_d8(cpu, 0x12).reg[FLAGS], FL_N | FL_H | FL_C);
-            assert_eq_flags!(cp_HL(cpu, &mem).reg[FLAGS], FL_N | FL_H | FL_C);
+            assert_eq_flags!(cp_HL(cpu_hl, &mem).reg[FLAGS], FL_N | FL_H | FL_C);
         }
 
         #[test]
@@ -2517,30 +2671,30 @@ pub mod cpu {
             };
 
             assert_eq!(jp_d16(cpu_c, 0x03, 0x02).pc, 0x0203);
-            assert_eq!(jp_d16(cpu_c, 0x03, 0x02).tsc, 16);
+            assert_eq!(jp_d16(cpu_c, 0x03, 0x02).tsc, Cycles::t(16));
             assert_eq!(jr_z_r8(cpu_z, 1).pc, cpu_z.adv_pc(2).pc + 1);
             assert_eq!(jr_z_r8(cpu_z, -0xF).pc, cpu_z.adv_pc(2).pc - 0xF);
             assert_eq!(jr_z_r8(cpu_c, 1).pc, cpu_c.adv_pc(2).pc);
             assert_eq!(jr_nz_r8(cpu_c, 1).pc, cpu_c.adv_pc(2).pc + 1);
             assert_eq!(jr_nz_r8(cpu_z, 1).pc, cpu_z.adv_pc(2).pc);
-            assert_eq!(jr_nz_r8(cpu_z, 1).tsc, cpu_z.tsc + 8);
+            assert_eq!(jr_nz_r8(cpu_z, 1).tsc, cpu_z.tsc + Cycles::t(8));
 
             assert_eq!(jr_c_r8(cpu_c, 1).pc, cpu_c.adv_pc(2).pc + 1);
             assert_eq!(jr_c_r8(cpu_z, 1).pc, cpu_z.adv_pc(2).pc);
-            assert_eq!(jr_c_r8(cpu_c, 1).tsc, cpu_c.tsc + 12);
-            assert_eq!(jr_c_r8(cpu_z, 1).tsc, cpu_z.tsc + 8);
+            assert_eq!(jr_c_r8(cpu_c, 1).tsc, cpu_c.tsc + Cycles::t(12));
+            assert_eq!(jr_c_r8(cpu_z, 1).tsc, cpu_z.tsc + Cycles::t(8));
 
             assert_eq!(jr_nc_r8(cpu_c, 1).pc, cpu_c.adv_pc(2).pc);
             assert_eq!(jr_nc_r8(cpu_z, 1).pc, cpu_z.adv_pc(2).pc + 1);
-            assert_eq!(jr_nc_r8(cpu_c, 1).tsc, cpu_c.tsc + 8);
-            assert_eq!(jr_nc_r8(cpu_z, 1).tsc, cpu_z.tsc + 12);
+            assert_eq!(jr_nc_r8(cpu_c, 1).tsc, cpu_c.tsc + Cycles::t(8));
+            assert_eq!(jr_nc_r8(cpu_z, 1).tsc, cpu_z.tsc + Cycles::t(12));
         }
 
         #[test]
         fn test_ld_HL_d8() {
             let cpu = CPUState {
                 //    B     C     D     E     H     L     fl    A
-                reg: [0x00, 0x01, 0x02, 0x03, 0x11, 0xFF, FL_C, 0xAA],
+                reg: [0x00, 0x01, 0x02, 0x03, 0xC1, 0xFF, FL_C, 0xAA],
                 ..INITIAL
             };
             let mut mem = Memory::new();
@@ -2552,7 +2706,7 @@ pub mod cpu {
         fn test_ldi() {
             let cpu = CPUState {
                 //    B     C     D     E     H     L     fl    A
-                reg: [0x00, 0x01, 0x02, 0x03, 0x11, 0x22, FL_C, 0xAA],
+                reg: [0x00, 0x01, 0x02, 0x03, 0xC1, 0x22, FL_C, 0xAA],
                 ..INITIAL
             };
             let mut mem = Memory::new();
@@ -2566,7 +2720,7 @@ pub mod cpu {
         fn test_ldd() {
             let cpu = CPUState {
                 //    B     C     D     E     H     L     fl    A
-                reg: [0x00, 0x01, 0x02, 0x03, 0x11, 0x22, FL_C, 0xAA],
+                reg: [0x00, 0x01, 0x02, 0x03, 0xC1, 0x22, FL_C, 0xAA],
                 ..INITIAL
             };
             let mut mem = Memory::new();
@@ -2624,17 +2778,17 @@ pub mod cpu {
         fn test_16b_loads() {
             let cpu = CPUState {
                 //    B     C     D     E     H     L     fl    A
-                reg: [0xBB, 0xCC, 0xDD, 0xEE, 0x11, 0x22, FL_C, 0xAA],
+                reg: [0xCB, 0xCC, 0xDD, 0xEE, 0x11, 0x22, FL_C, 0xAA],
                 ..INITIAL
             };
             let mut mem = Memory::new();
-            mem.write(0xBBCC, 0xAB);
+            mem.write(0xCBCC, 0xAB);
             mem.write(0xDDEE, 0xAD);
-            assert_eq!(ld_a_BC(cpu, &mem).reg[REG_A], mem.read(0xBBCC));
+            assert_eq!(ld_a_BC(cpu, &mem).reg[REG_A], mem.read(0xCBCC));
             assert_eq!(ld_a_DE(cpu, &mem).reg[REG_A], mem.read(0xDDEE));
 
             ld_BC_a(cpu, &mut mem);
-            assert_eq!(mem.read(0xBBCC), 0xAA);
+            assert_eq!(mem.read(0xCBCC), 0xAA);
 
             ld_DE_a(cpu, &mut mem);
             assert_eq!(mem.read(0xDDEE), 0xAA);
@@ -2716,7 +2870,7 @@ pub mod cpu {
             mem.write(TAC, 0b100); // (enabled, 1024 cycles per tick)
             assert_eq!(tac_enabled(&mem), true);
 
-            let new_timers = update_clocks(HardwareTimers::new(), &mut mem, 1024);
+            let new_timers = update_clocks(HardwareTimers::new(), &mut mem, Cycles::t(1024));
             assert_eq!(new_timers.timer, 0);
             assert_eq!(mem.read(TIMA), 1);
 
@@ -2724,7 +2878,7 @@ pub mod cpu {
             assert_eq!(mem.read(TIMA), 0);
 
             mem.write(TAC, 0b111); // (enabled, 256 cycles per tick)
-            let new_timers = update_clocks(HardwareTimers::new(), &mut mem, 1024);
+            let new_timers = update_clocks(HardwareTimers::new(), &mut mem, Cycles::t(1024));
             assert_eq!(new_timers.timer, 0);
             assert_eq!(mem.read(TIMA), 4);
 
@@ -2734,7 +2888,7 @@ pub mod cpu {
 
             mem.write(TMA, 0xAA);
             assert_ne!(mem.read(IF), FL_INT_TIMER);
-            let _even_newer_timers = update_clocks(new_timers, &mut mem, 256);
+            let _even_newer_timers = update_clocks(new_timers, &mut mem, Cycles::t(256));
             // should have overflowed as we just set it to 0xFF moments ago
             assert_eq!(mem.read(TIMA), 0xAA);
             assert_eq!(mem.read(IF), FL_INT_TIMER);
@@ -2778,13 +2932,22 @@ pub mod cpu {
 }
 
 pub mod memory {
+    use crate::apu::{NR52_BIT_POWER, NR_READ_MASKS};
     use crate::bits::{combine, hi, lo};
-    use crate::cpu::CPUState;
-    use crate::types::*;
-    use std::{
-        ops::{Index, IndexMut},
-        str::from_utf8,
+    use crate::cpu::{CPUState, FL_INT_JOYPAD, FL_INT_SERIAL};
+    use crate::dbg::{
+        stack_ok, Lint, SharedRomUsage, StackFault, StateHash, WatchHit, Watchpoint, INSTRUMENT,
+        ROM_USAGE_DATA,
     };
+    use crate::lcd::{LCDC_BIT_WINDOW_ENABLE, STAT_MASK_PPU_MODE};
+    use crate::mbc::{mbc_for, Mbc, NoMbc, SharedIrPort};
+    use crate::patch::RomPatch;
+    use crate::sav::RtcFooter;
+    use crate::types::*;
+    use serde::Serialize;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::ops::{Index, IndexMut};
 
     // 0000-3FFF   16KB ROM Bank 00     (in cartridge, fixed at bank 00)
     pub const MEM_BANK_00: Word = 0x0000;
@@ -2810,6 +2973,27 @@ pub mod memory {
     pub const MEM_HRAM: Word = 0xFF80;
     // FFFF        Interrupt Enable Register
 
+    /// Echo RAM is work RAM again this far up
+    pub const ECHO_OFFSET: Word = MEM_ECHO - MEM_WRAM_0;
+
+    /// The work RAM address an echo RAM address stands for, anything else as it is
+    pub const fn unmirror(addr: Word) -> Word {
+        match addr {
+            MEM_ECHO..MEM_OAM => addr - ECHO_OFFSET,
+            _ => addr,
+        }
+    }
+
+    /// The other half of a work RAM/echo RAM pair (C000-DDFF and E000-FDFF), None for
+    /// addresses that aren't mirrored
+    pub const fn mirror_of(addr: Word) -> Option<Word> {
+        match addr {
+            MEM_WRAM_0..0xDE00 => Some(addr + ECHO_OFFSET),
+            MEM_ECHO..MEM_OAM => Some(addr - ECHO_OFFSET),
+            _ => None,
+        }
+    }
+
     // RST locations (vectors)
     pub const VEC_RST_00: Word = 0x0000;
     pub const VEC_RST_08: Word = 0x0008;
@@ -2839,6 +3023,7 @@ pub mod memory {
     pub const NR10: Word = 0xFF10;
     pub const NR11: Word = 0xFF11;
     pub const NR12: Word = 0xFF12;
+    pub const NR13: Word = 0xFF13;
     pub const NR14: Word = 0xFF14;
     pub const NR21: Word = 0xFF16;
     pub const NR22: Word = 0xFF17;
@@ -2846,7 +3031,8 @@ pub mod memory {
     pub const NR30: Word = 0xFF1A;
     pub const NR31: Word = 0xFF1B;
     pub const NR32: Word = 0xFF1C;
-    pub const NR33: Word = 0xFF1E;
+    pub const NR33: Word = 0xFF1D;
+    pub const NR34: Word = 0xFF1E;
     pub const NR41: Word = 0xFF20;
     pub const NR42: Word = 0xFF21;
     pub const NR43: Word = 0xFF22;
@@ -2854,6 +3040,8 @@ pub mod memory {
     pub const NR50: Word = 0xFF24;
     pub const NR51: Word = 0xFF25;
     pub const NR52: Word = 0xFF26;
+    // 32 4 bit samples, high nibble first
+    pub const MEM_WAVE_RAM: Word = 0xFF30;
     // rendering
     pub const LCDC: Word = 0xFF40;
     pub const STAT: Word = 0xFF41;
@@ -2867,6 +3055,25 @@ pub mod memory {
     pub const OBP1: Word = 0xFF49;
     pub const WY: Word = 0xFF4A;
     pub const WX: Word = 0xFF4B;
+    pub const VBK: Word = 0xFF4F; // cgb only
+    pub const BCPS: Word = 0xFF68; // cgb only, background palette index (bit 7: auto-increment)
+    pub const BCPD: Word = 0xFF69; // cgb only, background palette data at BCPS
+    pub const OCPS: Word = 0xFF6A; // cgb only, same for objects
+    pub const OCPD: Word = 0xFF6B; // cgb only
+    pub const OPRI: Word = 0xFF6C; // cgb only, object priority mode
+
+    // joypad buttons, as held in Memory::buttons (same order as the JOYP nibbles)
+    pub const BTN_RIGHT: Byte = 1 << 0;
+    pub const BTN_LEFT: Byte = 1 << 1;
+    pub const BTN_UP: Byte = 1 << 2;
+    pub const BTN_DOWN: Byte = 1 << 3;
+    pub const BTN_A: Byte = 1 << 4;
+    pub const BTN_B: Byte = 1 << 5;
+    pub const BTN_SELECT: Byte = 1 << 6;
+    pub const BTN_START: Byte = 1 << 7;
+    const JOYP_SELECT_DPAD: Byte = 1 << 4; // active low
+    const JOYP_SELECT_BUTTONS: Byte = 1 << 5; // active low
+
     // interrupt registers
     pub const IF: Word = 0xFF0F;
     pub const IE: Word = 0xFFFF;
@@ -2875,7 +3082,11 @@ pub mod memory {
     pub const KB: usize = 0x0400; // one kilobyte
     pub const CART_SIZE_MAX: usize = 0x200000;
     pub const MEM_SIZE: usize = 0xFFFF + 1;
+    pub const PAGE_SIZE: usize = 0x0100; // what Memory's write journal keeps track of
     pub const BANK_SIZE: usize = 0x4000;
+    pub const EXT_RAM_BANK_SIZE: usize = 0x2000;
+    // sound register writes waiting on Apu::update, far more than an instruction makes
+    const APU_WRITES_MAX: usize = 1024;
 
     // ROM Header
     pub const ROM_ENTRY: Word = 0x0100;
@@ -2883,28 +3094,185 @@ pub mod memory {
     pub const ROM_TITLE: Word = 0x0134;
     pub const ROM_TITLE_END: Word = 0x0143 + 1;
     pub const ROM_MFR_CODE: Word = 0x013F;
+    pub const ROM_CGB: Word = 0x0143;
+    pub const ROM_NEW_LICENSEE: Word = 0x0144;
     pub const ROM_SGB: Word = 0x0146;
     pub const ROM_TYPE: Word = 0x0147;
     pub const ROM_SIZE: Word = 0x0148;
     pub const ROM_RAM_SIZE: Word = 0x0149;
     pub const ROM_DESTINATION: Word = 0x014A;
+    pub const ROM_OLD_LICENSEE: Word = 0x014B;
+    pub const ROM_VERSION: Word = 0x014C;
+    pub const ROM_HEADER_CHECKSUM: Word = 0x014D;
+    pub const ROM_GLOBAL_CHECKSUM: Word = 0x014E; // big endian, 014E-014F
+    pub const ROM_HEADER_END: Word = 0x0150;
+
+    /// Memory bank controller family, inferred from the cartridge type byte
+    #[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum MbcKind {
+        None,
+        Mbc1,
+        Mbc2,
+        Mbc3,
+        Mbc5,
+        Mbc7,
+        HuC1,
+        Mmm01,
+        Unsupported,
+    }
+
+    /// Publisher code from the header
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum Licensee {
+        /// One byte at 014B, what carts from before the SGB use
+        Old(Byte),
+        /// Two ascii characters at 0144-0145, used when the old code is 0x33
+        New([Byte; 2]),
+    }
+
+    impl std::fmt::Display for Licensee {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self {
+                Licensee::Old(code) => write!(f, "{:02X}", code),
+                Licensee::New(code) => write!(f, "{}", String::from_utf8_lossy(code)),
+            }
+        }
+    }
+
+    /// Why a rom couldn't be loaded
+    #[derive(Debug)]
+    pub enum CartridgeError {
+        /// The file couldn't be read (missing, no permission, ...)
+        Io(std::io::Error),
+        /// Too short to hold a header, the length is given
+        TooSmall(usize),
+        /// The rom size code at 0148 isn't one any cartridge uses
+        InvalidSize(Byte),
+        /// Something else in the header is nonsense
+        BadHeader(String),
+        /// An IPS/BPS patch that doesn't apply to this rom
+        BadPatch(String),
+    }
+
+    impl std::fmt::Display for CartridgeError {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self {
+                CartridgeError::Io(e) => write!(f, "couldn't read rom: {}", e),
+                CartridgeError::TooSmall(len) => write!(
+                    f,
+                    "rom is {} bytes, too small to hold a header ({} bytes)",
+                    len, ROM_HEADER_END
+                ),
+                CartridgeError::InvalidSize(code) => write!(f, "invalid rom size {:02X}", code),
+                CartridgeError::BadHeader(e) => write!(f, "bad header: {}", e),
+                CartridgeError::BadPatch(e) => write!(f, "can't apply patch: {}", e),
+            }
+        }
+    }
+
+    impl std::error::Error for CartridgeError {}
+
+    impl From<std::io::Error> for CartridgeError {
+        fn from(e: std::io::Error) -> Self {
+            CartridgeError::Io(e)
+        }
+    }
 
     pub struct Cartridge(Box<[Byte]>);
     impl Cartridge {
-        // todo: CGB flag
         // todo: MFR codes
-        // todo: Licensee codes
-        // todo: SGB flag
-        // todo: Old Licensee code
-        // todo: Mask rom version number
-        // todo: Checksum
-        // todo: Checksum (Global)
-        pub fn new(rom_path: &str) -> Cartridge {
-            let rom: Vec<Byte> = crate::io::read_bytes(rom_path);
-            Cartridge(rom.into_boxed_slice())
+        pub fn new(rom_path: &str) -> Result<Cartridge, CartridgeError> {
+            Cartridge::parse(crate::io::read_rom(rom_path)?)
+        }
+        /// Checks the header is usable before taking the rom, everything else assumes it is
+        pub fn parse(rom: Vec<Byte>) -> Result<Cartridge, CartridgeError> {
+            if rom.len() < ROM_HEADER_END as usize {
+                return Err(CartridgeError::TooSmall(rom.len()));
+            }
+            let cart = Cartridge(rom.into_boxed_slice());
+            match cart[ROM_SIZE] {
+                0x00..=0x08 | 0x52..=0x54 => {}
+                code => return Err(CartridgeError::InvalidSize(code)),
+            }
+            let ram = cart.header(ROM_RAM_SIZE);
+            if ram > 0x05 {
+                return Err(CartridgeError::BadHeader(format!(
+                    "invalid ram size {:02X}",
+                    ram
+                )));
+            }
+            Ok(cart)
+        }
+        /// Applies an IPS or BPS patch (a romhack or translation), see crate::patch
+        ///
+        /// The patched rom has to pass the same checks as a freshly loaded one, the cartridge is
+        /// left as it was otherwise.
+        pub fn apply_patch(&mut self, patch: &[Byte]) -> Result<(), CartridgeError> {
+            let rom = crate::patch::apply(&self.0, patch).map_err(CartridgeError::BadPatch)?;
+            *self = Cartridge::parse(rom)?;
+            Ok(())
+        }
+        /// The raw title field, including the CGB flag byte
+        pub fn title_bytes(&self) -> &[Byte] {
+            &self.0[ROM_TITLE as usize..ROM_TITLE_END as usize]
+        }
+        /// Printable cartridge title
+        ///
+        /// The title field is nominally upper case ascii padded with zeroes, but in practice
+        /// it's full of junk: CGB-era carts reuse the last byte as a flag (0x80/0xC0) and some
+        /// carts pad with spaces or high bytes. Anything that isn't printable ascii is dropped.
+        pub fn title(&self) -> String {
+            let raw = self.title_bytes();
+            let raw = if self.cgb_flag() {
+                &raw[..raw.len() - 1]
+            } else {
+                raw
+            };
+            raw.iter()
+                .take_while(|&&b| b != 0)
+                .filter(|b| b.is_ascii_graphic() || **b == b' ')
+                .map(|&b| b as char)
+                .collect::<String>()
+                .trim()
+                .to_string()
         }
-        pub fn title(&self) -> &str {
-            from_utf8(&self.0[ROM_TITLE as usize..ROM_TITLE_END as usize]).unwrap()
+        /// Whether the cart advertises CGB support (or requires it)
+        pub fn cgb_flag(&self) -> bool {
+            self[ROM_CGB] & 0x80 != 0
+        }
+        /// Whether the cart refuses to run on anything but a CGB (0xC0), rather than just
+        /// supporting it (0x80)
+        pub fn cgb_only(&self) -> bool {
+            self[ROM_CGB] == 0xC0
+        }
+        /// Whether the cart uses SGB functions (borders, palettes), an SGB ignores its
+        /// commands otherwise
+        pub fn sgb_flag(&self) -> bool {
+            self[ROM_SGB] == 0x03
+        }
+        /// Whether we run the cart on a Super Game Boy: it has to ask for one, and carts that
+        /// need a CGB get that instead
+        pub fn sgb_mode(&self) -> bool {
+            self.sgb_flag() && !self.cgb_only()
+        }
+        pub fn licensee(&self) -> Licensee {
+            match self[ROM_OLD_LICENSEE] {
+                0x33 => Licensee::New([self[ROM_NEW_LICENSEE], self[ROM_NEW_LICENSEE + 1]]),
+                code => Licensee::Old(code),
+            }
+        }
+        /// CRC-32 of the whole rom, what rom databases list next to the name
+        pub fn crc32(&self) -> u32 {
+            crate::checksum::crc32(&self.0)
+        }
+        /// SHA-1 of the whole rom in hex, see GameConfig::load
+        pub fn sha1(&self) -> String {
+            crate::checksum::hex(&crate::checksum::sha1(&self.0))
+        }
+        /// Revision of the game, usually 0
+        pub fn mask_rom_version(&self) -> Byte {
+            self[ROM_VERSION]
         }
         pub fn size(&self) -> usize {
             if self[ROM_SIZE] < 0x50 {
@@ -2926,8 +3294,20 @@ pub mod memory {
                 0
             }
         }
+        /// Where the header that describes the hardware starts: MMM01 compilations keep theirs
+        /// (the menu's) at the start of the last 32KB, the one in bank 0 is the first game's
+        fn header_base(&self) -> usize {
+            let last = self.0.len().saturating_sub(2 * BANK_SIZE);
+            match self.0.get(last + ROM_TYPE as usize) {
+                Some(0x0B..=0x0D) if last > 0 => last,
+                _ => 0,
+            }
+        }
+        fn header(&self, addr: Word) -> Byte {
+            self.0[self.header_base() + addr as usize]
+        }
         pub fn size_ram(&self) -> usize {
-            match self[ROM_RAM_SIZE] {
+            match self.header(ROM_RAM_SIZE) {
                 0x00 => KB * 0,
                 0x01 => KB * 2,
                 0x02 => KB * 8,
@@ -2938,7 +3318,7 @@ pub mod memory {
             }
         }
         pub fn hardware_type(&self) -> &str {
-            match self[ROM_TYPE] {
+            match self.header(ROM_TYPE) {
                 0x00 => "ROM ONLY",
                 0x01 => "MBC1",
                 0x02 => "MBC1+RAM",
@@ -2970,6 +3350,89 @@ pub mod memory {
                 _ => "???",
             }
         }
+        /// Header checksum over 0134-014C, the way the boot rom computes it
+        ///
+        /// The boot rom locks up if this doesn't match the byte at 014D, so a mismatch on a
+        /// real cart means a bad dump (or a homebrew header nobody fixed up).
+        pub fn header_checksum(&self) -> Byte {
+            self[ROM_TITLE as usize..ROM_HEADER_CHECKSUM as usize]
+                .iter()
+                .fold(0, |x: Byte, &b| x.wrapping_sub(b).wrapping_sub(1))
+        }
+        /// Sum of every byte in the rom except the global checksum itself
+        ///
+        /// Nothing on the console checks this, but it's stored at 014E-014F so dumps can be
+        /// verified.
+        pub fn global_checksum(&self) -> Word {
+            let stored = ROM_GLOBAL_CHECKSUM as usize..ROM_GLOBAL_CHECKSUM as usize + 2;
+            self.0
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !stored.contains(i))
+                .fold(0, |sum: Word, (_, &b)| sum.wrapping_add(b as Word))
+        }
+        /// Describes each checksum that doesn't match what the header says, empty when the rom
+        /// looks intact
+        pub fn checksum_errors(&self) -> Vec<String> {
+            let mut errors = vec![];
+            let header = self[ROM_HEADER_CHECKSUM];
+            if self.header_checksum() != header {
+                errors.push(format!(
+                    "header checksum is {:02X}, header says {:02X} (the boot rom would lock up)",
+                    self.header_checksum(),
+                    header
+                ));
+            }
+            let global = combine(self[ROM_GLOBAL_CHECKSUM], self[ROM_GLOBAL_CHECKSUM + 1]);
+            if self.global_checksum() != global {
+                errors.push(format!(
+                    "global checksum is {:04X}, header says {:04X}",
+                    self.global_checksum(),
+                    global
+                ));
+            }
+            errors
+        }
+        pub(crate) fn bytes(&self) -> &[Byte] {
+            &self.0
+        }
+        /// External RAM survives power off, see Memory::ext_ram
+        pub fn has_battery(&self) -> bool {
+            self.hardware_type().contains("+BATTERY")
+        }
+        pub fn mbc_kind(&self) -> MbcKind {
+            match self.header(ROM_TYPE) {
+                0x00 | 0x08 | 0x09 => MbcKind::None,
+                0x01..=0x03 => MbcKind::Mbc1,
+                0x05 | 0x06 => MbcKind::Mbc2,
+                0x0B..=0x0D => MbcKind::Mmm01,
+                0x0F..=0x13 => MbcKind::Mbc3,
+                0x19..=0x1E => MbcKind::Mbc5,
+                0x22 => MbcKind::Mbc7,
+                0xFF => MbcKind::HuC1,
+                _ => MbcKind::Unsupported,
+            }
+        }
+        /// One line header summary, as printed by `dis` and `--info`
+        pub fn summary(&self) -> String {
+            format!(
+                "{} | size: {} | banks: {} | ram: {} | hw: {} | dst: {} | lic: {} | ver: {}{}{}",
+                self.title(),
+                self.size(),
+                self.num_banks(),
+                self.size_ram(),
+                self.hardware_type(),
+                self.destination_code(),
+                self.licensee(),
+                self.mask_rom_version(),
+                match (self.cgb_flag(), self.cgb_only()) {
+                    (_, true) => " | cgb only",
+                    (true, _) => " | cgb",
+                    _ => "",
+                },
+                if self.sgb_flag() { " | sgb" } else { "" }
+            )
+        }
         pub fn destination_code(&self) -> &str {
             match self[ROM_DESTINATION] {
                 0x00 => "Japanese",
@@ -2977,6 +3440,65 @@ pub mod memory {
                 _ => "???",
             }
         }
+        /// Everything in the header, decoded, for `--header-json`
+        pub fn header_info(&self) -> HeaderInfo {
+            let global = combine(self[ROM_GLOBAL_CHECKSUM], self[ROM_GLOBAL_CHECKSUM + 1]);
+            HeaderInfo {
+                title: self.title(),
+                cartridge_type: self.header(ROM_TYPE),
+                hardware: self.hardware_type().to_string(),
+                mapper: self.mbc_kind(),
+                battery: self.has_battery(),
+                rom_size: self.size(),
+                rom_banks: self.size() / BANK_SIZE, // num_banks() says 0 without banking
+                ram_size: self.size_ram(),
+                cgb: self.cgb_flag(),
+                cgb_only: self.cgb_only(),
+                sgb: self.sgb_flag(),
+                licensee: self.licensee().to_string(),
+                destination: self.destination_code().to_string(),
+                version: self.mask_rom_version(),
+                header_checksum: self[ROM_HEADER_CHECKSUM],
+                header_checksum_ok: self.header_checksum() == self[ROM_HEADER_CHECKSUM],
+                global_checksum: global,
+                global_checksum_ok: self.global_checksum() == global,
+                crc32: format!("{:08x}", self.crc32()),
+                sha1: self.sha1(),
+            }
+        }
+    }
+
+    /// A decoded cartridge header, see Cartridge::header_info
+    ///
+    /// Sizes are in bytes, checksums are as stored in the header with whether the rom
+    /// matches them.
+    #[derive(Clone, Debug, PartialEq, Serialize)]
+    pub struct HeaderInfo {
+        pub title: String,
+        pub cartridge_type: Byte,
+        pub hardware: String,
+        pub mapper: MbcKind,
+        pub battery: bool,
+        pub rom_size: usize,
+        pub rom_banks: usize,
+        pub ram_size: usize,
+        pub cgb: bool,
+        pub cgb_only: bool,
+        pub sgb: bool,
+        pub licensee: String,
+        pub destination: String,
+        pub version: Byte,
+        pub header_checksum: Byte,
+        pub header_checksum_ok: bool,
+        pub global_checksum: Word,
+        pub global_checksum_ok: bool,
+        pub crc32: String,
+        pub sha1: String,
+    }
+    impl From<Vec<Byte>> for Cartridge {
+        fn from(rom: Vec<Byte>) -> Self {
+            Cartridge(rom.into_boxed_slice())
+        }
     }
     impl Index<Word> for Cartridge {
         type Output = Byte;
@@ -2997,22 +3519,101 @@ pub mod memory {
         }
     }
 
+    #[derive(Clone)]
     pub struct Memory {
-        pub(crate) data: [Byte; MEM_SIZE],
+        pub(crate) data: Vec<Byte>, // MEM_SIZE, see take_data()
         pub dma_req: bool,
+        // --- cartridge ---
+        mbc: Box<dyn Mbc>,              // 0000-7FFF and A000-BFFF
+        ejected: Option<Box<dyn Mbc>>,  // pulled out while running, see eject_cartridge
+        unsupported: Vec<&'static str>, // what the game asked of the mapper, see unsupported()
+        // --- cgb ---
+        /// The cartridge asked for color gameboy features (VRAM bank 1 and so on)
+        pub cgb: bool,
+        vram1: Vec<Byte>, // 8000-9FFF while VBK selects bank 1, empty outside cgb mode
+        bg_palettes: [Byte; 64], // 8 palettes of 4 little endian 15 bit colors, see BCPD
+        obj_palettes: [Byte; 64], // see OCPD
         // --- debug ---
         pub doctor: bool,
+        pub stack_guard: bool,
+        pub(crate) stack_fault: Option<StackFault>,
+        /// Cpu writes that stop emulation, see StepInfo::watch_hit
+        pub watches: Vec<Watchpoint>,
+        pub(crate) watch_hit: Option<WatchHit>,
+        pub(crate) watch_pc: Word, // the instruction running, for watch_hit
+        /// FL_INT_* bits the cpu treats as clear in IE, to see how a game copes without them
+        pub int_mask: Byte,
+        /// Note dubious things the rom does, see emu::Emulator::take_lints
+        pub lint: bool,
+        pub(crate) lints: RefCell<Vec<Lint>>, // reads note them too, so behind a RefCell
+        initialized: Vec<u64>,                // bitset of addresses written, empty until linting
+        rom_usage: Option<SharedRomUsage>,    // see set_rom_usage
+        // --- input ---
+        buttons: Byte,                     // BTN_* currently held
+        sgb: bool,                         // JOYP writes go to the sgb too, see sgb::Sgb
+        pub(crate) joyp_writes: Vec<Byte>, // for the sgb to decode its packets from
+        sgb_players: Byte,                 // see set_sgb_players
+        sgb_player: Byte,
+        // --- serial ---
+        serial: Vec<Byte>,         // every byte sent over the link cable
+        serial_taken: usize,       // how much of it serial_take has handed out
+        serial_in: VecDeque<Byte>, // what the other side sends next, see serial_push
+        // --- audio ---
+        pub(crate) apu_writes: VecDeque<(Word, Byte)>, // sound register writes for the apu to act on, see push_apu_write
+        pub(crate) wave_byte: Option<Word>, // see apu::Wave::current_byte, kept up by Apu::update
+        // --- cheats ---
+        rom_patches: Vec<(RomPatch, bool)>, // and whether it's on, see add_rom_patch
+        // --- meta ---
+        pub(crate) writes: u64, // bumped on every write, a cheap way to spot side effects
+        code: Vec<u64>, // bitset of RAM addresses holding cached instructions, see track_code
+        pub(crate) code_written: bool, // something wrote over one of those, see emu::BlockCache
+        written: [u64; MEM_SIZE / PAGE_SIZE / 64], // bitset of pages written, see take_written()
     }
     impl Memory {
         pub fn new() -> Memory {
             let mut mem = Memory {
-                data: [0; MEM_SIZE],
+                data: vec![0; MEM_SIZE],
                 dma_req: false,
+                mbc: Box::new(NoMbc::empty()),
+                ejected: None,
+                unsupported: vec![],
+                cgb: false,
+                vram1: vec![],
+                // the cgb boot rom leaves them all white
+                bg_palettes: [0xFF; 64],
+                obj_palettes: [0xFF; 64],
                 doctor: false,
+                stack_guard: false,
+                stack_fault: None,
+                watches: vec![],
+                watch_hit: None,
+                watch_pc: 0,
+                int_mask: 0,
+                lint: false,
+                lints: RefCell::new(vec![]),
+                initialized: vec![],
+                rom_usage: None,
+                buttons: 0,
+                sgb: false,
+                joyp_writes: vec![],
+                sgb_players: 1,
+                sgb_player: 0,
+                serial: vec![],
+                serial_taken: 0,
+                serial_in: VecDeque::new(),
+                apu_writes: VecDeque::new(),
+                wave_byte: None,
+                rom_patches: vec![],
+                writes: 0,
+                code: vec![],
+                code_written: false,
+                written: [0; MEM_SIZE / PAGE_SIZE / 64],
             };
             mem.write(TIMA, 0x00);
             mem.write(TMA, 0x00);
             mem.write(TAC, 0x00);
+            // first, the other sound registers ignore writes while the apu is off
+            mem.write(NR52, 0xF1);
             mem.write(NR10, 0x80);
             mem.write(NR11, 0xBF);
             mem.write(NR12, 0xF3);
@@ -3023,14 +3624,13 @@ pub mod memory {
             mem.write(NR30, 0x7F);
             mem.write(NR31, 0xFF);
             mem.write(NR32, 0x9F);
-            mem.write(NR33, 0xBF);
+            mem.write(NR34, 0xBF);
             mem.write(NR41, 0xFF);
             mem.write(NR42, 0x00);
             mem.write(NR43, 0x00);
             mem.write(NR44, 0xBF);
             mem.write(NR50, 0x77);
             mem.write(NR51, 0xF3);
-            mem.write(NR52, 0xF1);
             mem.write(LCDC, 0x91);
             mem.write(SCY, 0x00);
             mem.write(SCX, 0x00);
@@ -3041,18 +3641,240 @@ pub mod memory {
             mem.write(WY, 0x00);
             mem.write(WX, 0x00);
             mem.write(IE, 0x00);
+            // the boot rom's beep is long over by now, the channels start out silent
+            mem.apu_writes.clear();
             mem
         }
+        /// Queues a sound register write for Apu::update. Memory without an apu running (a
+        /// test, a tool poking at a rom) keeps only the last APU_WRITES_MAX
+        fn push_apu_write(&mut self, addr: Word, val: Byte) {
+            if self.apu_writes.len() == APU_WRITES_MAX {
+                self.apu_writes.pop_front();
+            }
+            self.apu_writes.push_back((addr, val));
+        }
+        /// Where a cpu access to wave RAM really goes: while channel 3 plays, whichever byte
+        /// it's reading, whatever the address
+        ///
+        /// On DMG that only works on the cycle the channel fetches, otherwise reads give FF
+        /// and writes are dropped. The apu only catches up between instructions, so every
+        /// access counts as landing on that cycle.
+        fn wave_addr(&self, addr: Word) -> Word {
+            match self.wave_byte {
+                Some(byte) => MEM_WAVE_RAM + byte,
+                None => addr,
+            }
+        }
         pub fn load_rom(&mut self, cart: &Cartridge) {
-            // raw copy, skip mem checks
-            self.data[MEM_BANK_00 as usize..MEM_VRAM as usize]
-                .copy_from_slice(&cart.0[MEM_BANK_00 as usize..MEM_VRAM as usize])
+            self.mbc = mbc_for(cart);
+            self.ejected = None;
+            self.unsupported.clear();
+            // a cart that only supports the cgb (0x80) checks A at boot to pick its dmg or
+            // cgb code, and we boot as a dmg (see CPUState::new), so it runs as a dmg game
+            self.cgb = cart.cgb_only();
+            self.vram1 = if self.cgb { vec![0; 0x2000] } else { vec![] };
+            self.sgb = cart.sgb_mode();
+            self.sgb_players = 1;
+            self.sgb_player = 0;
+            self[VBK] = 0;
+            // OAM order (the boot rom picks x order for dmg games on a cgb, which we run as dmg)
+            self[OPRI] = 0;
+        }
+        /// The byte at a VRAM address in bank 1, where the cgb keeps its background
+        /// attributes and more tiles. 0 outside cgb mode
+        pub fn vram1(&self, addr: Word) -> Byte {
+            self.vram1
+                .get((addr - MEM_VRAM) as usize)
+                .copied()
+                .unwrap_or(0)
+        }
+        /// sgb::MLT_REQ: with more than one player, JOYP reads which one's buttons are next
+        /// while neither half is selected
+        pub(crate) fn set_sgb_players(&mut self, players: Byte) {
+            self.sgb_players = players;
+            self.sgb_player = 0;
+        }
+        /// A cgb palette color (15 bit, red in the low bits) out of palette RAM
+        pub fn cgb_color(&self, obj: bool, palette: Byte, color: Byte) -> Word {
+            let ram = if obj {
+                &self.obj_palettes
+            } else {
+                &self.bg_palettes
+            };
+            let i = (palette as usize & 7) * 8 + (color as usize & 3) * 2;
+            combine(ram[i + 1], ram[i])
+        }
+        /// Palette RAM through BCPD/OCPD, at the index in BCPS/OCPS (data_port - 1)
+        fn palette_ram(&mut self, data_port: Word) -> &mut Byte {
+            let index = (self[data_port - 1] & 0x3F) as usize;
+            if data_port == BCPD {
+                &mut self.bg_palettes[index]
+            } else {
+                &mut self.obj_palettes[index]
+            }
+        }
+        /// Whether the cpu's VRAM accesses go to bank 1 (Index always gives bank 0)
+        fn vram1_selected(&self) -> bool {
+            self.cgb && self[VBK] & 1 != 0
+        }
+        /// Cartridge features the game has tried to use that the mapper doesn't emulate, in the
+        /// order they came up (each is logged as a warning the first time)
+        pub fn unsupported(&self) -> &[&'static str] {
+            &self.unsupported
+        }
+        fn check_unsupported(&mut self, addr: Word, val: Byte) {
+            if self.ejected.is_some() {
+                return; // nothing's listening
+            }
+            if let Some(feature) = self.mbc.unsupported(addr, val) {
+                if !self.unsupported.contains(&feature) {
+                    log::warn!(
+                        "unsupported mapper feature: {} (wrote {:02X} to {:04X})",
+                        feature,
+                        val,
+                        addr
+                    );
+                    self.unsupported.push(feature);
+                }
+            }
+        }
+        /// Pulls the cartridge out with the power on, for testing how games cope
+        ///
+        /// Nothing drives the bus without a cartridge, so ROM and RAM read 0xFF and writes go
+        /// nowhere. The cartridge keeps its RAM and mapper state for insert_cartridge.
+        pub fn eject_cartridge(&mut self) {
+            if self.ejected.is_none() {
+                let cart = std::mem::replace(&mut self.mbc, Box::new(NoMbc::open_bus()));
+                self.ejected = Some(cart);
+                self.code_written = true; // cached instructions came from the cartridge
+            }
+        }
+        /// Puts an ejected cartridge back
+        pub fn insert_cartridge(&mut self) {
+            if let Some(cart) = self.ejected.take() {
+                self.mbc = cart;
+                self.code_written = true;
+            }
+        }
+        pub fn cartridge_ejected(&self) -> bool {
+            self.ejected.is_some()
+        }
+        /// Swaps a ROM byte as the cpu reads it (a Game Genie code), on from the start.
+        /// Returns its index for toggle_rom_patch
+        pub fn add_rom_patch(&mut self, patch: RomPatch) -> usize {
+            self.rom_patches.push((patch, true));
+            self.code_written = true; // cached instructions came from the unpatched rom
+            self.rom_patches.len() - 1
+        }
+        /// Turns a patch on or off, giving back whether it's on now (None if there's no such
+        /// patch)
+        pub fn toggle_rom_patch(&mut self, index: usize) -> Option<bool> {
+            let (_, on) = self.rom_patches.get_mut(index)?;
+            *on = !*on;
+            self.code_written = true;
+            Some(*on)
+        }
+        pub fn rom_patches(&self) -> &[(RomPatch, bool)] {
+            &self.rom_patches
+        }
+        /// What the cartridge puts out at addr, through any patches that are on
+        fn read_cart_rom(&self, addr: Word) -> Byte {
+            let val = self.mbc.read_rom(addr);
+            self.rom_patches
+                .iter()
+                .filter(|(_, on)| *on)
+                .fold(val, |val, (patch, _)| patch.apply(addr, val))
+        }
+        /// The cartridge, plugged in or not
+        fn cart(&self) -> &dyn Mbc {
+            self.ejected.as_deref().unwrap_or(&*self.mbc)
+        }
+        fn cart_mut(&mut self) -> &mut dyn Mbc {
+            match &mut self.ejected {
+                Some(cart) => &mut **cart,
+                None => &mut *self.mbc,
+            }
+        }
+        /// Sets which BTN_* buttons are held, pressing any new ones raises the joypad interrupt
+        pub fn set_buttons(&mut self, buttons: Byte) {
+            if buttons & !self.buttons != 0 {
+                crate::cpu::request_interrupt(self, FL_INT_JOYPAD);
+            }
+            self.buttons = buttons;
+        }
+        pub fn buttons(&self) -> Byte {
+            self.buttons
+        }
+        /// Everything the hardware would remember (the rom itself never changes)
+        pub fn hash_state(&self, h: &mut StateHash) {
+            h.bytes(&self.data);
+            self.mbc.hash_state(h);
+            if let Some(cart) = &self.ejected {
+                cart.hash_state(h);
+            }
+            h.bytes(&[self.dma_req as Byte, self.buttons]);
+            h.bytes(&self.vram1);
+            h.bytes(&self.bg_palettes);
+            h.bytes(&self.obj_palettes);
+        }
+        /// All of the cartridge RAM (every bank), what a .sav file holds
+        pub fn ext_ram(&self) -> &[Byte] {
+            self.cart().ram()
+        }
+        /// Restores cartridge RAM, ignoring anything past the size the header asks for
+        pub fn load_ext_ram(&mut self, data: &[Byte]) {
+            let ram = self.cart_mut().ram_mut();
+            let len = data.len().min(ram.len());
+            ram[..len].copy_from_slice(&data[..len]);
+        }
+        /// The cartridge clock for the .sav footer, `now` being unix time in seconds
+        pub fn rtc(&self, now: u64) -> Option<RtcFooter> {
+            self.cart().rtc(now)
+        }
+        /// Restores the cartridge clock, running it forward by the time since it was saved
+        pub fn load_rtc(&mut self, rtc: &RtcFooter, now: u64) {
+            self.cart_mut().load_rtc(rtc, now);
+        }
+        /// Feeds the cartridge accelerometer (MBC7 carts), in g with right/down positive
+        pub fn set_tilt(&mut self, x: f32, y: f32) {
+            self.cart_mut().set_tilt(x, y);
+        }
+        /// Notes which ROM bytes get run and read from now on (None stops), see dbg::RomUsage
+        pub fn set_rom_usage(&mut self, usage: Option<SharedRomUsage>) {
+            self.rom_usage = usage;
+        }
+        pub(crate) fn tracks_rom_usage(&self) -> bool {
+            self.rom_usage.is_some()
+        }
+        /// read() for instruction bytes, which the usage map gets from the emulator as code
+        pub(crate) fn read_fetch(&mut self, addr: Word) -> Byte {
+            let usage = self.rom_usage.take();
+            let val = self.read(addr);
+            self.rom_usage = usage;
+            val
+        }
+        /// Flags the ROM byte mapped in at addr (below 8000) in the usage map, if there is one
+        pub(crate) fn mark_rom_usage(&self, addr: Word, flag: Byte) {
+            let Some(usage) = &self.rom_usage else {
+                return;
+            };
+            let bank = self.bank_at(addr);
+            if bank != usize::MAX {
+                let offset = bank * BANK_SIZE + addr as usize % BANK_SIZE;
+                usage.lock().unwrap().mark(offset, flag);
+            }
+        }
+        /// Connects the cartridge's infrared port (HuC1 carts) to something
+        pub fn set_ir_port(&mut self, port: SharedIrPort) {
+            self.cart_mut().set_ir_port(port);
         }
-        pub fn bank0(&mut self) -> &mut [Byte] {
-            &mut self.data[MEM_BANK_00 as usize..MEM_BANK_NN as usize]
+        /// What the cartridge in the slot plays on its VIN pin, see Mbc::vin
+        pub fn vin(&self) -> f64 {
+            self.mbc.vin()
         }
-        pub fn bank1(&mut self) -> &mut [Byte] {
-            &mut self.data[MEM_BANK_NN as usize..MEM_VRAM as usize]
+        /// Lets the cartridge hardware (e.g. its clock) keep time with the cpu
+        pub fn add_cartridge_cycles(&mut self, cycles: Cycles) {
+            self.cart_mut().add_cycles(cycles.as_t());
         }
         /// Update is called once per instruction decode
         ///
@@ -3065,14 +3887,91 @@ pub mod memory {
                 // https://gbdev.io/pandocs/OAM_DMA_Transfer.html#ff46--dma-oam-dma-source-address--start
                 // Source:      $XX00-$XX9F   ;XX = $00 to $DF
                 // Destination: $FE00-$FE9F
-                let offset = self[DMA];
-                let dma_start = crate::bits::combine(offset, 0x00) as usize;
-                let dma_end = (crate::bits::combine(offset, 0x9F) + 1) as usize;
-                let (main_chunk, oam_chunk) = self.data.split_at_mut(MEM_OAM as usize);
-                oam_chunk[0..0xA0].copy_from_slice(&main_chunk[dma_start..dma_end]);
+                // (read byte by byte, the source may be banked cartridge memory)
+                let dma_start = crate::bits::combine(self[DMA], 0x00);
+                for i in 0..0xA0 {
+                    self.data[(MEM_OAM + i) as usize] = self.read(dma_start + i);
+                }
+                self.mark_written(MEM_OAM);
+            }
+        }
+        /// The bank mapped in at an address: ROM bank below 8000, RAM bank in A000-BFFF, else 0
+        pub fn bank_at(&self, addr: Word) -> usize {
+            match addr {
+                MEM_BANK_00..=0x7FFF | MEM_EXT..=0xBFFF => self.mbc.bank_at(addr),
+                _ => 0,
+            }
+        }
+        /// Starts looking out for writes over cached instructions (see mark_code). It costs
+        /// every write a lookup, so it's off until the block cache is first used
+        pub(crate) fn track_code(&mut self) {
+            if self.code.is_empty() {
+                self.code = vec![0; MEM_SIZE / 64];
+                self.code_written = false;
             }
         }
+        /// Watch a RAM address for writes, they set code_written
+        pub(crate) fn mark_code(&mut self, addr: Word) {
+            let addr = unmirror(addr);
+            if addr >= MEM_VRAM {
+                self.code[addr as usize / 64] |= 1 << (addr % 64);
+            }
+        }
+        pub(crate) fn clear_code(&mut self) {
+            self.code.iter_mut().for_each(|bits| *bits = 0);
+            self.code_written = false;
+        }
+        fn mark_written(&mut self, addr: Word) {
+            let page = addr as usize / PAGE_SIZE;
+            self.written[page / 64] |= 1 << (page % 64);
+        }
+        /// The pages (addr / PAGE_SIZE) written since the last call, the journal emu::Rewind
+        /// keeps its deltas with. Cartridge ROM and RAM aren't in it, the controller has those.
+        pub(crate) fn take_written(&mut self) -> Vec<usize> {
+            let written = std::mem::take(&mut self.written);
+            (0..MEM_SIZE / PAGE_SIZE)
+                .filter(|page| written[page / 64] & (1 << (page % 64)) != 0)
+                .collect()
+        }
+        /// Takes the address space out, leaving a Memory that's only good for putting it back
+        /// with put_data(). Snapshots keep it in pages instead, see emu::Rewind
+        pub(crate) fn take_data(&mut self) -> Vec<Byte> {
+            self.code = vec![];
+            std::mem::take(&mut self.data)
+        }
+        pub(crate) fn put_data(&mut self, data: Vec<Byte>) {
+            assert_eq!(data.len(), MEM_SIZE);
+            self.data = data;
+            // like a clone, which starts with an empty emu::BlockCache
+            self.code = vec![];
+            self.code_written = false;
+        }
         pub fn write(&mut self, addr: Word, val: Byte) {
+            self.writes += 1;
+            if self.lint {
+                self.lint_write(addr, val);
+            }
+            if !self.code.is_empty() {
+                let ram = unmirror(addr);
+                if self.code[ram as usize / 64] & (1 << (ram % 64)) != 0 {
+                    self.code_written = true;
+                }
+            }
+            if !self.watches.is_empty() && self.watch_hit.is_none() {
+                let mode = self[STAT] & STAT_MASK_PPU_MODE;
+                // a watch on either half of echo RAM catches writes through the other
+                let hit = |w: &Watchpoint| {
+                    w.hit(addr, mode) || mirror_of(addr).is_some_and(|m| w.hit(m, mode))
+                };
+                if self.watches.iter().any(hit) {
+                    self.watch_hit = Some(WatchHit {
+                        pc: self.watch_pc,
+                        addr,
+                        val,
+                        mode,
+                    });
+                }
+            }
             let blocked = vec![
                 DIV,
                 // 0xFF41, // stat
@@ -3082,40 +3981,226 @@ pub mod memory {
             }
             match addr {
                 JOYP => {
-                    self[addr] |= 0x30 & val; // lower nibble is read only
+                    // only the select bits are writable, the rest comes from the buttons
+                    let select = val & (JOYP_SELECT_DPAD | JOYP_SELECT_BUTTONS);
+                    if self.sgb {
+                        // with several players, deselecting the buttons moves on to the next
+                        if select & JOYP_SELECT_BUTTONS != 0
+                            && self[addr] & JOYP_SELECT_BUTTONS == 0
+                        {
+                            self.sgb_player = (self.sgb_player + 1) % self.sgb_players;
+                        }
+                        self.joyp_writes.push(select);
+                    }
+                    self[addr] = select;
+                }
+                SC => {
+                    self[SC] = val;
+                    self.serial_transfer();
+                }
+                NR52 => {
+                    if val & NR52_BIT_POWER == 0 {
+                        // everything but wave RAM is cleared, and stays that way until powered on
+                        for reg in NR10..NR52 {
+                            self[reg] = 0;
+                        }
+                        self[NR52] = 0;
+                    } else {
+                        // the channel bits are read only, see Apu::update
+                        self[NR52] |= NR52_BIT_POWER;
+                    }
+                    self.push_apu_write(addr, val);
+                }
+                // with the apu off only the length counters can be loaded (on DMG), not duty
+                NR11 | NR21 | NR31 | NR41 if self[NR52] & NR52_BIT_POWER == 0 => {
+                    let val = if addr == NR11 || addr == NR21 {
+                        val & 0x3F
+                    } else {
+                        val
+                    };
+                    self[addr] = val;
+                    self.push_apu_write(addr, val);
+                }
+                NR10..=NR51 if self[NR52] & NR52_BIT_POWER == 0 => {}
+                NR10..=NR51 => {
+                    self[addr] = val;
+                    self.push_apu_write(addr, val);
+                }
+                MEM_WAVE_RAM..=0xFF3F => {
+                    let addr = self.wave_addr(addr);
+                    self[addr] = val;
+                }
+                VBK if self.cgb => self[VBK] = val & 1,
+                VBK => {}
+                BCPS | OCPS if self.cgb => self[addr] = val & 0xBF,
+                BCPD | OCPD if self.cgb => {
+                    *self.palette_ram(addr) = val;
+                    let spec = self[addr - 1];
+                    if spec & 0x80 != 0 {
+                        self[addr - 1] = 0x80 | (spec.wrapping_add(1) & 0x3F);
+                    }
+                }
+                BCPS..=OCPD => {}
+                OPRI if self.cgb => self[OPRI] = val & 1,
+                OPRI => {}
+                MEM_VRAM..=0x9FFF if self.vram1_selected() => {
+                    self.vram1[(addr - MEM_VRAM) as usize] = val;
+                }
+                MEM_BANK_00..=0x7FFF => {
+                    self.check_unsupported(addr, val);
+                    self.mbc.write_rom(addr, val);
+                }
+                MEM_EXT..=0xBFFF => {
+                    self.check_unsupported(addr, val);
+                    self.mbc.write_ram(addr, val);
                 }
                 _ => self[addr] = val,
             }
         }
-        pub fn read(&self, addr: Word) -> Byte {
-            match addr {
-                JOYP => {
-                    let bitset = if 0x30 & self[addr] == 0x30 { 0x0F } else { 0 };
-                    self[addr] | bitset
-                }
-                IE => self[addr] & 0x1F,
-                IF => self[addr] & 0x1F,
-                _ => self[addr],
+        /// Everything sent over the serial port so far, test roms print their results here
+        pub fn serial_output(&self) -> &[Byte] {
+            &self.serial
+        }
+        /// Queues a byte for the other end of the link cable to send, the partner for tests
+        /// and scripts. It goes in with the next transfer: straight away if the game is
+        /// already waiting on an external clock
+        pub fn serial_push(&mut self, byte: Byte) {
+            self.serial_in.push_back(byte);
+            self.serial_transfer();
+        }
+        /// The bytes sent over the serial port since the last call
+        pub fn serial_take(&mut self) -> Vec<Byte> {
+            let taken = self.serial[self.serial_taken..].to_vec();
+            self.serial_taken = self.serial.len();
+            taken
+        }
+        /// Swaps SB with the other side once a transfer's been asked for. With the internal
+        /// clock it's done straight away, a missing partner sending 0xFF. With the external
+        /// clock it waits until the partner has something to send
+        fn serial_transfer(&mut self) {
+            let sc = self[SC];
+            if sc & 0x80 == 0 {
+                return;
             }
+            let received = match self.serial_in.pop_front() {
+                Some(byte) => byte,
+                None if sc & 0x01 != 0 => 0xFF,
+                None => return,
+            };
+            self.serial.push(self[SB]);
+            self[SB] = received;
+            self[SC] = sc & 0x7F;
+            self[IF] |= FL_INT_SERIAL;
         }
-    }
+        fn lint_write(&mut self, addr: Word, val: Byte) {
+            if self.initialized.is_empty() {
+                self.initialized = vec![0; MEM_SIZE / 64];
+            }
+            let ram = unmirror(addr);
+            self.initialized[ram as usize / 64] |= 1 << (ram % 64);
+            let turns_on = |bit: Byte| val & bit != 0 && self[LCDC] & bit == 0;
+            let lint = match addr {
+                MEM_BANK_00..=0x7FFF if !self.mbc.has_registers() => Lint::RomWrite(addr),
+                LCDC if turns_on(LCDC_BIT_WINDOW_ENABLE) && (self[WX] > 166 || self[WY] > 143) => {
+                    Lint::WindowOffscreen {
+                        wx: self[WX],
+                        wy: self[WY],
+                    }
+                }
+                _ => return,
+            };
+            self.lints.get_mut().push(lint);
+        }
+        fn lint_read(&self, addr: Word) {
+            let ram = (MEM_WRAM_0..MEM_OAM).contains(&addr) || (MEM_HRAM..IE).contains(&addr);
+            let bit = unmirror(addr);
+            let written = self
+                .initialized
+                .get(bit as usize / 64)
+                .is_some_and(|bits| bits & (1 << (bit % 64)) != 0);
+            if ram && !written {
+                self.lints.borrow_mut().push(Lint::UninitializedRead(addr));
+            }
+        }
+        /// FL_INT_* bits both requested and enabled, less the ones masked off by int_mask
+        pub fn enabled_interrupts(&self) -> Byte {
+            self.read(IE) & self.read(IF) & !self.int_mask
+        }
+        pub fn read(&self, addr: Word) -> Byte {
+            if self.lint {
+                self.lint_read(addr);
+            }
+            match addr {
+                JOYP => {
+                    let select = self[addr] & (JOYP_SELECT_DPAD | JOYP_SELECT_BUTTONS);
+                    let mut held = 0;
+                    if select & JOYP_SELECT_DPAD == 0 {
+                        held |= self.buttons & 0x0F;
+                    }
+                    if select & JOYP_SELECT_BUTTONS == 0 {
+                        held |= self.buttons >> 4;
+                    }
+                    if select == JOYP_SELECT_DPAD | JOYP_SELECT_BUTTONS && self.sgb_players > 1 {
+                        // the sgb says which player's buttons are up next
+                        return 0xC0 | select | (0x0F - self.sgb_player);
+                    }
+                    if self.sgb_player != 0 {
+                        held = 0; // nobody's on the other controllers
+                    }
+                    0xC0 | select | (!held & 0x0F) // 0 == pressed
+                }
+                IE => self[addr] & 0x1F,
+                IF => self[addr] & 0x1F,
+                // the cpu sees the LY gameboy-doctor's logs were made with, the ppu (going
+                // through Index) keeps the real one https://robertheaton.com/gameboy-doctor/
+                LY if INSTRUMENT && self.doctor => 0x90,
+                NR10..MEM_WAVE_RAM => self[addr] | NR_READ_MASKS[(addr - NR10) as usize],
+                MEM_WAVE_RAM..=0xFF3F => self[self.wave_addr(addr)],
+                VBK if self.cgb => 0xFE | self[VBK],
+                VBK => 0xFF,
+                BCPS | OCPS if self.cgb => 0x40 | self[addr],
+                BCPD if self.cgb => self.bg_palettes[(self[BCPS] & 0x3F) as usize],
+                OCPD if self.cgb => self.obj_palettes[(self[OCPS] & 0x3F) as usize],
+                BCPS..=OCPD => 0xFF,
+                OPRI if self.cgb => 0xFE | self[OPRI],
+                OPRI => 0xFF,
+                MEM_VRAM..=0x9FFF if self.vram1_selected() => self.vram1(addr),
+                MEM_BANK_00..=0x7FFF => {
+                    if self.rom_usage.is_some() {
+                        self.mark_rom_usage(addr, ROM_USAGE_DATA);
+                    }
+                    self.read_cart_rom(addr)
+                }
+                MEM_EXT..=0xBFFF => self.mbc.read_ram(addr),
+                _ => self[addr],
+            }
+        }
+    }
+    // every byte value, for Index to return references to
+    const BYTE_VALUES: [Byte; 256] = {
+        let mut values = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            values[i] = i as Byte;
+            i += 1;
+        }
+        values
+    };
+
     impl Index<Word> for Memory {
         type Output = Byte;
         fn index(&self, index: Word) -> &Self::Output {
             match index {
-                LY => {
-                    if self.doctor {
-                        &0x90
-                    } else {
-                        &self.data[index as usize]
-                    }
-                } // for debugger https://robertheaton.com/gameboy-doctor/
-                _ => &self.data[index as usize],
+                // the cartridge hands out values, not references
+                MEM_BANK_00..=0x7FFF => &BYTE_VALUES[self.read_cart_rom(index) as usize],
+                MEM_EXT..=0xBFFF => &BYTE_VALUES[self.mbc.read_ram(index) as usize],
+                _ => &self.data[unmirror(index) as usize],
             }
         }
     }
     impl IndexMut<Word> for Memory {
         fn index_mut(&mut self, index: Word) -> &mut Self::Output {
+            let index = unmirror(index);
             match index {
                 DMA => {
                     // println!("[DMA] 0x{:X}", self[index]);
@@ -3130,6 +4215,7 @@ pub mod memory {
                     // println!("[{:04X}] {:02X}", index, self[index]);
                 }
             }
+            self.mark_written(index);
             &mut self.data[index as usize]
         }
     }
@@ -3148,6 +4234,9 @@ pub mod memory {
     }
     pub fn push_d16(cpu: CPUState, mem: &mut Memory, val: Word) -> CPUState {
         let sp = cpu.sp - 2;
+        if INSTRUMENT && mem.stack_guard && mem.stack_fault.is_none() && !(stack_ok(sp) && stack_ok(sp + 1)) {
+            mem.stack_fault = Some(StackFault::Push { pc: cpu.pc, addr: sp });
+        }
         mem.write(sp + 1, hi(val));
         mem.write(sp + 0, lo(val));
         CPUState { sp, ..cpu }
@@ -3164,870 +4253,11440 @@ pub mod memory {
         let sp = cpu.sp + 2;
         (CPUState { sp, ..cpu }, val)
     }
-}
 
-pub mod types {
-    pub type Byte = u8;
-    pub type Word = u16;
-    pub type SByte = i8;
-    pub type SWord = i16;
+    #[cfg(test)]
+    mod tests_memory {
+        use super::*;
+        use crate::mbc::tests_mbc::banked_cart;
 
-    #[derive(PartialEq, Debug)]
-    pub struct Instruction {
-        pub mnm: String,
-        pub len: u8, // bytes to read
-    }
-    #[derive(PartialEq, Debug)]
-    pub struct InstructionCB {
-        pub opcode: &'static str,
-        pub bit: u8,
-        pub reg: usize,
-    }
-    impl Instruction {
-        pub fn new(text: &str, len: u8) -> Self {
-            Self {
-                mnm: String::from(text),
-                len,
-            }
+        fn cart(rom_type: Byte, ram_size: Byte) -> Cartridge {
+            let mut rom = vec![0; BANK_SIZE * 2];
+            rom[ROM_TYPE as usize] = rom_type;
+            rom[ROM_RAM_SIZE as usize] = ram_size;
+            Cartridge(rom.into_boxed_slice())
         }
 
-        pub fn from_cb(icb: &InstructionCB) -> Self {
-            Self {
-                mnm: if icb.bit < 0xff {
-                    format!("{} {}, {}", icb.opcode, icb.bit, crate::decode::R[icb.reg])
-                } else {
-                    format!("{}, {}", icb.opcode, crate::decode::R[icb.reg])
-                },
-                len: 1,
+        #[test]
+        fn test_echo_ram() {
+            let mut mem = Memory::new();
+            mem.write(0xE123, 0x42);
+            assert_eq!((mem.read(0xC123), mem[0xC123]), (0x42, 0x42));
+            mem.write(0xDDFF, 0x17);
+            assert_eq!((mem.read(0xFDFF), mem[0xFDFF]), (0x17, 0x17));
+            mem[0xC000] = 0x99;
+            assert_eq!(mem.read(0xE000), 0x99);
+            // OAM sits right above, and its own thing
+            mem.write(MEM_OAM, 0x01);
+            assert_eq!(mem[MEM_OAM - ECHO_OFFSET], 0x00);
+
+            assert_eq!(mirror_of(0xC123), Some(0xE123));
+            assert_eq!(mirror_of(0xE123), Some(0xC123));
+            assert_eq!(mirror_of(0xDE00), None); // the top of work RAM 1 has no echo
+            assert_eq!(mirror_of(MEM_OAM), None);
+            assert_eq!(unmirror(0xFDFF), 0xDDFF);
+            assert_eq!(unmirror(0xDDFF), 0xDDFF);
+
+            // watching one half catches writes through the other
+            mem.watches = vec![Watchpoint {
+                start: 0xC000,
+                end: 0xC0FF,
+                mode: None,
+            }];
+            mem.write(0xE010, 0x01);
+            let hit = mem.watch_hit.take().unwrap();
+            assert_eq!(hit.addr, 0xE010);
+            assert!(hit.to_string().contains("E010 (echo of C010)"));
+
+            // nor does reading back through echo RAM count as reading garbage
+            mem.lint = true;
+            mem.write(0xC200, 0x00);
+            mem.read(0xE200);
+            mem.read(0xE300);
+            let lints = mem.lints.get_mut();
+            assert_eq!(*lints, vec![Lint::UninitializedRead(0xE300)]);
+        }
+
+        #[test]
+        fn test_title() {
+            let mut rom = vec![0; BANK_SIZE * 2];
+            rom[ROM_TITLE as usize..][..6].copy_from_slice(b"TETRIS");
+            let c = Cartridge(rom.into_boxed_slice());
+            assert_eq!(c.title(), "TETRIS");
+            assert!(!c.cgb_flag());
+
+            // cgb flag in the last byte, high bytes and padding in the title
+            let mut rom = vec![0; BANK_SIZE * 2];
+            rom[ROM_TITLE as usize..ROM_TITLE_END as usize]
+                .copy_from_slice(b"POKEMON\xFFGOLD   \xC0");
+            let c = Cartridge(rom.into_boxed_slice());
+            assert_eq!(c.title(), "POKEMONGOLD");
+            assert!(c.cgb_flag());
+            assert_eq!(c.title_bytes().len(), 16);
+            assert!(c.cgb_only());
+
+            // not even close to ascii
+            let mut rom = vec![0; BANK_SIZE * 2];
+            rom[ROM_TITLE as usize..ROM_TITLE_END as usize].copy_from_slice(&[0xE9; 16]);
+            let c = Cartridge(rom.into_boxed_slice());
+            assert_eq!(c.title(), "");
+        }
+
+        #[test]
+        fn test_header_fields() {
+            let mut rom = vec![0; BANK_SIZE * 2];
+            rom[ROM_OLD_LICENSEE as usize] = 0x01;
+            rom[ROM_VERSION as usize] = 0x02;
+            let mut c = Cartridge(rom.into_boxed_slice());
+            assert_eq!(c.licensee(), Licensee::Old(0x01));
+            assert_eq!(c.mask_rom_version(), 2);
+            assert!(!c.sgb_flag());
+
+            // 0x33 defers to the two character code
+            c.0[ROM_OLD_LICENSEE as usize] = 0x33;
+            c.0[ROM_NEW_LICENSEE as usize..][..2].copy_from_slice(b"01");
+            c.0[ROM_SGB as usize] = 0x03;
+            assert_eq!(c.licensee(), Licensee::New(*b"01"));
+            assert_eq!(c.licensee().to_string(), "01");
+            assert!(c.sgb_flag());
+            assert!(c.summary().ends_with("lic: 01 | ver: 2 | sgb"));
+
+            let info = c.header_info();
+            assert_eq!(info.licensee, "01");
+            assert_eq!((info.mapper, info.rom_banks), (MbcKind::None, 2));
+            assert!(info.sgb && !info.cgb);
+            assert!(!info.header_checksum_ok);
+            c.0[ROM_HEADER_CHECKSUM as usize] = c.header_checksum();
+            assert!(c.header_info().header_checksum_ok);
+        }
+
+        #[test]
+        fn test_cartridge_errors() {
+            assert!(matches!(
+                Cartridge::new("no/such/rom.gb"),
+                Err(CartridgeError::Io(_))
+            ));
+            assert!(matches!(
+                Cartridge::parse(vec![0; 0x100]),
+                Err(CartridgeError::TooSmall(0x100))
+            ));
+
+            let mut rom = vec![0; BANK_SIZE * 2];
+            rom[ROM_SIZE as usize] = 0x09;
+            assert!(matches!(
+                Cartridge::parse(rom.clone()),
+                Err(CartridgeError::InvalidSize(0x09))
+            ));
+            rom[ROM_SIZE as usize] = 0x00;
+            rom[ROM_RAM_SIZE as usize] = 0x06;
+            assert!(matches!(
+                Cartridge::parse(rom.clone()),
+                Err(CartridgeError::BadHeader(_))
+            ));
+            rom[ROM_RAM_SIZE as usize] = 0x03;
+            assert_eq!(Cartridge::parse(rom).unwrap().size_ram(), 32 * KB);
+        }
+
+        #[test]
+        fn test_checksums() {
+            let mut rom = vec![0; BANK_SIZE * 2];
+            rom[ROM_TITLE as usize..][..6].copy_from_slice(b"TETRIS");
+            rom[0x0100] = 0xC3;
+            let mut c = Cartridge(rom.into_boxed_slice());
+            assert_eq!(c.checksum_errors().len(), 2);
+
+            // 0 - (sum of 0134-014C) - 25
+            let sum = b"TETRIS".iter().map(|&b| b as usize).sum::<usize>();
+            assert_eq!(c.header_checksum(), (0usize.wrapping_sub(sum + 25)) as Byte);
+            c.0[ROM_HEADER_CHECKSUM as usize] = c.header_checksum();
+            assert_eq!(c.checksum_errors().len(), 1);
+
+            // the global checksum skips its own two bytes but covers the header checksum
+            let global = c.global_checksum();
+            assert_eq!(global as usize, sum + 0xC3 + c.header_checksum() as usize);
+            c.0[ROM_GLOBAL_CHECKSUM as usize] = hi(global);
+            c.0[ROM_GLOBAL_CHECKSUM as usize + 1] = lo(global);
+            assert_eq!(c.global_checksum(), global);
+            assert!(c.checksum_errors().is_empty());
+
+            c.0[0x4000] = 1;
+            assert_eq!(c.checksum_errors().len(), 1);
+        }
+
+        #[test]
+        fn test_joypad() {
+            let mut mem = Memory::new();
+            mem.set_buttons(BTN_A | BTN_DOWN);
+            assert_eq!(mem.read(IF) & FL_INT_JOYPAD, FL_INT_JOYPAD);
+
+            mem.write(JOYP, 0x20); // dpad
+            assert_eq!(mem.read(JOYP), 0xE7);
+            mem.write(JOYP, 0x10); // buttons
+            assert_eq!(mem.read(JOYP), 0xDE);
+            mem.write(JOYP, 0x30); // neither
+            assert_eq!(mem.read(JOYP), 0xFF);
+
+            // releasing doesn't interrupt
+            mem.write(IF, 0);
+            mem.set_buttons(BTN_A);
+            assert_eq!(mem.read(IF), 0);
+        }
+
+        #[test]
+        fn test_serial_push() {
+            let mut mem = Memory::new();
+            mem.write(IF, 0);
+            // the partner's byte comes back for ours
+            mem.serial_push(0x42);
+            mem.write(SB, 0x01);
+            mem.write(SC, 0x81);
+            assert_eq!(mem.read(SB), 0x42);
+            assert_eq!(mem.read(IF), FL_INT_SERIAL);
+
+            // on the external clock nothing happens until the partner sends
+            mem.write(IF, 0);
+            mem.write(SB, 0x02);
+            mem.write(SC, 0x80);
+            assert_eq!(mem.read(SC) & 0x80, 0x80);
+            assert_eq!(mem.read(IF), 0);
+            mem.serial_push(0x43);
+            assert_eq!(mem.read(SB), 0x43);
+            assert_eq!(mem.read(SC) & 0x80, 0);
+            assert_eq!(mem.read(IF), FL_INT_SERIAL);
+
+            assert_eq!(mem.serial_take(), [0x01, 0x02]);
+            assert!(mem.serial_take().is_empty());
+            assert_eq!(mem.serial_output(), [0x01, 0x02]);
+        }
+
+        #[test]
+        fn test_serial_output() {
+            let mut mem = Memory::new();
+            mem.write(IF, 0);
+            mem.write(SB, b'O');
+            mem.write(SC, 0x01); // external clock: waits forever for the other side
+            assert!(mem.serial_output().is_empty());
+            mem.write(SC, 0x81);
+            mem.write(SB, b'K');
+            mem.write(SC, 0x81);
+            assert_eq!(mem.serial_output(), b"OK");
+            assert_eq!(mem.read(SB), 0xFF);
+            assert_eq!(mem.read(SC) & 0x80, 0);
+            assert_eq!(mem.read(IF), FL_INT_SERIAL);
+        }
+
+        #[test]
+        fn test_apu_writes_capped() {
+            // nothing runs an apu here to take them
+            let mut mem = Memory::new();
+            for i in 0..APU_WRITES_MAX * 2 {
+                mem.write(NR50, i as Byte);
             }
+            assert_eq!(mem.apu_writes.len(), APU_WRITES_MAX);
+            assert_eq!(
+                mem.apu_writes.back(),
+                Some(&(NR50, (APU_WRITES_MAX * 2 - 1) as Byte))
+            );
         }
 
-        pub fn valid(&self) -> bool {
-            self.len > 0
+        #[test]
+        fn test_lint() {
+            let mut mem = Memory::new();
+            mem.load_rom(&cart(0x00, 0x00)); // rom only
+            mem.lint = true;
+            mem.read(0xC000);
+            mem.write(0xC001, 0x12);
+            mem.read(0xC001);
+            mem.write(0x2000, 0x01);
+            mem.write(WX, 200);
+            mem.write(LCDC, LCDC_BIT_WINDOW_ENABLE);
+            mem.write(LCDC, LCDC_BIT_WINDOW_ENABLE); // already on
+            assert_eq!(
+                mem.lints.take(),
+                vec![
+                    Lint::UninitializedRead(0xC000),
+                    Lint::RomWrite(0x2000),
+                    Lint::WindowOffscreen { wx: 200, wy: 0 },
+                ]
+            );
+
+            mem.load_rom(&cart(0x01, 0x00)); // MBC1
+            mem.write(0x2000, 0x01);
+            assert!(mem.lints.take().is_empty());
         }
 
-        pub fn prefix(&self) -> bool {
-            self.mnm == crate::decode::CBPREFIX
+        #[test]
+        fn test_eject_cartridge() {
+            let mut mem = Memory::new();
+            mem.load_rom(&banked_cart(0x03, 0x02, 8)); // MBC1+RAM+BATTERY, 128KB
+            mem.write(0x2000, 0x05);
+            assert_eq!(mem.read(MEM_BANK_NN), 5);
+
+            mem.eject_cartridge();
+            assert!(mem.cartridge_ejected());
+            assert_eq!(mem.read(MEM_BANK_00), 0xFF);
+            assert_eq!(mem.read(MEM_BANK_NN), 0xFF);
+            assert_eq!(mem.read(MEM_EXT), 0xFF);
+            mem.write(0x2000, 0x02); // nothing there to hear it
+            mem.write(MEM_EXT, 0x12);
+            assert_eq!(mem.read(MEM_EXT), 0xFF);
+
+            // back how it was left
+            mem.insert_cartridge();
+            assert!(!mem.cartridge_ejected());
+            assert_eq!(mem.read(MEM_BANK_NN), 5);
         }
 
-        pub fn mnm_args(&self, rom: &[Byte]) -> String {
-            match rom.len() {
-                1 => self.mnm.replace('n', &format!("${:02x}", rom[0])),
-                2 => self.mnm.replace(
-                    "nn",
-                    &format!("${:04x}", crate::bits::combine(rom[1], rom[0])),
-                ),
-                _ => panic!("mnemonic only intended for instructions with args"),
+        #[test]
+        fn test_rom_patches() {
+            let mut mem = Memory::new();
+            mem.load_rom(&banked_cart(0x01, 0x02, 8)); // MBC1, 128KB
+            let only_bank_3 = mem.add_rom_patch("4100:03:99".parse().unwrap());
+            mem.add_rom_patch("0150:42".parse().unwrap());
+            assert_eq!(mem.read(0x0150), 0x42);
+            assert_eq!(mem[0x0150], 0x42); // the ppu and debugger see it too
+
+            // follows the bank switches
+            mem.write(0x2000, 0x03);
+            assert_eq!(mem.read(0x4100), 0x99);
+            mem.write(0x2000, 0x05);
+            assert_eq!(mem.read(0x4100), 0x05);
+
+            mem.write(0x2000, 0x03);
+            assert_eq!(mem.toggle_rom_patch(only_bank_3), Some(false));
+            assert_eq!(mem.read(0x4100), 0x03);
+            assert_eq!(mem.toggle_rom_patch(only_bank_3), Some(true));
+            assert_eq!(mem.read(0x4100), 0x99);
+            assert_eq!(mem.toggle_rom_patch(2), None);
+            assert_eq!(mem.rom_patches().len(), 2);
+        }
+
+        #[test]
+        fn test_ext_ram_banking() {
+            let mut mem = Memory::new();
+            mem.load_rom(&cart(0x1B, 0x03)); // MBC5+RAM+BATTERY, 32KB
+            assert_eq!(mem.ext_ram().len(), 4 * EXT_RAM_BANK_SIZE);
+
+            mem.write(0x0000, 0x0A);
+            mem.write(MEM_EXT, 0x11);
+            mem.write(0x4000, 0x03);
+            mem.write(MEM_EXT, 0x33);
+            assert_eq!(mem.read(MEM_EXT), 0x33);
+            mem.write(0x4000, 0x00);
+            assert_eq!(mem.read(MEM_EXT), 0x11);
+
+            // bank 4 doesn't exist on a 32KB cart
+            mem.write(0x4000, 0x04);
+            mem.write(MEM_EXT, 0x44);
+            assert_eq!(mem.read(MEM_EXT), 0xFF);
+        }
+
+        #[test]
+        fn test_unsupported_reported_once() {
+            let mut mem = Memory::new();
+            mem.load_rom(&banked_cart(0x13, 0x02, 8)); // MBC3+RAM+BATTERY, no clock
+            for _ in 0..2 {
+                mem.write(0x6000, 0x00);
+                mem.write(0x6000, 0x01);
+            }
+            mem.write(0x4000, 0x08);
+            assert_eq!(mem.unsupported().len(), 2);
+            assert!(mem.unsupported()[0].contains("latch"));
+
+            mem.eject_cartridge();
+            mem.write(0x2000, 0x05);
+            assert_eq!(mem.unsupported().len(), 2);
+            mem.load_rom(&banked_cart(0x0F, 0x02, 8));
+            assert!(mem.unsupported().is_empty());
+        }
+
+        #[test]
+        fn test_vram_banks() {
+            let mut mem = Memory::new();
+            mem.load_rom(&cart(0x00, 0x00));
+            // dmg: there's no second bank
+            mem.write(VBK, 1);
+            assert_eq!(mem.read(VBK), 0xFF);
+            mem.write(0x8000, 0x12);
+            assert_eq!(mem.read(0x8000), 0x12);
+            assert_eq!(mem.vram1(0x8000), 0);
+
+            let mut rom = vec![0; BANK_SIZE * 2];
+            rom[ROM_CGB as usize] = 0xC0;
+            mem.load_rom(&Cartridge(rom.into_boxed_slice()));
+            assert_eq!(mem.read(VBK), 0xFE);
+            mem.write(VBK, 0xFF);
+            assert_eq!(mem.read(VBK), 0xFF);
+            mem.write(0x9FFF, 0x34);
+            assert_eq!(mem.read(0x9FFF), 0x34);
+            assert_eq!(mem.vram1(0x9FFF), 0x34);
+            // the ppu's view through Index is bank 0
+            assert_eq!(mem[0x9FFF], 0);
+            mem.write(VBK, 0);
+            assert_eq!(mem.read(0x8000), 0x12);
+            assert_eq!(mem.read(0x9FFF), 0);
+        }
+
+        #[test]
+        fn test_cgb_palette_ram() {
+            let mut mem = Memory::new();
+            mem.load_rom(&cart(0x00, 0x00));
+            mem.write(BCPS, 0x80);
+            mem.write(BCPD, 0x12);
+            assert_eq!(mem.read(BCPS), 0xFF); // dmg: no such thing
+            assert_eq!(mem.read(BCPD), 0xFF);
+
+            let mut rom = vec![0; BANK_SIZE * 2];
+            rom[ROM_CGB as usize] = 0xC0;
+            mem.load_rom(&Cartridge(rom.into_boxed_slice()));
+            // auto-increment, wrapping around the 64 bytes
+            mem.write(OCPS, 0x80 | 0x3E);
+            for val in [0x1F, 0x00, 0xE0, 0x03] {
+                mem.write(OCPD, val);
             }
+            assert_eq!(mem.read(OCPS), 0x80 | 0x40 | 0x02);
+            assert_eq!(mem.cgb_color(true, 7, 3), 0x001F);
+            assert_eq!(mem.cgb_color(true, 0, 0), 0x03E0);
+            assert_eq!(mem.cgb_color(false, 0, 0), 0xFFFF); // untouched
+
+            // without it the index stays put
+            mem.write(BCPS, 0x08);
+            mem.write(BCPD, 0x34);
+            mem.write(BCPD, 0x56);
+            assert_eq!(mem.read(BCPS), 0x48);
+            assert_eq!(mem.read(BCPD), 0x56);
+            assert_eq!(mem.cgb_color(false, 1, 0), 0xFF56);
+        }
+
+        #[test]
+        fn test_mbc1_rom_banking() {
+            let mut mem = Memory::new();
+            mem.load_rom(&banked_cart(0x01, 0x02, 8)); // MBC1, 128KB
+            assert_eq!(mem.read(MEM_BANK_NN), 1);
+
+            mem.write(0x2000, 3);
+            assert_eq!(mem.read(MEM_BANK_NN), 3);
+            assert_eq!(mem.read(0x7FFF), 3);
+            assert_eq!(mem.read(0x3FFF), 0);
+
+            mem.write(0x2000, 0); // 0 -> 1
+            assert_eq!(mem.read(MEM_BANK_NN), 1);
+
+            mem.write(0x2000, 0x0A); // masked to the number of banks
+            assert_eq!(mem.read(MEM_BANK_NN), 2);
+        }
+
+        #[test]
+        fn test_rom_dma_from_bank() {
+            let mut mem = Memory::new();
+            mem.load_rom(&banked_cart(0x01, 0x02, 8)); // MBC1, 128KB
+            mem.write(0x2000, 5);
+            mem.write(DMA, 0x40);
+            mem.update();
+            assert_eq!(mem[MEM_OAM], 5);
+            assert_eq!(mem[MEM_OAM + 0x9F], 5);
+        }
+
+        #[test]
+        fn test_mbc1_large_rom() {
+            let mut mem = Memory::new();
+            mem.load_rom(&banked_cart(0x01, 0x05, 64)); // MBC1, 1MB
+            mem.write(0x4000, 1);
+            mem.write(0x2000, 2);
+            assert_eq!(mem.read(MEM_BANK_NN), 34);
+            assert_eq!(mem.read(0x0100), 0);
+
+            mem.write(0x6000, 1); // upper bits apply to 0000-3FFF too
+            assert_eq!(mem.read(0x0100), 32);
+            assert_eq!(mem.read(MEM_BANK_NN), 34);
+        }
+
+        #[test]
+        fn test_ext_ram_missing() {
+            let mut mem = Memory::new();
+            mem.load_rom(&cart(0x00, 0x00));
+            mem.write(0xA123, 0x42);
+            assert_eq!(mem.read(0xA123), 0xFF);
+            // rom is never written through
+            mem.write(0x0150, 0x42);
+            assert_eq!(mem.read(0x0150), 0x00);
+        }
+
+        #[test]
+        fn test_battery() {
+            assert!(cart(0x03, 0x02).has_battery());
+            assert!(!cart(0x02, 0x02).has_battery());
+
+            let mut mem = Memory::new();
+            mem.load_rom(&cart(0x03, 0x02)); // MBC1+RAM+BATTERY, 8KB
+            mem.write(0x0000, 0x0A);
+            mem.write(0xA000, 0x42);
+            let sav = mem.ext_ram().to_vec();
+            assert_eq!(sav.len(), 0x2000);
+
+            let mut mem = Memory::new();
+            mem.load_rom(&cart(0x03, 0x02));
+            mem.load_ext_ram(&sav);
+            mem.write(0x0000, 0x0A);
+            assert_eq!(mem.read(0xA000), 0x42);
+            mem.load_ext_ram(&[0; 0x4000]); // too big, the extra is dropped
+            assert_eq!(mem.read(0xA000), 0x00);
+        }
+
+        #[test]
+        fn test_ext_ram_enable() {
+            let mut mem = Memory::new();
+            mem.load_rom(&cart(0x03, 0x02)); // MBC1+RAM+BATTERY, 8KB
+            mem.write(MEM_EXT, 0x42);
+            assert_eq!(mem.read(MEM_EXT), 0xFF); // locked until enabled
+            mem.write(0x1FFF, 0x1A); // only the low nibble counts
+            assert_eq!(mem.read(MEM_EXT), 0x00);
+            mem.write(MEM_EXT, 0x42);
+            assert_eq!(mem.read(MEM_EXT), 0x42);
+            mem.write(0x0000, 0x00);
+            assert_eq!(mem.read(MEM_EXT), 0xFF);
+            assert_eq!(mem.ext_ram()[0], 0x42); // still there while disabled
+
+            // no mbc, nothing to unlock
+            let mut mem = Memory::new();
+            mem.load_rom(&cart(0x09, 0x02)); // ROM+RAM+BATTERY
+            mem.write(MEM_EXT, 0x42);
+            assert_eq!(mem.read(MEM_EXT), 0x42);
+        }
+
+        #[test]
+        fn test_mbc1_ram_mode() {
+            let mut mem = Memory::new();
+            mem.load_rom(&cart(0x03, 0x03)); // MBC1+RAM+BATTERY, 32KB
+            mem.write(0x0000, 0x0A);
+            mem.write(0x4000, 0x02);
+            mem.write(MEM_EXT, 0x22); // mode 0, still bank 0
+            mem.write(0x6000, 0x01);
+            assert_eq!(mem.read(MEM_EXT), 0x00);
+            mem.write(MEM_EXT, 0x22);
+            mem.write(0x6000, 0x00);
+            assert_eq!(mem.read(MEM_EXT), 0x22);
         }
     }
 }
 
-#[rustfmt::skip]
-pub mod lcd {
-    use crate::bits::*;
-    use crate::cpu::*;
-    use crate::dbg::dump;
+pub mod mbc {
+    //! Memory bank controllers, the chips on the cartridge that page ROM and RAM in and out
+    //!
+    //! https://gbdev.io/pandocs/MBCs.html
+    use crate::bits::{hi, lo};
+    use crate::dbg::StateHash;
     use crate::memory::*;
+    use crate::pacing::CPU_HZ;
+    use crate::sav::RtcFooter;
     use crate::types::*;
-    use minifb::Window;
+    use std::sync::{Arc, Mutex};
+
+    /// Everything in 0000-7FFF and A000-BFFF goes through the cartridge's controller
+    pub trait Mbc {
+        fn read_rom(&self, addr: Word) -> Byte;
+        /// Writes to ROM never change it, they set the controller's registers
+        fn write_rom(&mut self, addr: Word, val: Byte);
+        /// Whether there's a controller for write_rom to reach, ROM-only carts have none
+        fn has_registers(&self) -> bool {
+            true
+        }
+        /// 0xFF where there's no RAM (missing, disabled or no such bank)
+        fn read_ram(&self, addr: Word) -> Byte;
+        fn write_ram(&mut self, addr: Word, val: Byte);
+        /// The bank visible at an address, usize::MAX when nothing is (e.g. RAM disabled)
+        fn bank_at(&self, addr: Word) -> usize;
+        /// All of the cartridge RAM (every bank), what a .sav file holds
+        fn ram(&self) -> &[Byte];
+        fn ram_mut(&mut self) -> &mut [Byte];
+        /// Registers and RAM, see Emulator::state_hash
+        fn hash_state(&self, h: &mut StateHash);
+        fn box_clone(&self) -> Box<dyn Mbc>;
+        /// Lets anything on the cartridge that keeps time (the MBC3 clock) catch up
+        fn add_cycles(&mut self, _cycles: u64) {}
+        /// The clock, as saved, for carts that have one. `now` is unix time in seconds
+        fn rtc(&self, _now: u64) -> Option<RtcFooter> {
+            None
+        }
+        /// Restores the clock, counting the time spent switched off
+        fn load_rtc(&mut self, _rtc: &RtcFooter, _now: u64) {}
+        /// Feeds the cartridge's accelerometer (MBC7), in g with right/down positive
+        fn set_tilt(&mut self, _x: f32, _y: f32) {}
+        /// Connects whatever is on the other side of the cartridge's infrared port (HuC1)
+        fn set_ir_port(&mut self, _port: SharedIrPort) {}
+        /// Sound the cartridge puts on the VIN pin right now, -1.0..=1.0, mixed in on the
+        /// sides NR50 asks for. No cartridge we know of has anything there
+        fn vin(&self) -> f64 {
+            0.0
+        }
+        /// What a write (0000-7FFF or A000-BFFF) asks of the cartridge that we don't do for
+        /// it, e.g. latching a clock the header says isn't there. See Memory::unsupported
+        fn unsupported(&self, _addr: Word, _val: Byte) -> Option<&'static str> {
+            None
+        }
+    }
 
-    // lcdc
-    pub const LCDC_BIT_ENABLE                     :Byte = BIT_7;
-    pub const LCDC_BIT_WINDOW_TILE_MAP_SELECT     :Byte = BIT_6;
-    pub const LCDC_BIT_WINDOW_ENABLE              :Byte = BIT_5;
-    pub const LCDC_BIT_BG_WINDOW_TILE_DATA_SELECT :Byte = BIT_4;
-    pub const LCDC_BIT_BG_TILE_MAP_SELECT         :Byte = BIT_3;
-    pub const LCDC_BIT_OBJ_SIZE                   :Byte = BIT_2;
-    pub const LCDC_BIT_OBJ_ENABLE                 :Byte = BIT_1;
-    pub const LCDC_BIT_BG_WINDOW_ENABLE           :Byte = BIT_0;
+    // for carts writing to RAM the header doesn't give them, A000-BFFF
+    const NO_RAM: &str = "cartridge RAM (the header says there's none)";
 
-    // lcd status
-    pub const STAT_BIT_NULL              :Byte = BIT_7;
-    pub const STAT_BIT_LYC_INT_SELECT    :Byte = BIT_6;
-    pub const STAT_BIT_MODE_2_INT_SELECT :Byte = BIT_5;
-    pub const STAT_BIT_MODE_1_INT_SELECT :Byte = BIT_4;
-    pub const STAT_BIT_MODE_0_INT_SELECT :Byte = BIT_3;
-    pub const STAT_BIT_LY_LYC_EQ         :Byte = BIT_2;
-    pub const STAT_MASK_PPU_MODE         :Byte = 0b011;
+    /// The far side of an infrared link: another Game Boy, a link cable stand-in, a test
+    pub trait IrPort {
+        /// Whether the receiver sees light right now
+        fn light_seen(&self) -> bool;
+        /// Our LED switching on or off
+        fn set_led(&mut self, on: bool);
+    }
 
-    // object attribute flags
-    pub const OAM_BIT_PRIORITY           :Byte = BIT_7;
-    pub const OAM_BIT_FLIP_Y             :Byte = BIT_6;
-    pub const OAM_BIT_FLIP_X             :Byte = BIT_5;
-    pub const OAM_BIT_DMG_PAL            :Byte = BIT_4; // dmg only
-    pub const OAM_BIT_BANK               :Byte = BIT_3;
-    pub const OAM_MASK_CGB_PAL           :Byte = 0b111; // color gameboy only
-    pub const OBJ_ATTR_SIZE              :Word = 4;
+    /// Shared since snapshots (rewind) of the cartridge all talk to the same outside world
+    pub type SharedIrPort = Arc<Mutex<dyn IrPort + Send>>;
 
-    // other constants
-    pub const PPU_TILE_WIDTH             :usize = 8;
-    
-    pub struct Sprite {
-        idx: Word
-    }
-    impl Sprite {
-        fn y(&self, mem: &Memory) -> Byte {
-            mem[MEM_OAM + self.idx * OBJ_ATTR_SIZE + 0]
+    /// Nobody there, the receiver never sees any light
+    pub struct NoIr;
+
+    impl IrPort for NoIr {
+        fn light_seen(&self) -> bool {
+            false
         }
-        fn x(&self, mem: &Memory) -> Byte {
-            mem[MEM_OAM + self.idx * OBJ_ATTR_SIZE + 1]
+        fn set_led(&mut self, _on: bool) {}
+    }
+
+    impl Clone for Box<dyn Mbc> {
+        fn clone(&self) -> Self {
+            self.box_clone()
         }
-        fn tile(&self, mem: &Memory) -> Byte {
-            if mem[LCDC] & LCDC_BIT_OBJ_SIZE != 0 {
-                // todo: CGB can reference VRAM in bank 0 or bank 1
-                mem[MEM_OAM + self.idx * OBJ_ATTR_SIZE + 2] & 0xFE // masked, ignore least sig. bit (hardware-enforced)
-            } else {
-                mem[MEM_OAM + self.idx * OBJ_ATTR_SIZE + 2]
+    }
+
+    /// The controller for a cartridge, picked from its hardware type
+    pub fn mbc_for(cart: &Cartridge) -> Box<dyn Mbc> {
+        let rom = Rom(Arc::from(cart.bytes()));
+        let ram = Ram::new(cart.size_ram());
+        match cart.mbc_kind() {
+            MbcKind::None => Box::new(NoMbc { rom, ram }),
+            MbcKind::Mbc1 => Box::new(Mbc1::new(rom, ram)),
+            MbcKind::Mbc2 => Box::new(Mbc2::new(rom)),
+            MbcKind::Mbc3 => {
+                let rtc = cart.hardware_type().contains("TIMER").then(Rtc::default);
+                Box::new(Mbc3::new(rom, ram, rtc))
             }
-        }
-        fn flags(&self, mem: &Memory) -> Byte {
-            mem[MEM_OAM + self.idx * OBJ_ATTR_SIZE + 3]
-        }
-        fn hit(&self, mem: &Memory) -> Byte {
-            if self.x(mem) != 0 {
-                let scanline = mem[LY] + 16;
-                let height = if mem[LCDC] & LCDC_BIT_OBJ_SIZE != 0 { 16 } else { 8 };
-                if scanline >= self.y(mem) && scanline < self.y(mem) + height {
-                    // todo: does this work for double height?
-                    let yy = self.y(mem);
-                    if self.flags(mem) & OAM_BIT_FLIP_Y != 0 {
-                        (height - 1) - (scanline - yy)
-                    } 
-                    else 
-                    {
-                        scanline - yy
-                    }
-                } else {
-                    SPRITE_NOT_HIT
-                }
-            } else {
-                SPRITE_NOT_HIT
+            MbcKind::Mbc5 => Box::new(Mbc5::new(rom, ram)),
+            MbcKind::Mbc7 => Box::new(Mbc7::new(rom)),
+            MbcKind::HuC1 => Box::new(HuC1::new(rom, ram)),
+            MbcKind::Mmm01 => Box::new(Mmm01::new(rom, ram)),
+            MbcKind::Unsupported => {
+                log::warn!(
+                    "unsupported cartridge type {}, running it without banking",
+                    cart.hardware_type()
+                );
+                Box::new(NoMbc { rom, ram })
             }
         }
     }
 
-    const SPRITE_NOT_HIT: Byte = 0xFF;
-    pub struct SpriteHit {
-        sprite: Sprite,
-        line: Byte
+    /// The cartridge ROM, shared between clones since it never changes
+    #[derive(Clone)]
+    struct Rom(Arc<[Byte]>);
+
+    impl Rom {
+        fn banks(&self) -> usize {
+            (self.0.len() / BANK_SIZE).max(1)
+        }
+        /// Bank numbers wrap around to the banks that exist, like the unused pins on the chip
+        fn bank(&self, bank: usize) -> usize {
+            bank % self.banks()
+        }
+        fn read(&self, bank: usize, addr: Word) -> Byte {
+            let offset = self.bank(bank) * BANK_SIZE + addr as usize % BANK_SIZE;
+            self.0.get(offset).copied().unwrap_or(0xFF)
+        }
     }
 
-    pub struct Display {
-        buffer: Vec<u32>,
-        buffer_sprites: Vec<SpriteHit>,
-        lcd_timing: u64,
-        // debug
-        pub doctor: bool,
-        doctor_LY: Byte,
+    /// Cartridge RAM, in EXT_RAM_BANK_SIZE banks
+    ///
+    /// Shared between clones until one of them writes to it, so rewind snapshots of a game
+    /// that isn't saving don't each carry a copy.
+    #[derive(Clone)]
+    struct Ram {
+        data: Arc<Vec<Byte>>,
+        enabled: bool, // games have to unlock RAM before using it
     }
 
-    impl Display {
-        pub fn new() -> Display {
-            Display {
-                buffer: vec![0; GB_SCREEN_WIDTH * GB_SCREEN_HEIGHT],
-                buffer_sprites: vec![],
-                lcd_timing: 0,
-                doctor: false,
-                doctor_LY: 0
+    impl Ram {
+        fn new(size: usize) -> Ram {
+            Ram {
+                data: Arc::new(vec![0; size]),
+                enabled: false,
             }
         }
+        /// The 0x0A unlock value, any other disables
+        fn set_enabled(&mut self, val: Byte) {
+            self.enabled = val & 0x0F == 0x0A;
+        }
+        fn offset(&self, bank: usize, addr: Word) -> Option<usize> {
+            let offset = bank * EXT_RAM_BANK_SIZE + (addr - MEM_EXT) as usize;
+            (self.enabled && offset < self.data.len()).then_some(offset)
+        }
+        fn read(&self, bank: usize, addr: Word) -> Byte {
+            self.offset(bank, addr).map_or(0xFF, |o| self.data[o])
+        }
+        fn write(&mut self, bank: usize, addr: Word, val: Byte) {
+            if let Some(o) = self.offset(bank, addr) {
+                self.data_mut()[o] = val;
+            }
+        }
+        fn bank_at(&self, bank: usize, addr: Word) -> usize {
+            self.offset(bank, addr).map_or(usize::MAX, |_| bank)
+        }
+        fn data_mut(&mut self) -> &mut [Byte] {
+            Arc::make_mut(&mut self.data).as_mut_slice()
+        }
+        fn hash_state(&self, h: &mut StateHash) {
+            h.bytes(&self.data);
+            h.bytes(&[self.enabled as Byte]);
+        }
+    }
 
-        pub fn update(&mut self, mem: &mut Memory, window: &mut Window, dt: u64 ) {
-            self.lcd_timing += dt;
-            lcd_compare_ly_lyc(mem);
-            match lcd_mode(&mem) {
-                // oam search
-                2 => {
-                    if self.lcd_timing >= TICKS_PER_OAM_SEARCH {
-                        self.buffer_sprites.clear();
-                        for n in 0..40 {
-                            let s = Sprite { idx: n };
-                            let l = s.hit(&mem);
-                            if self.buffer_sprites.len() < 10 && l != SPRITE_NOT_HIT {
-                                self.buffer_sprites.push(SpriteHit{sprite: s, line: l});
-                            }
-                        }
-                        set_lcd_mode(3, mem);
-                        self.lcd_timing -= TICKS_PER_OAM_SEARCH;
-                    }
-                }
-                // vram io
-                3 => {
-                    if self.lcd_timing >= TICKS_PER_VRAM_IO {
-                        // draw the scanline
-                        // ===========================================
-                        let cur_line: Byte = if self.doctor { self.doctor_LY } else { mem[LY] };
-                        let ln_start: usize = GB_SCREEN_WIDTH * cur_line as usize;
-                        let ln_end: usize = ln_start + GB_SCREEN_WIDTH;
-
-                        // draw background
-                        // -------------------------------------------
-                        // todo: acc: this code is inaccurate, LCDC can actually be modified mid-scanline
-                        // but cerboy currently only draws the line in a single shot (instead of per-dot)
-                        let bg_tilemap_start: Word = if bit_test(3, mem[LCDC]) {
-                            0x9C00
-                        } else {
-                            0x9800
-                        };
-                        let (bg_signed_addressing, bg_tile_data_start) = if bit_test(4, mem[LCDC]) {
-                            (false, MEM_VRAM as Word)
-                        } else {
-                            // in signed addressing the 0 tile is at 0x9000
-                            (true, MEM_VRAM + 0x1000 as Word)
-                            // (true, MEM_VRAM + 0x0800 as Word) // <--- actual range starts at 0x8800 but that is -127, not zero
-                        };
-                        let (bg_y, _) = mem[SCY].overflowing_add(cur_line);
-                        let bg_tile_line = bg_y as Word % 8;
-
-                        for (c, it) in self.buffer[ln_start..ln_end].iter_mut().enumerate() {
-                            let (bg_x, _) = mem[SCX].overflowing_add(c as Byte);
-                            let bg_tile_index: Word = bg_x as Word / 8 + bg_y as Word / 8 * 32;
-                            let bg_tile_id = mem[bg_tilemap_start + bg_tile_index];
-                            let bg_tile_data_offset = if bg_signed_addressing {
-                                (signed(bg_tile_id) as Word).wrapping_mul(BYTES_PER_TILE)
-                            } else {
-                                bg_tile_id as Word * BYTES_PER_TILE
-                            };
-                            let bg_tile_data = bg_tile_data_start.wrapping_add(bg_tile_data_offset);
-                            let bg_tile_line_offset = bg_tile_data + bg_tile_line * 2;
-                            let bg_tile_line_data = ppu_decode_tile_line(mem[bg_tile_line_offset], mem[bg_tile_line_offset + 1]);
-                            let bg_tile_current_pixel = 7 - ((c as Byte + mem[SCX]) % 8);
-                            *it = palette_lookup(bg_tile_line_data[bg_tile_current_pixel as usize], mem[BGP], &PAL_CLASSIC);
-                        }
-
-                        // draw sprites
-                        // FE00-FE9F   Sprite Attribute Table (OAM)
-                        // -------------------------------------------
-                        for (c, it) in self.buffer[ln_start..ln_end].iter_mut().enumerate() {
-                            // the x attr for the sprite is an offset from -8 to allow
-                            // for off-screen (left side) positions.
-                            // We can simply adjust the value of c on this line 
-                            // to account for this.
-                            let c_off = (c + 8) as Byte;
-                            // nyctrip
-                            // todo: non-cgb: lower-x sprites are drawn on top of higher-x
-                            for hit in self.buffer_sprites.iter() {
-                                let spr = &hit.sprite;
-                                if c_off >= spr.x(&mem) && c_off < (spr.x(&mem) + 8) {
-                                    let data_size_mul = if hit.line > 7 { 2 } else { 1 }; // for double height sprites
-                                    let spr_tile_data_offset = spr.tile(&mem) as Word * BYTES_PER_TILE * data_size_mul;
-                                    let tile_hit_line = hit.line % 8;
-                                    // from here we can work in a tile-local context
-                                    let spr_tile_data_line_offset = 
-                                        MEM_VRAM + 
-                                        spr_tile_data_offset + 
-                                        tile_hit_line as Word * 2;
-                                    let spr_tile_line_data = ppu_decode_tile_line(mem[spr_tile_data_line_offset], mem[spr_tile_data_line_offset + 1]);
-                                    let spr_pix = 7 - (c_off - spr.x(&mem));
-                                    if spr_tile_line_data[spr_pix as usize] != 0 {
-                                        // todo: draw in correct priority order for opaque pixels
-                                        *it = palette_lookup(spr_tile_line_data[spr_pix as usize], mem[OBP0], &PAL_ICE_CREAM); // todo: OBP1
-                                    }
-                                }
-                            }
-                        }
-
-                        // draw window
-                        // -------------------------------------------
-                        // for i in buffer[ln_start..ln_end].iter_mut() {}
+    /// ROM only (32KB), maybe with some RAM that's always there
+    #[derive(Clone)]
+    pub struct NoMbc {
+        rom: Rom,
+        ram: Ram,
+    }
 
-                        // ===========================================
+    impl NoMbc {
+        /// Stands in until a cartridge is loaded, all zeros
+        pub fn empty() -> NoMbc {
+            NoMbc {
+                rom: Rom(Arc::from(vec![0; BANK_SIZE * 2])),
+                ram: Ram::new(0),
+            }
+        }
+        /// An empty cartridge slot, every read floats high
+        pub fn open_bus() -> NoMbc {
+            NoMbc {
+                rom: Rom(Arc::from(vec![])),
+                ram: Ram::new(0),
+            }
+        }
+    }
 
-                        set_lcd_mode(0, mem);
-                        self.lcd_timing -= TICKS_PER_VRAM_IO;
-                    }
-                }
-                // hblank
-                0 => {
-                    let cur_line: &mut Byte = if self.doctor { &mut self.doctor_LY } else { &mut mem[LY] };
-                    if self.lcd_timing >= TICKS_PER_HBLANK {
-                        *cur_line += 1;
-                        self.lcd_timing -= TICKS_PER_HBLANK;
-                        if *cur_line == GB_SCREEN_HEIGHT as Byte {
-                            // values 144 to 153 are vblank
-                            request_interrupt(mem, FL_INT_VBLANK);
-                            set_lcd_mode(1, mem);
-                        } else {
-                            set_lcd_mode(2, mem);
-                        }
-                    }
+    impl Mbc for NoMbc {
+        fn read_rom(&self, addr: Word) -> Byte {
+            self.rom.read(addr as usize / BANK_SIZE, addr)
+        }
+        fn write_rom(&mut self, _addr: Word, _val: Byte) {}
+        fn has_registers(&self) -> bool {
+            false
+        }
+        fn unsupported(&self, addr: Word, val: Byte) -> Option<&'static str> {
+            match addr {
+                // plenty of 32KB games select bank 1 anyway, anything else wants a controller
+                0x2000..=0x3FFF if val & 0x1F > 1 => {
+                    Some("ROM banking (the header says there's no controller)")
                 }
-                // vblank
-                1 => {
-                    let cur_line: &mut Byte = if self.doctor { &mut self.doctor_LY } else { &mut mem[LY] };
-                    *cur_line = (GB_SCREEN_HEIGHT as u64 + self.lcd_timing / TICKS_PER_SCANLINE) as Byte;
-                    if self.lcd_timing >= TICKS_PER_VBLANK {
-                        *cur_line = 0;
-                        set_lcd_mode(2, mem);
-                        self.lcd_timing -= TICKS_PER_VBLANK;
-
-                        window
-                            .update_with_buffer(&self.buffer, GB_SCREEN_WIDTH, GB_SCREEN_HEIGHT)
-                            .unwrap();
+                MEM_EXT..=0xBFFF if self.ram.data.is_empty() => Some(NO_RAM),
+                _ => None,
+            }
+        }
+        fn read_ram(&self, addr: Word) -> Byte {
+            self.ram
+                .data
+                .get((addr - MEM_EXT) as usize)
+                .copied()
+                .unwrap_or(0xFF)
+        }
+        fn write_ram(&mut self, addr: Word, val: Byte) {
+            if let Some(b) = self.ram.data_mut().get_mut((addr - MEM_EXT) as usize) {
+                *b = val;
+            }
+        }
+        fn bank_at(&self, addr: Word) -> usize {
+            match addr {
+                MEM_BANK_00..=0x3FFF => 0,
+                MEM_BANK_NN..=0x7FFF => 1,
+                _ => 0,
+            }
+        }
+        fn ram(&self) -> &[Byte] {
+            &self.ram.data
+        }
+        fn ram_mut(&mut self) -> &mut [Byte] {
+            self.ram.data_mut()
+        }
+        fn hash_state(&self, h: &mut StateHash) {
+            self.ram.hash_state(h);
+        }
+        fn box_clone(&self) -> Box<dyn Mbc> {
+            Box::new(self.clone())
+        }
+    }
 
-                        if self.doctor {
-                            dump("mem.bin", &mem).unwrap()
-                        }
-                    }
-                }
-                _ => panic!("invalid LCD mode"),
-            };
+    /// Up to 2MB ROM / 32KB RAM
+    #[derive(Clone)]
+    pub struct Mbc1 {
+        rom: Rom,
+        ram: Ram,
+        rom_bank: usize, // low 5 bits of the ROM bank number
+        upper: usize,    // 2 bits, RAM bank or upper ROM bank bits
+        mode: bool,      // true: upper bits apply to RAM and 0000-3FFF too
+    }
+
+    impl Mbc1 {
+        fn new(rom: Rom, ram: Ram) -> Mbc1 {
+            Mbc1 {
+                rom,
+                ram,
+                rom_bank: 1,
+                upper: 0,
+                mode: false,
+            }
+        }
+        fn rom_banks(&self) -> [usize; 2] {
+            // mode 1 also applies the upper bits to the 0000-3FFF area (1MB+ carts)
+            let bank_00 = if self.mode { self.upper << 5 } else { 0 };
+            [
+                self.rom.bank(bank_00),
+                self.rom.bank(self.upper << 5 | self.rom_bank),
+            ]
+        }
+        fn ram_bank(&self) -> usize {
+            if self.mode {
+                self.upper
+            } else {
+                0 // mode 0 locks RAM to bank 0
+            }
         }
     }
-    
-    pub fn lcd_compare_ly_lyc(mem: &mut Memory) -> bool {
-        // https://gbdev.io/pandocs/STAT.html#ff45--lyc-ly-compare
-        let equal = mem.read(LY) == mem.read(LYC);
-        let comparison = bit_set(STAT_BIT_LY_LYC_EQ, mem.read(STAT), equal);
-        mem.write(STAT, comparison);
-        if equal && comparison & STAT_BIT_LYC_INT_SELECT != 0 {
-            // if LYC int select is enabled, request an interrupt
-            request_interrupt(mem, FL_INT_STAT);
+
+    impl Mbc for Mbc1 {
+        fn read_rom(&self, addr: Word) -> Byte {
+            self.rom
+                .read(self.rom_banks()[addr as usize / BANK_SIZE], addr)
+        }
+        fn write_rom(&mut self, addr: Word, val: Byte) {
+            match addr {
+                0x0000..=0x1FFF => self.ram.set_enabled(val),
+                // bank 0 can't be selected here, it reads as bank 1 (only the 5 bits count)
+                0x2000..=0x3FFF => self.rom_bank = ((val & 0x1F) as usize).max(1),
+                0x4000..=0x5FFF => self.upper = (val & 0b11) as usize,
+                _ => self.mode = val & 1 != 0,
+            }
+        }
+        fn unsupported(&self, addr: Word, _val: Byte) -> Option<&'static str> {
+            match addr {
+                MEM_EXT..=0xBFFF if self.ram.enabled && self.ram.data.is_empty() => Some(NO_RAM),
+                _ => None,
+            }
+        }
+        fn read_ram(&self, addr: Word) -> Byte {
+            self.ram.read(self.ram_bank(), addr)
+        }
+        fn write_ram(&mut self, addr: Word, val: Byte) {
+            self.ram.write(self.ram_bank(), addr, val);
+        }
+        fn bank_at(&self, addr: Word) -> usize {
+            match addr {
+                MEM_BANK_00..=0x7FFF => self.rom_banks()[addr as usize / BANK_SIZE],
+                _ => self.ram.bank_at(self.ram_bank(), addr),
+            }
+        }
+        fn ram(&self) -> &[Byte] {
+            &self.ram.data
+        }
+        fn ram_mut(&mut self) -> &mut [Byte] {
+            self.ram.data_mut()
+        }
+        fn hash_state(&self, h: &mut StateHash) {
+            self.ram.hash_state(h);
+            h.bytes(&[self.rom_bank as Byte, self.upper as Byte, self.mode as Byte]);
+        }
+        fn box_clone(&self) -> Box<dyn Mbc> {
+            Box::new(self.clone())
         }
-        equal
     }
 
-    pub fn lcd_mode(mem: &Memory) -> Byte {
-        mem.read(STAT) & STAT_MASK_PPU_MODE
+    /// Up to 256KB ROM, with 512 half-bytes of RAM built in
+    #[derive(Clone)]
+    pub struct Mbc2 {
+        rom: Rom,
+        ram: Ram,
+        rom_bank: usize, // 4 bits
     }
 
-    pub fn set_lcd_mode(mode: Byte, mem: &mut Memory) {
-        mem.write(STAT, (mem.read(STAT) & !STAT_MASK_PPU_MODE) | (mode & STAT_MASK_PPU_MODE));
+    const MBC2_RAM_SIZE: usize = 0x200;
+
+    impl Mbc2 {
+        fn new(rom: Rom) -> Mbc2 {
+            Mbc2 {
+                rom,
+                ram: Ram::new(MBC2_RAM_SIZE),
+                rom_bank: 1,
+            }
+        }
+        // the 512 bytes repeat all through A000-BFFF
+        fn ram_index(addr: Word) -> usize {
+            (addr - MEM_EXT) as usize % MBC2_RAM_SIZE
+        }
     }
 
-    pub fn ppu_decode_tile_line(low: Byte, high: Byte) -> [Byte; PPU_TILE_WIDTH] {
-        let mut result = [0; PPU_TILE_WIDTH];
-        for i in 0..PPU_TILE_WIDTH {
-            let mask = 1 << i;
-            let masked_low = (low & mask) >> i;
-            let masked_high = if i > 0 {
-                (high & mask) >> (i - 1)
+    impl Mbc for Mbc2 {
+        fn read_rom(&self, addr: Word) -> Byte {
+            match addr {
+                MEM_BANK_00..=0x3FFF => self.rom.read(0, addr),
+                _ => self.rom.read(self.rom_bank, addr),
+            }
+        }
+        fn write_rom(&mut self, addr: Word, val: Byte) {
+            // a single register range, address bit 8 picks RAM enable (0) or ROM bank (1)
+            match addr {
+                0x0000..=0x3FFF if addr & 0x0100 == 0 => self.ram.set_enabled(val),
+                0x0000..=0x3FFF => self.rom_bank = ((val & 0x0F) as usize).max(1),
+                _ => {}
+            }
+        }
+        fn read_ram(&self, addr: Word) -> Byte {
+            if self.ram.enabled {
+                0xF0 | self.ram.data[Mbc2::ram_index(addr)] // only the low nibble exists
             } else {
-                (high & mask) << 1
-            };
-            result[i] = masked_high | masked_low;
+                0xFF
+            }
+        }
+        fn write_ram(&mut self, addr: Word, val: Byte) {
+            if self.ram.enabled {
+                self.ram.data_mut()[Mbc2::ram_index(addr)] = val & 0x0F;
+            }
+        }
+        fn bank_at(&self, addr: Word) -> usize {
+            match addr {
+                MEM_BANK_00..=0x3FFF => 0,
+                MEM_BANK_NN..=0x7FFF => self.rom.bank(self.rom_bank),
+                _ if self.ram.enabled => 0,
+                _ => usize::MAX,
+            }
+        }
+        fn ram(&self) -> &[Byte] {
+            &self.ram.data
+        }
+        fn ram_mut(&mut self) -> &mut [Byte] {
+            self.ram.data_mut()
+        }
+        fn hash_state(&self, h: &mut StateHash) {
+            self.ram.hash_state(h);
+            h.bytes(&[self.rom_bank as Byte]);
+        }
+        fn box_clone(&self) -> Box<dyn Mbc> {
+            Box::new(self.clone())
         }
-        result
     }
-}
-
-pub mod decode {
-    use crate::cpu::*;
-    use crate::types::*;
-
-    // https://gb-archive.github.io/salvage/decoding_gbz80_opcodes/Decoding%20Gamboy%20Z80%20Opcodes.html
-    // https://www.pastraiser.com/cpu/gameboy/gameboy_opcodes.html
-
-    // used for CB decoding, some bit functions reference (HL) instead of a register
-    pub const ADR_HL: usize = 6;
-    pub const R_ID: [usize; 8] = [REG_B, REG_C, REG_D, REG_E, REG_H, REG_L, ADR_HL, REG_A];
 
-    // arg tables for printing mnemonics
-    pub const R: [&'static str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
-    const RP: [&'static str; 4] = ["BC", "DE", "HL", "SP"];
-    const RP2: [&'static str; 4] = ["BC", "DE", "HL", "AF"];
-    const CC: [&'static str; 4] = ["NZ", "Z", "NC", "C"];
-    const ALU: [&'static str; 8] = [
-        "ADD A,", "ADC A,", "SUB", "SBC A,", "AND", "XOR", "OR", "CP",
-    ];
-    const ROT: [&'static str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+    /// Up to 2MB ROM / 32KB RAM, and a real time clock
+    #[derive(Clone)]
+    pub struct Mbc3 {
+        rom: Rom,
+        ram: Ram,
+        rtc: Option<Rtc>,
+        rom_bank: usize, // 7 bits
+        ram_bank: usize, // 0-3 RAM, 08-0C clock registers
+        latch_prev: Byte,
+    }
+
+    impl Mbc3 {
+        fn new(rom: Rom, ram: Ram, rtc: Option<Rtc>) -> Mbc3 {
+            Mbc3 {
+                rom,
+                ram,
+                rtc,
+                rom_bank: 1,
+                ram_bank: 0,
+                latch_prev: 0xFF,
+            }
+        }
+        /// The clock register mapped into A000-BFFF, if one is
+        fn rtc_reg(&self) -> Option<usize> {
+            match self.ram_bank {
+                0x08..=0x0C if self.ram.enabled && self.rtc.is_some() => Some(self.ram_bank - 0x08),
+                _ => None,
+            }
+        }
+    }
 
-    // """
-    // Upon establishing the opcode, the Z80's path of action is generally dictated by these values:
+    impl Mbc for Mbc3 {
+        fn read_rom(&self, addr: Word) -> Byte {
+            match addr {
+                MEM_BANK_00..=0x3FFF => self.rom.read(0, addr),
+                _ => self.rom.read(self.rom_bank, addr),
+            }
+        }
+        fn write_rom(&mut self, addr: Word, val: Byte) {
+            match addr {
+                0x0000..=0x1FFF => self.ram.set_enabled(val),
+                0x2000..=0x3FFF => self.rom_bank = ((val & 0x7F) as usize).max(1),
+                0x4000..=0x5FFF => self.ram_bank = val as usize,
+                _ => {
+                    // writing 00 then 01 copies the running clock into the readable registers
+                    if let Some(rtc) = &mut self.rtc {
+                        if self.latch_prev == 0x00 && val == 0x01 {
+                            rtc.latched = rtc.regs;
+                        }
+                    }
+                    self.latch_prev = val;
+                }
+            }
+        }
+        fn unsupported(&self, addr: Word, val: Byte) -> Option<&'static str> {
+            match addr {
+                0x4000..=0x5FFF if (0x08..=0x0C).contains(&val) && self.rtc.is_none() => {
+                    Some("MBC3 clock registers (the header says there's no clock)")
+                }
+                0x6000..=0x7FFF if self.latch_prev == 0x00 && val == 0x01 && self.rtc.is_none() => {
+                    Some("MBC3 clock latch (the header says there's no clock)")
+                }
+                MEM_EXT..=0xBFFF
+                    if self.ram.enabled && self.rtc_reg().is_none() && self.ram.data.is_empty() =>
+                {
+                    Some(NO_RAM)
+                }
+                _ => None,
+            }
+        }
+        fn read_ram(&self, addr: Word) -> Byte {
+            match (self.rtc_reg(), &self.rtc) {
+                (Some(r), Some(rtc)) => rtc.latched[r],
+                _ => self.ram.read(self.ram_bank, addr),
+            }
+        }
+        fn write_ram(&mut self, addr: Word, val: Byte) {
+            match (self.rtc_reg(), &mut self.rtc) {
+                (Some(r), Some(rtc)) => rtc.write(r, val),
+                _ => self.ram.write(self.ram_bank, addr, val),
+            }
+        }
+        fn bank_at(&self, addr: Word) -> usize {
+            match addr {
+                MEM_BANK_00..=0x3FFF => 0,
+                MEM_BANK_NN..=0x7FFF => self.rom.bank(self.rom_bank),
+                _ => self.ram.bank_at(self.ram_bank, addr),
+            }
+        }
+        fn ram(&self) -> &[Byte] {
+            &self.ram.data
+        }
+        fn ram_mut(&mut self) -> &mut [Byte] {
+            self.ram.data_mut()
+        }
+        fn hash_state(&self, h: &mut StateHash) {
+            self.ram.hash_state(h);
+            h.bytes(&[
+                self.rom_bank as Byte,
+                self.ram_bank as Byte,
+                self.latch_prev,
+            ]);
+            if let Some(rtc) = &self.rtc {
+                rtc.hash_state(h);
+            }
+        }
+        fn box_clone(&self) -> Box<dyn Mbc> {
+            Box::new(self.clone())
+        }
+        fn add_cycles(&mut self, cycles: u64) {
+            if let Some(rtc) = &mut self.rtc {
+                rtc.add_cycles(cycles);
+            }
+        }
+        fn rtc(&self, now: u64) -> Option<RtcFooter> {
+            self.rtc.as_ref().map(|rtc| RtcFooter {
+                regs: rtc.regs,
+                latched: rtc.latched,
+                timestamp: now,
+            })
+        }
+        fn load_rtc(&mut self, saved: &RtcFooter, now: u64) {
+            if let Some(rtc) = &mut self.rtc {
+                rtc.regs = saved.regs;
+                rtc.latched = saved.latched;
+                rtc.cycles = 0;
+                rtc.advance(now.saturating_sub(saved.timestamp));
+            }
+        }
+    }
 
-    // x = the opcode's 1st octal digit (i.e. bits 7-6)
-    // y = the opcode's 2nd octal digit (i.e. bits 5-3)
-    // z = the opcode's 3rd octal digit (i.e. bits 2-0)
-    // p = y rightshifted one position (i.e. bits 5-4)
-    // q = y modulo 2 (i.e. bit 3)
+    const RTC_S: usize = 0;
+    const RTC_M: usize = 1;
+    const RTC_H: usize = 2;
+    const RTC_DL: usize = 3;
+    const RTC_DH: usize = 4;
+    const RTC_DH_HALT: Byte = 0x40;
+    const RTC_DH_CARRY: Byte = 0x80;
+    /// Bits that exist in each register, the rest read back as 0
+    const RTC_MASKS: [Byte; 5] = [0x3F, 0x3F, 0x1F, 0xFF, 0xC1];
+
+    /// The MBC3 real time clock, counted in emulated time so it stays deterministic
+    #[derive(Clone, Default)]
+    struct Rtc {
+        regs: [Byte; 5],    // S, M, H, DL, DH (day bit 8, halt, day carry)
+        latched: [Byte; 5], // what A000-BFFF reads
+        cycles: u64,        // into the current second
+    }
+
+    impl Rtc {
+        fn write(&mut self, reg: usize, val: Byte) {
+            self.regs[reg] = val & RTC_MASKS[reg];
+            if reg == RTC_S {
+                self.cycles = 0;
+            }
+        }
+        fn add_cycles(&mut self, cycles: u64) {
+            if self.regs[RTC_DH] & RTC_DH_HALT != 0 {
+                return;
+            }
+            self.cycles += cycles;
+            if self.cycles >= CPU_HZ {
+                self.advance(self.cycles / CPU_HZ);
+                self.cycles %= CPU_HZ;
+            }
+        }
+        /// Moves the clock forward, unless it's halted. The day counter is 9 bits and sets
+        /// the carry flag (which stays set until cleared) when it overflows
+        fn advance(&mut self, secs: u64) {
+            let r = &mut self.regs;
+            if secs == 0 || r[RTC_DH] & RTC_DH_HALT != 0 {
+                return;
+            }
+            let days = (r[RTC_DL] as u64) | ((r[RTC_DH] as u64 & 0x01) << 8);
+            let t = secs
+                + r[RTC_S] as u64
+                + r[RTC_M] as u64 * 60
+                + r[RTC_H] as u64 * 3600
+                + days * 86400;
+            let days = t / 86400;
+            r[RTC_S] = (t % 60) as Byte;
+            r[RTC_M] = (t / 60 % 60) as Byte;
+            r[RTC_H] = (t / 3600 % 24) as Byte;
+            r[RTC_DL] = days as Byte;
+            r[RTC_DH] = (r[RTC_DH] & !0x01) | ((days >> 8) & 0x01) as Byte;
+            if days > 0x1FF {
+                r[RTC_DH] |= RTC_DH_CARRY;
+            }
+        }
+        fn hash_state(&self, h: &mut StateHash) {
+            h.bytes(&self.regs);
+            h.bytes(&self.latched);
+            h.u64(self.cycles);
+        }
+    }
 
-    // The following placeholders for instructions and operands are used:
+    /// Up to 8MB ROM / 128KB RAM
+    #[derive(Clone)]
+    pub struct Mbc5 {
+        rom: Rom,
+        ram: Ram,
+        rom_bank: usize, // 9 bits, bank 0 is allowed here
+        ram_bank: usize, // 4 bits
+    }
 
-    // d = displacement byte (8-bit signed integer)
-    // n = 8-bit immediate operand (unsigned integer)
-    // nn = 16-bit immediate operand (unsigned integer)
-    // tab[x] = whatever is contained in the table named tab at index x (analogous for y and z and other table names)
-    // """
+    impl Mbc5 {
+        fn new(rom: Rom, ram: Ram) -> Mbc5 {
+            Mbc5 {
+                rom,
+                ram,
+                rom_bank: 1,
+                ram_bank: 0,
+            }
+        }
+    }
 
-    const fn x(op: Byte) -> Byte {
-        op >> 6
+    impl Mbc for Mbc5 {
+        fn read_rom(&self, addr: Word) -> Byte {
+            match addr {
+                MEM_BANK_00..=0x3FFF => self.rom.read(0, addr),
+                _ => self.rom.read(self.rom_bank, addr),
+            }
+        }
+        fn write_rom(&mut self, addr: Word, val: Byte) {
+            match addr {
+                0x0000..=0x1FFF => self.ram.set_enabled(val),
+                0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | val as usize,
+                0x3000..=0x3FFF => {
+                    self.rom_bank = (self.rom_bank & 0xFF) | ((val as usize & 1) << 8)
+                }
+                0x4000..=0x5FFF => self.ram_bank = (val & 0x0F) as usize,
+                _ => {}
+            }
+        }
+        fn unsupported(&self, addr: Word, val: Byte) -> Option<&'static str> {
+            match addr {
+                // bit 3 is the motor on rumble carts, only 128KB of RAM has a bank for it
+                0x4000..=0x5FFF
+                    if val & 0x08 != 0 && self.ram.data.len() <= 8 * EXT_RAM_BANK_SIZE =>
+                {
+                    Some("MBC5 rumble motor")
+                }
+                MEM_EXT..=0xBFFF if self.ram.enabled && self.ram.data.is_empty() => Some(NO_RAM),
+                _ => None,
+            }
+        }
+        fn read_ram(&self, addr: Word) -> Byte {
+            self.ram.read(self.ram_bank, addr)
+        }
+        fn write_ram(&mut self, addr: Word, val: Byte) {
+            self.ram.write(self.ram_bank, addr, val);
+        }
+        fn bank_at(&self, addr: Word) -> usize {
+            match addr {
+                MEM_BANK_00..=0x3FFF => 0,
+                MEM_BANK_NN..=0x7FFF => self.rom.bank(self.rom_bank),
+                _ => self.ram.bank_at(self.ram_bank, addr),
+            }
+        }
+        fn ram(&self) -> &[Byte] {
+            &self.ram.data
+        }
+        fn ram_mut(&mut self) -> &mut [Byte] {
+            self.ram.data_mut()
+        }
+        fn hash_state(&self, h: &mut StateHash) {
+            self.ram.hash_state(h);
+            h.bytes(&self.rom_bank.to_le_bytes()[..2]);
+            h.bytes(&[self.ram_bank as Byte]);
+        }
+        fn box_clone(&self) -> Box<dyn Mbc> {
+            Box::new(self.clone())
+        }
     }
-    const fn y(op: Byte) -> Byte {
-        op >> 3 & 0b111
+
+    // accelerometer readings: level is 0x81D0, tilting by 1g moves it about 0x70
+    const TILT_CENTER: f32 = 0x81D0 as f32;
+    const TILT_PER_G: f32 = 0x70 as f32;
+    const TILT_ERASED: Word = 0x8000;
+
+    /// Up to 2MB ROM, a serial EEPROM for saves and an accelerometer (Kirby Tilt 'n' Tumble)
+    ///
+    /// Only A000-AFFF is mapped, as registers selected by address bits 4-7.
+    #[derive(Clone)]
+    pub struct Mbc7 {
+        rom: Rom,
+        rom_bank: usize,        // 7 bits
+        ram_enabled: [bool; 2], // needs both 0A to 0000-1FFF and 40 to 4000-5FFF
+        tilt: (f32, f32),       // what the sensor reads now, see Mbc::set_tilt
+        latched: (Word, Word),  // what the game reads, updated by the erase/latch sequence
+        erased: bool,
+        eeprom: Eeprom,
+    }
+
+    impl Mbc7 {
+        fn new(rom: Rom) -> Mbc7 {
+            Mbc7 {
+                rom,
+                rom_bank: 1,
+                ram_enabled: [false; 2],
+                tilt: (0.0, 0.0),
+                latched: (TILT_ERASED, TILT_ERASED),
+                erased: false,
+                eeprom: Eeprom::new(EEPROM_SIZE),
+            }
+        }
+        fn sample(g: f32) -> Word {
+            (TILT_CENTER - g * TILT_PER_G).clamp(0.0, Word::MAX as f32) as Word
+        }
     }
-    const fn z(op: Byte) -> Byte {
-        op & 0b111
+
+    impl Mbc for Mbc7 {
+        fn read_rom(&self, addr: Word) -> Byte {
+            match addr {
+                MEM_BANK_00..=0x3FFF => self.rom.read(0, addr),
+                _ => self.rom.read(self.rom_bank, addr),
+            }
+        }
+        fn write_rom(&mut self, addr: Word, val: Byte) {
+            match addr {
+                0x0000..=0x1FFF => self.ram_enabled[0] = val == 0x0A,
+                0x2000..=0x3FFF => self.rom_bank = ((val & 0x7F) as usize).max(1),
+                0x4000..=0x5FFF => self.ram_enabled[1] = val == 0x40,
+                _ => {}
+            }
+        }
+        fn read_ram(&self, addr: Word) -> Byte {
+            if self.ram_enabled != [true; 2] || addr >= 0xB000 {
+                return 0xFF;
+            }
+            let (x, y) = self.latched;
+            match (addr >> 4) & 0x0F {
+                0x2 => lo(x),
+                0x3 => hi(x),
+                0x4 => lo(y),
+                0x5 => hi(y),
+                0x6 => 0x00,
+                0x8 => self.eeprom.read(),
+                _ => 0xFF,
+            }
+        }
+        fn write_ram(&mut self, addr: Word, val: Byte) {
+            if self.ram_enabled != [true; 2] || addr >= 0xB000 {
+                return;
+            }
+            match ((addr >> 4) & 0x0F, val) {
+                (0x0, 0x55) => {
+                    self.erased = true;
+                    self.latched = (TILT_ERASED, TILT_ERASED);
+                }
+                (0x1, 0xAA) if self.erased => {
+                    self.erased = false;
+                    self.latched = (Mbc7::sample(self.tilt.0), Mbc7::sample(self.tilt.1));
+                }
+                (0x8, _) => self.eeprom.write(val),
+                _ => {}
+            }
+        }
+        fn bank_at(&self, addr: Word) -> usize {
+            match addr {
+                MEM_BANK_00..=0x3FFF => 0,
+                MEM_BANK_NN..=0x7FFF => self.rom.bank(self.rom_bank),
+                _ => usize::MAX, // registers, never code
+            }
+        }
+        fn ram(&self) -> &[Byte] {
+            &self.eeprom.data
+        }
+        fn ram_mut(&mut self) -> &mut [Byte] {
+            &mut self.eeprom.data
+        }
+        fn hash_state(&self, h: &mut StateHash) {
+            self.eeprom.hash_state(h);
+            h.bytes(&[self.rom_bank as Byte, self.ram_enabled[0] as Byte]);
+            h.bytes(&[self.ram_enabled[1] as Byte, self.erased as Byte]);
+            h.bytes(&self.latched.0.to_le_bytes());
+            h.bytes(&self.latched.1.to_le_bytes());
+        }
+        fn box_clone(&self) -> Box<dyn Mbc> {
+            Box::new(self.clone())
+        }
+        fn set_tilt(&mut self, x: f32, y: f32) {
+            self.tilt = (x, y);
+        }
     }
-    const fn p(op: Byte) -> Byte {
-        y(op) >> 1
+
+    /// Multi-game compilations: an MBC1-style mapper inside a window of the ROM that the menu
+    /// picks before handing over
+    ///
+    /// Powers on unmapped, showing the last 32KB (the menu). While unmapped every register is
+    /// writable, including the outer bank bits and masks that pick the game's window; setting
+    /// bit 6 of 0000-1FFF maps the game in and locks all of that, leaving the game only the
+    /// bank bits the mask gives it.
+    /// https://gbdev.io/pandocs/MMM01.html
+    #[derive(Clone)]
+    pub struct Mmm01 {
+        rom: Rom,
+        ram: Ram,
+        mapped: bool,
+        rom_bank: usize, // 9 bits: high 2 (4000 bits 4-5), mid 2 (2000 bits 5-6), low 5
+        rom_mask: usize, // rom bank low bits 1-4 the game can't change (6000 bits 2-5)
+        ram_bank: usize, // 4 bits: high 2 (4000 bits 2-3) and low 2
+        ram_mask: usize, // ram bank low bits the game can't change (0000 bits 4-5)
+        mode: bool,      // MBC1 banking mode (6000 bit 0), RAM banking when set
+    }
+
+    impl Mmm01 {
+        fn new(rom: Rom, ram: Ram) -> Mmm01 {
+            Mmm01 {
+                rom,
+                ram,
+                mapped: false,
+                rom_bank: 0,
+                rom_mask: 0,
+                ram_bank: 0,
+                ram_mask: 0,
+                mode: false,
+            }
+        }
+        /// Rom bank low bits the game controls once mapped
+        fn game_rom_bits(&self) -> usize {
+            0x1F & !self.rom_mask
+        }
+        /// Bank at 0000-3FFF and 4000-7FFF
+        fn rom_banks(&self) -> (usize, usize) {
+            if !self.mapped {
+                return (0x1FE, 0x1FF);
+            }
+            let base = self.rom_bank & !self.game_rom_bits();
+            let mut nn = self.rom_bank;
+            if nn & self.game_rom_bits() == 0 {
+                nn |= 1; // like MBC1, bank 0 can't be selected up here
+            }
+            (base, nn)
+        }
+        fn ram_bank(&self) -> usize {
+            if self.mode || !self.mapped {
+                self.ram_bank
+            } else {
+                self.ram_bank & !0x03 // MBC1 rom mode, RAM bank bits held at 0
+            }
+        }
     }
-    const fn q(op: Byte) -> Byte {
-        y(op) & 0b1
+
+    impl Mbc for Mmm01 {
+        fn read_rom(&self, addr: Word) -> Byte {
+            let (b00, bnn) = self.rom_banks();
+            match addr {
+                MEM_BANK_00..=0x3FFF => self.rom.read(b00, addr),
+                _ => self.rom.read(bnn, addr),
+            }
+        }
+        fn write_rom(&mut self, addr: Word, val: Byte) {
+            let val = val as usize;
+            let unmapped = !self.mapped;
+            match addr {
+                0x0000..=0x1FFF => {
+                    self.ram.set_enabled(val as Byte);
+                    if unmapped {
+                        self.ram_mask = (val >> 4) & 0x03;
+                        self.mapped = val & 0x40 != 0;
+                    }
+                }
+                0x2000..=0x3FFF => {
+                    let game = if unmapped { 0x1F } else { self.game_rom_bits() };
+                    self.rom_bank = (self.rom_bank & !game) | (val & game);
+                    if unmapped {
+                        self.rom_bank = (self.rom_bank & !0x60) | (val & 0x60);
+                    }
+                }
+                0x4000..=0x5FFF => {
+                    let game = if unmapped {
+                        0x03
+                    } else {
+                        0x03 & !self.ram_mask
+                    };
+                    self.ram_bank = (self.ram_bank & !game) | (val & game);
+                    if unmapped {
+                        self.ram_bank = (self.ram_bank & 0x03) | (val & 0x0C);
+                        self.rom_bank = (self.rom_bank & 0x7F) | ((val & 0x30) << 3);
+                    }
+                }
+                _ => {
+                    self.mode = val & 0x01 != 0;
+                    if unmapped {
+                        self.rom_mask = ((val >> 2) & 0x0F) << 1;
+                    }
+                }
+            }
+        }
+        fn read_ram(&self, addr: Word) -> Byte {
+            self.ram.read(self.ram_bank(), addr)
+        }
+        fn write_ram(&mut self, addr: Word, val: Byte) {
+            self.ram.write(self.ram_bank(), addr, val);
+        }
+        fn bank_at(&self, addr: Word) -> usize {
+            let (b00, bnn) = self.rom_banks();
+            match addr {
+                MEM_BANK_00..=0x3FFF => self.rom.bank(b00),
+                MEM_BANK_NN..=0x7FFF => self.rom.bank(bnn),
+                _ => self.ram.bank_at(self.ram_bank(), addr),
+            }
+        }
+        fn ram(&self) -> &[Byte] {
+            &self.ram.data
+        }
+        fn ram_mut(&mut self) -> &mut [Byte] {
+            self.ram.data_mut()
+        }
+        fn hash_state(&self, h: &mut StateHash) {
+            self.ram.hash_state(h);
+            h.bytes(&self.rom_bank.to_le_bytes()[..2]);
+            h.bytes(&[
+                self.rom_mask as Byte,
+                self.ram_bank as Byte,
+                self.ram_mask as Byte,
+            ]);
+            h.bytes(&[self.mapped as Byte, self.mode as Byte]);
+        }
+        fn box_clone(&self) -> Box<dyn Mbc> {
+            Box::new(self.clone())
+        }
     }
 
-    const INVALID: &'static str = "INVALID";
-    pub const CBPREFIX: &'static str = "(CB PREFIX)";
+    /// Hudson's MBC1 lookalike: up to 1MB ROM / 32KB RAM, plus an infrared port (Pokemon TCG)
+    ///
+    /// Writing 0E to 0000-1FFF swaps A000-BFFF from RAM over to the IR register, anything
+    /// else swaps it back. There's no RAM enable.
+    #[derive(Clone)]
+    pub struct HuC1 {
+        rom: Rom,
+        ram: Ram,
+        rom_bank: usize, // 6 bits
+        ram_bank: usize, // 2 bits
+        ir_mode: bool,
+        led: bool,
+        ir: SharedIrPort,
+    }
+
+    impl HuC1 {
+        fn new(rom: Rom, mut ram: Ram) -> HuC1 {
+            ram.enabled = true;
+            HuC1 {
+                rom,
+                ram,
+                rom_bank: 1,
+                ram_bank: 0,
+                ir_mode: false,
+                led: false,
+                ir: Arc::new(Mutex::new(NoIr)),
+            }
+        }
+    }
 
-    // todo: Instruction is constantly allocating heap strings, I feel like there
-    // should be a way to do this at compile time but I can't figure it out
-    #[allow(non_snake_case)]
-    pub fn decode(op: Byte) -> Instruction {
-        let _ALU_y = ALU[y(op) as usize % ALU.len()];
-        let _CC_y = CC[y(op) as usize % CC.len()];
-        let _R_y = R[y(op) as usize % R.len()];
-        let _R_z = R[z(op) as usize % R.len()];
-        let _RP_p = RP[p(op) as usize % RP.len()];
-        let _RP2_p = RP2[p(op) as usize % RP2.len()];
-        let _y8 = y(op) * 8;
-        match x(op) {
-            0 => match z(op) {
-                0 => match y(op) {
-                    0 => Instruction::new("NOP", 1),
-                    1 => Instruction::new("LD (nn), SP", 3),
-                    2 => Instruction::new("STOP", 1),
-                    3 => Instruction::new("JR n", 2),
-                    v @ 4..=7 => {
-                        let i: usize = (v - 4) as usize;
-                        let _CC_i: &'static str = CC[i];
-                        Instruction {
-                            mnm: format!("JR {_CC_i}, n"),
-                            len: 2,
+    impl Mbc for HuC1 {
+        fn read_rom(&self, addr: Word) -> Byte {
+            match addr {
+                MEM_BANK_00..=0x3FFF => self.rom.read(0, addr),
+                _ => self.rom.read(self.rom_bank, addr),
+            }
+        }
+        fn write_rom(&mut self, addr: Word, val: Byte) {
+            match addr {
+                0x0000..=0x1FFF => self.ir_mode = val == 0x0E,
+                0x2000..=0x3FFF => self.rom_bank = ((val & 0x3F) as usize).max(1),
+                0x4000..=0x5FFF => self.ram_bank = (val & 0x03) as usize,
+                _ => {}
+            }
+        }
+        fn read_ram(&self, addr: Word) -> Byte {
+            if self.ir_mode {
+                // C0 is dark, C1 light seen
+                0xC0 | self.ir.lock().unwrap().light_seen() as Byte
+            } else {
+                self.ram.read(self.ram_bank, addr)
+            }
+        }
+        fn write_ram(&mut self, addr: Word, val: Byte) {
+            if self.ir_mode {
+                let led = val & 0x01 != 0;
+                if led != self.led {
+                    self.led = led;
+                    self.ir.lock().unwrap().set_led(led);
+                }
+            } else {
+                self.ram.write(self.ram_bank, addr, val);
+            }
+        }
+        fn bank_at(&self, addr: Word) -> usize {
+            match addr {
+                MEM_BANK_00..=0x3FFF => 0,
+                MEM_BANK_NN..=0x7FFF => self.rom.bank(self.rom_bank),
+                _ if self.ir_mode => usize::MAX,
+                _ => self.ram.bank_at(self.ram_bank, addr),
+            }
+        }
+        fn ram(&self) -> &[Byte] {
+            &self.ram.data
+        }
+        fn ram_mut(&mut self) -> &mut [Byte] {
+            self.ram.data_mut()
+        }
+        fn hash_state(&self, h: &mut StateHash) {
+            self.ram.hash_state(h);
+            h.bytes(&[self.rom_bank as Byte, self.ram_bank as Byte]);
+            h.bytes(&[self.ir_mode as Byte, self.led as Byte]);
+        }
+        fn box_clone(&self) -> Box<dyn Mbc> {
+            Box::new(self.clone())
+        }
+        fn set_ir_port(&mut self, port: SharedIrPort) {
+            self.ir = port;
+        }
+    }
+
+    // 93LC56, 128 16-bit words
+    const EEPROM_SIZE: usize = 256;
+    const EEPROM_CS: Byte = 0x80;
+    const EEPROM_CLK: Byte = 0x40;
+    const EEPROM_DI: Byte = 0x02;
+    const EEPROM_DO: Byte = 0x01;
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    enum EepromMode {
+        Idle,     // waiting for a start bit
+        Command,  // clocking in 2 opcode + 8 address bits
+        Read,     // clocking out a word
+        Write,    // clocking in a word for one address
+        WriteAll, // clocking in a word for every address
+    }
+
+    /// The MBC7's serial EEPROM, bit-banged through the CS/CLK/DI/DO lines at Ax8x
+    ///
+    /// Bits move on the rising edge of CLK while CS is high, commands are a start bit, a 2 bit
+    /// opcode and an 8 bit address. Writes finish instantly (DO reads 1, ready, right away).
+    /// Words are kept little endian in `data`, which is what goes in the .sav.
+    #[derive(Clone)]
+    struct Eeprom {
+        data: Vec<Byte>,
+        pins: Byte, // CS, CLK and DI as last written
+        out: bool,  // DO
+        mode: EepromMode,
+        shift: Word, // bits in (or left to go out)
+        count: u8,   // how many bits have moved in this mode
+        addr: usize, // word being written
+        write_enabled: bool,
+    }
+
+    impl Eeprom {
+        fn new(size: usize) -> Eeprom {
+            Eeprom {
+                data: vec![0xFF; size],
+                pins: 0,
+                out: true,
+                mode: EepromMode::Idle,
+                shift: 0,
+                count: 0,
+                addr: 0,
+                write_enabled: false,
+            }
+        }
+        fn words(&self) -> usize {
+            self.data.len() / 2
+        }
+        fn word(&self, addr: usize) -> Word {
+            let i = (addr % self.words()) * 2;
+            Word::from_le_bytes([self.data[i], self.data[i + 1]])
+        }
+        fn set_word(&mut self, addr: usize, val: Word) {
+            if self.write_enabled {
+                let i = (addr % self.words()) * 2;
+                self.data[i..i + 2].copy_from_slice(&val.to_le_bytes());
+            }
+        }
+        fn read(&self) -> Byte {
+            self.pins | self.out as Byte
+        }
+        fn write(&mut self, val: Byte) {
+            let rising = val & EEPROM_CLK != 0 && self.pins & EEPROM_CLK == 0;
+            self.pins = val & (EEPROM_CS | EEPROM_CLK | EEPROM_DI);
+            if val & EEPROM_CS == 0 {
+                self.mode = EepromMode::Idle;
+                return;
+            }
+            if !rising {
+                return;
+            }
+            let di = (val & EEPROM_DI != 0) as Word;
+            match self.mode {
+                EepromMode::Idle => {
+                    if di != 0 {
+                        self.mode = EepromMode::Command;
+                        self.shift = 0;
+                        self.count = 0;
+                    }
+                }
+                EepromMode::Read => {
+                    self.out = self.shift & 0x8000 != 0;
+                    self.shift <<= 1;
+                    self.count -= 1;
+                    if self.count == 0 {
+                        self.mode = EepromMode::Idle;
+                    }
+                }
+                _ => {
+                    self.shift = self.shift << 1 | di;
+                    self.count += 1;
+                    match (self.mode, self.count) {
+                        (EepromMode::Command, 10) => self.command(),
+                        (EepromMode::Write, 16) => {
+                            self.set_word(self.addr, self.shift);
+                            self.done();
+                        }
+                        (EepromMode::WriteAll, 16) => {
+                            for addr in 0..self.words() {
+                                self.set_word(addr, self.shift);
+                            }
+                            self.done();
                         }
+                        _ => {}
                     }
-                    _ => Instruction::new(INVALID, 0),
+                }
+            }
+        }
+        /// A finished write/erase, DO reports ready
+        fn done(&mut self) {
+            self.out = true;
+            self.mode = EepromMode::Idle;
+        }
+        fn command(&mut self) {
+            let op = (self.shift >> 8) & 0b11;
+            let addr = (self.shift & 0xFF) as usize;
+            self.shift = 0;
+            self.count = 0;
+            match (op, addr >> 6) {
+                (0b10, _) => {
+                    // a dummy 0 bit first, then the word
+                    self.out = false;
+                    self.shift = self.word(addr);
+                    self.count = 16;
+                    self.mode = EepromMode::Read;
+                }
+                (0b01, _) => {
+                    self.addr = addr;
+                    self.mode = EepromMode::Write;
+                }
+                (0b11, _) => {
+                    self.set_word(addr, 0xFFFF);
+                    self.done();
+                }
+                (_, 0b11) => {
+                    self.write_enabled = true;
+                    self.mode = EepromMode::Idle;
+                }
+                (_, 0b00) => {
+                    self.write_enabled = false;
+                    self.mode = EepromMode::Idle;
+                }
+                (_, 0b10) => {
+                    for addr in 0..self.words() {
+                        self.set_word(addr, 0xFFFF);
+                    }
+                    self.done();
+                }
+                _ => self.mode = EepromMode::WriteAll,
+            }
+        }
+        fn hash_state(&self, h: &mut StateHash) {
+            h.bytes(&self.data);
+            h.bytes(&[self.pins, self.out as Byte, self.mode as Byte, self.count]);
+            h.bytes(&self.shift.to_le_bytes());
+            h.bytes(&[self.addr as Byte, self.write_enabled as Byte]);
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) mod tests_mbc {
+        use super::*;
+        use crate::bits::combine;
+
+        // every byte of each bank holds its bank number, tests_memory uses it too
+        pub(crate) fn banked_cart(rom_type: Byte, rom_size: Byte, banks: usize) -> Cartridge {
+            let mut rom = vec![];
+            for bank in 0..banks {
+                rom.extend(std::iter::repeat_n(bank as Byte, BANK_SIZE));
+            }
+            rom[ROM_TYPE as usize] = rom_type;
+            rom[ROM_SIZE as usize] = rom_size;
+            Cartridge::from(rom)
+        }
+
+        #[test]
+        fn test_mbc2() {
+            let mut mbc = mbc_for(&banked_cart(0x06, 0x03, 16)); // MBC2+BATTERY, 256KB
+            mbc.write_rom(0x2100, 0x07); // A8 set: rom bank
+            assert_eq!(mbc.read_rom(MEM_BANK_NN), 7);
+            mbc.write_rom(0x2100, 0x00);
+            assert_eq!(mbc.read_rom(MEM_BANK_NN), 1);
+
+            assert_eq!(mbc.ram().len(), 512);
+            mbc.write_rom(0x0000, 0x0A); // A8 clear: ram enable
+            mbc.write_ram(0xA001, 0x5C);
+            assert_eq!(mbc.read_ram(0xA001), 0xFC);
+            assert_eq!(mbc.read_ram(0xA201), 0xFC); // mirrored
+        }
+
+        #[test]
+        fn test_mbc5() {
+            let mut mbc = mbc_for(&banked_cart(0x19, 0x08, 512)); // MBC5, 8MB
+            mbc.write_rom(0x2000, 0x00);
+            assert_eq!(mbc.read_rom(MEM_BANK_NN), 0); // bank 0 is fine here
+            mbc.write_rom(0x2000, 0x05);
+            mbc.write_rom(0x3000, 0x01);
+            assert_eq!(mbc.bank_at(MEM_BANK_NN), 0x105);
+            assert_eq!(mbc.read_rom(MEM_BANK_NN), 0x05); // bank 261's fill byte
+        }
+
+        // xorshift64, a failing run can be repeated from its seed
+        struct Rng(u64);
+
+        impl Rng {
+            fn next(&mut self) -> u64 {
+                self.0 ^= self.0 << 13;
+                self.0 ^= self.0 >> 7;
+                self.0 ^= self.0 << 17;
+                self.0
+            }
+        }
+
+        /// Random controller writes, RAM writes and reads, checking what has to hold whatever
+        /// a game does: banks in range, ROM reads matching bank_at(), RAM shut while disabled
+        fn fuzz_mbc(cart: &Cartridge, seed: u64, steps: usize) {
+            let kind = cart.mbc_kind();
+            let mut mbc = mbc_for(cart);
+            let rom_banks = cart.size() / BANK_SIZE;
+            let ram_banks = mbc.ram().len().div_ceil(EXT_RAM_BANK_SIZE);
+            let mut rng = Rng(seed);
+            let mut ram_enabled = false;
+            for step in 0..steps {
+                let r = rng.next();
+                let (offset, val) = ((r >> 8) as Word, r as Byte);
+                let at = format!("{:?} seed {} step {}", kind, seed, step);
+                match (r >> 32) % 4 {
+                    // controller registers, often enough with the RAM unlock value
+                    0 | 1 => {
+                        let addr = offset & 0x7FFF;
+                        let val = if (r >> 40).is_multiple_of(4) {
+                            0x0A
+                        } else {
+                            val
+                        };
+                        mbc.write_rom(addr, val);
+                        let enable = match kind {
+                            MbcKind::Mbc2 => addr < 0x4000 && addr & 0x0100 == 0,
+                            _ => addr < 0x2000,
+                        };
+                        if enable {
+                            ram_enabled = val & 0x0F == 0x0A;
+                        }
+                    }
+                    2 => mbc.write_ram(MEM_EXT + offset % 0x2000, val),
+                    _ => {}
+                }
+
+                // every byte of a bank is its number, so a read shows which bank is mapped
+                for addr in [MEM_BANK_00 + 0x0200, MEM_BANK_NN + offset % 0x4000] {
+                    let bank = mbc.bank_at(addr);
+                    assert!(
+                        bank < rom_banks,
+                        "{}: rom bank {} at {:04X}",
+                        at,
+                        bank,
+                        addr
+                    );
+                    assert_eq!(mbc.read_rom(addr), bank as Byte, "{}: at {:04X}", at, addr);
+                }
+
+                let addr = MEM_EXT + offset % 0x2000;
+                if !ram_enabled {
+                    assert_eq!(mbc.read_ram(addr), 0xFF, "{}: disabled RAM", at);
+                }
+                let bank = mbc.bank_at(addr);
+                if bank != usize::MAX {
+                    assert!(
+                        ram_enabled,
+                        "{}: RAM bank {} mapped while disabled",
+                        at, bank
+                    );
+                    assert!(
+                        bank < ram_banks,
+                        "{}: RAM bank {} at {:04X}",
+                        at,
+                        bank,
+                        addr
+                    );
+                    mbc.write_ram(addr, val);
+                    let expected = if kind == MbcKind::Mbc2 {
+                        0xF0 | val
+                    } else {
+                        val
+                    };
+                    assert_eq!(mbc.read_ram(addr), expected, "{}: RAM at {:04X}", at, addr);
+                }
+            }
+        }
+
+        #[test]
+        fn test_fuzz_mbc() {
+            // (type, rom size, banks, ram size): full size and smaller than the registers reach
+            let carts = [
+                (0x03, 0x05, 64, 0x03),  // MBC1+RAM+BATTERY, 1MB, 32KB
+                (0x03, 0x02, 8, 0x02),   // 128KB, 8KB
+                (0x06, 0x03, 16, 0x00),  // MBC2+BATTERY, 256KB
+                (0x06, 0x01, 4, 0x00),   // 64KB
+                (0x10, 0x06, 128, 0x03), // MBC3+TIMER+RAM+BATTERY, 2MB, 32KB
+                (0x13, 0x02, 8, 0x02),   // MBC3+RAM+BATTERY, 128KB, 8KB
+                (0x1B, 0x08, 512, 0x04), // MBC5+RAM+BATTERY, 8MB, 128KB
+                (0x1B, 0x03, 16, 0x02),  // 256KB, 8KB
+            ];
+            for (rom_type, rom_size, banks, ram_size) in carts {
+                let mut rom = banked_cart(rom_type, rom_size, banks).bytes().to_vec();
+                rom[ROM_RAM_SIZE as usize] = ram_size;
+                let cart = Cartridge::from(rom);
+                for seed in 1..=4u64 {
+                    fuzz_mbc(&cart, seed.wrapping_mul(0x9E37_79B9_7F4A_7C15), 5000);
+                }
+            }
+        }
+
+        #[test]
+        fn test_clone() {
+            let mut mbc = mbc_for(&banked_cart(0x03, 0x02, 8)); // MBC1+RAM+BATTERY
+            mbc.write_rom(0x2000, 3);
+            let copy = mbc.clone();
+            mbc.write_rom(0x2000, 4);
+            assert_eq!(copy.read_rom(MEM_BANK_NN), 3);
+            assert_eq!(mbc.read_rom(MEM_BANK_NN), 4);
+        }
+
+        #[test]
+        fn test_mbc3_rtc() {
+            let mut mbc = mbc_for(&banked_cart(0x0F, 0x02, 8)); // MBC3+TIMER+BATTERY
+            let latch = |mbc: &mut Box<dyn Mbc>| {
+                mbc.write_rom(0x6000, 0x00);
+                mbc.write_rom(0x6000, 0x01);
+            };
+            mbc.write_rom(0x0000, 0x0A);
+            mbc.write_rom(0x4000, 0x08); // seconds
+            mbc.write_ram(MEM_EXT, 59);
+            mbc.write_rom(0x4000, 0x09); // minutes
+            mbc.write_ram(MEM_EXT, 59);
+
+            mbc.add_cycles(CPU_HZ * 3 / 2);
+            mbc.write_rom(0x4000, 0x08);
+            assert_eq!(mbc.read_ram(MEM_EXT), 0); // not latched yet
+            latch(&mut mbc);
+            assert_eq!(mbc.read_ram(MEM_EXT), 0);
+            mbc.write_rom(0x4000, 0x0A);
+            assert_eq!(mbc.read_ram(MEM_EXT), 1); // an hour in
+
+            // only a 00 -> 01 sequence latches
+            mbc.add_cycles(CPU_HZ);
+            mbc.write_rom(0x6000, 0x01);
+            mbc.write_rom(0x4000, 0x08);
+            assert_eq!(mbc.read_ram(MEM_EXT), 0);
+            latch(&mut mbc);
+            assert_eq!(mbc.read_ram(MEM_EXT), 1);
+
+            // back to the RAM banks, of which there are none here
+            mbc.write_rom(0x4000, 0x00);
+            assert_eq!(mbc.read_ram(MEM_EXT), 0xFF);
+
+            // saved, then switched off for 2 days and 10 seconds
+            let saved = mbc.rtc(1000).unwrap();
+            assert_eq!(saved.regs, [1, 0, 1, 0, 0]);
+            let mut loaded = mbc_for(&banked_cart(0x0F, 0x02, 8));
+            loaded.load_rtc(&saved, 1000 + 2 * 86400 + 10);
+            assert_eq!(loaded.rtc(0).unwrap().regs, [11, 0, 1, 2, 0]);
+
+            // halted clocks don't move, and the day counter overflows into the carry bit
+            let mut halted = saved;
+            halted.regs[4] = 0x41;
+            loaded.load_rtc(&halted, 1_000_000);
+            loaded.add_cycles(CPU_HZ * 10);
+            assert_eq!(loaded.rtc(0).unwrap().regs, halted.regs);
+            halted.regs[4] = 0x01;
+            halted.regs[3] = 0xFF;
+            loaded.load_rtc(&halted, 1000 + 86400);
+            assert_eq!(loaded.rtc(0).unwrap().regs[3..], [0x00, 0x80]);
+
+            assert!(mbc_for(&banked_cart(0x13, 0x02, 8)).rtc(0).is_none());
+        }
+
+        #[test]
+        fn test_unsupported() {
+            let mut mbc = mbc_for(&banked_cart(0x13, 0x02, 8)); // MBC3+RAM+BATTERY, no clock
+            assert_eq!(mbc.unsupported(0x2000, 0x02), None);
+            assert!(mbc
+                .unsupported(0x4000, 0x08)
+                .unwrap()
+                .contains("clock registers"));
+            assert_eq!(mbc.unsupported(0x6000, 0x01), None); // not after a 00
+            mbc.write_rom(0x6000, 0x00);
+            assert!(mbc
+                .unsupported(0x6000, 0x01)
+                .unwrap()
+                .contains("clock latch"));
+            let timer = mbc_for(&banked_cart(0x0F, 0x02, 8)); // MBC3+TIMER+BATTERY
+            assert_eq!(timer.unsupported(0x4000, 0x08), None);
+
+            let mut rom_only = mbc_for(&banked_cart(0x00, 0x00, 2));
+            assert_eq!(rom_only.unsupported(0x2000, 0x01), None);
+            assert!(rom_only.unsupported(0x2000, 0x03).is_some());
+            assert_eq!(rom_only.unsupported(MEM_EXT, 0x12), Some(NO_RAM));
+            rom_only.write_ram(MEM_EXT, 0x12);
+            assert_eq!(rom_only.read_ram(MEM_EXT), 0xFF);
+
+            let mut mbc1 = mbc_for(&banked_cart(0x01, 0x02, 8)); // MBC1, no RAM
+            assert_eq!(mbc1.unsupported(MEM_EXT, 0x12), None); // disabled, a stray write
+            mbc1.write_rom(0x0000, 0x0A);
+            assert_eq!(mbc1.unsupported(MEM_EXT, 0x12), Some(NO_RAM));
+        }
+
+        #[test]
+        fn test_mmm01() {
+            // 512KB: a 256KB MBC1 game at bank 0, a 128KB one at bank 16, menu in the last 32KB
+            let mut rom = banked_cart(0x01, 0x04, 32).bytes().to_vec();
+            let menu = rom.len() - 2 * BANK_SIZE;
+            rom[menu + ROM_TYPE as usize] = 0x0D; // MMM01+RAM+BATTERY
+            rom[menu + ROM_RAM_SIZE as usize] = 0x02;
+            let cart = Cartridge::from(rom);
+            assert_eq!(cart.mbc_kind(), MbcKind::Mmm01);
+            assert!(cart.has_battery());
+            assert_eq!(cart.size_ram(), 8 * KB);
+
+            let mut mbc = mbc_for(&cart);
+            assert_eq!(mbc.read_rom(MEM_BANK_00 + 0x200), 30); // menu
+            assert_eq!(mbc.read_rom(MEM_BANK_NN), 31);
+
+            // the menu picks the second game: outer bank 16, game gets 3 bank bits (8 banks)
+            mbc.write_rom(0x2000, 0x10);
+            mbc.write_rom(0x6000, 0b1100 << 2); // mask rom bank bits 3-4
+            mbc.write_rom(0x0000, 0x40); // map in and lock
+            assert_eq!(mbc.read_rom(MEM_BANK_00 + 0x200), 16);
+            assert_eq!(mbc.read_rom(MEM_BANK_NN), 17);
+            mbc.write_rom(0x2000, 0x05);
+            assert_eq!(mbc.read_rom(MEM_BANK_NN), 21);
+            mbc.write_rom(0x2000, 0x1F); // the outer bits don't budge
+            assert_eq!(mbc.read_rom(MEM_BANK_NN), 23);
+            assert_eq!(mbc.read_rom(MEM_BANK_00 + 0x200), 16);
+
+            // locked: no going back to the menu
+            mbc.write_rom(0x0000, 0x00);
+            mbc.write_rom(0x6000, 0x00);
+            assert_eq!(mbc.read_rom(MEM_BANK_00 + 0x200), 16);
+        }
+
+        #[test]
+        fn test_huc1() {
+            // a port that echoes our LED back, like a mirror
+            struct Mirror(bool);
+            impl IrPort for Mirror {
+                fn light_seen(&self) -> bool {
+                    self.0
+                }
+                fn set_led(&mut self, on: bool) {
+                    self.0 = on;
+                }
+            }
+
+            let mut rom = banked_cart(0xFF, 0x05, 64).bytes().to_vec();
+            rom[ROM_RAM_SIZE as usize] = 0x03; // 32KB
+            let mut mbc = mbc_for(&Cartridge::from(rom)); // HuC1+RAM+BATTERY
+            mbc.write_rom(0x2000, 0x3F);
+            assert_eq!(mbc.read_rom(MEM_BANK_NN), 0x3F);
+            mbc.write_rom(0x4000, 0x02);
+            mbc.write_ram(MEM_EXT, 0x42); // no enable needed
+            assert_eq!(mbc.ram()[2 * EXT_RAM_BANK_SIZE], 0x42);
+
+            mbc.write_rom(0x0000, 0x0E);
+            assert_eq!(mbc.read_ram(MEM_EXT), 0xC0); // nobody there by default
+            mbc.set_ir_port(Arc::new(Mutex::new(Mirror(false))));
+            mbc.write_ram(MEM_EXT, 0x01);
+            assert_eq!(mbc.read_ram(MEM_EXT), 0xC1);
+            assert_eq!(mbc.ram()[2 * EXT_RAM_BANK_SIZE], 0x42); // RAM untouched
+
+            mbc.write_rom(0x0000, 0x0A);
+            assert_eq!(mbc.read_ram(MEM_EXT), 0x42);
+        }
+
+        // clocks bits into the MBC7 EEPROM, returning what DO read after each one
+        fn eeprom_bits(mbc: &mut Box<dyn Mbc>, bits: &[Byte]) -> Vec<Byte> {
+            let mut out = vec![];
+            for &bit in bits {
+                mbc.write_ram(0xA080, EEPROM_CS | bit << 1);
+                mbc.write_ram(0xA080, EEPROM_CS | EEPROM_CLK | bit << 1);
+                out.push(mbc.read_ram(0xA080) & EEPROM_DO);
+            }
+            out
+        }
+
+        fn word_bits(val: Word, n: usize) -> Vec<Byte> {
+            (0..n).rev().map(|b| (val >> b & 1) as Byte).collect()
+        }
+
+        #[test]
+        fn test_mbc7() {
+            let mut mbc = mbc_for(&banked_cart(0x22, 0x05, 64)); // MBC7+SENSOR+RUMBLE+RAM+BATTERY
+            mbc.write_rom(0x2000, 0x21);
+            assert_eq!(mbc.read_rom(MEM_BANK_NN), 0x21);
+            assert_eq!(mbc.read_ram(0xA020), 0xFF); // locked until both enables are written
+            mbc.write_rom(0x0000, 0x0A);
+            mbc.write_rom(0x4000, 0x40);
+
+            // the sensor only shows up after an erase + latch
+            mbc.set_tilt(1.0, -0.5);
+            assert_eq!(mbc.read_ram(0xA030), 0x80);
+            mbc.write_ram(0xA010, 0xAA);
+            assert_eq!(mbc.read_ram(0xA030), 0x80);
+            mbc.write_ram(0xA000, 0x55);
+            mbc.write_ram(0xA010, 0xAA);
+            let x = combine(mbc.read_ram(0xA030), mbc.read_ram(0xA020));
+            let y = combine(mbc.read_ram(0xA050), mbc.read_ram(0xA040));
+            assert_eq!((x, y), (0x81D0 - 0x70, 0x81D0 + 0x38));
+
+            // EWEN, then WRITE word 3, then READ it back
+            eeprom_bits(&mut mbc, &[1, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0]);
+            let mut write = vec![1, 0, 1];
+            write.extend(word_bits(3, 8));
+            write.extend(word_bits(0xBEEF, 16));
+            let done = eeprom_bits(&mut mbc, &write);
+            assert_eq!(done.last(), Some(&1)); // ready
+            assert_eq!(mbc.ram()[6..8], [0xEF, 0xBE]);
+
+            mbc.write_ram(0xA080, 0x00); // CS low ends the command
+            let mut read = vec![1, 1, 0];
+            read.extend(word_bits(3, 8));
+            eeprom_bits(&mut mbc, &read);
+            let word = eeprom_bits(&mut mbc, &[0; 16]);
+            assert_eq!(word, word_bits(0xBEEF, 16));
+        }
+    }
+}
+
+pub mod types {
+    pub type Byte = u8;
+    pub type Word = u16;
+    pub type SByte = i8;
+    pub type SWord = i16;
+
+    #[derive(PartialEq, Debug)]
+    pub struct Instruction {
+        pub mnm: String,
+        pub len: u8, // bytes to read
+    }
+    #[derive(PartialEq, Debug)]
+    pub struct InstructionCB {
+        pub opcode: &'static str,
+        pub bit: u8,
+        pub reg: usize,
+    }
+    impl Instruction {
+        pub fn new(text: &str, len: u8) -> Self {
+            Self {
+                mnm: String::from(text),
+                len,
+            }
+        }
+
+        pub fn from_cb(icb: &InstructionCB) -> Self {
+            Self {
+                mnm: if icb.bit < 0xff {
+                    format!("{} {}, {}", icb.opcode, icb.bit, crate::decode::R[icb.reg])
+                } else {
+                    format!("{}, {}", icb.opcode, crate::decode::R[icb.reg])
                 },
-                1 => match q(op) {
-                    0 => Instruction {
-                        mnm: format!("LD {_RP_p}, nn"),
-                        len: 3,
-                    },
-                    1 => Instruction {
-                        mnm: format!("ADD HL, {_RP_p}"),
-                        len: 1,
-                    },
-                    _ => Instruction::new(INVALID, 0),
-                },
-                2 => match q(op) {
-                    0 => match p(op) {
-                        0 => Instruction::new("LD (BC), A", 1),
-                        1 => Instruction::new("LD (DE), A", 1),
-                        2 => Instruction::new("LD (HL+), A", 1),
-                        3 => Instruction::new("LD (HL-), A", 1),
-                        _ => Instruction::new(INVALID, 0),
-                    },
-                    1 => match p(op) {
-                        0 => Instruction::new("LD A, (BC)", 1),
-                        1 => Instruction::new("LD A, (DE)", 1),
-                        2 => Instruction::new("LD A, (HL+)", 1),
-                        3 => Instruction::new("LD A, (HL-)", 1),
-                        _ => Instruction::new(INVALID, 0),
-                    },
-                    _ => Instruction::new(INVALID, 0),
-                },
-                3 => match q(op) {
-                    0 => Instruction {
-                        mnm: format!("INC {_RP_p}"),
-                        len: 1,
-                    },
-                    1 => Instruction {
-                        mnm: format!("DEC, {_RP_p}"),
-                        len: 1,
-                    },
-                    _ => Instruction::new(INVALID, 0),
-                },
-                4 => Instruction {
-                    mnm: format!("INC {_R_y}"),
-                    len: 1,
-                },
-                5 => Instruction {
-                    mnm: format!("DEC {_R_y}"),
-                    len: 1,
-                },
-                6 => Instruction {
-                    mnm: format!("LD {_R_y}, n"),
-                    len: 2,
-                },
-                7 => match y(op) {
-                    0 => Instruction::new("RLCA", 1),
-                    1 => Instruction::new("RRCA", 1),
-                    2 => Instruction::new("RLA", 1),
-                    3 => Instruction::new("RRA", 1),
-                    4 => Instruction::new("DAA", 1),
-                    5 => Instruction::new("CPL", 1),
-                    6 => Instruction::new("SCF", 1),
-                    7 => Instruction::new("CCF", 1),
-                    _ => Instruction::new(INVALID, 0),
-                },
-                _ => Instruction::new(INVALID, 0),
-            },
-            1 => match z(op) {
-                6 => match y(op) {
-                    6 => Instruction::new("HALT", 1),
-                    _ => Instruction::new(INVALID, 0),
-                },
-                _ => Instruction {
-                    mnm: format!("LD {_R_y}, {_R_z}"),
-                    len: 1,
-                },
-            },
-            2 => Instruction {
-                mnm: format!("{_ALU_y} {_R_z}"),
                 len: 1,
-            },
-            3 => match z(op) {
-                0 => match y(op) {
-                    0..=3 => Instruction {
-                        mnm: format!("RET {_CC_y}"),
-                        len: 1,
-                    },
-                    4 => Instruction::new("LD (0xFF00 + n), A", 2),
-                    5 => Instruction::new("ADD SP, n", 2),
-                    6 => Instruction::new("LD A, (0xFF00 + n)", 2),
-                    7 => Instruction::new("LD HL, SP + n", 2),
-                    _ => Instruction::new(INVALID, 0),
-                },
-                1 => match q(op) {
-                    0 => Instruction {
-                        mnm: format!("POP {_RP2_p}"),
-                        len: 1,
-                    },
-                    1 => match p(op) {
-                        0 => Instruction::new("RET", 1),
-                        1 => Instruction::new("RETI", 1),
-                        2 => Instruction::new("JP HL", 1),
-                        3 => Instruction::new("LD SP, HL", 1),
-                        _ => Instruction::new(INVALID, 0),
-                    },
-                    _ => Instruction::new(INVALID, 0),
-                },
-                2 => match y(op) {
-                    0..=3 => Instruction {
-                        mnm: format!("JP {_CC_y}, nn"),
-                        len: 3,
-                    },
-                    4 => Instruction::new("LD (0xFF00 + C), A", 1),
-                    5 => Instruction::new("LD (nn), A", 3),
-                    6 => Instruction::new("LD A, (0xFF00 + C)", 1),
-                    7 => Instruction::new("LD A, (nn)", 3),
-                    _ => Instruction::new(INVALID, 0),
-                },
-                3 => match y(op) {
-                    0 => Instruction::new("JP nn", 3),
-                    1 => Instruction::new(CBPREFIX, 1),
-                    6 => Instruction::new("DI", 1),
-                    7 => Instruction::new("EI", 1),
-                    _ => Instruction::new(INVALID, 0),
-                },
-                4 => match y(op) {
-                    0..=3 => Instruction {
-                        mnm: format!("CALL {_CC_y}, nn"),
-                        len: 3,
-                    },
-                    _ => Instruction::new(INVALID, 0),
-                },
-                5 => match q(op) {
-                    0 => Instruction {
-                        mnm: format!("PUSH {_RP2_p}"),
-                        len: 1,
-                    },
-                    1 => match p(op) {
-                        0 => Instruction::new("CALL nn", 3),
-                        _ => Instruction::new(INVALID, 0),
-                    },
-                    _ => Instruction::new(INVALID, 0),
-                },
-                6 => Instruction {
-                    mnm: format!("{_ALU_y} n"),
-                    len: 2,
-                },
-                7 => Instruction {
-                    mnm: format!("RST {_y8:02x}H"),
-                    len: 1,
-                },
-                _ => todo!(),
-            },
-            _ => todo!(),
+            }
+        }
+
+        pub fn valid(&self) -> bool {
+            self.len > 0
+        }
+
+        pub fn prefix(&self) -> bool {
+            self.mnm == crate::decode::CBPREFIX
+        }
+
+        pub fn mnm_args(&self, rom: &[Byte]) -> String {
+            match rom.len() {
+                1 => self.mnm.replace('n', &format!("${:02x}", rom[0])),
+                2 => self.mnm.replace(
+                    "nn",
+                    &format!("${:04x}", crate::bits::combine(rom[1], rom[0])),
+                ),
+                _ => panic!("mnemonic only intended for instructions with args"),
+            }
+        }
+    }
+
+    /// A span of emulated time in T-cycles, the 4194304Hz clock everything runs off
+    ///
+    /// Instruction timings are often given in M-cycles (the 1048576Hz memory clock, one bus
+    /// access each), Cycles::m takes those so the two never get mixed up as plain numbers.
+    /// CGB double speed would run the cpu at twice as many T-cycles per second while the ppu
+    /// and apu stay at single speed, so they'd be handed half of what the cpu took.
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub struct Cycles(u64);
+
+    impl Cycles {
+        pub const ZERO: Cycles = Cycles(0);
+
+        pub const fn t(t: u64) -> Cycles {
+            Cycles(t)
+        }
+
+        pub const fn m(m: u64) -> Cycles {
+            Cycles(m * 4)
+        }
+
+        pub const fn as_t(self) -> u64 {
+            self.0
+        }
+
+        /// Rounded down, a T-cycle count not on an M-cycle boundary loses the remainder
+        pub const fn as_m(self) -> u64 {
+            self.0 / 4
+        }
+
+        pub const fn saturating_sub(self, other: Cycles) -> Cycles {
+            Cycles(self.0.saturating_sub(other.0))
+        }
+    }
+
+    impl std::ops::Add for Cycles {
+        type Output = Cycles;
+        fn add(self, other: Cycles) -> Cycles {
+            Cycles(self.0 + other.0)
+        }
+    }
+
+    impl std::ops::Sub for Cycles {
+        type Output = Cycles;
+        fn sub(self, other: Cycles) -> Cycles {
+            Cycles(self.0 - other.0)
+        }
+    }
+
+    impl std::ops::AddAssign for Cycles {
+        fn add_assign(&mut self, other: Cycles) {
+            self.0 += other.0;
+        }
+    }
+
+    impl std::ops::SubAssign for Cycles {
+        fn sub_assign(&mut self, other: Cycles) {
+            self.0 -= other.0;
+        }
+    }
+
+    impl std::fmt::Display for Cycles {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "{} cycles", self.0)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests_types {
+        use super::*;
+
+        #[test]
+        fn test_cycles() {
+            assert_eq!(Cycles::m(3), Cycles::t(12));
+            assert_eq!(Cycles::t(14).as_m(), 3);
+            assert_eq!(Cycles::m(2) + Cycles::t(4) - Cycles::m(1), Cycles::t(8));
+            assert_eq!(Cycles::t(4).saturating_sub(Cycles::t(8)), Cycles::ZERO);
+            let mut c = Cycles::ZERO;
+            c += Cycles::m(1);
+            assert_eq!(c.to_string(), "4 cycles");
+        }
+    }
+}
+
+#[rustfmt::skip]
+pub mod lcd {
+    use crate::bits::*;
+    use crate::cpu::*;
+    use crate::dbg::{dump, StateHash, INSTRUMENT};
+    use crate::memory::*;
+    use crate::types::*;
+    use std::collections::VecDeque;
+
+    // lcdc
+    pub const LCDC_BIT_ENABLE                     :Byte = BIT_7;
+    pub const LCDC_BIT_WINDOW_TILE_MAP_SELECT     :Byte = BIT_6;
+    pub const LCDC_BIT_WINDOW_ENABLE              :Byte = BIT_5;
+    pub const LCDC_BIT_BG_WINDOW_TILE_DATA_SELECT :Byte = BIT_4;
+    pub const LCDC_BIT_BG_TILE_MAP_SELECT         :Byte = BIT_3;
+    pub const LCDC_BIT_OBJ_SIZE                   :Byte = BIT_2;
+    pub const LCDC_BIT_OBJ_ENABLE                 :Byte = BIT_1;
+    pub const LCDC_BIT_BG_WINDOW_ENABLE           :Byte = BIT_0;
+
+    // lcd status
+    pub const STAT_BIT_NULL              :Byte = BIT_7;
+    pub const STAT_BIT_LYC_INT_SELECT    :Byte = BIT_6;
+    pub const STAT_BIT_MODE_2_INT_SELECT :Byte = BIT_5;
+    pub const STAT_BIT_MODE_1_INT_SELECT :Byte = BIT_4;
+    pub const STAT_BIT_MODE_0_INT_SELECT :Byte = BIT_3;
+    pub const STAT_BIT_LY_LYC_EQ         :Byte = BIT_2;
+    pub const STAT_MASK_PPU_MODE         :Byte = 0b011;
+
+    // object attribute flags
+    pub const OAM_BIT_PRIORITY           :Byte = BIT_7;
+    pub const OAM_BIT_FLIP_Y             :Byte = BIT_6;
+    pub const OAM_BIT_FLIP_X             :Byte = BIT_5;
+    pub const OAM_BIT_DMG_PAL            :Byte = BIT_4; // dmg only
+    pub const OAM_BIT_BANK               :Byte = BIT_3;
+    pub const OAM_MASK_CGB_PAL           :Byte = 0b111; // color gameboy only
+    pub const OBJ_ATTR_SIZE              :Word = 4;
+
+    // object priority mode (cgb only, dmg always goes by x)
+    pub const OPRI_BIT_X                 :Byte = BIT_0; // by x then OAM order like dmg, else just OAM order
+
+    // background attribute flags, in VRAM bank 1 behind each tile map entry (color gameboy only)
+    pub const BG_ATTR_PRIORITY           :Byte = BIT_7;
+    pub const BG_ATTR_FLIP_Y             :Byte = BIT_6;
+    pub const BG_ATTR_FLIP_X             :Byte = BIT_5;
+    pub const BG_ATTR_BANK               :Byte = BIT_3;
+    pub const BG_ATTR_MASK_PAL           :Byte = 0b111;
+
+    // other constants
+    pub const PPU_TILE_WIDTH             :usize = 8;
+
+    /// Colors for the background and both object palettes
+    ///
+    /// In toml either the three color lists, or the name of a built in set (see named())
+    #[derive(Copy, Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+    #[serde(try_from = "PaletteSetToml")]
+    pub struct PaletteSet {
+        pub bg: [u32; 4],
+        pub obj0: [u32; 4],
+        pub obj1: [u32; 4],
+    }
+    pub const PALETTES_DEFAULT: PaletteSet = PaletteSet { bg: PAL_CLASSIC, obj0: PAL_CLASSIC, obj1: PAL_CLASSIC };
+
+    // color-blind safe sets: hues only differ along blue/yellow-orange (which both red-green
+    // deficiencies still see), and each palette's shades are spread out in brightness so they
+    // stay readable with no color at all
+    const PAL_DEUT_BG   :[u32; 4] = [0xFFFFFF, 0xFFC857, 0x3C6FC0, 0x0B1A33];
+    const PAL_DEUT_OBJ0 :[u32; 4] = [0xFFFFFF, 0xFE9B5A, 0x8C3C96, 0x000000];
+    const PAL_DEUT_OBJ1 :[u32; 4] = [0xFFFFFF, 0x9AD7FF, 0x1F5FA0, 0x000000];
+    // protans see reds as dark, so nothing leans on red for brightness
+    const PAL_PROT_BG   :[u32; 4] = [0xFFFFFF, 0xF0E442, 0x0072B2, 0x101010];
+    const PAL_PROT_OBJ0 :[u32; 4] = [0xFFFFFF, 0x56B4E9, 0x5A3FA0, 0x000000];
+    const PAL_PROT_OBJ1 :[u32; 4] = [0xFFFFFF, 0xE6B800, 0x6E5A00, 0x000000];
+    pub const PALETTES_DEUTERANOPIA: PaletteSet = PaletteSet { bg: PAL_DEUT_BG, obj0: PAL_DEUT_OBJ0, obj1: PAL_DEUT_OBJ1 };
+    pub const PALETTES_PROTANOPIA: PaletteSet = PaletteSet { bg: PAL_PROT_BG, obj0: PAL_PROT_OBJ0, obj1: PAL_PROT_OBJ1 };
+
+    // where high_contrast() puts each shade's brightness: as far apart as they can be
+    const HIGH_CONTRAST_LUMA: [f32; 4] = [255.0, 170.0, 85.0, 0.0];
+
+    /// Perceived brightness (rec. 601), 0-255
+    pub fn luma(color: u32) -> f32 {
+        0.299 * (color >> 16 & 0xFF) as f32 + 0.587 * (color >> 8 & 0xFF) as f32 + 0.114 * (color & 0xFF) as f32
+    }
+
+    /// Mixes a color with white or black until its brightness hits `target`, keeping its hue
+    fn with_luma(color: u32, target: f32) -> u32 {
+        let l = luma(color);
+        let (toward, amount) = if target >= l {
+            (255.0, if l < 255.0 { (target - l) / (255.0 - l) } else { 0.0 })
+        } else {
+            (0.0, (l - target) / l)
+        };
+        let channel = |shift: u32| {
+            let c = (color >> shift & 0xFF) as f32;
+            ((c + (toward - c) * amount).round() as u32) << shift
+        };
+        channel(16) | channel(8) | channel(0)
+    }
+
+    impl PaletteSet {
+        /// The built in sets, by the names used in settings.toml and game configs
+        pub fn named(name: &str) -> Option<PaletteSet> {
+            match name {
+                "default"      => Some(PALETTES_DEFAULT),
+                "cgb"          => Some(PALETTES_CGB_COMPAT_DEFAULT),
+                "deuteranopia" => Some(PALETTES_DEUTERANOPIA),
+                "protanopia"   => Some(PALETTES_PROTANOPIA),
+                _ => None,
+            }
+        }
+
+        /// Evens out the brightness steps between shades (lightest white, darkest black) while
+        /// keeping each shade's hue, so shades are as far apart as they can get
+        pub fn high_contrast(&self) -> PaletteSet {
+            let spread = |pal: [u32; 4]| {
+                let mut out = pal;
+                for (color, target) in out.iter_mut().zip(HIGH_CONTRAST_LUMA) {
+                    *color = with_luma(*color, target);
+                }
+                out
+            };
+            PaletteSet { bg: spread(self.bg), obj0: spread(self.obj0), obj1: spread(self.obj1) }
+        }
+    }
+
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum PaletteSetToml {
+        Named(String),
+        Colors { bg: [u32; 4], obj0: [u32; 4], obj1: [u32; 4] },
+    }
+
+    impl std::convert::TryFrom<PaletteSetToml> for PaletteSet {
+        type Error = String;
+        fn try_from(toml: PaletteSetToml) -> Result<Self, Self::Error> {
+            match toml {
+                PaletteSetToml::Named(name) => PaletteSet::named(&name).ok_or_else(|| {
+                    format!("unknown palettes \"{}\" (default, cgb, deuteranopia, protanopia)", name)
+                }),
+                PaletteSetToml::Colors { bg, obj0, obj1 } => Ok(PaletteSet { bg, obj0, obj1 }),
+            }
+        }
+    }
+
+    // cgb boot rom colorization for dmg carts
+    // https://gbdev.io/pandocs/Power_Up_Sequence.html#compatibility-palettes
+    const CGB_WHITE_RED    :[u32; 4] = [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000];
+    const CGB_DARK_GREEN   :[u32; 4] = [0xFFFFFF, 0x7BFF31, 0x0063C5, 0x000000];
+    pub const PALETTES_CGB_COMPAT_DEFAULT: PaletteSet = PaletteSet { bg: CGB_DARK_GREEN, obj0: CGB_WHITE_RED, obj1: CGB_WHITE_RED };
+
+    // the boot rom's 30 palettes, 4 colors each
+    const CGB_COMPAT_COLORS: [u32; 120] = [
+        0xFFFFFF, 0xFFAD63, 0x843100, 0x000000, 0xFFE6C5, 0xCE9C84, 0x846B29, 0x5A3108, // 0, 1
+        0xFFFFFF, 0x8C8CDE, 0x52528C, 0x000000, 0xFFFFFF, 0x7BFF31, 0x008400, 0x000000, // 2, 3
+        0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000, 0xFFFFFF, 0xA5A5A5, 0x525252, 0x000000, // 4, 5
+        0xFFFFFF, 0xFFFF00, 0x7B4A00, 0x000000, 0xFFFFFF, 0x7BFF00, 0xB57300, 0x000000, // 6, 7
+        0xFFFFFF, 0xADAD84, 0x42737B, 0x000000, 0xA59CFF, 0xFFFF00, 0x006300, 0x000000, // 8, 9
+        0xFFFFCE, 0x63EFEF, 0x9C8431, 0x5A5A5A, 0xB5B5FF, 0xFFFF94, 0xAD5A42, 0x000000, // 10, 11
+        0xFFFFA5, 0xFF9494, 0x9494FF, 0x000000, 0xFFFF9C, 0x94B5FF, 0x639473, 0x003A3A, // 12, 13
+        0x6BFF00, 0xFFFFFF, 0xFF524A, 0x000000, 0x52DE00, 0xFF8400, 0xFFFF00, 0xFFFFFF, // 14, 15
+        0xFFFFFF, 0xFF7300, 0x944200, 0x000000, 0xFFC542, 0xFFD600, 0x943A00, 0x4A0000, // 16, 17
+        0xFFFFFF, 0x52FF00, 0xFF4200, 0x000000, 0xFF6352, 0xD60000, 0x630000, 0x000000, // 18, 19
+        0xFFFFFF, 0xFF9C00, 0xFF0000, 0x000000, 0xFFFFFF, 0x00FF00, 0x318400, 0x004A00, // 20, 21
+        0xFFFFFF, 0x5ABDFF, 0xFF0000, 0x0000FF, 0xFFFFFF, 0xFFFF7B, 0x0084FF, 0xFF0000, // 22, 23
+        0xFFFFFF, 0xFFFF00, 0xFF0000, 0x000000, 0xFFFF00, 0xFF0000, 0x630000, 0x000000, // 24, 25
+        0xFFFFFF, 0xFFCE00, 0x9C6300, 0x000000, 0x000000, 0x008484, 0xFFDE00, 0xFFFFFF, // 26, 27
+        0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000, 0xFFFFFF, 0x7BFF31, 0x0063C5, 0x000000, // 28, 29
+    ];
+
+    // (obj0, obj1, bg) as where they start in CGB_COMPAT_COLORS. A few start partway into
+    // a palette, the boot rom's table does the same
+    const CGB_COMPAT_COMBOS: [(usize, usize, usize); 51] = [
+        ( 16,  16, 116), ( 72,  72,  72), ( 80,  80,  80), ( 96,  96,  96), ( 36,  36,  36), (  0,   0,   0),
+        (108, 108, 108), ( 20,  20,  20), ( 48,  48,  48), (104, 104, 104), ( 64,  32,  32), ( 16, 112, 112),
+        ( 16,   8,   8), ( 12,  16,  16), ( 16, 116, 116), (112,  16, 112), (  8,  68,   8), ( 64,  64,  32),
+        ( 16,  16,  28), ( 16,  16,  72), ( 16,  16,  80), ( 76,  76,  36), ( 15,  15,  44), ( 68,  68,   8),
+        ( 16,  16,   8), ( 16,  16,  12), (112, 112,   0), ( 12,  12,   0), (  0,   0,   4), ( 72,  88,  72),
+        ( 80,  88,  80), ( 96,  88,  96), ( 64,  88,  32), ( 68,  16,  52), (111,   0,  56), (111,  16,  60),
+        ( 76,  88,  36), ( 64, 112,  40), ( 16,  92, 112), ( 68,  88,   8), ( 16,   0,   8), ( 16, 112,  12),
+        (112,  12,   0), ( 12, 112,  16), ( 84, 112,  16), ( 12, 112,   0), (100,  12, 112), (  0, 112,  32),
+        ( 16,  12, 112), (112,  12,  24), ( 16, 112, 116),
+    ];
+
+    // (title checksum, 4th title letter or 0 if the checksum is unambiguous, CGB_COMPAT_COMBOS index)
+    const CGB_COMPAT_TABLE: [(Byte, Byte, Byte); 93] = [
+        (0x88, 0,     4), (0x16, 0,     5), (0x36, 0,    35), (0xD1, 0,    34), (0xDB, 0,     3),
+        (0xF2, 0,    31), (0x3C, 0,    15), (0x8C, 0,    10), (0x92, 0,     5), (0x3D, 0,    19),
+        (0x5C, 0,    36), (0x58, 0,     7), (0xC9, 0,    37), (0x3E, 0,    30), (0x70, 0,    44),
+        (0x1D, 0,    21), (0x59, 0,    32), (0x69, 0,    31), (0x19, 0,    20), (0x35, 0,     5),
+        (0xA8, 0,    33), (0x14, 0,    13), (0xAA, 0,    14), (0x75, 0,     5), (0x95, 0,    29),
+        (0x99, 0,     5), (0x34, 0,    18), (0x6F, 0,     9), (0x15, 0,     3), (0xFF, 0,     2),
+        (0x97, 0,    26), (0x4B, 0,    25), (0x90, 0,    25), (0x17, 0,    41), (0x10, 0,    42),
+        (0x39, 0,    26), (0xF7, 0,    45), (0xF6, 0,    42), (0xA2, 0,    45), (0x49, 0,    36),
+        (0x4E, 0,    38), (0x43, 0,    26), (0x68, 0,    42), (0xE0, 0,    30), (0x8B, 0,    41),
+        (0xF0, 0,    34), (0xCE, 0,    34), (0x0C, 0,     5), (0x29, 0,    42), (0xE8, 0,     6),
+        (0xB7, 0,     5), (0x86, 0,    33), (0x9A, 0,    25), (0x52, 0,    42), (0x01, 0,    42),
+        (0x9D, 0,    40), (0x71, 0,    14), (0x9C, 0,    16), (0xBD, 0,    25), (0x5D, 0,    42),
+        (0x6D, 0,    42), (0x67, 0,     5), (0x3F, 0,     0), (0x6B, 0,    39), (0xB3, b'B', 36),
+        (0x46, b'E', 22), (0x28, b'F', 25), (0xA5, b'A',  6), (0xC6, b'A', 32), (0xD3, b'R', 12),
+        (0x27, b'B', 36), (0x61, b'E', 11), (0x18, b'K', 39), (0x66, b'E', 18), (0x6A, b'K', 39),
+        (0xBF, b' ', 24), (0x0D, b'R', 31), (0xF4, b'-', 50), (0xB3, b'U', 17), (0x46, b'R', 46),
+        (0x28, b'A',  6), (0xA5, b'R', 27), (0xC6, b' ',  0), (0xD3, b'I', 47), (0x27, b'N', 41),
+        (0x61, b'A', 41), (0x18, b'I',  0), (0x66, b'L',  0), (0x6A, b'I', 19), (0xBF, b'C', 34),
+        (0x0D, b'E', 23), (0xF4, b' ', 18), (0xB3, b'R', 29),
+    ];
+
+    /// The palettes a CGB would pick for a dmg cart on boot
+    ///
+    /// Only Nintendo-licensed carts are looked up (by the sum of their title bytes, with the
+    /// 4th letter breaking ties), everyone else gets the default.
+    pub fn cgb_compat_palettes(cart: &Cartridge) -> PaletteSet {
+        let nintendo = cart[ROM_OLD_LICENSEE] == 0x01
+            || (cart[ROM_OLD_LICENSEE] == 0x33 && cart[ROM_NEW_LICENSEE] == b'0' && cart[ROM_NEW_LICENSEE + 1] == b'1');
+        if !nintendo {
+            return PALETTES_CGB_COMPAT_DEFAULT;
+        }
+        let checksum = cart.title_bytes().iter().fold(0 as Byte, |acc, b| acc.wrapping_add(*b));
+        let letter = cart[ROM_TITLE + 3];
+        for (sum, fourth, combo) in CGB_COMPAT_TABLE {
+            if sum == checksum && (fourth == 0 || fourth == letter) {
+                let (obj0, obj1, bg) = CGB_COMPAT_COMBOS[combo as usize];
+                let c = &CGB_COMPAT_COLORS;
+                let palette = |start: usize| [c[start], c[start + 1], c[start + 2], c[start + 3]];
+                return PaletteSet { bg: palette(bg), obj0: palette(obj0), obj1: palette(obj1) };
+            }
+        }
+        PALETTES_CGB_COMPAT_DEFAULT
+    }
+    
+    #[derive(Clone)]
+    pub struct Sprite {
+        idx: Word
+    }
+    impl Sprite {
+        fn y(&self, mem: &Memory) -> Byte {
+            mem[MEM_OAM + self.idx * OBJ_ATTR_SIZE + 0]
+        }
+        fn x(&self, mem: &Memory) -> Byte {
+            mem[MEM_OAM + self.idx * OBJ_ATTR_SIZE + 1]
+        }
+        fn tile(&self, mem: &Memory) -> Byte {
+            if mem[LCDC] & LCDC_BIT_OBJ_SIZE != 0 {
+                mem[MEM_OAM + self.idx * OBJ_ATTR_SIZE + 2] & 0xFE // masked, ignore least sig. bit (hardware-enforced)
+            } else {
+                mem[MEM_OAM + self.idx * OBJ_ATTR_SIZE + 2]
+            }
+        }
+        fn flags(&self, mem: &Memory) -> Byte {
+            mem[MEM_OAM + self.idx * OBJ_ATTR_SIZE + 3]
+        }
+        /// The row of the sprite on this line. Only y counts, a sprite off the side of the
+        /// screen (x 0 or 168+) still takes one of the line's 10 slots
+        fn hit(&self, mem: &Memory) -> Byte {
+            let scanline = mem[LY] + 16;
+            let height = if mem[LCDC] & LCDC_BIT_OBJ_SIZE != 0 { 16 } else { 8 };
+            if scanline >= self.y(mem) && (scanline as Word) < self.y(mem) as Word + height as Word {
+                // flipping a tall sprite swaps its tiles too, the row runs over both
+                let yy = self.y(mem);
+                if self.flags(mem) & OAM_BIT_FLIP_Y != 0 {
+                    (height - 1) - (scanline - yy)
+                } 
+                else 
+                {
+                    scanline - yy
+                }
+            } else {
+                SPRITE_NOT_HIT
+            }
+        }
+    }
+
+    // LY on the last line of vblank, for the first TICKS_LY_LAST of it
+    // https://gbdev.io/pandocs/STAT.html#ff44--ly-lcd-y-coordinate-read-only
+    const LY_LAST: Byte = 153;
+    const TICKS_LY_LAST: u64 = 4;
+
+    const SPRITE_NOT_HIT: Byte = 0xFF;
+    const SPRITES_PER_LINE: usize = 10;
+    #[derive(Clone)]
+    pub struct SpriteHit {
+        sprite: Sprite,
+        line: Byte
+    }
+
+    /// The background half of the ppu's pixel pipeline
+    ///
+    /// The fetcher reads a tile's row out of vram over 6 dots (tile number, low bitplane, high
+    /// bitplane) and pushes its 8 pixels once the fifo has run dry. A pixel is shifted out to
+    /// the screen every dot there's one to shift. LCDC, SCX and SCY are read as each tile is
+    /// fetched, so games changing them mid-line get the split they expect. In cgb mode the
+    /// tile's attributes come along with its number, and say which bank its data is in and
+    /// how it's flipped.
+    // https://gbdev.io/pandocs/pixel_fifo.html
+    #[derive(Clone)]
+    struct PixelFifo {
+        pixels: VecDeque<(Byte, Byte)>, // bg color indices (before BGP) and BG_ATTR_* flags
+        dots: u64,              // mode 3 dots clocked so far on this line
+        x: usize,               // the next pixel on the line to draw
+        discard: Byte,          // fine scroll, pixels still to drop at the start of the line
+        fetch_x: Byte,          // tile column being fetched, counted from the left edge of the screen
+        fetch_dot: u64,         // dots into the current fetch
+        tile_row: Word,         // address of the fetched tile's row
+        attrs: Byte,            // BG_ATTR_* of the fetched tile, 0 outside cgb mode
+        low: Byte,
+        high: Byte,
+    }
+
+    impl PixelFifo {
+        fn new() -> PixelFifo {
+            PixelFifo {
+                pixels: VecDeque::with_capacity(8),
+                dots: 0,
+                x: 0,
+                discard: 0,
+                fetch_x: 0,
+                fetch_dot: 0,
+                tile_row: 0,
+                attrs: 0,
+                low: 0,
+                high: 0,
+            }
+        }
+
+        /// Empties the pipeline at the start of mode 3
+        fn start_line(&mut self, mem: &Memory) {
+            self.pixels.clear();
+            self.dots = 0;
+            self.x = 0;
+            // the low bits of SCX are only looked at once per line
+            self.discard = mem[SCX] % 8;
+            self.fetch_x = 0;
+            self.fetch_dot = 0;
+        }
+
+        fn fetch(&mut self, mem: &Memory) {
+            match self.fetch_dot {
+                // tile number
+                1 => {
+                    let map: Word = if mem[LCDC] & LCDC_BIT_BG_TILE_MAP_SELECT != 0 { 0x9C00 } else { 0x9800 };
+                    let y = mem[SCY].wrapping_add(mem[LY]);
+                    let column = ((mem[SCX] / 8).wrapping_add(self.fetch_x) % 32) as Word;
+                    let id = mem[map + column + y as Word / 8 * 32];
+                    self.attrs = mem.vram1(map + column + y as Word / 8 * 32);
+                    let tile = if mem[LCDC] & LCDC_BIT_BG_WINDOW_TILE_DATA_SELECT != 0 {
+                        MEM_VRAM + id as Word * BYTES_PER_TILE
+                    } else {
+                        // in signed addressing the 0 tile is at 0x9000
+                        (MEM_VRAM + 0x1000).wrapping_add((signed(id) as Word).wrapping_mul(BYTES_PER_TILE))
+                    };
+                    let row = if self.attrs & BG_ATTR_FLIP_Y != 0 { 7 - y % 8 } else { y % 8 };
+                    self.tile_row = tile + row as Word * 2;
+                }
+                3 => self.low = self.read_tile(mem, self.tile_row),
+                5 => self.high = self.read_tile(mem, self.tile_row + 1),
+                _ => {}
+            }
+            if self.fetch_dot >= 5 && self.pixels.is_empty() {
+                // leftmost pixel first
+                let line = ppu_decode_tile_line(self.low, self.high);
+                let attrs = self.attrs;
+                if attrs & BG_ATTR_FLIP_X != 0 {
+                    self.pixels.extend(line.iter().map(|&px| (px, attrs)));
+                } else {
+                    self.pixels.extend(line.iter().rev().map(|&px| (px, attrs)));
+                }
+                self.fetch_x = self.fetch_x.wrapping_add(1);
+                self.fetch_dot = 0;
+            } else {
+                self.fetch_dot += 1;
+            }
+        }
+
+        fn read_tile(&self, mem: &Memory, addr: Word) -> Byte {
+            if self.attrs & BG_ATTR_BANK != 0 {
+                mem.vram1(addr)
+            } else {
+                mem[addr]
+            }
+        }
+
+        /// The next background pixel for the screen and its attributes, if there's one ready
+        fn shift(&mut self) -> Option<(Byte, Byte)> {
+            while self.discard > 0 && !self.pixels.is_empty() {
+                self.pixels.pop_front();
+                self.discard -= 1;
+            }
+            if self.x >= GB_SCREEN_WIDTH {
+                return None;
+            }
+            self.pixels.pop_front()
+        }
+
+        fn hash_state(&self, h: &mut StateHash) {
+            h.u64(self.dots);
+            h.u64(self.fetch_dot);
+            h.bytes(&[self.fetch_x, self.discard, self.attrs, self.low, self.high]);
+            h.bytes(&self.tile_row.to_le_bytes());
+            h.bytes(&(self.x as u64).to_le_bytes());
+            for (px, attrs) in self.pixels.iter() {
+                h.bytes(&[*px, *attrs]);
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct Display {
+        pub(crate) buffer: Vec<u32>, // the frame being drawn
+        pub(crate) front: Vec<u32>,  // the last completed frame, swapped with buffer on entering vblank
+        buffer_sprites: Vec<SpriteHit>,
+        fifo: PixelFifo,
+        off: bool, // LCDC_BIT_ENABLE was last seen clear, see update_off()
+        stat_line: bool, // see update_stat_line()
+        vram_io_ticks: u64, // how long mode 3 is on this line, see scan_oam()
+        lcd_timing: u64,
+        pub palettes: PaletteSet,
+        // enhancements
+        pub flicker_reduction: bool, // see reduce_flicker()
+        pub ghosting: u32, // percent, see ghost()
+        pub(crate) sprite_history: Vec<Byte>, // per pixel, bit n set if a sprite covered it n frames ago
+        pub(crate) last_raw: Vec<u32>, // the previous frame as drawn, before reduce_flicker()
+        // the shades (0-3, after BGP/OBP) of buffer and front, for the sgb to color. Empty
+        // unless keep_shades() was called
+        pub(crate) buffer_shades: Vec<Byte>,
+        pub(crate) shades: Vec<Byte>,
+        // debug
+        pub doctor: bool,
+    }
+
+    impl Display {
+        pub fn new() -> Display {
+            Display {
+                buffer: vec![0; GB_SCREEN_WIDTH * GB_SCREEN_HEIGHT],
+                front: vec![0; GB_SCREEN_WIDTH * GB_SCREEN_HEIGHT],
+                buffer_sprites: vec![],
+                fifo: PixelFifo::new(),
+                off: false,
+                stat_line: false,
+                vram_io_ticks: TICKS_PER_VRAM_IO,
+                lcd_timing: 0,
+                palettes: PALETTES_DEFAULT,
+                flicker_reduction: false,
+                ghosting: 0,
+                sprite_history: vec![0; GB_SCREEN_WIDTH * GB_SCREEN_HEIGHT],
+                last_raw: vec![0; GB_SCREEN_WIDTH * GB_SCREEN_HEIGHT],
+                buffer_shades: vec![],
+                shades: vec![],
+                doctor: false,
+            }
+        }
+
+        /// The most recently completed frame, GB_SCREEN_WIDTH * GB_SCREEN_HEIGHT pixels
+        ///
+        /// Lines are drawn into a separate buffer, this never shows a half drawn frame.
+        pub fn frame(&self) -> &[u32] {
+            &self.front
+        }
+
+        /// Trades the completed frame for another buffer of the same size without copying,
+        /// e.g. to hand it over to another thread. The buffer given back becomes frame()
+        /// until the next one is done.
+        pub fn swap_frame(&mut self, frame: &mut Vec<u32>) {
+            assert_eq!(frame.len(), self.front.len());
+            std::mem::swap(&mut self.front, frame);
+        }
+
+        /// Has the ppu note each pixel's shade as well as its color, see shades()
+        pub fn keep_shades(&mut self) {
+            self.buffer_shades = vec![0; GB_SCREEN_WIDTH * GB_SCREEN_HEIGHT];
+            self.shades = vec![0; GB_SCREEN_WIDTH * GB_SCREEN_HEIGHT];
+        }
+
+        /// The shade (0 white to 3 black) of each pixel of frame(), what the Super Game Boy
+        /// gets from the ppu instead of colors. Empty unless keep_shades() was called
+        pub fn shades(&self) -> &[Byte] {
+            &self.shades
+        }
+
+        /// Blends pixels where sprites come and go every other frame with the frame before
+        ///
+        /// Games with more sprites than the hardware can show on a line take turns drawing
+        /// them, which flickers (badly on a fast LCD). A pixel a sprite covered on alternate
+        /// frames for the last 4 gets the average of this frame and the last one, so the
+        /// sprites show steadily at half strength. Sprites moving or animating normally never
+        /// alternate like that and are left alone, unlike blending every frame.
+        fn reduce_flicker(&mut self) {
+            for (i, px) in self.buffer.iter_mut().enumerate() {
+                let history = self.sprite_history[i] & 0x0F;
+                let raw = *px;
+                if history == 0b0101 || history == 0b1010 {
+                    *px = ((raw & 0xFEFEFE) >> 1) + ((self.last_raw[i] & 0xFEFEFE) >> 1);
+                }
+                self.last_raw[i] = raw;
+                self.sprite_history[i] = history << 1;
+            }
+        }
+
+        /// Blends the frame before into this one, as a slow LCD's pixels only get part of the
+        /// way to their new shade each frame
+        ///
+        /// ghosting is how much of the last frame shown is kept, so a change fades in over a
+        /// few frames, and something drawn every other frame (which games use for
+        /// transparency) shows steadily at part strength. In sgb mode the sgb's coloring
+        /// comes after and replaces it.
+        fn ghost(&mut self) {
+            let keep = self.ghosting.min(100) as i32;
+            // rounded towards the new shade, so it always gets there in the end
+            let mix = |new: u32, old: u32| {
+                let delta = new as i32 - old as i32;
+                (old as i32 + (delta.abs() * (100 - keep) + 99) / 100 * delta.signum()) as u32
+            };
+            for (px, &old) in self.buffer.iter_mut().zip(self.front.iter()) {
+                let new = *px;
+                *px = [16, 8, 0]
+                    .iter()
+                    .fold(0, |c, &shift| c | mix((new >> shift) & 0xFF, (old >> shift) & 0xFF) << shift);
+            }
+        }
+
+        /// ppu timing, the sprites found for the current line and the pixel pipeline, the frames
+        /// are just output
+        pub fn hash_state(&self, h: &mut StateHash) {
+            h.u64(self.lcd_timing);
+            h.bytes(&[self.off as Byte, self.stat_line as Byte]);
+            h.u64(self.vram_io_ticks);
+            for hit in self.buffer_sprites.iter() {
+                h.bytes(&hit.sprite.idx.to_le_bytes());
+                h.bytes(&[hit.line]);
+            }
+            self.fifo.hash_state(h);
+        }
+
+        /// The scanline the ppu is currently on, whatever the cpu is shown (see Memory::read)
+        pub fn ly(&self, mem: &Memory) -> Byte {
+            mem[LY]
+        }
+
+        /// Cycles until the ppu next changes mode (or LY, during vblank, or the next blank frame
+        /// with the lcd off)
+        pub fn cycles_until_event(&self, mem: &Memory) -> Cycles {
+            if self.off {
+                return Cycles::t(TICKS_PER_FRAME.saturating_sub(self.lcd_timing));
+            }
+            let end = match lcd_mode(mem) {
+                2 => TICKS_PER_OAM_SEARCH,
+                3 => self.vram_io_ticks,
+                0 => self.hblank_ticks(),
+                _ => {
+                    let line_start = self.lcd_timing / TICKS_PER_SCANLINE * TICKS_PER_SCANLINE;
+                    if mem[LY] == LY_LAST {
+                        line_start + TICKS_LY_LAST
+                    } else {
+                        line_start + TICKS_PER_SCANLINE
+                    }
+                }
+            };
+            Cycles::t(end.saturating_sub(self.lcd_timing))
+        }
+
+        /// Position within the current scanline, 0..TICKS_PER_SCANLINE
+        #[cfg(any(test, feature = "instrument"))]
+        pub fn dot(&self, mem: &Memory) -> u64 {
+            match lcd_mode(mem) {
+                2 => self.lcd_timing,
+                3 => TICKS_PER_OAM_SEARCH + self.lcd_timing,
+                0 => TICKS_PER_OAM_SEARCH + self.vram_io_ticks + self.lcd_timing,
+                _ => self.lcd_timing % TICKS_PER_SCANLINE,
+            }
+        }
+
+        /// Jumps the ppu straight to a line and dot, for tests
+        ///
+        /// The mode (and the internal timing within it) follows from where that puts us. No
+        /// interrupts are raised, LY=LYC is checked on the next update as usual.
+        #[cfg(any(test, feature = "instrument"))]
+        pub fn set_position(&mut self, mem: &mut Memory, ly: Byte, dot: u64) {
+            assert!((ly as u64) < 154 && dot < TICKS_PER_SCANLINE, "invalid ppu position {}:{}", ly, dot);
+            mem[LY] = ly;
+            // the line's sprites, and so how long its mode 3 is
+            self.scan_oam(mem);
+            let (mode, timing) = if ly as usize >= GB_SCREEN_HEIGHT {
+                (1, (ly as u64 - GB_SCREEN_HEIGHT as u64) * TICKS_PER_SCANLINE + dot)
+            } else if dot < TICKS_PER_OAM_SEARCH {
+                (2, dot)
+            } else if dot < TICKS_PER_OAM_SEARCH + self.vram_io_ticks {
+                (3, dot - TICKS_PER_OAM_SEARCH)
+            } else {
+                (0, dot - TICKS_PER_OAM_SEARCH - self.vram_io_ticks)
+            };
+            self.lcd_timing = timing;
+            set_lcd_mode(mode, mem);
+            // a line jumped into partway is drawn from its start on the next update
+            self.fifo.start_line(mem);
+        }
+
+        /// Finds the (up to 10) sprites on this line and works out how long drawing it takes
+        fn scan_oam(&mut self, mem: &Memory) {
+            self.buffer_sprites.clear();
+            for n in 0..40 {
+                let s = Sprite { idx: n };
+                let l = s.hit(mem);
+                if self.buffer_sprites.len() < SPRITES_PER_LINE && l != SPRITE_NOT_HIT {
+                    self.buffer_sprites.push(SpriteHit{sprite: s, line: l});
+                }
+            }
+            // sprites are fetched left to right, and with dmg priority lower x is drawn on top,
+            // OAM order breaks ties (the sort is stable)
+            self.buffer_sprites.sort_by_key(|hit| hit.sprite.x(mem));
+
+            // mode 3 is held up by the fine scroll pixels thrown away at the start of the line
+            // and 6 to 11 dots per sprite fetched
+            // https://gbdev.io/pandocs/Rendering.html#mode-3-length
+            let scx = mem[SCX];
+            let mut ticks = TICKS_PER_VRAM_IO + (scx % 8) as u64;
+            let mut last_tile = None;
+            for hit in self.buffer_sprites.iter() {
+                let x = hit.sprite.x(mem);
+                if x >= GB_SCREEN_WIDTH as Byte + 8 {
+                    continue; // off the right edge, never fetched
+                }
+                // the background tile under the sprite's left edge has to be fetched first,
+                // which only costs the first sprite over each tile
+                let tile = (x as Word + scx as Word) / 8;
+                ticks += 6;
+                if x == 0 {
+                    ticks += 5;
+                } else if last_tile != Some(tile) {
+                    ticks += 5u64.saturating_sub(((x as Word + scx as Word) % 8) as u64);
+                }
+                last_tile = Some(tile);
+            }
+            self.vram_io_ticks = ticks;
+
+            if mem.cgb && mem[OPRI] & OPRI_BIT_X == 0 {
+                // cgb priority: OAM order alone
+                self.buffer_sprites.sort_by_key(|hit| hit.sprite.idx);
+            }
+        }
+
+        /// What's left of the line after mode 3
+        fn hblank_ticks(&self) -> u64 {
+            TICKS_PER_SCANLINE - TICKS_PER_OAM_SEARCH - self.vram_io_ticks
+        }
+
+        /// One dot of mode 3: the fetcher works on the next tile and a pixel is shifted out
+        fn clock_dot(&mut self, mem: &Memory) {
+            self.fifo.fetch(mem);
+            if let Some((bg, attrs)) = self.fifo.shift() {
+                self.draw_pixel(mem, bg, attrs);
+            }
+            self.fifo.dots += 1;
+        }
+
+        /// Puts the next pixel of the line on screen, bg being its background color index and
+        /// attrs the BG_ATTR_* flags of its tile
+        ///
+        /// Palettes and the sprites' tiles are read as the pixel goes out, so those can
+        /// change mid-line too.
+        fn draw_pixel(&mut self, mem: &Memory, bg: Byte, attrs: Byte) {
+            let c = self.fifo.x;
+            let px = GB_SCREEN_WIDTH * mem[LY] as usize + c;
+            self.buffer[px] = if mem.cgb {
+                cgb_rgb(mem.cgb_color(false, attrs & BG_ATTR_MASK_PAL, bg))
+            } else {
+                palette_lookup(bg, mem[BGP], &self.palettes.bg)
+            };
+            if !self.buffer_shades.is_empty() {
+                self.buffer_shades[px] = shade(bg, mem[BGP]);
+            }
+            self.fifo.x += 1;
+
+            // draw sprites
+            // FE00-FE9F   Sprite Attribute Table (OAM)
+            // -------------------------------------------
+            // the x attr for the sprite is an offset from -8 to allow
+            // for off-screen (left side) positions.
+            // We can simply adjust the value of c on this line
+            // to account for this.
+            let c_off = (c + 8) as Byte;
+            // in priority order, the first opaque pixel wins (even if it then
+            // goes behind the background, lower priority sprites don't show through)
+            for hit in self.buffer_sprites.iter() {
+                let spr = &hit.sprite;
+                if c_off >= spr.x(mem) && (c_off as Word) < spr.x(mem) as Word + 8 {
+                    // a tall sprite's two tiles are one after the other, so its rows are too
+                    let spr_tile_data_line_offset =
+                        MEM_VRAM +
+                        spr.tile(mem) as Word * BYTES_PER_TILE +
+                        hit.line as Word * 2;
+                    let spr_tile_line_data = if mem.cgb && spr.flags(mem) & OAM_BIT_BANK != 0 {
+                        ppu_decode_tile_line(mem.vram1(spr_tile_data_line_offset), mem.vram1(spr_tile_data_line_offset + 1))
+                    } else {
+                        ppu_decode_tile_line(mem[spr_tile_data_line_offset], mem[spr_tile_data_line_offset + 1])
+                    };
+                    // decoded right to left
+                    let spr_pix = if spr.flags(mem) & OAM_BIT_FLIP_X != 0 {
+                        c_off - spr.x(mem)
+                    } else {
+                        7 - (c_off - spr.x(mem))
+                    };
+                    if spr_tile_line_data[spr_pix as usize] != 0 {
+                        // either the sprite or (cgb) the tile can put the background in front
+                        if (spr.flags(mem) & OAM_BIT_PRIORITY != 0 || attrs & BG_ATTR_PRIORITY != 0) && bg != 0 {
+                            break;
+                        }
+                        let color = spr_tile_line_data[spr_pix as usize];
+                        let obp = if spr.flags(mem) & OAM_BIT_DMG_PAL != 0 { mem[OBP1] } else { mem[OBP0] };
+                        self.buffer[px] = if mem.cgb {
+                            cgb_rgb(mem.cgb_color(true, spr.flags(mem) & OAM_MASK_CGB_PAL, color))
+                        } else if spr.flags(mem) & OAM_BIT_DMG_PAL != 0 {
+                            palette_lookup(color, obp, &self.palettes.obj1)
+                        } else {
+                            palette_lookup(color, obp, &self.palettes.obj0)
+                        };
+                        if !self.buffer_shades.is_empty() {
+                            self.buffer_shades[px] = shade(color, obp);
+                        }
+                        self.sprite_history[px] |= 1;
+                        break;
+                    }
+                }
+            }
+        }
+
+        /// The ppu while LCDC says the lcd is off: LY stays 0, STAT reads mode 0 and the
+        /// screen is blank
+        ///
+        /// Frames are still counted out at the usual rate (blank ones), so whoever's waiting
+        /// on the next frame isn't left hanging by a game that keeps the lcd off for a while.
+        /// `dt` is how long it's been off when it was just turned off, as far as anyone knows.
+        fn update_off(&mut self, mem: &mut Memory, dt: u64) -> bool {
+            if !self.off {
+                self.off = true;
+                self.stat_line = false;
+                self.lcd_timing = dt;
+                mem[LY] = 0;
+                // LYC isn't compared again until it's back on
+                lcd_compare_ly_lyc(mem);
+                set_lcd_mode(0, mem);
+                let blank = if mem.cgb { CGB_WHITE } else { self.palettes.bg[0] };
+                self.buffer.iter_mut().for_each(|px| *px = blank);
+                self.front.iter_mut().for_each(|px| *px = blank);
+                self.buffer_shades.iter_mut().for_each(|px| *px = 0);
+                self.shades.iter_mut().for_each(|px| *px = 0);
+            }
+            if self.lcd_timing >= TICKS_PER_FRAME {
+                self.lcd_timing -= TICKS_PER_FRAME;
+                return true;
+            }
+            false
+        }
+
+        /// Advances the ppu by dt
+        ///
+        /// Returns true when a frame has been completed (on leaving vblank), at which point
+        /// frame() is ready to be presented.
+        pub fn update(&mut self, mem: &mut Memory, dt: Cycles) -> bool {
+            let mut frame_ready = false;
+            self.lcd_timing += dt.as_t();
+            if mem[LCDC] & LCDC_BIT_ENABLE == 0 {
+                return self.update_off(mem, dt.as_t());
+            }
+            if self.off {
+                // back on, from the top of a fresh frame (dt in, for all anyone knows)
+                self.off = false;
+                self.lcd_timing = dt.as_t();
+                mem[LY] = 0;
+                self.scan_oam(mem);
+                set_lcd_mode(2, mem);
+            }
+            match lcd_mode(&mem) {
+                // oam search
+                2 => {
+                    if self.lcd_timing >= TICKS_PER_OAM_SEARCH {
+                        self.scan_oam(mem);
+                        self.fifo.start_line(mem);
+                        set_lcd_mode(3, mem);
+                        self.lcd_timing -= TICKS_PER_OAM_SEARCH;
+                    }
+                }
+                // vram io
+                3 => {
+                    // clock the pixel pipeline up to now, so a register written since the last
+                    // update only affects the pixels after it
+                    let dots = self.lcd_timing.min(self.vram_io_ticks);
+                    while self.fifo.dots < dots {
+                        self.clock_dot(mem);
+                    }
+                    if self.lcd_timing >= self.vram_io_ticks {
+                        // todo: acc: the fifo doesn't stall while sprites are fetched, it's
+                        // usually done early. Just in case, the rest of the line is drawn now
+                        while self.fifo.x < GB_SCREEN_WIDTH {
+                            self.clock_dot(mem);
+                        }
+
+                        // draw window
+                        // -------------------------------------------
+                        // for i in buffer[ln_start..ln_end].iter_mut() {}
+
+                        set_lcd_mode(0, mem);
+                        self.lcd_timing -= self.vram_io_ticks;
+                    }
+                }
+                // hblank
+                0 => {
+                    let cur_line: &mut Byte = &mut mem[LY];
+                    if self.lcd_timing >= self.hblank_ticks() {
+                        *cur_line += 1;
+                        self.lcd_timing -= self.hblank_ticks();
+                        if *cur_line == GB_SCREEN_HEIGHT as Byte {
+                            // values 144 to 153 are vblank
+                            if self.flicker_reduction {
+                                self.reduce_flicker();
+                            }
+                            if self.ghosting > 0 {
+                                self.ghost();
+                            }
+                            std::mem::swap(&mut self.buffer, &mut self.front);
+                            std::mem::swap(&mut self.buffer_shades, &mut self.shades);
+                            request_interrupt(mem, FL_INT_VBLANK);
+                            set_lcd_mode(1, mem);
+                        } else {
+                            set_lcd_mode(2, mem);
+                        }
+                    }
+                }
+                // vblank
+                1 => {
+                    let cur_line: &mut Byte = &mut mem[LY];
+                    *cur_line = (GB_SCREEN_HEIGHT as u64 + self.lcd_timing / TICKS_PER_SCANLINE) as Byte;
+                    if *cur_line == LY_LAST && self.lcd_timing % TICKS_PER_SCANLINE >= TICKS_LY_LAST {
+                        // the last line only reads as 153 briefly, then as 0 (so LYC=0 matches early)
+                        *cur_line = 0;
+                    }
+                    if self.lcd_timing >= TICKS_PER_VBLANK {
+                        *cur_line = 0;
+                        set_lcd_mode(2, mem);
+                        self.lcd_timing -= TICKS_PER_VBLANK;
+                        frame_ready = true;
+
+                        if INSTRUMENT && self.doctor {
+                            dump("mem.bin", &mem).unwrap()
+                        }
+                    }
+                }
+                _ => panic!("invalid LCD mode"),
+            };
+            lcd_compare_ly_lyc(mem);
+            self.update_stat_line(mem);
+            frame_ready
+        }
+
+        /// Requests the STAT interrupt on a rising edge of the line ORing together the enabled
+        /// STAT conditions
+        ///
+        /// While one condition holds the line high another can't raise a second interrupt
+        /// ("STAT blocking"), e.g. with hblank and LYC both enabled, LY matching LYC as hblank
+        /// ends doesn't interrupt again.
+        // https://gbdev.io/pandocs/Interrupt_Sources.html#int-48--stat-interrupt
+        fn update_stat_line(&mut self, mem: &mut Memory) {
+            let stat = mem.read(STAT);
+            let select = match lcd_mode(mem) {
+                0 => STAT_BIT_MODE_0_INT_SELECT,
+                1 => STAT_BIT_MODE_1_INT_SELECT,
+                2 => STAT_BIT_MODE_2_INT_SELECT,
+                _ => 0,
+            };
+            let line = stat & select != 0
+                || (stat & STAT_BIT_LY_LYC_EQ != 0 && stat & STAT_BIT_LYC_INT_SELECT != 0);
+            if line && !self.stat_line {
+                request_interrupt(mem, FL_INT_STAT);
+            }
+            self.stat_line = line;
+        }
+    }
+    
+    const CGB_WHITE: u32 = 0xFFFFFF;
+
+    /// A 15 bit cgb color (5 bits each of red, green, blue from the low bits up) as 0xRRGGBB
+    pub fn cgb_rgb(color: Word) -> u32 {
+        // the top bits repeat at the bottom, so 0x1F becomes 0xFF
+        let channel = |shift: Word| {
+            let c = ((color >> shift) & 0x1F) as u32;
+            (c << 3) | (c >> 2)
+        };
+        channel(0) << 16 | channel(5) << 8 | channel(10)
+    }
+
+    pub fn lcd_compare_ly_lyc(mem: &mut Memory) -> bool {
+        // https://gbdev.io/pandocs/STAT.html#ff45--lyc-ly-compare
+        let equal = mem[LY] == mem.read(LYC);
+        let comparison = bit_set(STAT_BIT_LY_LYC_EQ, mem.read(STAT), equal);
+        mem.write(STAT, comparison);
+        equal
+    }
+
+    pub fn lcd_mode(mem: &Memory) -> Byte {
+        mem.read(STAT) & STAT_MASK_PPU_MODE
+    }
+
+    pub fn set_lcd_mode(mode: Byte, mem: &mut Memory) {
+        mem.write(STAT, (mem.read(STAT) & !STAT_MASK_PPU_MODE) | (mode & STAT_MASK_PPU_MODE));
+    }
+
+    // byte i of SPREAD[b] is bit i of b, so a pair of lookups decodes a whole tile line
+    const SPREAD: [u64; 256] = {
+        let mut table = [0; 256];
+        let mut b = 0;
+        while b < 256 {
+            let mut i = 0;
+            while i < 8 {
+                table[b] |= (((b >> i) & 1) as u64) << (i * 8);
+                i += 1;
+            }
+            b += 1;
+        }
+        table
+    };
+
+    /// Pixel color ids of a tile line, from the two bitplanes (pixel i is bit i)
+    pub fn ppu_decode_tile_line(low: Byte, high: Byte) -> [Byte; PPU_TILE_WIDTH] {
+        (SPREAD[low as usize] | SPREAD[high as usize] << 1).to_le_bytes()
+    }
+
+    #[cfg(test)]
+    mod tests_lcd {
+        use super::*;
+
+        fn cart(title: &[u8], licensee: Byte) -> Cartridge {
+            let mut rom = vec![0; BANK_SIZE * 2];
+            rom[ROM_TITLE as usize..][..title.len()].copy_from_slice(title);
+            rom[ROM_OLD_LICENSEE as usize] = licensee;
+            Cartridge::from(rom)
+        }
+
+        #[test]
+        fn test_set_position() {
+            let mut mem = Memory::new();
+            let mut lcd = Display::new();
+            for (ly, dot, mode) in [(0, 0, 2), (5, 100, 3), (5, 300, 0), (150, 12, 1)] {
+                lcd.set_position(&mut mem, ly, dot);
+                assert_eq!(lcd.ly(&mem), ly);
+                assert_eq!(lcd.dot(&mem), dot);
+                assert_eq!(lcd_mode(&mem), mode);
+            }
+        }
+
+        #[test]
+        fn test_lyc_interrupt() {
+            let mut mem = Memory::new();
+            let mut lcd = Display::new();
+            mem[LYC] = 10;
+            mem[STAT] |= STAT_BIT_LYC_INT_SELECT;
+
+            lcd.set_position(&mut mem, 9, 0);
+            lcd.update(&mut mem, Cycles::ZERO);
+            assert_eq!(mem[IF] & FL_INT_STAT, 0);
+            assert_eq!(mem[STAT] & STAT_BIT_LY_LYC_EQ, 0);
+
+            lcd.set_position(&mut mem, 10, 0);
+            lcd.update(&mut mem, Cycles::ZERO);
+            assert_eq!(mem[IF] & FL_INT_STAT, FL_INT_STAT);
+            assert_eq!(mem[STAT] & STAT_BIT_LY_LYC_EQ, STAT_BIT_LY_LYC_EQ);
+        }
+
+        #[test]
+        fn test_stat_blocking() {
+            let mut mem = Memory::new();
+            let mut lcd = Display::new();
+            mem[LYC] = 10;
+            mem[STAT] |= STAT_BIT_LYC_INT_SELECT;
+            lcd.set_position(&mut mem, 10, 0);
+            lcd.update(&mut mem, Cycles::ZERO);
+            assert_eq!(mem[IF] & FL_INT_STAT, FL_INT_STAT);
+            // once per rising edge, not every update LY matches
+            mem[IF] = 0;
+            lcd.update(&mut mem, Cycles::t(4));
+            assert_eq!(mem[IF] & FL_INT_STAT, 0);
+
+            // hblank on line 9 holds the line high into line 10's LYC match
+            lcd.set_position(&mut mem, 9, 0);
+            lcd.update(&mut mem, Cycles::ZERO);
+            mem[STAT] |= STAT_BIT_MODE_0_INT_SELECT;
+            lcd.set_position(&mut mem, 9, TICKS_PER_SCANLINE - 8);
+            lcd.update(&mut mem, Cycles::ZERO);
+            assert_eq!(mem[IF] & FL_INT_STAT, FL_INT_STAT);
+            mem[IF] = 0;
+            lcd.update(&mut mem, Cycles::t(8));
+            assert_eq!(lcd.ly(&mem), 10);
+            assert_eq!(mem[IF] & FL_INT_STAT, 0);
+
+            // without it the match interrupts
+            mem[STAT] &= !STAT_BIT_MODE_0_INT_SELECT;
+            lcd.set_position(&mut mem, 9, TICKS_PER_SCANLINE - 8);
+            lcd.update(&mut mem, Cycles::ZERO);
+            lcd.update(&mut mem, Cycles::t(8));
+            assert_eq!(mem[IF] & FL_INT_STAT, FL_INT_STAT);
+        }
+
+        #[test]
+        fn test_doctor_ly() {
+            let mut mem = Memory::new();
+            let mut lcd = Display::new();
+            mem.doctor = true;
+            mem[LYC] = 10;
+            mem[STAT] |= STAT_BIT_LYC_INT_SELECT;
+            lcd.set_position(&mut mem, 10, 0);
+            lcd.update(&mut mem, Cycles::ZERO);
+            // only the cpu is lied to, the ppu still counts lines and compares LYC
+            assert_eq!(lcd.ly(&mem), 10);
+            assert_eq!(mem[IF] & FL_INT_STAT, FL_INT_STAT);
+            assert_eq!(mem.read(LY), if INSTRUMENT { 0x90 } else { 10 });
+        }
+
+        #[test]
+        fn test_vblank_entry() {
+            let mut mem = Memory::new();
+            let mut lcd = Display::new();
+            lcd.set_position(&mut mem, 143, TICKS_PER_SCANLINE - 1);
+            lcd.update(&mut mem, Cycles::t(4));
+            assert_eq!(lcd_mode(&mem), 1);
+            assert_eq!(lcd.ly(&mem), 144);
+            assert_eq!(mem[IF] & FL_INT_VBLANK, FL_INT_VBLANK);
+        }
+
+        #[test]
+        fn test_ly_153() {
+            let mut mem = Memory::new();
+            let mut lcd = Display::new();
+            mem[LYC] = 0;
+            mem[STAT] |= STAT_BIT_LYC_INT_SELECT;
+            lcd.set_position(&mut mem, 152, TICKS_PER_SCANLINE - 4);
+            lcd.update(&mut mem, Cycles::t(4));
+            assert_eq!(lcd.ly(&mem), 153);
+            assert_eq!(lcd.cycles_until_event(&mem), Cycles::t(4));
+
+            // 0 for the rest of the line, still in vblank
+            lcd.update(&mut mem, Cycles::t(4));
+            assert_eq!(lcd.ly(&mem), 0);
+            assert_eq!(lcd_mode(&mem), 1);
+            lcd.update(&mut mem, Cycles::ZERO);
+            assert_eq!(mem[IF] & FL_INT_STAT, FL_INT_STAT);
+            assert_eq!(lcd.cycles_until_event(&mem), Cycles::t(TICKS_PER_SCANLINE - 4));
+
+            assert!(lcd.update(&mut mem, Cycles::t(TICKS_PER_SCANLINE - 4)));
+            assert_eq!(lcd.ly(&mem), 0);
+            assert_eq!(lcd_mode(&mem), 2);
+        }
+
+        #[test]
+        fn test_object_palettes() {
+            let mut mem = Memory::new();
+            let mut lcd = Display::new();
+            lcd.palettes = PaletteSet { bg: PAL_CLASSIC, obj0: PAL_ICE_CREAM, obj1: PAL_VBOY };
+            mem[BGP] = 0x00;
+            mem[OBP0] = 0xFF; // everything shade 3
+            mem[OBP1] = 0x55; // everything shade 1
+            for addr in MEM_VRAM..MEM_VRAM + BYTES_PER_TILE {
+                mem[addr] = 0xFF; // tile 0 is solid
+            }
+            // sprite 0 in the top left corner, sprite 1 beside it on OBP1
+            mem[MEM_OAM] = 16;
+            mem[MEM_OAM + 1] = 8;
+            mem[MEM_OAM + 4] = 16;
+            mem[MEM_OAM + 5] = 16;
+            mem[MEM_OAM + 7] = OAM_BIT_DMG_PAL;
+            // from wherever power on leaves the ppu to the end of a whole frame
+            for ly in [144, 0, 144] {
+                while lcd.ly(&mem) != ly {
+                    lcd.update(&mut mem, Cycles::t(4));
+                }
+            }
+            assert_eq!(lcd.frame()[0], PAL_ICE_CREAM[3]);
+            assert_eq!(lcd.frame()[8], PAL_VBOY[1]);
+        }
+
+        #[test]
+        fn test_sprite_priority() {
+            let mut mem = Memory::new();
+            let mut lcd = Display::new();
+            mem[BGP] = 0x00;
+            mem[OBP0] = 0xE4;
+            for row in 0..8 {
+                // tile 0 solid color 3, tile 1 solid color 1
+                mem[MEM_VRAM + row * 2] = 0xFF;
+                mem[MEM_VRAM + row * 2 + 1] = 0xFF;
+                mem[MEM_VRAM + BYTES_PER_TILE + row * 2] = 0xFF;
+            }
+            let mut place = |idx: Word, y: Byte, x: Byte, tile: Byte| {
+                let attr = MEM_OAM + idx * OBJ_ATTR_SIZE;
+                mem[attr] = y + 16;
+                mem[attr + 1] = x;
+                mem[attr + 2] = tile;
+            };
+            // line 0: the lower x is on top even though it's later in OAM
+            place(0, 0, 8 + 4, 0);
+            place(1, 0, 8, 1);
+            // line 8: same x, OAM order decides
+            place(2, 8, 8, 0);
+            place(3, 8, 8, 1);
+            // line 16: 11 sprites, the one off the left edge still uses up a slot
+            place(4, 16, 0, 0);
+            for i in 0..10 {
+                place(5 + i, 16, 8 + i as Byte * 8, 0);
+            }
+            for ly in [144, 0, 144] {
+                while lcd.ly(&mem) != ly {
+                    lcd.update(&mut mem, Cycles::t(4));
+                }
+            }
+            let at = |x: usize, y: usize| lcd.frame()[y * GB_SCREEN_WIDTH + x];
+            let obj = PALETTES_DEFAULT.obj0;
+            assert_eq!(at(4, 0), obj[1]);
+            assert_eq!(at(8, 0), obj[3]);
+            assert_eq!(at(0, 8), obj[3]);
+            assert_eq!(at(64, 16), obj[3]);
+            assert_eq!(at(72, 16), PALETTES_DEFAULT.bg[0]);
+        }
+
+        #[test]
+        fn test_sprite_behind_bg() {
+            let mut mem = Memory::new();
+            let mut lcd = Display::new();
+            mem[LCDC] = 0x91; // tiles at 8000, map at 9800
+            mem[BGP] = 0xE4;
+            mem[OBP0] = 0xE4;
+            for row in 0..8 {
+                // tile 0 empty, tile 1 solid color 1, tile 2 solid color 3
+                mem[MEM_VRAM + BYTES_PER_TILE + row * 2] = 0xFF;
+                mem[MEM_VRAM + BYTES_PER_TILE * 2 + row * 2] = 0xFF;
+                mem[MEM_VRAM + BYTES_PER_TILE * 2 + row * 2 + 1] = 0xFF;
+            }
+            // the top left background tile on the first two rows isn't color 0
+            mem[0x9800] = 1;
+            mem[0x9820] = 1;
+            let mut place = |idx: Word, y: Byte, x: Byte, flags: Byte| {
+                let attr = MEM_OAM + idx * OBJ_ATTR_SIZE;
+                mem[attr] = y + 16;
+                mem[attr + 1] = x + 8;
+                mem[attr + 2] = 2;
+                mem[attr + 3] = flags;
+            };
+            // line 0: behind the background, hidden by color 1 but not by color 0
+            place(0, 0, 4, OAM_BIT_PRIORITY);
+            // line 8: a hidden sprite still wins over the one under it
+            place(1, 8, 0, OAM_BIT_PRIORITY);
+            place(2, 8, 0, 0);
+            for ly in [144, 0, 144] {
+                while lcd.ly(&mem) != ly {
+                    lcd.update(&mut mem, Cycles::t(4));
+                }
+            }
+            let at = |x: usize, y: usize| lcd.frame()[y * GB_SCREEN_WIDTH + x];
+            assert_eq!(at(4, 0), PALETTES_DEFAULT.bg[1]);
+            assert_eq!(at(8, 0), PALETTES_DEFAULT.obj0[3]);
+            assert_eq!(at(0, 8), PALETTES_DEFAULT.bg[1]);
+        }
+
+        #[test]
+        fn test_dual_mode_cart() {
+            // a cart that runs on both (0x80) is booted as a dmg game, it never fills palette
+            // RAM and is drawn with BGP
+            let mut mem = Memory::new();
+            let mut lcd = Display::new();
+            let mut rom = vec![0; BANK_SIZE * 2];
+            rom[ROM_CGB as usize] = 0x80;
+            mem.load_rom(&Cartridge::from(rom));
+            mem[LCDC] = 0x91;
+            mem[BGP] = 0xE4;
+            for row in 0..8 {
+                mem[MEM_VRAM + BYTES_PER_TILE + row * 2 + 1] = 0xFF; // tile 1 is solid color 2
+            }
+            mem[0x9800] = 1;
+            for ly in [144, 0, 144] {
+                while lcd.ly(&mem) != ly {
+                    lcd.update(&mut mem, Cycles::t(4));
+                }
+            }
+            assert_eq!(lcd.frame()[0], PALETTES_DEFAULT.bg[2]);
+            assert_eq!(lcd.frame()[8], PALETTES_DEFAULT.bg[0]);
+        }
+
+        #[test]
+        fn test_cgb_bg_attributes() {
+            let mut mem = Memory::new();
+            let mut lcd = Display::new();
+            let mut rom = vec![0; BANK_SIZE * 2];
+            rom[ROM_CGB as usize] = 0xC0;
+            mem.load_rom(&Cartridge::from(rom));
+            mem[LCDC] = 0x91; // tiles at 8000, map at 9800
+            // white, red, green, blue for the background, the other way around for objects
+            mem.write(BCPS, 0x80);
+            mem.write(OCPS, 0x80);
+            for &(bg, obj) in [(0x7FFF, 0x7FFF), (0x001F, 0x7C00), (0x03E0, 0x03E0), (0x7C00, 0x001F)].iter() {
+                mem.write(BCPD, lo(bg));
+                mem.write(BCPD, hi(bg));
+                mem.write(OCPD, lo(obj));
+                mem.write(OCPD, hi(obj));
+            }
+            // tile 1's top row is color 1 on the left half and color 3 in bank 1, its other
+            // rows are empty
+            mem[MEM_VRAM + BYTES_PER_TILE] = 0xF0;
+            mem.write(VBK, 1);
+            mem.write(MEM_VRAM + BYTES_PER_TILE, 0xF0);
+            mem.write(MEM_VRAM + BYTES_PER_TILE + 1, 0xF0);
+            // the first row of tiles: as is, flipped across, from bank 1, flipped down
+            for (column, &attrs) in [0, BG_ATTR_FLIP_X, BG_ATTR_BANK, BG_ATTR_FLIP_Y].iter().enumerate() {
+                mem[0x9800 + column as Word] = 1;
+                mem.write(0x9800 + column as Word, attrs);
+            }
+            // an empty tile in front of a sprite
+            mem.write(0x9804, BG_ATTR_PRIORITY);
+            mem[0x9804] = 1;
+            mem.write(VBK, 0);
+            for addr in MEM_VRAM + BYTES_PER_TILE * 2..MEM_VRAM + BYTES_PER_TILE * 3 {
+                mem[addr] = 0xFF; // tile 2 is solid color 3
+            }
+            mem[MEM_OAM] = 16;
+            mem[MEM_OAM + 1] = 8 + 32;
+            mem[MEM_OAM + 2] = 2;
+            for ly in [144, 0, 144] {
+                while lcd.ly(&mem) != ly {
+                    lcd.update(&mut mem, Cycles::t(4));
+                }
+            }
+            let at = |x: usize, y: usize| lcd.frame()[y * GB_SCREEN_WIDTH + x];
+            let bg = [0xFFFFFF, 0xFF0000, 0x00FF00, 0x0000FF];
+            assert_eq!((at(0, 0), at(4, 0)), (bg[1], bg[0]));
+            assert_eq!((at(8, 0), at(12, 0)), (bg[0], bg[1]));
+            assert_eq!((at(16, 0), at(20, 0)), (bg[3], bg[0]));
+            assert_eq!((at(24, 0), at(24, 7)), (bg[0], bg[1]));
+            // the priority bit only wins over sprites where the background isn't color 0
+            assert_eq!((at(32, 0), at(36, 0)), (bg[1], 0xFF0000));
+        }
+
+        #[test]
+        fn test_cgb_object_priority() {
+            let mut mem = Memory::new();
+            let mut lcd = Display::new();
+            let mut rom = vec![0; BANK_SIZE * 2];
+            rom[ROM_CGB as usize] = 0xC0;
+            mem.load_rom(&Cartridge::from(rom));
+            // object palette 1 color 3 is black, the rest stay white
+            mem.write(OCPS, 8 + 6);
+            mem.write(OCPD, 0x00);
+            mem.write(OCPS, 8 + 7);
+            mem.write(OCPD, 0x00);
+            for addr in MEM_VRAM..MEM_VRAM + BYTES_PER_TILE {
+                mem[addr] = 0xFF; // tile 0 is solid
+            }
+            // sprite 0 overlaps the right half of sprite 1, which is further left
+            mem[MEM_OAM] = 16;
+            mem[MEM_OAM + 1] = 8 + 4;
+            mem[MEM_OAM + 4] = 16;
+            mem[MEM_OAM + 5] = 8;
+            mem[MEM_OAM + 7] = 1;
+            let mut overlap = vec![];
+            for opri in [0x00, 0x01] {
+                mem.write(OPRI, opri);
+                for ly in [144, 0, 144] {
+                    while lcd.ly(&mem) != ly {
+                        lcd.update(&mut mem, Cycles::t(4));
+                    }
+                }
+                overlap.push(lcd.frame()[4]);
+            }
+            // OAM order, then x like dmg
+            assert_eq!(overlap, vec![CGB_WHITE, 0x000000]);
+        }
+
+        #[test]
+        fn test_mid_line_writes() {
+            let mut mem = Memory::new();
+            let mut lcd = Display::new();
+            let run_to = |lcd: &mut Display, mem: &mut Memory, ly: Byte, dot: u64| {
+                // (the end of vblank reads as line 0 too)
+                while lcd.ly(mem) != ly || lcd.dot(mem) < dot || (lcd_mode(mem) == 1) != (ly >= 144) {
+                    lcd.update(mem, Cycles::t(4));
+                }
+            };
+            // the background is all color 0, BGP decides what that looks like
+            mem[BGP] = 0x00;
+            run_to(&mut lcd, &mut mem, 144, 0);
+            run_to(&mut lcd, &mut mem, 0, TICKS_PER_OAM_SEARCH + 80);
+            mem[BGP] = 0x03;
+            run_to(&mut lcd, &mut mem, 1, 0);
+            mem[BGP] = 0x00;
+            run_to(&mut lcd, &mut mem, 144, 0);
+
+            let bg = PALETTES_DEFAULT.bg;
+            let line: Vec<u32> = lcd.frame()[..GB_SCREEN_WIDTH].to_vec();
+            let split = line.iter().position(|&px| px == bg[3]).unwrap();
+            assert!(split > 60 && split < 90, "split at {}", split);
+            assert!(line[..split].iter().all(|&px| px == bg[0]));
+            assert!(line[split..].iter().all(|&px| px == bg[3]));
+            assert_eq!(lcd.frame()[GB_SCREEN_WIDTH], bg[0]);
+        }
+
+        #[test]
+        fn test_mode3_length() {
+            let mut mem = Memory::new();
+            let mut lcd = Display::new();
+            let mode3 = |lcd: &mut Display, mem: &mut Memory| {
+                lcd.set_position(mem, 0, TICKS_PER_OAM_SEARCH);
+                let mut dots = 0;
+                while lcd_mode(mem) == 3 {
+                    lcd.update(mem, Cycles::t(1));
+                    dots += 1;
+                }
+                dots
+            };
+            assert_eq!(mode3(&mut lcd, &mut mem), TICKS_PER_VRAM_IO);
+            // fine scrolling
+            mem[SCX] = 3;
+            assert_eq!(mode3(&mut lcd, &mut mem), TICKS_PER_VRAM_IO + 3);
+            mem[SCX] = 0;
+            // a sprite off the left edge costs the most, one lined up with a tile the least
+            mem[MEM_OAM] = 16;
+            assert_eq!(mode3(&mut lcd, &mut mem), TICKS_PER_VRAM_IO + 11);
+            mem[MEM_OAM + 1] = 8 + 8;
+            assert_eq!(mode3(&mut lcd, &mut mem), TICKS_PER_VRAM_IO + 11);
+            mem[MEM_OAM + 1] = 8 + 13;
+            assert_eq!(mode3(&mut lcd, &mut mem), TICKS_PER_VRAM_IO + 6);
+            // a second sprite over the same tile only pays for its own fetch
+            mem[MEM_OAM + 4] = 16;
+            mem[MEM_OAM + 5] = 8 + 14;
+            assert_eq!(mode3(&mut lcd, &mut mem), TICKS_PER_VRAM_IO + 12);
+            // hblank takes up the slack
+            assert_eq!(lcd.dot(&mem), TICKS_PER_OAM_SEARCH + TICKS_PER_VRAM_IO + 12);
+            assert_eq!(
+                lcd.cycles_until_event(&mem),
+                Cycles::t(TICKS_PER_HBLANK - 12)
+            );
+        }
+
+        #[test]
+        fn test_lcd_off() {
+            let mut mem = Memory::new();
+            let mut lcd = Display::new();
+            mem[LCDC] = 0x91;
+            mem[BGP] = 0xFF;
+            lcd.set_position(&mut mem, 50, 100);
+            mem.write(LYC, 0);
+            mem[LCDC] &= !LCDC_BIT_ENABLE;
+            assert!(!lcd.update(&mut mem, Cycles::t(4)));
+            assert_eq!(lcd.ly(&mem), 0);
+            assert_eq!(lcd_mode(&mem), 0);
+            assert!(lcd.frame().iter().all(|&px| px == PALETTES_DEFAULT.bg[0]));
+            // LY went to 0 along with it
+            assert_ne!(mem.read(STAT) & STAT_BIT_LY_LYC_EQ, 0);
+
+            // still a (blank) frame every frame's worth of cycles, counted from turning it off
+            assert_eq!(lcd.cycles_until_event(&mem), Cycles::t(TICKS_PER_FRAME - 4));
+            assert!(!lcd.update(&mut mem, Cycles::t(TICKS_PER_FRAME - 8)));
+            assert!(lcd.update(&mut mem, Cycles::t(4)));
+            assert_eq!(lcd.ly(&mem), 0);
+
+            mem[LCDC] |= LCDC_BIT_ENABLE;
+            lcd.update(&mut mem, Cycles::t(4));
+            assert_eq!(lcd.ly(&mem), 0);
+            assert_eq!(lcd_mode(&mem), 2);
+            assert_eq!(lcd.dot(&mem), 4);
+        }
+
+        #[test]
+        fn test_tall_sprites() {
+            let mut mem = Memory::new();
+            let mut lcd = Display::new();
+            mem[LCDC] = 0x91 | LCDC_BIT_OBJ_SIZE;
+            mem[BGP] = 0x00;
+            mem[OBP0] = 0xE4;
+            for row in 0..8 {
+                // tile 2 is color 1 on the left half, tile 3 color 3 on the left half
+                mem[MEM_VRAM + BYTES_PER_TILE * 2 + row * 2] = 0xF0;
+                mem[MEM_VRAM + BYTES_PER_TILE * 3 + row * 2] = 0xF0;
+                mem[MEM_VRAM + BYTES_PER_TILE * 3 + row * 2 + 1] = 0xF0;
+            }
+            // as is, flipped down (the odd tile number is the same pair), flipped across
+            for (i, &flags) in [0, OAM_BIT_FLIP_Y, OAM_BIT_FLIP_X].iter().enumerate() {
+                let attr = MEM_OAM + i as Word * OBJ_ATTR_SIZE;
+                mem[attr] = 16;
+                mem[attr + 1] = 8 + i as Byte * 8;
+                mem[attr + 2] = 2 + i as Byte % 2;
+                mem[attr + 3] = flags;
+            }
+            for ly in [144, 0, 144] {
+                while lcd.ly(&mem) != ly {
+                    lcd.update(&mut mem, Cycles::t(4));
+                }
+            }
+            let at = |x: usize, y: usize| lcd.frame()[y * GB_SCREEN_WIDTH + x];
+            let obj = PALETTES_DEFAULT.obj0;
+            let bg = PALETTES_DEFAULT.bg[0];
+            assert_eq!([at(0, 0), at(0, 7), at(0, 8), at(0, 15), at(0, 16)], [obj[1], obj[1], obj[3], obj[3], bg]);
+            assert_eq!([at(8, 0), at(8, 7), at(8, 8), at(8, 15)], [obj[3], obj[3], obj[1], obj[1]]);
+            assert_eq!([at(16, 0), at(20, 0), at(20, 8), at(19, 8)], [bg, obj[1], obj[3], bg]);
+        }
+
+        #[test]
+        fn test_flicker_reduction() {
+            let mut mem = Memory::new();
+            let mut lcd = Display::new();
+            lcd.flicker_reduction = true;
+            let run_to_line = |lcd: &mut Display, mem: &mut Memory, ly: Byte| {
+                while lcd.ly(mem) != ly {
+                    lcd.update(mem, Cycles::t(4));
+                }
+            };
+            mem[BGP] = 0x00;
+            mem[OBP0] = 0xFF;
+            for addr in MEM_VRAM..MEM_VRAM + BYTES_PER_TILE {
+                mem[addr] = 0xFF; // tile 0 is solid
+            }
+            mem[MEM_OAM] = 16; // sprite 0 in the top left corner...
+            run_to_line(&mut lcd, &mut mem, 144);
+            run_to_line(&mut lcd, &mut mem, 0);
+
+            // ...on every other frame
+            let mut corner = vec![];
+            for frame in 0..5 {
+                mem[MEM_OAM + 1] = if frame % 2 == 0 { 8 } else { 0 };
+                run_to_line(&mut lcd, &mut mem, 144);
+                corner.push(lcd.frame()[0]);
+                assert_eq!(lcd.frame()[8], PALETTES_DEFAULT.bg[0]);
+                run_to_line(&mut lcd, &mut mem, 0);
+            }
+            let (sprite, bg) = (PALETTES_DEFAULT.obj0[3], PALETTES_DEFAULT.bg[0]);
+            assert_eq!(corner[..2], [sprite, bg]);
+            // from the 4th frame of history on, it's blended
+            assert!(corner[2..].iter().all(|px| *px == corner[2]));
+            assert!(corner[2] != sprite && corner[2] != bg);
+
+            // a sprite that stays put isn't touched
+            mem[MEM_OAM + 1] = 8;
+            for _ in 0..4 {
+                run_to_line(&mut lcd, &mut mem, 144);
+                run_to_line(&mut lcd, &mut mem, 0);
+            }
+            assert_eq!(lcd.frame()[0], sprite);
+        }
+
+        #[test]
+        fn test_ghosting() {
+            let mut mem = Memory::new();
+            let mut lcd = Display::new();
+            lcd.palettes.bg = [0xFFFFFF; 4];
+            lcd.palettes.obj0 = [0x000000; 4];
+            lcd.ghosting = 50;
+            let run_to_line = |lcd: &mut Display, mem: &mut Memory, ly: Byte| {
+                while lcd.ly(mem) != ly {
+                    lcd.update(mem, Cycles::t(4));
+                }
+            };
+            for addr in MEM_VRAM..MEM_VRAM + BYTES_PER_TILE {
+                mem[addr] = 0xFF; // tile 0 is solid
+            }
+            mem[MEM_OAM] = 16; // sprite 0 in the top left corner, once it's on screen
+            for _ in 0..10 {
+                // the screen starts out black, and fades to white
+                run_to_line(&mut lcd, &mut mem, 144);
+                run_to_line(&mut lcd, &mut mem, 0);
+            }
+            assert_eq!(lcd.frame()[0], 0xFFFFFF);
+            mem[MEM_OAM + 1] = 8;
+
+            // fades in from white, half way each frame
+            let mut corner = vec![];
+            for _ in 0..3 {
+                run_to_line(&mut lcd, &mut mem, 144);
+                corner.push(lcd.frame()[0]);
+                assert_eq!(lcd.frame()[8], 0xFFFFFF);
+                run_to_line(&mut lcd, &mut mem, 0);
+            }
+            assert_eq!(corner, [0x7F7F7F, 0x3F3F3F, 0x1F1F1F]);
+
+            // and back out once it's gone
+            mem[MEM_OAM + 1] = 0;
+            run_to_line(&mut lcd, &mut mem, 144);
+            assert_eq!(lcd.frame()[0], 0x8F8F8F);
+
+            // off, frames are as drawn
+            lcd.ghosting = 0;
+            run_to_line(&mut lcd, &mut mem, 0);
+            run_to_line(&mut lcd, &mut mem, 144);
+            assert_eq!(lcd.frame()[0], 0xFFFFFF);
+        }
+
+        #[test]
+        fn test_double_buffering() {
+            let mut mem = Memory::new();
+            let mut lcd = Display::new();
+            let run_to_line = |lcd: &mut Display, mem: &mut Memory, ly: Byte| {
+                while lcd.ly(mem) != ly {
+                    lcd.update(mem, Cycles::t(4));
+                }
+            };
+            let white = vec![PALETTES_DEFAULT.bg[0]; GB_SCREEN_WIDTH * GB_SCREEN_HEIGHT];
+            let black = vec![PALETTES_DEFAULT.bg[3]; GB_SCREEN_WIDTH * GB_SCREEN_HEIGHT];
+
+            mem[BGP] = 0x00; // blank tiles come out as color 0
+            run_to_line(&mut lcd, &mut mem, 144);
+            run_to_line(&mut lcd, &mut mem, 0); // a whole frame from the top
+            run_to_line(&mut lcd, &mut mem, 144);
+            assert_eq!(lcd.frame(), &white[..]);
+
+            mem[BGP] = 0xFF; // ...and now as color 3
+            run_to_line(&mut lcd, &mut mem, 10);
+            assert_eq!(lcd.frame(), &white[..]); // lines 0-9 went to the back buffer
+            run_to_line(&mut lcd, &mut mem, 144);
+            assert_eq!(lcd.frame(), &black[..]);
+
+            let mut frame = white.clone();
+            lcd.swap_frame(&mut frame);
+            assert_eq!(frame, black);
+            assert_eq!(lcd.frame(), &white[..]);
+        }
+
+        #[test]
+        fn test_accessible_palettes() {
+            // brightness is what has to differ between shades, hue can't be relied on
+            let min_gap = |pal: [u32; 4]| pal.windows(2).map(|w| luma(w[0]) - luma(w[1])).fold(f32::MAX, f32::min);
+            for name in ["deuteranopia", "protanopia"] {
+                let set = PaletteSet::named(name).unwrap();
+                for pal in [set.bg, set.obj0, set.obj1] {
+                    assert!(min_gap(pal) > 40.0, "{} {:X?}", name, pal);
+                }
+            }
+            assert_eq!(PaletteSet::named("nope"), None);
+
+            for set in [PALETTES_DEFAULT, PALETTES_CGB_COMPAT_DEFAULT, PALETTES_PROTANOPIA] {
+                let high = set.high_contrast();
+                for pal in [high.bg, high.obj0, high.obj1] {
+                    assert!(min_gap(pal) > 84.0, "{:X?}", pal);
+                    assert_eq!((pal[0], pal[3]), (0xFFFFFF, 0x000000));
+                }
+            }
+            // hue stays put: a red stays red, just lighter or darker
+            let red = with_luma(0x800000, 170.0);
+            assert!(red >> 16 > (red >> 8 & 0xFF) && red >> 8 & 0xFF == red & 0xFF);
+            assert!((luma(red) - 170.0).abs() < 1.0);
+        }
+
+        #[test]
+        fn test_cgb_compat_palettes() {
+            const CGB_WHITE_BLUE: [u32; 4] = [0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000];
+            const CGB_WHITE_GREEN: [u32; 4] = [0xFFFFFF, 0x7BFF31, 0x008400, 0x000000];
+            assert_eq!(cgb_compat_palettes(&cart(b"POKEMON BLUE", 0x01)).bg, CGB_WHITE_BLUE);
+            let red = cgb_compat_palettes(&cart(b"POKEMON RED", 0x01));
+            assert_eq!((red.bg, red.obj0, red.obj1), (CGB_WHITE_RED, CGB_WHITE_GREEN, CGB_WHITE_RED));
+            // 0x28 is GOLF and another game, the 4th letter tells them apart
+            assert_eq!(cgb_compat_palettes(&cart(b"GOLF", 0x01)).bg, CGB_WHITE_GREEN);
+            assert_eq!(cgb_compat_palettes(&cart(b"GOLE\x01", 0x01)), PALETTES_CGB_COMPAT_DEFAULT);
+            // same title, not licensed by nintendo
+            assert_eq!(cgb_compat_palettes(&cart(b"POKEMON RED", 0x00)), PALETTES_CGB_COMPAT_DEFAULT);
+            assert_eq!(cgb_compat_palettes(&cart(b"HOMEBREW!", 0x01)), PALETTES_CGB_COMPAT_DEFAULT);
+        }
+
+        #[test]
+        fn test_decode_tile_line() {
+            assert_eq!(ppu_decode_tile_line(0b1010_0101, 0b1100_0011), [3, 2, 1, 0, 0, 1, 2, 3]);
+            // against the straightforward per-bit version, every input
+            for low in 0..=0xFF {
+                for high in 0..=0xFF {
+                    let expected: Vec<Byte> = (0..PPU_TILE_WIDTH).map(|i| ((high >> i) & 1) << 1 | ((low >> i) & 1)).collect();
+                    assert_eq!(ppu_decode_tile_line(low, high)[..], expected[..]);
+                }
+            }
+        }
+    }
+}
+
+pub mod apu {
+    //! Sound: the frame sequencer and the channels, mixed down to stereo samples
+    //!
+    //! Games talk to the apu through the NRxx registers. Memory queues up writes to them
+    //! (triggers, length reloads and so on happen on the write) and update() acts on them
+    //! before running the channels for the cycles the cpu just took.
+    //! todo: channels 2 and 4 are still silent
+    use crate::bits::*;
+    use crate::cpu::GB_SCREEN_WIDTH;
+    use crate::dbg::StateHash;
+    use crate::memory::*;
+    use crate::pacing::CPU_HZ;
+    use crate::types::*;
+    use serde::{Deserialize, Serialize};
+    use std::collections::VecDeque;
+
+    // nr52
+    pub const NR52_BIT_POWER: Byte = BIT_7;
+    pub const NR52_BIT_CH1_ON: Byte = BIT_0;
+    pub const NR52_BIT_CH3_ON: Byte = BIT_2;
+
+    // nr50
+    pub const NR50_BIT_VIN_LEFT: Byte = BIT_7;
+    pub const NR50_BIT_VIN_RIGHT: Byte = BIT_3;
+
+    // nr30
+    pub const NR30_BIT_DAC: Byte = BIT_7;
+
+    // nrx4
+    pub const NRX4_BIT_TRIGGER: Byte = BIT_7;
+    pub const NRX4_BIT_LENGTH_ENABLE: Byte = BIT_6;
+
+    /// ORed into reads of NR10 up to wave RAM: unused bits and write-only registers read as 1
+    #[rustfmt::skip]
+    pub const NR_READ_MASKS: [Byte; (MEM_WAVE_RAM - NR10) as usize] = [
+        0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+        0xFF, 0x3F, 0x00, 0xFF, 0xBF, // (NR20)-NR24
+        0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+        0xFF, 0xFF, 0x00, 0x00, 0xBF, // (NR40)-NR44
+        0x00, 0x00, 0x70,             // NR50-NR52
+        0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    ];
+
+    /// The frame sequencer steps at 512Hz, clocking length, sweep and envelope
+    pub const TICKS_PER_FRAME_SEQ: u64 = 8192;
+    /// Samples (left and right each count) kept while nothing takes them, older ones are dropped
+    pub const SAMPLES_MAX: usize = 16384;
+    /// Default sample rate, see Apu::sample_rate
+    pub const SAMPLE_RATE: f64 = 48000.0;
+
+    // waveforms for 12.5%, 25%, 50% and 75% duty
+    const DUTY: [[Byte; 8]; 4] = [
+        [0, 0, 0, 0, 0, 0, 0, 1],
+        [1, 0, 0, 0, 0, 0, 0, 1],
+        [1, 0, 0, 0, 0, 1, 1, 1],
+        [0, 1, 1, 1, 1, 1, 1, 0],
+    ];
+
+    /// Which capacitor takes the DC offset out of the output, see Apu::mix
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum HighPass {
+        /// The raw DAC output, offset and all (it pops as channels come and go)
+        Off,
+        #[default]
+        Dmg,
+        /// GBC and GBA, which take out a bit more of the bass
+        Cgb,
+    }
+
+    impl HighPass {
+        /// How much of its charge the capacitor keeps per cycle
+        fn charge(&self) -> Option<f64> {
+            match self {
+                HighPass::Off => None,
+                HighPass::Dmg => Some(0.999958),
+                HighPass::Cgb => Some(0.998943),
+            }
+        }
+    }
+
+    /// Volume envelope (NRx2)
+    #[derive(Clone, Debug, Default)]
+    struct Envelope {
+        volume: Byte,
+        increase: bool,
+        pace: Byte, // frame sequencer ticks (at 64Hz) per volume step, 0 holds the volume
+        timer: Byte,
+        running: bool, // until the volume hits the end it's heading for, then stops for good
+    }
+
+    impl Envelope {
+        /// `envelope_next` when the frame sequencer's next step clocks the envelope, which
+        /// makes the first volume step come a tick late
+        fn trigger(&mut self, nrx2: Byte, envelope_next: bool) {
+            self.volume = nrx2 >> 4;
+            self.increase = nrx2 & BIT_3 != 0;
+            self.pace = nrx2 & 0x07;
+            self.timer = self.pace + envelope_next as Byte;
+            self.running = true;
+        }
+
+        fn clock(&mut self) {
+            if self.pace == 0 || !self.running {
+                return;
+            }
+            self.timer = self.timer.saturating_sub(1);
+            if self.timer == 0 {
+                self.timer = self.pace;
+                if self.increase && self.volume < 15 {
+                    self.volume += 1;
+                } else if !self.increase && self.volume > 0 {
+                    self.volume -= 1;
+                } else {
+                    self.running = false;
+                }
+            }
+        }
+
+        /// NRx2 written while the channel plays ("zombie mode"): rather than reloading, the
+        /// volume gets nudged, which some games lean on to fade without retriggering
+        ///
+        /// A held volume (pace 0) still running goes up 1, otherwise a decreasing one goes
+        /// up 2. Flipping the direction turns the volume into 16 - volume, all modulo 16.
+        /// https://gbdev.gg8.se/wiki/articles/Gameboy_sound_hardware#Obscure_Behavior
+        fn zombie(&mut self, nrx2: Byte) {
+            let increase = nrx2 & BIT_3 != 0;
+            if self.pace == 0 && self.running {
+                self.volume = self.volume.wrapping_add(1);
+            } else if !self.increase {
+                self.volume = self.volume.wrapping_add(2);
+            }
+            if increase != self.increase {
+                self.volume = 16u8.wrapping_sub(self.volume);
+            }
+            self.volume &= 0x0F;
+            self.increase = increase;
+            self.pace = nrx2 & 0x07;
+        }
+    }
+
+    /// Frequency sweep (NR10), channel 1 only
+    #[derive(Clone, Debug, Default)]
+    struct Sweep {
+        enabled: bool,
+        timer: Byte,
+        shadow: Word,  // the period being swept, written back to NR13/NR14
+        negated: bool, // a calculation subtracted since the trigger, see Square::write
+    }
+
+    /// A square wave channel
+    #[derive(Clone, Debug, Default)]
+    pub struct Square {
+        enabled: bool,
+        dac: bool, // NRx2 has a volume or an increasing envelope
+        duty: Byte,
+        step: usize, // position in the duty waveform
+        period: Word,
+        timer: u64,  // cycles until the next step through the waveform
+        length: u16, // counts down to silence when enabled, 64 at most
+        length_enabled: bool,
+        envelope: Envelope,
+        sweep: Sweep,
+    }
+
+    impl Square {
+        fn step_cycles(&self) -> u64 {
+            (2048 - self.period as u64) * 4
+        }
+
+        pub fn enabled(&self) -> bool {
+            self.enabled
+        }
+
+        /// Volume being put out right now, 0..=15
+        pub fn output(&self) -> Byte {
+            if self.enabled && DUTY[self.duty as usize][self.step] != 0 {
+                self.envelope.volume
+            } else {
+                0
+            }
+        }
+
+        fn tick(&mut self, cycles: u64) {
+            self.timer -= cycles;
+            if self.timer == 0 {
+                self.timer = self.step_cycles();
+                self.step = (self.step + 1) % 8;
+            }
+        }
+
+        fn clock_length(&mut self) {
+            if self.length_enabled && self.length > 0 {
+                self.length -= 1;
+                if self.length == 0 {
+                    self.enabled = false;
+                }
+            }
+        }
+
+        /// The period the sweep is heading to next, turning the channel off if it's too high
+        fn sweep_next(&mut self, nr10: Byte) -> Word {
+            let delta = self.sweep.shadow >> (nr10 & 0x07);
+            let next = if nr10 & BIT_3 != 0 {
+                self.sweep.negated = true;
+                self.sweep.shadow - delta
+            } else {
+                self.sweep.shadow + delta
+            };
+            if next > 0x7FF {
+                self.enabled = false;
+            }
+            next
+        }
+
+        fn clock_sweep(&mut self, mem: &mut Memory) {
+            let nr10 = mem[NR10];
+            let pace = (nr10 >> 4) & 0x07;
+            self.sweep.timer = self.sweep.timer.saturating_sub(1);
+            if self.sweep.timer != 0 {
+                return;
+            }
+            self.sweep.timer = if pace == 0 { 8 } else { pace };
+            if !self.sweep.enabled || pace == 0 {
+                return;
+            }
+            let next = self.sweep_next(nr10);
+            if next <= 0x7FF && nr10 & 0x07 != 0 {
+                self.sweep.shadow = next;
+                self.period = next;
+                mem[NR13] = lo(next);
+                mem[NR14] = (mem[NR14] & !0x07) | hi(next);
+                // checked again with the new period, without using the result
+                self.sweep_next(nr10);
+            }
+        }
+
+        fn trigger(&mut self, mem: &Memory, sweep: bool, frame_seq: Byte) {
+            self.enabled = self.dac;
+            self.timer = self.step_cycles();
+            self.envelope.trigger(mem[NR12], frame_seq == 7);
+            if self.length == 0 {
+                self.length = 64;
+            }
+            if sweep {
+                let nr10 = mem[NR10];
+                let pace = (nr10 >> 4) & 0x07;
+                self.sweep.shadow = self.period;
+                self.sweep.negated = false;
+                self.sweep.timer = if pace == 0 { 8 } else { pace };
+                self.sweep.enabled = pace != 0 || nr10 & 0x07 != 0;
+                if nr10 & 0x07 != 0 {
+                    self.sweep_next(nr10);
+                }
+            }
+        }
+
+        /// A write to one of the channel's registers, NRx1..=NRx4 as 1..=4. `frame_seq` is the
+        /// frame sequencer step coming up next
+        fn write(&mut self, reg: Word, val: Byte, mem: &Memory, sweep: bool, frame_seq: Byte) {
+            match reg {
+                // going from subtracting back to adding after the sweep has subtracted at
+                // least once since the trigger switches the channel off
+                0 if self.sweep.negated && val & BIT_3 == 0 => self.enabled = false,
+                1 => {
+                    self.duty = val >> 6;
+                    self.length = 64 - (val & 0x3F) as u16;
+                }
+                2 => {
+                    if self.enabled {
+                        self.envelope.zombie(val);
+                    }
+                    self.dac = val & 0xF8 != 0;
+                    if !self.dac {
+                        self.enabled = false;
+                    }
+                }
+                3 => self.period = (self.period & 0x700) | val as Word,
+                4 => {
+                    self.period = (self.period & 0xFF) | ((val & 0x07) as Word) << 8;
+                    self.length_enabled = val & NRX4_BIT_LENGTH_ENABLE != 0;
+                    if val & NRX4_BIT_TRIGGER != 0 {
+                        self.trigger(mem, sweep, frame_seq);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        fn hash_state(&self, h: &mut StateHash) {
+            h.bytes(&[
+                self.enabled as Byte,
+                self.dac as Byte,
+                self.duty,
+                self.step as Byte,
+                self.length_enabled as Byte,
+                self.envelope.volume,
+                self.envelope.increase as Byte,
+                self.envelope.pace,
+                self.envelope.timer,
+                self.envelope.running as Byte,
+                self.sweep.enabled as Byte,
+                self.sweep.timer,
+                self.sweep.negated as Byte,
+            ]);
+            h.bytes(&self.period.to_le_bytes());
+            h.bytes(&self.sweep.shadow.to_le_bytes());
+            h.bytes(&self.length.to_le_bytes());
+            h.u64(self.timer);
+        }
+    }
+
+    /// The wave channel, playing back the 32 samples in wave RAM
+    #[derive(Clone, Debug, Default)]
+    pub struct Wave {
+        enabled: bool,
+        dac: bool,       // NR30
+        volume: Byte,    // NR32: 0 mutes, then 100%, 50% and 25%
+        position: usize, // which sample in wave RAM is playing
+        sample: Byte,
+        period: Word,
+        timer: u64,  // cycles until the next sample
+        length: u16, // counts down to silence when enabled, 256 at most
+        length_enabled: bool,
+    }
+
+    impl Wave {
+        fn step_cycles(&self) -> u64 {
+            (2048 - self.period as u64) * 2
+        }
+
+        pub fn enabled(&self) -> bool {
+            self.enabled
+        }
+
+        /// Which byte of wave RAM the channel is reading, while it plays
+        pub fn current_byte(&self) -> Option<Word> {
+            if self.enabled {
+                Some(self.position as Word / 2)
+            } else {
+                None
+            }
+        }
+
+        /// Volume being put out right now, 0..=15
+        pub fn output(&self) -> Byte {
+            match self.volume {
+                _ if !self.enabled => 0,
+                0 => 0,
+                shift => self.sample >> (shift - 1),
+            }
+        }
+
+        fn tick(&mut self, cycles: u64, mem: &Memory) {
+            self.timer -= cycles;
+            if self.timer == 0 {
+                self.timer = self.step_cycles();
+                self.position = (self.position + 1) % 32;
+                let byte = mem[MEM_WAVE_RAM + self.position as Word / 2];
+                self.sample = if self.position.is_multiple_of(2) {
+                    byte >> 4
+                } else {
+                    byte & 0x0F
+                };
+            }
+        }
+
+        fn clock_length(&mut self) {
+            if self.length_enabled && self.length > 0 {
+                self.length -= 1;
+                if self.length == 0 {
+                    self.enabled = false;
+                }
+            }
+        }
+
+        /// A write to one of the channel's registers, NR30..=NR34 as 0..=4
+        fn write(&mut self, reg: Word, val: Byte) {
+            match reg {
+                0 => {
+                    self.dac = val & NR30_BIT_DAC != 0;
+                    if !self.dac {
+                        self.enabled = false;
+                    }
+                }
+                1 => self.length = 256 - val as u16,
+                2 => self.volume = (val >> 5) & 0x03,
+                3 => self.period = (self.period & 0x700) | val as Word,
+                4 => {
+                    self.period = (self.period & 0xFF) | ((val & 0x07) as Word) << 8;
+                    self.length_enabled = val & NRX4_BIT_LENGTH_ENABLE != 0;
+                    if val & NRX4_BIT_TRIGGER != 0 {
+                        // starts over from the top, the first sample played is the second one
+                        self.enabled = self.dac;
+                        self.timer = self.step_cycles();
+                        self.position = 0;
+                        if self.length == 0 {
+                            self.length = 256;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        fn hash_state(&self, h: &mut StateHash) {
+            h.bytes(&[
+                self.enabled as Byte,
+                self.dac as Byte,
+                self.volume,
+                self.position as Byte,
+                self.sample,
+                self.length_enabled as Byte,
+            ]);
+            h.bytes(&self.period.to_le_bytes());
+            h.bytes(&self.length.to_le_bytes());
+            h.u64(self.timer);
+        }
+    }
+
+    /// Outputs kept per channel for the debug view, a pixel each across the screen
+    pub const SCOPE_LEN: usize = GB_SCREEN_WIDTH;
+
+    /// The last SCOPE_LEN outputs of each channel through its DAC (-1.0..=1.0), one per
+    /// sample made, before panning and muting. See dbg::draw_apu
+    #[derive(Clone, Debug, Default)]
+    pub struct Scope {
+        pub channels: [VecDeque<f32>; 4],
+    }
+
+    impl Scope {
+        fn push(&mut self, outputs: [f64; 4]) {
+            for (channel, out) in self.channels.iter_mut().zip(outputs) {
+                if channel.len() == SCOPE_LEN {
+                    channel.pop_front();
+                }
+                channel.push_back(out as f32);
+            }
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct Apu {
+        /// Samples made per emulated second, e.g. AvSync::source_rate() to play back in time
+        pub sample_rate: f64,
+        pub high_pass: HighPass,
+        /// Host side volume applied to the mix, 0.0..=1.0
+        pub volume: f32,
+        /// Recent channel outputs for the debug view, None (the default) doesn't keep them
+        pub scope: Option<Scope>,
+        muted: [bool; 4], // channels left out of the mix, see set_muted
+        pub ch1: Square,
+        pub ch3: Wave,
+        frame_seq: Byte,  // which of the 8 frame sequencer steps is next
+        frame_timer: u64, // cycles until then
+        sample_clock: f64,
+        capacitor: [f64; 2],
+        samples: Vec<f32>, // left, right, left, right...
+    }
+
+    impl Default for Apu {
+        fn default() -> Self {
+            Apu::new()
+        }
+    }
+
+    impl Apu {
+        pub fn new() -> Apu {
+            Apu {
+                sample_rate: SAMPLE_RATE,
+                high_pass: HighPass::default(),
+                volume: 1.0,
+                scope: None,
+                muted: [false; 4],
+                ch1: Square::default(),
+                ch3: Wave::default(),
+                frame_seq: 0,
+                frame_timer: TICKS_PER_FRAME_SEQ,
+                sample_clock: 0.0,
+                capacitor: [0.0; 2],
+                samples: vec![],
+            }
+        }
+
+        /// Leaves a channel (1-4) out of the mix, for picking apart a game's music. The
+        /// channel carries on as usual, the game can't tell
+        pub fn set_muted(&mut self, channel: usize, muted: bool) {
+            self.muted[channel - 1] = muted;
+        }
+
+        pub fn muted(&self, channel: usize) -> bool {
+            self.muted[channel - 1]
+        }
+
+        /// Mutes every channel but this one, or unmutes them all if it was already alone
+        pub fn solo(&mut self, channel: usize) {
+            let alone = (1..=4).all(|ch| self.muted(ch) == (ch != channel));
+            for ch in 1..=4 {
+                self.set_muted(ch, !alone && ch != channel);
+            }
+        }
+
+        /// Hands over the samples made so far, interleaved left and right in -1.0..=1.0
+        pub fn take_samples(&mut self) -> Vec<f32> {
+            std::mem::take(&mut self.samples)
+        }
+
+        /// Frame sequencer and channel state, the samples are just output
+        pub fn hash_state(&self, h: &mut StateHash) {
+            h.bytes(&[self.frame_seq]);
+            h.u64(self.frame_timer);
+            self.ch1.hash_state(h);
+            self.ch3.hash_state(h);
+        }
+
+        fn write(&mut self, mem: &mut Memory, addr: Word, val: Byte) {
+            match addr {
+                NR10..=NR14 => self.ch1.write(addr - NR10, val, mem, true, self.frame_seq),
+                NR30..=NR34 => self.ch3.write(addr - NR30, val),
+                NR52 if val & NR52_BIT_POWER == 0 => {
+                    // the registers were cleared by Memory::write, the frame sequencer starts
+                    // over from step 0 when powered back on
+                    self.ch1 = Square::default();
+                    self.ch3 = Wave::default();
+                    self.frame_seq = 0;
+                    self.frame_timer = TICKS_PER_FRAME_SEQ;
+                }
+                _ => {}
+            }
+        }
+
+        fn clock_frame_seq(&mut self, mem: &mut Memory) {
+            if self.frame_seq.is_multiple_of(2) {
+                self.ch1.clock_length();
+                self.ch3.clock_length();
+            }
+            if self.frame_seq == 2 || self.frame_seq == 6 {
+                self.ch1.clock_sweep(mem);
+            }
+            if self.frame_seq == 7 {
+                self.ch1.envelope.clock();
+            }
+            self.frame_seq = (self.frame_seq + 1) % 8;
+        }
+
+        /// Channel output through its DAC, -1.0..=1.0 (0 with the DAC off)
+        fn dac(dac: bool, volume: Byte) -> f64 {
+            if dac {
+                volume as f64 / 7.5 - 1.0
+            } else {
+                0.0
+            }
+        }
+
+        /// Pans and scales the channels by NR51/NR50, takes out the DC offset the way the
+        /// capacitors on the real outputs do (see HighPass), then applies the host volume
+        ///
+        /// NR51 has a bit per channel for each side (channel n on the right is bit n - 1, on
+        /// the left bit n + 3), NR50 a 3 bit volume per side where 0 is 1/8 and 7 is full.
+        /// NR50's other bits add the cartridge's VIN pin (Mbc::vin) to a side like a fifth
+        /// channel, silence for every cartridge there is.
+        fn mix(&mut self, mem: &Memory) {
+            let (nr50, nr51) = (mem[NR50], mem[NR51]);
+            // channels 2 and 4 aren't emulated yet, they stay silent with their DACs off
+            let dacs = [self.ch1.dac, false, self.ch3.dac, false];
+            let outputs = [
+                Apu::dac(dacs[0], self.ch1.output()),
+                0.0,
+                Apu::dac(dacs[2], self.ch3.output()),
+                0.0,
+            ];
+            if let Some(scope) = &mut self.scope {
+                scope.push(outputs);
+            }
+            let any_dac = dacs.contains(&true);
+            let vin = mem.vin();
+            for (side, shift) in [(0, 4), (1, 0)] {
+                let mut out: f64 = (0..4)
+                    .filter(|ch| nr51 & (1 << (ch + shift)) != 0 && !self.muted[*ch])
+                    .map(|ch| outputs[ch])
+                    .sum();
+                if nr50 & (NR50_BIT_VIN_RIGHT << shift) != 0 {
+                    out += vin;
+                }
+                let volume = ((nr50 >> shift) & 0x07) as f64 + 1.0;
+                let input = out / 4.0 * volume / 8.0;
+                let mut filtered = 0.0;
+                if any_dac {
+                    filtered = input;
+                    if let Some(charge) = self.high_pass.charge() {
+                        filtered = input - self.capacitor[side];
+                        self.capacitor[side] =
+                            input - filtered * charge.powf(CPU_HZ as f64 / self.sample_rate);
+                    }
+                }
+                self.samples.push(filtered as f32 * self.volume);
+            }
+            if self.samples.len() > SAMPLES_MAX {
+                let excess = self.samples.len() - SAMPLES_MAX;
+                self.samples.drain(..excess);
+            }
+        }
+
+        /// Acts on the sound register writes since the last update, then runs for some cycles
+        pub fn update(&mut self, mem: &mut Memory, cycles: Cycles) {
+            for (addr, val) in std::mem::take(&mut mem.apu_writes) {
+                self.write(mem, addr, val);
+            }
+
+            let powered = mem[NR52] & NR52_BIT_POWER != 0;
+            let mut left = cycles.as_t();
+            while left > 0 {
+                // run up to whatever happens next: a frame sequencer step, a waveform step or
+                // a sample
+                let until_sample =
+                    ((CPU_HZ as f64 - self.sample_clock) / self.sample_rate).ceil() as u64;
+                let mut run = left.min(until_sample.max(1));
+                if powered {
+                    run = run.min(self.frame_timer);
+                    for timer in [self.ch1.timer, self.ch3.timer] {
+                        if timer > 0 {
+                            run = run.min(timer);
+                        }
+                    }
+                }
+                left -= run;
+
+                if powered {
+                    if self.ch1.timer > 0 {
+                        self.ch1.tick(run);
+                    }
+                    if self.ch3.timer > 0 {
+                        self.ch3.tick(run, mem);
+                    }
+                    self.frame_timer -= run;
+                    if self.frame_timer == 0 {
+                        self.frame_timer = TICKS_PER_FRAME_SEQ;
+                        self.clock_frame_seq(mem);
+                    }
+                }
+
+                self.sample_clock += run as f64 * self.sample_rate;
+                if self.sample_clock >= CPU_HZ as f64 {
+                    self.sample_clock -= CPU_HZ as f64;
+                    self.mix(mem);
+                }
+            }
+
+            let mut status = 0;
+            if self.ch1.enabled {
+                status |= NR52_BIT_CH1_ON;
+            }
+            if self.ch3.enabled {
+                status |= NR52_BIT_CH3_ON;
+            }
+            mem[NR52] = (mem[NR52] & NR52_BIT_POWER) | 0x70 | status;
+            mem.wave_byte = self.ch3.current_byte();
+        }
+    }
+
+    /// Converts interleaved stereo from one sample rate to another by linear interpolation,
+    /// for sound cards that won't run at the rate the apu was asked for
+    ///
+    /// Carries the last frame over between calls, a stream comes out the same however it's
+    /// cut up.
+    #[derive(Clone, Debug)]
+    pub struct Resampler {
+        step: f64, // input frames per output frame
+        pos: f64,  // where the next output frame falls, 0.0 being `last`
+        last: [f32; 2],
+    }
+
+    impl Resampler {
+        pub fn new(from_hz: f64, to_hz: f64) -> Resampler {
+            Resampler {
+                step: from_hz / to_hz,
+                pos: 0.0,
+                last: [0.0; 2],
+            }
+        }
+
+        /// Changes the rates without a jump in the stream, for AvSync's small corrections
+        pub fn set_rates(&mut self, from_hz: f64, to_hz: f64) {
+            self.step = from_hz / to_hz;
+        }
+
+        /// Resamples `input` onto the end of `out`
+        pub fn process(&mut self, input: &[f32], out: &mut Vec<f32>) {
+            let frames = input.len() / 2;
+            if frames == 0 {
+                return;
+            }
+            let last = self.last;
+            let frame = |i: usize| match i {
+                0 => last,
+                _ => [input[2 * i - 2], input[2 * i - 1]],
+            };
+            while self.pos < frames as f64 {
+                let i = self.pos as usize;
+                let t = (self.pos - i as f64) as f32;
+                let (a, b) = (frame(i), frame(i + 1));
+                out.push(a[0] + (b[0] - a[0]) * t);
+                out.push(a[1] + (b[1] - a[1]) * t);
+                self.pos += self.step;
+            }
+            self.pos -= frames as f64;
+            self.last = frame(frames);
+        }
+    }
+
+    #[cfg(test)]
+    mod tests_apu {
+        use super::*;
+
+        fn apu() -> (Apu, Memory) {
+            let mut apu = Apu::new();
+            let mut mem = Memory::new();
+            apu.update(&mut mem, Cycles::ZERO);
+            (apu, mem)
+        }
+
+        #[test]
+        fn test_square() {
+            let (mut apu, mut mem) = apu();
+            assert_eq!(mem[NR52], 0xF0);
+            mem.write(NR11, 0x80 | 0x3F); // 50% duty, 1 step of length
+            mem.write(NR12, 0xF0);
+            mem.write(NR13, 0xE0);
+            mem.write(NR14, NRX4_BIT_TRIGGER | 0x07); // period 0x7E0, 128 cycles per step
+            apu.update(&mut mem, Cycles::ZERO);
+            assert!(apu.ch1.enabled());
+            assert_eq!(mem[NR52], 0xF1);
+
+            // 50% duty: 1 high, 4 low, 3 high
+            let mut wave = vec![];
+            for _ in 0..8 {
+                wave.push(apu.ch1.output());
+                apu.update(&mut mem, Cycles::t(128));
+            }
+            assert_eq!(wave, [15, 0, 0, 0, 0, 15, 15, 15]);
+
+            let samples = apu.take_samples();
+            let expected = (1024.0 * SAMPLE_RATE / CPU_HZ as f64) as usize * 2;
+            assert!(samples.len().abs_diff(expected) <= 2);
+            assert!(samples.iter().any(|s| *s != 0.0));
+            assert!(apu.take_samples().is_empty());
+
+            // lengths count off on the frame sequencer, this one only had one left
+            mem.write(NR14, NRX4_BIT_LENGTH_ENABLE | 0x07);
+            apu.update(&mut mem, Cycles::t(TICKS_PER_FRAME_SEQ * 2));
+            assert!(!apu.ch1.enabled());
+            assert_eq!(mem[NR52], 0xF0);
+        }
+
+        #[test]
+        fn test_envelope() {
+            let (mut apu, mut mem) = apu();
+            mem.write(NR12, 0x21); // volume 2, decreasing every 64Hz tick
+            mem.write(NR14, NRX4_BIT_TRIGGER);
+            apu.update(&mut mem, Cycles::ZERO);
+            assert_eq!(apu.ch1.envelope.volume, 2);
+            apu.update(&mut mem, Cycles::t(TICKS_PER_FRAME_SEQ * 8));
+            assert_eq!(apu.ch1.envelope.volume, 1);
+            apu.update(&mut mem, Cycles::t(TICKS_PER_FRAME_SEQ * 16));
+            assert_eq!(apu.ch1.envelope.volume, 0);
+            assert!(apu.ch1.enabled()); // silent, but still on
+
+            // no volume and decreasing turns the dac, and with it the channel, off
+            mem.write(NR12, 0x00);
+            apu.update(&mut mem, Cycles::ZERO);
+            assert!(!apu.ch1.enabled());
+        }
+
+        #[test]
+        fn test_envelope_quirks() {
+            let (mut apu, mut mem) = apu();
+            mem.write(NR12, 0x58); // volume 5, increasing, held
+            mem.write(NR14, NRX4_BIT_TRIGGER);
+            apu.update(&mut mem, Cycles::ZERO);
+
+            // writing NR12 while playing nudges the volume instead of setting it
+            let mut volumes = vec![];
+            // (the volume nibble is ignored, it just keeps the DAC on)
+            for nr12 in [0x08, 0x08, 0x10, 0x10, 0x18] {
+                mem.write(NR12, nr12);
+                apu.update(&mut mem, Cycles::ZERO);
+                volumes.push(apu.ch1.envelope.volume);
+            }
+            // +1, +1, +1 and flipped to 16 - 8, +1, +1 and flipped to 16 - 10
+            assert_eq!(volumes, [6, 7, 8, 9, 6]);
+
+            // triggered with the envelope clocked next, the first step comes a tick late
+            mem.write(NR12, 0xF1);
+            apu.update(&mut mem, Cycles::t(TICKS_PER_FRAME_SEQ * 7));
+            mem.write(NR14, NRX4_BIT_TRIGGER);
+            apu.update(&mut mem, Cycles::t(TICKS_PER_FRAME_SEQ));
+            assert_eq!(apu.ch1.envelope.volume, 15);
+            apu.update(&mut mem, Cycles::t(TICKS_PER_FRAME_SEQ * 8));
+            assert_eq!(apu.ch1.envelope.volume, 14);
+        }
+
+        #[test]
+        fn test_wave() {
+            let (mut apu, mut mem) = apu();
+            for i in 0..16 {
+                let (hi, lo) = ((i * 2) % 16, (i * 2 + 1) % 16);
+                mem.write(MEM_WAVE_RAM + i, (hi << 4 | lo) as Byte);
+            }
+            mem.write(NR30, NR30_BIT_DAC);
+            mem.write(NR31, 0xFF); // 1 step of length
+            mem.write(NR32, 0x20); // full volume
+            mem.write(NR33, 0xF8);
+            mem.write(NR34, NRX4_BIT_TRIGGER | 0x07); // period 0x7F8, 16 cycles per sample
+            apu.update(&mut mem, Cycles::ZERO);
+            assert!(apu.ch3.enabled());
+            assert_eq!(mem[NR52], 0xF4);
+
+            // wave RAM counts 0..=15 (twice), playing from the second sample
+            let mut wave = vec![];
+            for _ in 0..4 {
+                apu.update(&mut mem, Cycles::t(16));
+                wave.push(apu.ch3.output());
+            }
+            assert_eq!(wave, [1, 2, 3, 4]);
+
+            // 50% shifts the samples down by one
+            mem.write(NR32, 0x40);
+            apu.update(&mut mem, Cycles::t(16));
+            assert_eq!(apu.ch3.output(), 5 >> 1);
+
+            mem.write(NR34, NRX4_BIT_LENGTH_ENABLE | 0x07);
+            apu.update(&mut mem, Cycles::t(TICKS_PER_FRAME_SEQ * 2));
+            assert!(!apu.ch3.enabled());
+            assert_eq!(mem[NR52], 0xF0);
+
+            // turning the dac off stops it as well
+            mem.write(NR34, NRX4_BIT_TRIGGER);
+            apu.update(&mut mem, Cycles::ZERO);
+            assert!(apu.ch3.enabled());
+            mem.write(NR30, 0x00);
+            apu.update(&mut mem, Cycles::ZERO);
+            assert!(!apu.ch3.enabled());
+        }
+
+        #[test]
+        fn test_panning() {
+            // the first sample of channel 1, with NR51 and NR50 set up before it starts
+            let run = |nr50: Byte, nr51: Byte| {
+                let (mut apu, mut mem) = apu();
+                mem.write(NR50, nr50);
+                mem.write(NR51, nr51);
+                mem.write(NR11, 0xC0);
+                mem.write(NR12, 0xF0);
+                mem.write(NR13, 0x00);
+                mem.write(NR14, NRX4_BIT_TRIGGER);
+                apu.update(&mut mem, Cycles::t(256));
+                let samples = apu.take_samples();
+                (samples[0], samples[1])
+            };
+            let (left, right) = run(0x77, 0x11);
+            assert_ne!(left, 0.0);
+            assert_eq!(left, right);
+
+            // routing
+            assert_eq!(run(0x77, 0x10), (left, 0.0));
+            assert_eq!(run(0x77, 0x01), (0.0, right));
+            assert_eq!(run(0x77, 0x44), (0.0, 0.0)); // channel 3 only, with its DAC off
+
+            // volume, a side at 0 is 1/8 of full
+            let (quiet, loud) = run(0x07, 0x11);
+            assert_eq!(loud, right);
+            assert!((quiet * 8.0 - left).abs() < 1e-6);
+            let (half, _) = run(0x37, 0x11);
+            assert!((half * 2.0 - left).abs() < 1e-6);
+        }
+
+        #[test]
+        fn test_mute() {
+            // channel 1 on both sides, left out of the mix
+            let (mut apu, mut mem) = apu();
+            apu.set_muted(1, true);
+            mem.write(NR11, 0xC0);
+            mem.write(NR12, 0xF0);
+            mem.write(NR14, NRX4_BIT_TRIGGER);
+            apu.update(&mut mem, Cycles::t(256));
+            assert!(apu.ch1.enabled());
+            assert!(apu.take_samples().iter().all(|s| *s == 0.0));
+            apu.set_muted(1, false);
+            apu.update(&mut mem, Cycles::t(256));
+            assert!(apu.take_samples().iter().any(|s| *s != 0.0));
+
+            apu.solo(3);
+            assert_eq!(
+                (1..=4).map(|ch| apu.muted(ch)).collect::<Vec<_>>(),
+                [true, true, false, true]
+            );
+            apu.solo(3);
+            assert!((1..=4).all(|ch| !apu.muted(ch)));
+        }
+
+        #[test]
+        fn test_vin() {
+            // the VIN bits read back, and with nothing on the pin don't change the mix
+            let run = |nr50| {
+                let (mut apu, mut mem) = apu();
+                mem.write(NR50, nr50);
+                mem.write(NR51, 0x11);
+                mem.write(NR11, 0x80);
+                mem.write(NR12, 0xF0);
+                mem.write(NR14, NRX4_BIT_TRIGGER);
+                apu.update(&mut mem, Cycles::t(4096));
+                (mem.read(NR50), apu.take_samples())
+            };
+            let (nr50, with_vin) = run(0x77 | NR50_BIT_VIN_LEFT | NR50_BIT_VIN_RIGHT);
+            assert_eq!(nr50, 0xFF);
+            let (nr50, without) = run(0x77);
+            assert_eq!(nr50, 0x77);
+            assert_eq!(with_vin, without);
+        }
+
+        #[test]
+        fn test_resampler() {
+            // a ramp, fed in uneven pieces, comes out as the same ramp at 3/4 the rate
+            let input: Vec<f32> = (0..401).flat_map(|i| [i as f32, -(i as f32)]).collect();
+            let mut resampler = Resampler::new(48000.0, 36000.0);
+            let mut out = vec![];
+            for piece in input.chunks(2 * 37) {
+                resampler.process(piece, &mut out);
+            }
+            assert_eq!(out.len(), 301 * 2);
+            for (n, frame) in out.chunks(2).enumerate().skip(1) {
+                // output frame n sits n * 4/3 input frames in, one behind for `last`
+                let expected = n as f32 * 4.0 / 3.0 - 1.0;
+                assert!((frame[0] - expected).abs() < 1e-3, "{} {:?}", n, frame);
+                assert_eq!(frame[1], -frame[0]);
+            }
+
+            // the same rate passes everything through, a frame late
+            let mut resampler = Resampler::new(44100.0, 44100.0);
+            let mut out = vec![];
+            resampler.process(&[0.5, 0.25, 1.0, -1.0], &mut out);
+            assert_eq!(out, [0.0, 0.0, 0.5, 0.25]);
+        }
+
+        #[test]
+        fn test_high_pass() {
+            // channel 3 at volume 0 with its DAC on puts out a steady offset
+            let run = |high_pass, volume| {
+                let (mut apu, mut mem) = apu();
+                apu.high_pass = high_pass;
+                apu.volume = volume;
+                mem.write(NR51, 0x44);
+                mem.write(NR30, NR30_BIT_DAC);
+                mem.write(NR34, NRX4_BIT_TRIGGER);
+                apu.update(&mut mem, Cycles::t(CPU_HZ / 10));
+                apu.take_samples()
+            };
+            let raw = run(HighPass::Off, 1.0);
+            assert!(raw.iter().all(|s| *s == raw[0] && *s < 0.0));
+
+            // the capacitors drain it away, the cgb's faster
+            let dmg = run(HighPass::Dmg, 1.0);
+            let cgb = run(HighPass::Cgb, 1.0);
+            assert_eq!(dmg[0], raw[0]);
+            let last = dmg.len() - 1;
+            assert!(dmg[last].abs() < raw[0].abs() / 2.0);
+            assert!(cgb[last].abs() < dmg[last].abs() / 10.0);
+
+            let half = run(HighPass::Dmg, 0.5);
+            assert!((half[last] * 2.0 - dmg[last]).abs() < 1e-6);
+            assert!(run(HighPass::Dmg, 0.0).iter().all(|s| *s == 0.0));
+        }
+
+        #[test]
+        fn test_wave_ram_access() {
+            let (mut apu, mut mem) = apu();
+            for i in 0..16 {
+                mem.write(MEM_WAVE_RAM + i, i as Byte * 0x11);
+            }
+            mem.write(NR30, NR30_BIT_DAC);
+            mem.write(NR32, 0x20);
+            mem.write(NR33, 0xF8);
+            mem.write(NR34, NRX4_BIT_TRIGGER | 0x07); // 16 cycles per sample
+            apu.update(&mut mem, Cycles::ZERO);
+
+            // whatever the address, the access goes to the byte being played
+            assert_eq!(mem.read(MEM_WAVE_RAM + 0x0A), 0x00);
+            apu.update(&mut mem, Cycles::t(32)); // onto the second byte
+            assert_eq!(mem.read(MEM_WAVE_RAM + 0x0A), 0x11);
+            mem.write(MEM_WAVE_RAM, 0xAB);
+            assert_eq!(mem[MEM_WAVE_RAM + 1], 0xAB);
+            assert_eq!(mem[MEM_WAVE_RAM], 0x00);
+
+            // stopped, wave RAM is just memory again
+            mem.write(NR30, 0x00);
+            apu.update(&mut mem, Cycles::ZERO);
+            assert_eq!(mem.read(MEM_WAVE_RAM + 0x0A), 0xAA);
+        }
+
+        #[test]
+        fn test_power() {
+            let (mut apu, mut mem) = apu();
+            mem.write(NR50, 0x77);
+            mem.write(MEM_WAVE_RAM, 0x12);
+            mem.write(NR52, 0x00);
+            apu.update(&mut mem, Cycles::ZERO);
+            // cleared, reads are just the unused bits
+            let regs: Vec<Byte> = (NR10..MEM_WAVE_RAM).map(|a| mem.read(a)).collect();
+            assert_eq!(regs, NR_READ_MASKS);
+            assert_eq!(mem.read(MEM_WAVE_RAM), 0x12);
+
+            // off: writes are dropped, except lengths
+            mem.write(NR50, 0x77);
+            mem.write(NR11, 0xBF);
+            assert_eq!(mem.read(NR50), 0x00);
+            assert_eq!(mem[NR11], 0x3F); // no duty
+
+            mem.write(NR52, 0x8F); // only power can be written
+            apu.update(&mut mem, Cycles::ZERO);
+            assert_eq!(mem.read(NR52), 0xF0);
+            mem.write(NR50, 0x77);
+            mem.write(NR30, 0x80);
+            assert_eq!(mem.read(NR50), 0x77);
+            assert_eq!(mem.read(NR30), 0xFF);
+            assert_eq!(mem.read(NR13), 0xFF); // write only
+        }
+
+        #[test]
+        fn test_sweep() {
+            let (mut apu, mut mem) = apu();
+            mem.write(NR10, 0x11); // every 128Hz tick, adding period >> 1
+            mem.write(NR12, 0xF0);
+            mem.write(NR13, 0x00);
+            mem.write(NR14, NRX4_BIT_TRIGGER | 0x02);
+            apu.update(&mut mem, Cycles::t(TICKS_PER_FRAME_SEQ * 3));
+            assert_eq!((mem[NR14] & 0x07, mem[NR13]), (0x03, 0x00));
+            apu.update(&mut mem, Cycles::t(TICKS_PER_FRAME_SEQ * 4));
+            assert_eq!((mem[NR14] & 0x07, mem[NR13]), (0x04, 0x80));
+            assert!(apu.ch1.enabled());
+
+            // 0x6C0 + 0x360 would be past 0x7FF
+            apu.update(&mut mem, Cycles::t(TICKS_PER_FRAME_SEQ * 4));
+            assert!(!apu.ch1.enabled());
+
+            // powering off clears the registers and ignores writes until powered back on
+            mem.write(NR52, 0x00);
+            mem.write(NR12, 0xF0);
+            apu.update(&mut mem, Cycles::ZERO);
+            assert_eq!(mem[NR10], 0x00);
+            assert_eq!(mem[NR12], 0x00);
+            assert_eq!(mem[NR52], 0x70);
+        }
+
+        #[test]
+        fn test_sweep_quirks() {
+            let (mut apu, mut mem) = apu();
+            mem.write(NR12, 0xF0);
+
+            // with a shift the overflow check runs on trigger too: 0x700 + 0x380
+            mem.write(NR10, 0x01);
+            mem.write(NR13, 0x00);
+            mem.write(NR14, NRX4_BIT_TRIGGER | 0x07);
+            apu.update(&mut mem, Cycles::ZERO);
+            assert!(!apu.ch1.enabled());
+
+            // switching back to adding after subtracting turns the channel off...
+            mem.write(NR10, 0x19); // every 128Hz tick, subtracting period >> 1
+            mem.write(NR14, NRX4_BIT_TRIGGER | 0x04);
+            apu.update(&mut mem, Cycles::ZERO);
+            assert!(apu.ch1.enabled());
+            mem.write(NR10, 0x11);
+            apu.update(&mut mem, Cycles::ZERO);
+            assert!(!apu.ch1.enabled());
+
+            // ...but not when it never subtracted since the trigger
+            mem.write(NR10, 0x18); // subtracting, but with no shift nothing's calculated
+            mem.write(NR14, NRX4_BIT_TRIGGER | 0x04);
+            apu.update(&mut mem, Cycles::ZERO);
+            mem.write(NR10, 0x10);
+            apu.update(&mut mem, Cycles::ZERO);
+            assert!(apu.ch1.enabled());
+        }
+    }
+}
+
+pub mod decode {
+    use crate::cpu::*;
+    use crate::types::*;
+
+    // https://gb-archive.github.io/salvage/decoding_gbz80_opcodes/Decoding%20Gamboy%20Z80%20Opcodes.html
+    // https://www.pastraiser.com/cpu/gameboy/gameboy_opcodes.html
+
+    // used for CB decoding, some bit functions reference (HL) instead of a register
+    pub const ADR_HL: usize = 6;
+    pub const R_ID: [usize; 8] = [REG_B, REG_C, REG_D, REG_E, REG_H, REG_L, ADR_HL, REG_A];
+
+    // arg tables for printing mnemonics
+    pub const R: [&'static str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+    const RP: [&'static str; 4] = ["BC", "DE", "HL", "SP"];
+    const RP2: [&'static str; 4] = ["BC", "DE", "HL", "AF"];
+    const CC: [&'static str; 4] = ["NZ", "Z", "NC", "C"];
+    const ALU: [&'static str; 8] = [
+        "ADD A,", "ADC A,", "SUB", "SBC A,", "AND", "XOR", "OR", "CP",
+    ];
+    const ROT: [&'static str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+
+    // """
+    // Upon establishing the opcode, the Z80's path of action is generally dictated by these values:
+
+    // x = the opcode's 1st octal digit (i.e. bits 7-6)
+    // y = the opcode's 2nd octal digit (i.e. bits 5-3)
+    // z = the opcode's 3rd octal digit (i.e. bits 2-0)
+    // p = y rightshifted one position (i.e. bits 5-4)
+    // q = y modulo 2 (i.e. bit 3)
+
+    // The following placeholders for instructions and operands are used:
+
+    // d = displacement byte (8-bit signed integer)
+    // n = 8-bit immediate operand (unsigned integer)
+    // nn = 16-bit immediate operand (unsigned integer)
+    // tab[x] = whatever is contained in the table named tab at index x (analogous for y and z and other table names)
+    // """
+
+    const fn x(op: Byte) -> Byte {
+        op >> 6
+    }
+    const fn y(op: Byte) -> Byte {
+        op >> 3 & 0b111
+    }
+    const fn z(op: Byte) -> Byte {
+        op & 0b111
+    }
+    const fn p(op: Byte) -> Byte {
+        y(op) >> 1
+    }
+    const fn q(op: Byte) -> Byte {
+        y(op) & 0b1
+    }
+
+    const INVALID: &'static str = "INVALID";
+    pub const CBPREFIX: &'static str = "(CB PREFIX)";
+
+    // todo: Instruction is constantly allocating heap strings, I feel like there
+    // should be a way to do this at compile time but I can't figure it out
+    #[allow(non_snake_case)]
+    pub fn decode(op: Byte) -> Instruction {
+        let _ALU_y = ALU[y(op) as usize % ALU.len()];
+        let _CC_y = CC[y(op) as usize % CC.len()];
+        let _R_y = R[y(op) as usize % R.len()];
+        let _R_z = R[z(op) as usize % R.len()];
+        let _RP_p = RP[p(op) as usize % RP.len()];
+        let _RP2_p = RP2[p(op) as usize % RP2.len()];
+        let _y8 = y(op) * 8;
+        match x(op) {
+            0 => match z(op) {
+                0 => match y(op) {
+                    0 => Instruction::new("NOP", 1),
+                    1 => Instruction::new("LD (nn), SP", 3),
+                    2 => Instruction::new("STOP", 1),
+                    3 => Instruction::new("JR n", 2),
+                    v @ 4..=7 => {
+                        let i: usize = (v - 4) as usize;
+                        let _CC_i: &'static str = CC[i];
+                        Instruction {
+                            mnm: format!("JR {_CC_i}, n"),
+                            len: 2,
+                        }
+                    }
+                    _ => Instruction::new(INVALID, 0),
+                },
+                1 => match q(op) {
+                    0 => Instruction {
+                        mnm: format!("LD {_RP_p}, nn"),
+                        len: 3,
+                    },
+                    1 => Instruction {
+                        mnm: format!("ADD HL, {_RP_p}"),
+                        len: 1,
+                    },
+                    _ => Instruction::new(INVALID, 0),
+                },
+                2 => match q(op) {
+                    0 => match p(op) {
+                        0 => Instruction::new("LD (BC), A", 1),
+                        1 => Instruction::new("LD (DE), A", 1),
+                        2 => Instruction::new("LD (HL+), A", 1),
+                        3 => Instruction::new("LD (HL-), A", 1),
+                        _ => Instruction::new(INVALID, 0),
+                    },
+                    1 => match p(op) {
+                        0 => Instruction::new("LD A, (BC)", 1),
+                        1 => Instruction::new("LD A, (DE)", 1),
+                        2 => Instruction::new("LD A, (HL+)", 1),
+                        3 => Instruction::new("LD A, (HL-)", 1),
+                        _ => Instruction::new(INVALID, 0),
+                    },
+                    _ => Instruction::new(INVALID, 0),
+                },
+                3 => match q(op) {
+                    0 => Instruction {
+                        mnm: format!("INC {_RP_p}"),
+                        len: 1,
+                    },
+                    1 => Instruction {
+                        mnm: format!("DEC, {_RP_p}"),
+                        len: 1,
+                    },
+                    _ => Instruction::new(INVALID, 0),
+                },
+                4 => Instruction {
+                    mnm: format!("INC {_R_y}"),
+                    len: 1,
+                },
+                5 => Instruction {
+                    mnm: format!("DEC {_R_y}"),
+                    len: 1,
+                },
+                6 => Instruction {
+                    mnm: format!("LD {_R_y}, n"),
+                    len: 2,
+                },
+                7 => match y(op) {
+                    0 => Instruction::new("RLCA", 1),
+                    1 => Instruction::new("RRCA", 1),
+                    2 => Instruction::new("RLA", 1),
+                    3 => Instruction::new("RRA", 1),
+                    4 => Instruction::new("DAA", 1),
+                    5 => Instruction::new("CPL", 1),
+                    6 => Instruction::new("SCF", 1),
+                    7 => Instruction::new("CCF", 1),
+                    _ => Instruction::new(INVALID, 0),
+                },
+                _ => Instruction::new(INVALID, 0),
+            },
+            1 => match z(op) {
+                6 => match y(op) {
+                    6 => Instruction::new("HALT", 1),
+                    _ => Instruction::new(INVALID, 0),
+                },
+                _ => Instruction {
+                    mnm: format!("LD {_R_y}, {_R_z}"),
+                    len: 1,
+                },
+            },
+            2 => Instruction {
+                mnm: format!("{_ALU_y} {_R_z}"),
+                len: 1,
+            },
+            3 => match z(op) {
+                0 => match y(op) {
+                    0..=3 => Instruction {
+                        mnm: format!("RET {_CC_y}"),
+                        len: 1,
+                    },
+                    4 => Instruction::new("LD (0xFF00 + n), A", 2),
+                    5 => Instruction::new("ADD SP, n", 2),
+                    6 => Instruction::new("LD A, (0xFF00 + n)", 2),
+                    7 => Instruction::new("LD HL, SP + n", 2),
+                    _ => Instruction::new(INVALID, 0),
+                },
+                1 => match q(op) {
+                    0 => Instruction {
+                        mnm: format!("POP {_RP2_p}"),
+                        len: 1,
+                    },
+                    1 => match p(op) {
+                        0 => Instruction::new("RET", 1),
+                        1 => Instruction::new("RETI", 1),
+                        2 => Instruction::new("JP HL", 1),
+                        3 => Instruction::new("LD SP, HL", 1),
+                        _ => Instruction::new(INVALID, 0),
+                    },
+                    _ => Instruction::new(INVALID, 0),
+                },
+                2 => match y(op) {
+                    0..=3 => Instruction {
+                        mnm: format!("JP {_CC_y}, nn"),
+                        len: 3,
+                    },
+                    4 => Instruction::new("LD (0xFF00 + C), A", 1),
+                    5 => Instruction::new("LD (nn), A", 3),
+                    6 => Instruction::new("LD A, (0xFF00 + C)", 1),
+                    7 => Instruction::new("LD A, (nn)", 3),
+                    _ => Instruction::new(INVALID, 0),
+                },
+                3 => match y(op) {
+                    0 => Instruction::new("JP nn", 3),
+                    1 => Instruction::new(CBPREFIX, 1),
+                    6 => Instruction::new("DI", 1),
+                    7 => Instruction::new("EI", 1),
+                    _ => Instruction::new(INVALID, 0),
+                },
+                4 => match y(op) {
+                    0..=3 => Instruction {
+                        mnm: format!("CALL {_CC_y}, nn"),
+                        len: 3,
+                    },
+                    _ => Instruction::new(INVALID, 0),
+                },
+                5 => match q(op) {
+                    0 => Instruction {
+                        mnm: format!("PUSH {_RP2_p}"),
+                        len: 1,
+                    },
+                    1 => match p(op) {
+                        0 => Instruction::new("CALL nn", 3),
+                        _ => Instruction::new(INVALID, 0),
+                    },
+                    _ => Instruction::new(INVALID, 0),
+                },
+                6 => Instruction {
+                    mnm: format!("{_ALU_y} n"),
+                    len: 2,
+                },
+                7 => Instruction {
+                    mnm: format!("RST {_y8:02x}H"),
+                    len: 1,
+                },
+                _ => todo!(),
+            },
+            _ => todo!(),
+        }
+    }
+
+    #[allow(non_snake_case)]
+    pub fn decodeCB(op: Byte) -> InstructionCB {
+        let _ROT_y = ROT[y(op) as usize];
+        let _R_z = R_ID[z(op) as usize];
+        let _y = y(op);
+        match x(op) {
+            0 => InstructionCB {
+                // mnm: format!("{_ROT_y} {_R_z}"),
+                opcode: _ROT_y,
+                bit: 0xFF,
+                reg: _R_z,
+            },
+            1 => InstructionCB {
+                // mnm: format!("BIT {_y}, {_R_z}"),
+                opcode: "BIT",
+                bit: _y,
+                reg: _R_z,
+            },
+            2 => InstructionCB {
+                // mnm: format!("RES {_y}, {_R_z}"),
+                opcode: "RES",
+                bit: _y,
+                reg: _R_z,
+            },
+            3 => InstructionCB {
+                // mnm: format!("SET {_y}, {_R_z}"),
+                opcode: "SET",
+                bit: _y,
+                reg: _R_z,
+            },
+            _ => InstructionCB {
+                opcode: "INVALID",
+                bit: 0xFF,
+                reg: usize::max_value(),
+            },
+        }
+    }
+
+    #[cfg(test)]
+    mod tests_decode {
+        use super::*;
+        #[test]
+        fn test_xyzpq() {
+            let t = 0b11_010_001;
+            assert_eq!(x(t), 0b11);
+            assert_eq!(y(t), 0b010);
+            assert_eq!(z(t), 0b001);
+            assert_eq!(p(t), 0b01);
+            assert_eq!(q(t), 0b0);
+        }
+
+        #[test]
+        #[rustfmt::skip]
+        fn test_reg_b() {
+            assert_eq!(decodeCB(0x00), InstructionCB{opcode:"RLC",  bit: 0xff, reg: REG_B});
+            assert_eq!(decodeCB(0x10), InstructionCB{opcode:"RL",   bit: 0xff, reg: REG_B});
+            assert_eq!(decodeCB(0x20), InstructionCB{opcode:"SLA",  bit: 0xff, reg: REG_B});
+            assert_eq!(decodeCB(0x30), InstructionCB{opcode:"SWAP", bit: 0xff, reg: REG_B});
+            assert_eq!(decodeCB(0x40), InstructionCB{opcode:"BIT",  bit: 0,    reg: REG_B});
+            assert_eq!(decodeCB(0x50), InstructionCB{opcode:"BIT",  bit: 2,    reg: REG_B});
+            assert_eq!(decodeCB(0x60), InstructionCB{opcode:"BIT",  bit: 4,    reg: REG_B});
+            assert_eq!(decodeCB(0x70), InstructionCB{opcode:"BIT",  bit: 6,    reg: REG_B});
+            assert_eq!(decodeCB(0x80), InstructionCB{opcode:"RES",  bit: 0,    reg: REG_B});
+            assert_eq!(decodeCB(0x90), InstructionCB{opcode:"RES",  bit: 2,    reg: REG_B});
+            assert_eq!(decodeCB(0xA0), InstructionCB{opcode:"RES",  bit: 4,    reg: REG_B});
+            assert_eq!(decodeCB(0xB0), InstructionCB{opcode:"RES",  bit: 6,    reg: REG_B});
+            assert_eq!(decodeCB(0xC0), InstructionCB{opcode:"SET",  bit: 0,    reg: REG_B});
+            assert_eq!(decodeCB(0xD0), InstructionCB{opcode:"SET",  bit: 2,    reg: REG_B});
+            assert_eq!(decodeCB(0xE0), InstructionCB{opcode:"SET",  bit: 4,    reg: REG_B});
+            assert_eq!(decodeCB(0xF0), InstructionCB{opcode:"SET",  bit: 6,    reg: REG_B});
+            
+            assert_eq!(decodeCB(0x08), InstructionCB{opcode:"RRC",  bit: 0xff, reg: REG_B});
+            assert_eq!(decodeCB(0x18), InstructionCB{opcode:"RR",   bit: 0xff, reg: REG_B});
+            assert_eq!(decodeCB(0x28), InstructionCB{opcode:"SRA",  bit: 0xff, reg: REG_B});
+            assert_eq!(decodeCB(0x38), InstructionCB{opcode:"SRL",  bit: 0xff, reg: REG_B});
+            assert_eq!(decodeCB(0x48), InstructionCB{opcode:"BIT",  bit: 1,    reg: REG_B});
+            assert_eq!(decodeCB(0x58), InstructionCB{opcode:"BIT",  bit: 3,    reg: REG_B});
+            assert_eq!(decodeCB(0x68), InstructionCB{opcode:"BIT",  bit: 5,    reg: REG_B});
+            assert_eq!(decodeCB(0x78), InstructionCB{opcode:"BIT",  bit: 7,    reg: REG_B});
+            assert_eq!(decodeCB(0x88), InstructionCB{opcode:"RES",  bit: 1,    reg: REG_B});
+            assert_eq!(decodeCB(0x98), InstructionCB{opcode:"RES",  bit: 3,    reg: REG_B});
+            assert_eq!(decodeCB(0xA8), InstructionCB{opcode:"RES",  bit: 5,    reg: REG_B});
+            assert_eq!(decodeCB(0xB8), InstructionCB{opcode:"RES",  bit: 7,    reg: REG_B});
+            assert_eq!(decodeCB(0xC8), InstructionCB{opcode:"SET",  bit: 1,    reg: REG_B});
+            assert_eq!(decodeCB(0xD8), InstructionCB{opcode:"SET",  bit: 3,    reg: REG_B});
+            assert_eq!(decodeCB(0xE8), InstructionCB{opcode:"SET",  bit: 5,    reg: REG_B});
+            assert_eq!(decodeCB(0xF8), InstructionCB{opcode:"SET",  bit: 7,    reg: REG_B});
+        }
+    }
+}
+
+pub mod io {
+    use crate::config::SplitAction;
+    use crate::types::Byte;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::path::{Path, PathBuf};
+
+    pub fn read_bytes(path: &str) -> std::io::Result<Vec<Byte>> {
+        let mut file = std::fs::File::open(path)?;
+        let info = file.metadata()?;
+
+        // todo: not sure if I actually want this but it made clippy happy
+        // consider instead #[allow(clippy::unused_io_amount)]
+        let mut rom: Vec<Byte> = vec![0; info.len() as usize];
+        file.read_exact(&mut rom)?;
+
+        Ok(rom)
+    }
+
+    fn is_archive(path: &Path) -> Option<&'static str> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "gz" => Some("gz"),
+            "zip" => Some("zip"),
+            _ => None,
+        }
+    }
+
+    /// Reads a rom, unpacking it first when it's in a .gz or .zip (the first .gb/.gbc inside)
+    pub fn read_rom(path: &str) -> std::io::Result<Vec<Byte>> {
+        let bytes = read_bytes(path)?;
+        match is_archive(Path::new(path)) {
+            Some("gz") => {
+                let mut rom = vec![];
+                flate2::read::GzDecoder::new(&bytes[..]).read_to_end(&mut rom)?;
+                Ok(rom)
+            }
+            Some(_) => unzip_rom(&bytes),
+            None => Ok(bytes),
+        }
+    }
+
+    /// Finds the first .gb/.gbc in a zip and inflates it
+    ///
+    /// Goes by the central directory at the end, since the headers in front of each file
+    /// can leave the sizes out.
+    fn unzip_rom(zip: &[Byte]) -> std::io::Result<Vec<Byte>> {
+        let invalid = |e: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+        let u16_at = |at: usize| -> std::io::Result<usize> {
+            let b = zip
+                .get(at..at + 2)
+                .ok_or_else(|| invalid("zip is cut short"))?;
+            Ok(u16::from_le_bytes([b[0], b[1]]) as usize)
+        };
+        let u32_at =
+            |at: usize| -> std::io::Result<usize> { Ok(u16_at(at)? | u16_at(at + 2)? << 16) };
+        // end of central directory record, up to 64KB of comment can follow it
+        let end = (0..zip.len().saturating_sub(21))
+            .rev()
+            .find(|&i| zip[i..].starts_with(b"PK\x05\x06"))
+            .ok_or_else(|| invalid("not a zip file"))?;
+        let mut entry = u32_at(end + 16)?;
+        for _ in 0..u16_at(end + 10)? {
+            let rest = zip
+                .get(entry..)
+                .ok_or_else(|| invalid("zip is cut short"))?;
+            if !rest.starts_with(b"PK\x01\x02") {
+                return Err(invalid("zip directory is corrupt"));
+            }
+            let method = u16_at(entry + 10)?;
+            let packed = u32_at(entry + 20)?;
+            let name_len = u16_at(entry + 28)?;
+            let local = u32_at(entry + 42)?;
+            let name = zip
+                .get(entry + 46..entry + 46 + name_len)
+                .ok_or_else(|| invalid("zip is cut short"))?;
+            let name = String::from_utf8_lossy(name).to_ascii_lowercase();
+            entry += 46 + name_len + u16_at(entry + 30)? + u16_at(entry + 32)?;
+            if !(name.ends_with(".gb") || name.ends_with(".gbc")) {
+                continue;
+            }
+
+            let start = local + 30 + u16_at(local + 26)? + u16_at(local + 28)?;
+            let data = zip
+                .get(start..start + packed)
+                .ok_or_else(|| invalid("zip is cut short"))?;
+            return match method {
+                0 => Ok(data.to_vec()),
+                8 => {
+                    let mut rom = vec![];
+                    flate2::read::DeflateDecoder::new(data).read_to_end(&mut rom)?;
+                    Ok(rom)
+                }
+                _ => Err(invalid("zip uses an unsupported compression method")),
+            };
+        }
+        Err(invalid("no .gb or .gbc in the zip"))
+    }
+
+    /// The rom's path, minus any .gz/.zip around it
+    fn unpacked_path(rom_path: &str) -> PathBuf {
+        let path = Path::new(rom_path);
+        match is_archive(path) {
+            Some(_) => path.with_extension(""),
+            None => path.to_path_buf(),
+        }
+    }
+
+    /// Per-game config, next to the rom
+    pub fn config_path_for(rom_path: &str) -> PathBuf {
+        unpacked_path(rom_path).with_extension("toml")
+    }
+
+    /// Where battery backed cartridge RAM is kept, next to the rom unless there's a shared
+    /// saves directory
+    pub fn sav_path_for(rom_path: &str, saves_dir: Option<&Path>) -> PathBuf {
+        let beside = unpacked_path(rom_path).with_extension("sav");
+        match (saves_dir, beside.file_name()) {
+            (Some(dir), Some(name)) => dir.join(name),
+            _ => beside,
+        }
+    }
+
+    /// Replaces a file in one step: writes a temp file next to it then renames it over
+    ///
+    /// Sync tools watching the directory never see a half written file, and a crash midway
+    /// leaves the old one intact.
+    pub fn write_atomic(path: &Path, bytes: &[Byte]) -> std::io::Result<()> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut file = std::fs::File::create(&tmp)?;
+        std::io::Write::write_all(&mut file, bytes)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)
+    }
+
+    /// Earlier copies of a file kept by write_with_backups, oldest first
+    pub fn backups_for(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+            return Ok(vec![]);
+        };
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        let prefix = format!("{}.", name.to_string_lossy());
+        let mut backups = vec![];
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?.path();
+            let Some(file) = entry.file_name().map(|f| f.to_string_lossy().into_owned()) else {
+                continue;
+            };
+            let stamp = file.strip_prefix(&prefix).and_then(|rest| {
+                rest.strip_suffix(".bak")
+                    .or_else(|| rest.strip_suffix(".bak.gz"))
+            });
+            if let Some(Ok(stamp)) = stamp.map(|s| s.parse::<u64>()) {
+                backups.push((stamp, entry));
+            }
+        }
+        backups.sort();
+        Ok(backups.into_iter().map(|(_, path)| path).collect())
+    }
+
+    /// Like write_atomic, but first moves what was there aside as `<name>.<unix time>.bak`
+    /// (gzipped to `.bak.gz` if asked), dropping all but the newest `keep` of those
+    ///
+    /// Nothing is backed up when the contents haven't changed, so opening and closing a
+    /// game a few times doesn't push the older saves out.
+    pub fn write_with_backups(
+        path: &Path,
+        bytes: &[Byte],
+        keep: usize,
+        compress: bool,
+        now: u64,
+    ) -> std::io::Result<()> {
+        let old = match std::fs::read(path) {
+            Ok(old) if old == bytes => return Ok(()),
+            Ok(old) => Some(old),
+            Err(_) => None,
+        };
+        if let Some(old) = old.filter(|_| keep > 0) {
+            let mut backup = path.as_os_str().to_owned();
+            backup.push(format!(".{now}.bak"));
+            if compress {
+                backup.push(".gz");
+                let mut enc = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+                std::io::Write::write_all(&mut enc, &old)?;
+                write_atomic(Path::new(&backup), &enc.finish()?)?;
+            } else {
+                write_atomic(Path::new(&backup), &old)?;
+            }
+            let backups = backups_for(path)?;
+            for stale in &backups[..backups.len().saturating_sub(keep)] {
+                std::fs::remove_file(stale)?;
+            }
+        }
+        write_atomic(path, bytes)
+    }
+
+    /// Where LiveSplit's server component listens by default
+    pub const LIVESPLIT_ADDR: &str = "127.0.0.1:16834";
+
+    /// Sends triggers' split actions to LiveSplit Server (started from LiveSplit's Control
+    /// menu), so runs autosplit without a memory reader watching the emulator
+    pub struct LiveSplit<W: Write> {
+        out: W,
+    }
+
+    impl LiveSplit<std::net::TcpStream> {
+        pub fn connect(addr: &str) -> std::io::Result<Self> {
+            let stream = std::net::TcpStream::connect(addr)?;
+            stream.set_nodelay(true)?;
+            Ok(LiveSplit::new(stream))
+        }
+    }
+
+    impl<W: Write> LiveSplit<W> {
+        pub fn new(out: W) -> Self {
+            LiveSplit { out }
+        }
+
+        /// One command per line, as the server reads them
+        pub fn send(&mut self, action: SplitAction) -> std::io::Result<()> {
+            let command = match action {
+                SplitAction::Start => "starttimer",
+                SplitAction::Split => "split",
+                SplitAction::Reset => "reset",
+            };
+            self.out.write_all(format!("{}\r\n", command).as_bytes())?;
+            self.out.flush()
+        }
+
+        pub fn get_ref(&self) -> &W {
+            &self.out
+        }
+    }
+
+    // RIFF header, fmt chunk and the data chunk's header
+    const WAV_HEADER_LEN: u32 = 44;
+
+    /// Streams interleaved stereo samples (see Apu::take_samples) into a 16 bit .wav
+    ///
+    /// The header's sizes aren't known until the end, finish() fills them in (dropping the
+    /// writer does too, ignoring errors).
+    pub struct WavWriter<W: Write + Seek> {
+        out: W,
+        sample_rate: u32,
+        data_len: u32,
+    }
+
+    impl WavWriter<std::io::BufWriter<std::fs::File>> {
+        pub fn create(path: &Path, sample_rate: u32) -> std::io::Result<Self> {
+            WavWriter::new(
+                std::io::BufWriter::new(std::fs::File::create(path)?),
+                sample_rate,
+            )
+        }
+    }
+
+    impl<W: Write + Seek> WavWriter<W> {
+        pub fn new(out: W, sample_rate: u32) -> std::io::Result<Self> {
+            let mut wav = WavWriter {
+                out,
+                sample_rate,
+                data_len: 0,
+            };
+            wav.write_header()?;
+            Ok(wav)
+        }
+
+        fn write_header(&mut self) -> std::io::Result<()> {
+            let channels: u16 = 2;
+            let block_align = channels * 2;
+            let mut header = vec![];
+            header.extend(b"RIFF");
+            header.extend((WAV_HEADER_LEN - 8 + self.data_len).to_le_bytes());
+            header.extend(b"WAVEfmt ");
+            header.extend(16u32.to_le_bytes());
+            header.extend(1u16.to_le_bytes()); // PCM
+            header.extend(channels.to_le_bytes());
+            header.extend(self.sample_rate.to_le_bytes());
+            header.extend((self.sample_rate * block_align as u32).to_le_bytes());
+            header.extend(block_align.to_le_bytes());
+            header.extend(16u16.to_le_bytes());
+            header.extend(b"data");
+            header.extend(self.data_len.to_le_bytes());
+            self.out.write_all(&header)
+        }
+
+        /// Fails without writing anything once the file would outgrow what the header can
+        /// hold (4GB, about 6 hours at 48kHz), what's been written so far is still good
+        pub fn write(&mut self, samples: &[f32]) -> std::io::Result<()> {
+            let pcm: Vec<u8> = samples
+                .iter()
+                .flat_map(|s| ((s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes())
+                .collect();
+            let data_len = self.data_len as usize + pcm.len();
+            if data_len > (u32::MAX - (WAV_HEADER_LEN - 8)) as usize {
+                return Err(std::io::Error::other("the .wav is full"));
+            }
+            self.out.write_all(&pcm)?;
+            self.data_len = data_len as u32;
+            Ok(())
+        }
+
+        /// Fills in the header, more can still be written after
+        pub fn finish(&mut self) -> std::io::Result<()> {
+            self.out.seek(SeekFrom::Start(0))?;
+            self.write_header()?;
+            self.out.seek(SeekFrom::End(0))?;
+            self.out.flush()
+        }
+
+        pub fn get_ref(&self) -> &W {
+            &self.out
+        }
+    }
+
+    impl<W: Write + Seek> Drop for WavWriter<W> {
+        fn drop(&mut self) {
+            let _ = self.finish();
+        }
+    }
+
+    #[cfg(test)]
+    mod tests_io {
+        use super::*;
+
+        #[test]
+        fn test_wav_writer() {
+            let mut wav = WavWriter::new(std::io::Cursor::new(vec![]), 48000).unwrap();
+            wav.write(&[0.0, 1.0]).unwrap();
+            wav.write(&[-1.0, 2.0]).unwrap();
+            wav.finish().unwrap();
+            let bytes = wav.get_ref().get_ref();
+            assert_eq!(bytes.len(), WAV_HEADER_LEN as usize + 8);
+            assert_eq!(&bytes[0..4], b"RIFF");
+            assert_eq!(bytes[4..8], (36u32 + 8).to_le_bytes());
+            assert_eq!(&bytes[8..16], b"WAVEfmt ");
+            assert_eq!(bytes[24..28], 48000u32.to_le_bytes());
+            assert_eq!(bytes[40..44], 8u32.to_le_bytes());
+            let pcm: Vec<i16> = bytes[44..]
+                .chunks(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                .collect();
+            assert_eq!(pcm, vec![0, i16::MAX, -i16::MAX, i16::MAX]);
+
+            // up to where the header's sizes would overflow
+            let full = u32::MAX - (WAV_HEADER_LEN - 8);
+            wav.data_len = full - 4;
+            wav.write(&[0.0, 0.0]).unwrap();
+            assert!(wav.write(&[0.0, 0.0]).is_err());
+            assert_eq!(wav.data_len, full);
+        }
+
+        #[test]
+        fn test_livesplit() {
+            let mut livesplit = LiveSplit::new(vec![]);
+            for action in [SplitAction::Start, SplitAction::Split, SplitAction::Reset] {
+                livesplit.send(action).unwrap();
+            }
+            assert_eq!(livesplit.get_ref(), b"starttimer\r\nsplit\r\nreset\r\n");
+        }
+
+        #[test]
+        fn test_sav_path_for() {
+            assert_eq!(
+                sav_path_for("roms/zelda.gb", None),
+                PathBuf::from("roms/zelda.sav")
+            );
+            let saves = Path::new("/saves");
+            assert_eq!(
+                sav_path_for("roms/zelda.gb", Some(saves)),
+                PathBuf::from("/saves/zelda.sav")
+            );
+        }
+
+        #[test]
+        fn test_archive_paths() {
+            assert_eq!(
+                sav_path_for("roms/zelda.gb.gz", None),
+                PathBuf::from("roms/zelda.sav")
+            );
+            assert_eq!(
+                sav_path_for("roms/zelda.zip", None),
+                PathBuf::from("roms/zelda.sav")
+            );
+            assert_eq!(
+                config_path_for("roms/zelda.GBC.zip"),
+                PathBuf::from("roms/zelda.toml")
+            );
+        }
+
+        #[test]
+        fn test_read_rom_archives() {
+            use std::io::Write;
+            let rom: Vec<Byte> = (0..=255).cycle().take(0x8000).collect();
+            let dir = std::env::temp_dir().join("cerboy_test_read_rom_archives");
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let gz = dir.join("game.gb.gz");
+            let mut enc = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+            enc.write_all(&rom).unwrap();
+            std::fs::write(&gz, enc.finish().unwrap()).unwrap();
+            assert_eq!(read_rom(gz.to_str().unwrap()).unwrap(), rom);
+
+            // a readme first, then the rom deflated
+            let mut enc = flate2::write::DeflateEncoder::new(vec![], flate2::Compression::best());
+            enc.write_all(&rom).unwrap();
+            let packed = enc.finish().unwrap();
+            let files: [(&str, u16, &[Byte], usize); 2] = [
+                ("README.txt", 0, b"hi", 2),
+                ("Game.GB", 8, &packed, rom.len()),
+            ];
+            let (mut zip, mut dir_entries) = (vec![], vec![]);
+            for (name, method, data, size) in files {
+                let offset = zip.len() as u32;
+                zip.extend(b"PK\x03\x04");
+                zip.extend([20, 0, 0, 0]);
+                zip.extend(method.to_le_bytes());
+                zip.extend([0; 8]); // time, date, crc (not checked)
+                zip.extend((data.len() as u32).to_le_bytes());
+                zip.extend((size as u32).to_le_bytes());
+                zip.extend((name.len() as u16).to_le_bytes());
+                zip.extend([0, 0]);
+                zip.extend(name.as_bytes());
+                zip.extend(data);
+
+                dir_entries.extend(b"PK\x01\x02");
+                dir_entries.extend([20, 0, 20, 0, 0, 0]);
+                dir_entries.extend(method.to_le_bytes());
+                dir_entries.extend([0; 8]);
+                dir_entries.extend((data.len() as u32).to_le_bytes());
+                dir_entries.extend((size as u32).to_le_bytes());
+                dir_entries.extend((name.len() as u16).to_le_bytes());
+                dir_entries.extend([0; 12]);
+                dir_entries.extend(offset.to_le_bytes());
+                dir_entries.extend(name.as_bytes());
+            }
+            let dir_offset = zip.len() as u32;
+            zip.extend(&dir_entries);
+            zip.extend(b"PK\x05\x06");
+            zip.extend([0; 4]);
+            zip.extend([2, 0, 2, 0]);
+            zip.extend((dir_entries.len() as u32).to_le_bytes());
+            zip.extend(dir_offset.to_le_bytes());
+            zip.extend([0, 0]);
+            let path = dir.join("game.zip");
+            std::fs::write(&path, &zip).unwrap();
+            assert_eq!(read_rom(path.to_str().unwrap()).unwrap(), rom);
+
+            // just the end record, its directory is past the end of the file
+            let cut = &zip[zip.len() - 22..];
+            let e = unzip_rom(cut).unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn test_write_atomic() {
+            let dir = std::env::temp_dir().join("cerboy_test_write_atomic");
+            let _ = std::fs::remove_dir_all(&dir);
+            let path = dir.join("game.sav");
+            write_atomic(&path, &[1, 2, 3]).unwrap();
+            write_atomic(&path, &[4, 5]).unwrap();
+            assert_eq!(std::fs::read(&path).unwrap(), vec![4, 5]);
+            // only the save is left behind
+            assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn test_write_with_backups() {
+            let dir = std::env::temp_dir().join("cerboy_test_write_with_backups");
+            let _ = std::fs::remove_dir_all(&dir);
+            let path = dir.join("game.sav");
+            for (now, save) in (100..108).zip(0..) {
+                write_with_backups(&path, &[save], 3, now == 107, now).unwrap();
+            }
+            // rewriting the same thing doesn't take a backup
+            write_with_backups(&path, &[7], 3, false, 108).unwrap();
+            assert_eq!(std::fs::read(&path).unwrap(), vec![7]);
+
+            let backups = backups_for(&path).unwrap();
+            let names: Vec<_> = backups
+                .iter()
+                .map(|b| b.file_name().unwrap().to_string_lossy().into_owned())
+                .collect();
+            assert_eq!(
+                names,
+                [
+                    "game.sav.105.bak",
+                    "game.sav.106.bak",
+                    "game.sav.107.bak.gz"
+                ]
+            );
+            let mut newest = vec![];
+            let packed = std::fs::read(&backups[2]).unwrap();
+            flate2::read::GzDecoder::new(&packed[..])
+                .read_to_end(&mut newest)
+                .unwrap();
+            assert_eq!(newest, vec![6]);
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
+
+pub mod bits {
+    use crate::types::{Byte, SByte, Word};
+
+    // bit masks
+    pub const BIT_0: Byte = 1 << 0;
+    pub const BIT_1: Byte = 1 << 1;
+    pub const BIT_2: Byte = 1 << 2;
+    pub const BIT_3: Byte = 1 << 3;
+    pub const BIT_4: Byte = 1 << 4;
+    pub const BIT_5: Byte = 1 << 5;
+    pub const BIT_6: Byte = 1 << 6;
+    pub const BIT_7: Byte = 1 << 7;
+
+    pub const HIGH_MASK: Word = 0xFF00;
+    pub const LOW_MASK: Word = 0x00FF;
+    pub const HIGH_MASK_NIB: Byte = 0xF0;
+    pub const LOW_MASK_NIB: Byte = 0x0F;
+
+    pub const fn hi(reg: Word) -> Byte {
+        (reg >> Byte::BITS) as Byte
+    }
+
+    pub const fn lo(reg: Word) -> Byte {
+        (reg & LOW_MASK) as Byte
+    }
+
+    pub const fn combine(high: Byte, low: Byte) -> Word {
+        (high as Word) << Byte::BITS | (low as Word)
+    }
+
+    pub const fn fl_set(flag: Byte, set: bool) -> Byte {
+        (set as u8) * flag
+    }
+
+    pub const fn fl_z(val: Byte) -> Byte {
+        fl_set(crate::cpu::FL_Z, val == 0)
+    }
+
+    pub const fn bit(idx: Byte, val: Byte) -> Byte {
+        (val >> idx) & 1
+    }
+
+    pub const fn bit_test(idx: Byte, val: Byte) -> bool {
+        bit(idx, val) != 0
+    }
+
+    pub const fn bit_set(idx: Byte, val: Byte, set: bool) -> Byte {
+        if set {
+            val | idx
+        } else {
+            val & !idx
+        }
+    }
+
+    #[test]
+    fn test_bit_test() {
+        let x: Byte = 0b00000101;
+        assert_eq!(bit_test(7, x), false);
+        assert_eq!(bit_test(6, x), false);
+        assert_eq!(bit_test(5, x), false);
+        assert_eq!(bit_test(4, x), false);
+        assert_eq!(bit_test(3, x), false);
+        assert_eq!(bit_test(2, x), true);
+        assert_eq!(bit_test(1, x), false);
+        assert_eq!(bit_test(0, x), true);
+    }
+
+    // can't be const for some reason https://github.com/rust-lang/rust/issues/53605
+    pub fn signed(val: Byte) -> SByte {
+        unsafe { std::mem::transmute(val) }
+    }
+}
+
+pub mod dbg {
+    use std::collections::HashSet;
+    use std::fs;
+    #[cfg(feature = "instrument")]
+    use std::fs::File;
+    #[cfg(feature = "instrument")]
+    use std::io::{BufWriter, Write};
+    use std::sync::{Arc, Mutex};
+
+    use crate::apu::{Apu, NR50_BIT_VIN_LEFT, NR50_BIT_VIN_RIGHT, SCOPE_LEN};
+    use crate::bits::{BIT_0, BIT_1};
+    use crate::cpu::*;
+    use crate::lcd::*;
+    use crate::memory::*;
+    use crate::osd::draw_text;
+    use crate::types::*;
+
+    /// True when built with the `instrument` feature
+    ///
+    /// Debug-only checks are written as `if INSTRUMENT && ...` so they fold away entirely
+    /// in builds without the feature.
+    pub const INSTRUMENT: bool = cfg!(feature = "instrument");
+
+    /// FNV-1a over a fixed byte order, the same machine state hashes the same on any host
+    ///
+    /// Only hardware state goes in, not host side things like caches or debug switches.
+    /// See Emulator::state_hash.
+    pub struct StateHash(u64);
+
+    impl StateHash {
+        pub fn new() -> StateHash {
+            StateHash(0xCBF2_9CE4_8422_2325)
+        }
+        pub fn bytes(&mut self, bytes: &[Byte]) {
+            for b in bytes {
+                self.0 = (self.0 ^ *b as u64).wrapping_mul(0x0000_0100_0000_01B3);
+            }
+        }
+        pub fn u64(&mut self, val: u64) {
+            self.bytes(&val.to_le_bytes());
+        }
+        pub fn finish(&self) -> u64 {
+            self.0
+        }
+    }
+
+    /// Where the time goes, see Profiler
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum Section {
+        Cpu,
+        Ppu,
+        Apu,
+        /// filters and handing the frame to the window
+        Present,
+        /// sleeping until the next frame is due, see pacing::Pacer
+        Wait,
+    }
+
+    impl Section {
+        const ALL: [Section; 5] = [
+            Section::Cpu,
+            Section::Ppu,
+            Section::Apu,
+            Section::Present,
+            Section::Wait,
+        ];
+
+        fn name(self) -> &'static str {
+            match self {
+                Section::Cpu => "cpu",
+                Section::Ppu => "ppu",
+                Section::Apu => "apu",
+                Section::Present => "present",
+                Section::Wait => "wait",
+            }
+        }
+    }
+
+    /// Time spent in each Section since profiling started (or was last taken)
+    #[derive(Clone, Debug)]
+    pub struct Profile {
+        spent: [std::time::Duration; Section::ALL.len()],
+        since: std::time::Instant,
+    }
+
+    impl Profile {
+        fn new() -> Profile {
+            Profile {
+                spent: Default::default(),
+                since: std::time::Instant::now(),
+            }
+        }
+
+        /// Share of the time since profiling started, 0.0..=1.0
+        pub fn share(&self, section: Section) -> f64 {
+            let total = self.since.elapsed().as_secs_f64();
+            if total == 0.0 {
+                return 0.0;
+            }
+            self.spent[section as usize].as_secs_f64() / total
+        }
+    }
+
+    /// e.g. "cpu 41.0% ppu 30.2% apu 4.1% present 9.5% wait 12.0% other 3.2%"
+    impl std::fmt::Display for Profile {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            let mut other = 1.0;
+            for section in Section::ALL {
+                let share = self.share(section);
+                other -= share;
+                write!(f, "{} {:.1}% ", section.name(), share * 100.0)?;
+            }
+            write!(f, "other {:.1}%", other.max(0.0) * 100.0)
+        }
+    }
+
+    /// Times the Sections into a Profile while on (only with the `instrument` feature,
+    /// otherwise nothing is ever timed). The emulator keeps one, see Emulator::profiler
+    #[derive(Clone, Debug)]
+    pub struct Profiler {
+        on: bool,
+        profile: Profile,
+    }
+
+    impl Profiler {
+        pub fn new() -> Profiler {
+            Profiler {
+                on: false,
+                profile: Profile::new(),
+            }
+        }
+
+        /// Starts (or stops) timing, either way starting the profile over
+        pub fn set_on(&mut self, on: bool) {
+            self.on = INSTRUMENT && on;
+            self.take();
+        }
+
+        pub fn on(&self) -> bool {
+            self.on
+        }
+
+        /// The time spent so far, starting over
+        pub fn take(&mut self) -> Profile {
+            std::mem::replace(&mut self.profile, Profile::new())
+        }
+
+        /// The time a section starts, when profiling. Hand it to stop() at the end of the
+        /// section; sections shouldn't nest, the time would count twice
+        pub fn start(&self) -> Option<std::time::Instant> {
+            self.on.then(std::time::Instant::now)
+        }
+
+        /// See start()
+        pub fn stop(&mut self, section: Section, start: Option<std::time::Instant>) {
+            if let Some(start) = start {
+                self.profile.spent[section as usize] += start.elapsed();
+            }
+        }
+    }
+
+    impl Default for Profiler {
+        fn default() -> Self {
+            Profiler::new()
+        }
+    }
+
+    impl Default for StateHash {
+        fn default() -> Self {
+            StateHash::new()
+        }
+    }
+
+    #[cfg(feature = "instrument")]
+    pub struct CPULog {
+        cpu: CPUState,
+        mem_next: [Byte; 4],
+    }
+
+    #[cfg(feature = "instrument")]
+    impl std::fmt::Display for CPULog {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+                self.cpu.reg[REG_A],
+                self.cpu.reg[FLAGS],
+                self.cpu.reg[REG_B],
+                self.cpu.reg[REG_C],
+                self.cpu.reg[REG_D],
+                self.cpu.reg[REG_E],
+                self.cpu.reg[REG_H],
+                self.cpu.reg[REG_L],
+                self.cpu.sp,
+                self.cpu.pc,
+                self.mem_next[0],
+                self.mem_next[1],
+                self.mem_next[2],
+                self.mem_next[3]
+            )
+        }
+    }
+
+    #[cfg(feature = "instrument")]
+    pub fn log_cpu(buffer: &mut Vec<CPULog>, cpu: &CPUState, mem: &Memory) {
+        buffer.push(CPULog {
+            cpu: cpu.clone(),
+            mem_next: [
+                mem.read(cpu.pc + 0),
+                mem.read(cpu.pc + 1),
+                mem.read(cpu.pc + 2),
+                mem.read(cpu.pc + 3),
+            ],
+        });
+    }
+
+    #[cfg(feature = "instrument")]
+    pub fn write_cpu_logs(logs: &Vec<CPULog>) -> std::io::Result<()> {
+        let f = File::create("cpu.log")?;
+        let mut writer = BufWriter::with_capacity(1 << 16, f);
+        for log in logs {
+            writeln!(writer, "{}", log)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// The stack belongs in work ram or high ram, anywhere else it's trampling something
+    pub fn stack_ok(addr: Word) -> bool {
+        (MEM_WRAM_0..MEM_ECHO).contains(&addr) || (MEM_HRAM..IE).contains(&addr)
+    }
+
+    /// The stack wandered somewhere it shouldn't, see Memory::stack_guard
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum StackFault {
+        /// SP was moved into VRAM/OAM/IO
+        Sp { pc: Word, sp: Word },
+        /// a push wrote outside WRAM/HRAM
+        Push { pc: Word, addr: Word },
+    }
+
+    impl StackFault {
+        /// SP pointing into memory the stack would corrupt (it's fine for it to sit at the
+        /// top of a region, e.g. FFFE, since pushes predecrement)
+        pub fn check_sp(pc: Word, sp: Word) -> Option<StackFault> {
+            let bad = (MEM_VRAM..MEM_EXT).contains(&sp) || (MEM_OAM..MEM_HRAM).contains(&sp);
+            if bad {
+                Some(StackFault::Sp { pc, sp })
+            } else {
+                None
+            }
+        }
+    }
+
+    impl std::fmt::Display for StackFault {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self {
+                StackFault::Sp { pc, sp } => write!(f, "stack fault @ {pc:04X}: SP moved to {sp:04X}"),
+                StackFault::Push { pc, addr } => write!(f, "stack fault @ {pc:04X}: push to {addr:04X}"),
+            }
+        }
+    }
+
+    /// Stops emulation when the cpu writes somewhere, see Memory::watches
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub struct Watchpoint {
+        pub start: Word,
+        pub end: Word, // inclusive
+        /// Only while the ppu is in this mode (0-3)
+        pub mode: Option<Byte>,
+    }
+
+    impl Watchpoint {
+        pub fn hit(&self, addr: Word, mode: Byte) -> bool {
+            (self.start..=self.end).contains(&addr) && self.mode.is_none_or(|m| m == mode)
+        }
+    }
+
+    /// A write that tripped a watchpoint
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub struct WatchHit {
+        pub pc: Word,
+        pub addr: Word,
+        pub val: Byte,
+        pub mode: Byte, // ppu mode at the time
+    }
+
+    impl std::fmt::Display for WatchHit {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            let (pc, addr, val, mode) = (self.pc, self.addr, self.val, self.mode);
+            let name = match (io_name(addr), mirror_of(addr)) {
+                (Some(n), _) => format!(" ({n})"),
+                (None, Some(ram)) if addr >= MEM_ECHO => format!(" (echo of {ram:04X})"),
+                _ => String::new(),
+            };
+            write!(
+                f,
+                "watchpoint @ {pc:04X}: wrote {val:02X} to {addr:04X}{name} in ppu mode {mode}"
+            )
+        }
+    }
+
+    /// The usual suspects, as watchpoints
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum WatchPreset {
+        OamWrite,
+        /// the ppu owns VRAM while drawing, writes get dropped on hardware
+        VramWriteDuringMode3,
+        IfWrite,
+        HramWrite,
+        IoWrite,
+    }
+
+    impl WatchPreset {
+        pub fn watchpoints(&self) -> Vec<Watchpoint> {
+            let range = |start, end, mode| Watchpoint { start, end, mode };
+            match self {
+                WatchPreset::OamWrite => vec![range(MEM_OAM, MEM_NOT_USABLE - 1, None)],
+                WatchPreset::VramWriteDuringMode3 => vec![range(MEM_VRAM, MEM_EXT - 1, Some(3))],
+                WatchPreset::IfWrite => vec![range(IF, IF, None)],
+                WatchPreset::HramWrite => vec![range(MEM_HRAM, IE - 1, None)],
+                WatchPreset::IoWrite => vec![range(MEM_IO_PORTS, MEM_HRAM - 1, None)],
+            }
+        }
+    }
+
+    impl std::str::FromStr for WatchPreset {
+        type Err = String;
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s.trim_start_matches("break-on-") {
+                "oam-write" => Ok(WatchPreset::OamWrite),
+                "vram-write-during-mode3" => Ok(WatchPreset::VramWriteDuringMode3),
+                "if-write" => Ok(WatchPreset::IfWrite),
+                "hram-write" => Ok(WatchPreset::HramWrite),
+                "io-write" => Ok(WatchPreset::IoWrite),
+                _ => Err(format!(
+                    "unknown watchpoint preset {} (oam-write, vram-write-during-mode3, \
+                     if-write, hram-write, io-write)",
+                    s
+                )),
+            }
+        }
+    }
+
+    /// Something a rom does that works, but probably not the way its author meant, see
+    /// Memory::lint
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    pub enum Lint {
+        /// work or high ram read before anything was written there, garbage on hardware
+        UninitializedRead(Word),
+        /// a write to ROM on a cartridge without a mapper to take it
+        RomWrite(Word),
+        /// the cpu locks up on these
+        InvalidOpcode(Byte),
+        /// LCDC turned the window on with WX/WY past the edge of the screen
+        WindowOffscreen { wx: Byte, wy: Byte },
+    }
+
+    /// A lint and the instruction that caused it
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub struct LintWarning {
+        pub lint: Lint,
+        pub bank: usize,
+        pub pc: Word,
+    }
+
+    impl std::fmt::Display for LintWarning {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "warning @ {:02X}:{:04X}: ", self.bank, self.pc)?;
+            match self.lint {
+                Lint::UninitializedRead(addr) => {
+                    write!(f, "read {addr:04X} before anything was written there")
+                }
+                Lint::RomWrite(addr) => {
+                    write!(
+                        f,
+                        "wrote to ROM at {addr:04X}, but the cartridge has no mapper"
+                    )
+                }
+                Lint::InvalidOpcode(op) => write!(f, "invalid opcode {op:02X} locks up the cpu"),
+                Lint::WindowOffscreen { wx, wy } => {
+                    write!(f, "turned the window on off screen (WX {wx}, WY {wy})")
+                }
+            }
+        }
+    }
+
+    /// Turns lints into warnings, each kind only once per instruction so loops don't flood
+    #[derive(Clone, Default)]
+    pub struct Linter {
+        seen: HashSet<(std::mem::Discriminant<Lint>, usize, Word)>,
+        warnings: Vec<LintWarning>,
+    }
+
+    impl Linter {
+        pub fn add(&mut self, lint: Lint, bank: usize, pc: Word) {
+            if self.seen.insert((std::mem::discriminant(&lint), bank, pc)) {
+                self.warnings.push(LintWarning { lint, bank, pc });
+            }
+        }
+
+        /// The warnings added since the last call
+        pub fn take(&mut self) -> Vec<LintWarning> {
+            std::mem::take(&mut self.warnings)
+        }
+    }
+
+    /// A ROM byte that ran as (part of) an instruction, see RomUsage
+    pub const ROM_USAGE_CODE: Byte = BIT_0;
+    /// A ROM byte the cpu read as data. Fetching instructions doesn't count (see
+    /// Memory::read_fetch), so a byte that's both was run and read, a jump table say
+    pub const ROM_USAGE_DATA: Byte = BIT_1;
+    // stretches of unused ROM shorter than this are left out of the report
+    const ROM_USAGE_MIN_GAP: usize = 16;
+
+    /// Which ROM bytes a session ran or read, for finding dead code and data in a rom hack and
+    /// checking a mapper switches in the banks it should. See Memory::set_rom_usage
+    ///
+    /// One ROM_USAGE_* flags byte per ROM byte, laid out like the code/data logs (.cdl) other
+    /// emulators write.
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub struct RomUsage {
+        flags: Vec<Byte>,
+    }
+
+    /// Shared between clones of a Memory, so rewinding doesn't forget what already ran
+    pub type SharedRomUsage = Arc<Mutex<RomUsage>>;
+
+    impl RomUsage {
+        pub fn new(rom_len: usize) -> RomUsage {
+            RomUsage {
+                flags: vec![0; rom_len],
+            }
+        }
+
+        pub fn mark(&mut self, offset: usize, flag: Byte) {
+            if offset >= self.flags.len() {
+                self.flags.resize(offset + 1, 0);
+            }
+            self.flags[offset] |= flag;
+        }
+
+        pub fn flags(&self) -> &[Byte] {
+            &self.flags
+        }
+
+        /// Bytes of a bank that ran as code, were only read as data and were never touched
+        pub fn bank_counts(&self, bank: usize) -> (usize, usize, usize) {
+            let flags = self.flags.chunks(BANK_SIZE).nth(bank).unwrap_or_default();
+            let code = flags.iter().filter(|f| *f & ROM_USAGE_CODE != 0).count();
+            let unused = flags.iter().filter(|f| **f == 0).count();
+            (code, flags.len() - code - unused, unused)
+        }
+
+        /// Runs of ROM used the same way (code, data or not at all) as (offset, len, flags),
+        /// with anything that ran counted as code
+        pub fn ranges(&self) -> Vec<(usize, usize, Byte)> {
+            let kind = |f: Byte| {
+                if f & ROM_USAGE_CODE != 0 {
+                    ROM_USAGE_CODE
+                } else {
+                    f
+                }
+            };
+            let mut ranges: Vec<(usize, usize, Byte)> = vec![];
+            for (offset, &f) in self.flags.iter().enumerate() {
+                match ranges.last_mut() {
+                    Some((_, len, last)) if *last == kind(f) => *len += 1,
+                    _ => ranges.push((offset, 1, kind(f))),
+                }
+            }
+            ranges
+        }
+
+        /// A line per bank with how much of it was used, then the unused stretches (as
+        /// bank:addr ranges) worth a look
+        pub fn report(&self) -> String {
+            let mut out = String::new();
+            for bank in 0..self.flags.len().div_ceil(BANK_SIZE) {
+                let (code, data, unused) = self.bank_counts(bank);
+                out += &format!(
+                    "bank {:02X}: {:5} code {:5} data {:5} unused\n",
+                    bank, code, data, unused
+                );
+            }
+            let addr = |offset: usize| {
+                let bank = offset / BANK_SIZE;
+                let base = if bank == 0 { 0 } else { MEM_BANK_NN as usize };
+                format!("{:02X}:{:04X}", bank, base + offset % BANK_SIZE)
+            };
+            for (offset, len, flags) in self.ranges() {
+                if flags == 0 && len >= ROM_USAGE_MIN_GAP {
+                    let last = offset + len - 1;
+                    out += &format!("unused {}-{} ({} bytes)\n", addr(offset), addr(last), len);
+                }
+            }
+            out
+        }
+    }
+
+    pub fn dump(path: &str, mem: &Memory) -> std::io::Result<()> {
+        let bytes: Vec<Byte> = (0..=0xFFFF).map(|addr: Word| mem[addr]).collect();
+        fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    const VEC_NAMES: [&str; 5] = ["VBLANK", "STAT", "TIMER", "SERIAL", "JOYPAD"];
+
+    pub const fn str_interrupt(i: Word) -> &'static str {
+        let idx = (i - VEC_INT_VBLANK) / 0x08;
+        VEC_NAMES[idx as usize]
+    }
+
+    pub fn str_flags(flags: Byte) -> String {
+        format!(
+            "{}{}{}{}",
+            if flags & FL_C != 0 { "C" } else { "—" },
+            if flags & FL_H != 0 { "H" } else { "—" },
+            if flags & FL_N != 0 { "N" } else { "—" },
+            if flags & FL_Z != 0 { "Z" } else { "—" },
+        )
+    }
+
+    /// Wraps text in inverse video for terminals
+    fn highlight(text: &str) -> String {
+        format!("\x1b[7m{}\x1b[0m", text)
+    }
+
+    // memory changes listed per step before the rest are just counted
+    const STEP_DIFF_MAX_BYTES: usize = 16;
+
+    /// The registers after a single step with whatever changed highlighted, then the memory
+    /// bytes that changed (outside the cartridge)
+    pub fn step_diff(prev: &CPUState, prev_mem: &Memory, cpu: &CPUState, mem: &Memory) -> String {
+        let field = |name: &str, old: Word, new: Word, width: usize| {
+            let text = format!("{}:{:0width$X}", name, new, width = width);
+            if old == new {
+                text
+            } else {
+                highlight(&text)
+            }
+        };
+        let mut parts = vec![field(
+            "A",
+            prev.reg[REG_A] as Word,
+            cpu.reg[REG_A] as Word,
+            2,
+        )];
+        let flags: String = [(FL_Z, 'Z'), (FL_N, 'N'), (FL_H, 'H'), (FL_C, 'C')]
+            .iter()
+            .map(|&(fl, c)| {
+                let text = if cpu.reg[FLAGS] & fl != 0 { c } else { '-' }.to_string();
+                if (prev.reg[FLAGS] ^ cpu.reg[FLAGS]) & fl != 0 {
+                    highlight(&text)
+                } else {
+                    text
+                }
+            })
+            .collect();
+        parts.push(format!("F:{}", flags));
+        let regs = [
+            ("B", REG_B),
+            ("C", REG_C),
+            ("D", REG_D),
+            ("E", REG_E),
+            ("H", REG_H),
+            ("L", REG_L),
+        ];
+        for (name, reg) in regs {
+            parts.push(field(name, prev.reg[reg] as Word, cpu.reg[reg] as Word, 2));
+        }
+        parts.push(field("SP", prev.sp, cpu.sp, 4));
+        parts.push(field("PC", prev.pc, cpu.pc, 4));
+        parts.push(field("IME", prev.ime as Word, cpu.ime as Word, 1));
+        let mut out = parts.join(" ");
+
+        let changed: Vec<usize> = (0..MEM_SIZE)
+            .filter(|&i| prev_mem.data[i] != mem.data[i])
+            .collect();
+        if !changed.is_empty() {
+            let bytes: Vec<String> = changed
+                .iter()
+                .take(STEP_DIFF_MAX_BYTES)
+                .map(|&i| {
+                    let change = format!("{:02X}->{:02X}", prev_mem.data[i], mem.data[i]);
+                    // work RAM shows up in echo RAM too, say so before anyone goes looking
+                    let echo = mirror_of(i as Word)
+                        .map(|m| format!("/{:04X}", m))
+                        .unwrap_or_default();
+                    format!("{:04X}{} {}", i, echo, highlight(&change))
+                })
+                .collect();
+            out += &format!("\nmem: {}", bytes.join(", "));
+            if changed.len() > STEP_DIFF_MAX_BYTES {
+                out += &format!(" ({} more)", changed.len() - STEP_DIFF_MAX_BYTES);
+            }
+        }
+        out
+    }
+
+    #[rustfmt::skip]
+    pub fn print_lcdc(mem: &Memory) {
+        // print LCDC diagnostics
+        let lcdc_v = mem.read(LCDC);
+        let lcdc_7 = if lcdc_v & LCDC_BIT_ENABLE != 0                     { " on" }    else { "off" };
+        let lcdc_6 = if lcdc_v & LCDC_BIT_WINDOW_TILE_MAP_SELECT != 0     { "0x9C00" } else { "0x9800" };
+        let lcdc_5 = if lcdc_v & LCDC_BIT_WINDOW_ENABLE != 0              { " on" }    else { "off" };
+        let lcdc_4 = if lcdc_v & LCDC_BIT_BG_WINDOW_TILE_DATA_SELECT != 0 { "0x8000" } else { "0x8800" };
+        let lcdc_3 = if lcdc_v & LCDC_BIT_BG_TILE_MAP_SELECT != 0         { "0x9C00" } else { "0x9800" };
+        let lcdc_2 = if lcdc_v & LCDC_BIT_OBJ_SIZE != 0                   { "16" }     else { " 8" };
+        let lcdc_1 = if lcdc_v & LCDC_BIT_OBJ_ENABLE != 0                 { " on" }    else { "off" };
+        let lcdc_0 = if lcdc_v & LCDC_BIT_BG_WINDOW_ENABLE != 0           { " on" }    else { "off" };
+        println!("{lcdc_v:#10b} LCDC [scr: {lcdc_7}, wnd_map: {lcdc_6}, wnd: {lcdc_5}, bg/wnd_dat: {lcdc_4}, bg_map: {lcdc_3}, obj_sz: {lcdc_2}, obj: {lcdc_1}, bg: {lcdc_0}]");
+    }
+
+    /// A line per sound channel: on or off (NR52), its five registers as written and
+    /// whether it's muted, e.g. "CH1 ON  80 BF F3 C1 87"
+    pub fn apu_summary(mem: &Memory, apu: &Apu) -> Vec<String> {
+        (1..=4)
+            .map(|ch| {
+                let on = mem[NR52] & (1 << (ch - 1)) != 0;
+                let base = NR10 + 5 * (ch as Word - 1);
+                let regs: Vec<String> = (base..base + 5)
+                    .map(|addr| format!("{:02X}", mem[addr]))
+                    .collect();
+                format!(
+                    "CH{} {} {}{}",
+                    ch,
+                    if on { "ON " } else { "OFF" },
+                    regs.join(" "),
+                    if apu.muted(ch) { " MUTED" } else { "" }
+                )
+            })
+            .collect()
+    }
+
+    const SCOPE_COLORS: [u32; 4] = [0xFF6060, 0x60FF60, 0x60A0FF, 0xFFFF60];
+
+    /// The sound counterpart of print_lcdc: copies a frame to out darkened, with a band per
+    /// channel top to bottom showing its apu_summary line over its recent output (newest on
+    /// the right, needs Apu::scope to be on)
+    pub fn draw_apu(mem: &Memory, apu: &Apu, frame: &[u32], out: &mut Vec<u32>) {
+        const BAND_H: usize = GB_SCREEN_HEIGHT / 4;
+        const WAVE_TOP: usize = 8; // under the text
+        const WAVE_H: usize = BAND_H - WAVE_TOP - 1;
+        out.clear();
+        out.extend(frame.iter().map(|p| (p >> 2) & 0x3F3F3F));
+        for (i, line) in apu_summary(mem, apu).iter().enumerate() {
+            let color = SCOPE_COLORS[i];
+            let top = i * BAND_H;
+            draw_text(out, GB_SCREEN_WIDTH, 1, top + 1, line, color);
+            let Some(scope) = &apu.scope else {
+                continue;
+            };
+            let samples = &scope.channels[i];
+            let x0 = SCOPE_LEN - samples.len();
+            let mut prev = None;
+            for (x, s) in samples.iter().enumerate() {
+                let y = ((1.0 - s.clamp(-1.0, 1.0)) / 2.0 * (WAVE_H - 1) as f32).round() as usize;
+                // join up with the last sample so square waves get their edges
+                let (from, to) = match prev {
+                    Some(p) if p < y => (p + 1, y),
+                    Some(p) if p > y => (y, p - 1),
+                    _ => (y, y),
+                };
+                for y in from..=to {
+                    out[(top + WAVE_TOP + y) * GB_SCREEN_WIDTH + x0 + x] = color;
+                }
+                prev = Some(y);
+            }
+        }
+    }
+
+    /// Width of the debug layout: the game, the tile data (16 tiles across) and a tile map
+    pub const LAYOUT_WIDTH: usize = GB_SCREEN_WIDTH + 128 + 256;
+    /// Tall enough for the whole tile map
+    pub const LAYOUT_HEIGHT: usize = 256;
+    const LAYOUT_BACKGROUND: u32 = 0x202020;
+    const LAYOUT_TEXT: u32 = 0xFFFFFF;
+    const LAYOUT_VIEWPORT: u32 = 0xFF4040;
+    const TILES_X: usize = GB_SCREEN_WIDTH;
+    const OAM_Y: usize = 192 + 2; // under the 24 rows of tiles
+    const MAP_X: usize = TILES_X + 128;
+    // frontend lines fit under the registers, see DebugLayout::compose
+    const LAYOUT_STATUS_LINES: usize = 3;
+
+    /// The game with debug panels beside it in one frame, for frontends that only get the one
+    /// window (WASM, some tiling WMs). Left to right: the game with the registers under it,
+    /// the tile data with OAM under it, then the background map with the screen outlined
+    #[derive(Clone, Debug)]
+    pub struct DebugLayout {
+        panels: Vec<u32>, // LAYOUT_WIDTH x LAYOUT_HEIGHT, the game's spot left empty
+    }
+
+    impl Default for DebugLayout {
+        fn default() -> Self {
+            DebugLayout {
+                panels: vec![LAYOUT_BACKGROUND; LAYOUT_WIDTH * LAYOUT_HEIGHT],
+            }
+        }
+    }
+
+    impl DebugLayout {
+        /// Redraws the panels from where the machine is now, in the lcd's colors
+        pub fn update(&mut self, cpu: &CPUState, mem: &Memory, palettes: &PaletteSet) {
+            self.panels.fill(LAYOUT_BACKGROUND);
+            let out = &mut self.panels;
+            let lcdc = mem[LCDC];
+
+            let reg = |r| cpu.reg[r];
+            let bank = |addr| match mem.bank_at(addr) {
+                usize::MAX => "--".to_string(),
+                bank => format!("{:02X}", bank),
+            };
+            let lines = [
+                format!(
+                    "AF {:02X}{:02X} BC {:02X}{:02X} DE {:02X}{:02X}",
+                    reg(REG_A),
+                    reg(FLAGS),
+                    reg(REG_B),
+                    reg(REG_C),
+                    reg(REG_D),
+                    reg(REG_E)
+                ),
+                format!(
+                    "HL {:02X}{:02X} SP {:04X} PC {:04X}",
+                    reg(REG_H),
+                    reg(REG_L),
+                    cpu.sp,
+                    cpu.pc
+                ),
+                format!(
+                    "IME {} IE {:02X} IF {:02X}{}",
+                    cpu.ime as Byte,
+                    mem.read(IE),
+                    mem.read(IF),
+                    if cpu.halt { " HALT" } else { "" }
+                ),
+                format!(
+                    "LCDC {:02X} STAT {:02X} LY {:02X} LYC {:02X}",
+                    lcdc, mem[STAT], mem[LY], mem[LYC]
+                ),
+                format!(
+                    "SCX {:02X} SCY {:02X} WX {:02X} WY {:02X}",
+                    mem[SCX], mem[SCY], mem[WX], mem[WY]
+                ),
+                format!(
+                    "BGP {:02X} OBP0 {:02X} OBP1 {:02X}",
+                    mem[BGP], mem[OBP0], mem[OBP1]
+                ),
+                format!(
+                    "DIV {:02X} TIMA {:02X} TMA {:02X} TAC {:02X}",
+                    mem[DIV], mem[TIMA], mem[TMA], mem[TAC]
+                ),
+                format!("ROM {} RAM {}", bank(MEM_BANK_NN), bank(MEM_EXT)),
+            ];
+            for (i, line) in lines.iter().enumerate() {
+                let y = GB_SCREEN_HEIGHT + 2 + i * 7;
+                draw_text(out, LAYOUT_WIDTH, 1, y, line, LAYOUT_TEXT);
+            }
+
+            // all 384 tiles, 16 to a row
+            for tile in 0..384 {
+                let (x, y) = (TILES_X + tile % 16 * 8, tile / 16 * 8);
+                let addr = MEM_VRAM + tile as Word * 16;
+                draw_tile(out, (x, y), addr, 8, None, mem, (mem[BGP], &palettes.bg));
+            }
+
+            let tall = lcdc & LCDC_BIT_OBJ_SIZE != 0;
+            for (i, obj) in (MEM_OAM..MEM_NOT_USABLE).step_by(4).enumerate() {
+                let (tile, attr) = (mem[obj + 2], mem[obj + 3]);
+                let tile = if tall { tile & 0xFE } else { tile };
+                let (pal, colors) = if attr & OAM_BIT_DMG_PAL != 0 {
+                    (mem[OBP1], &palettes.obj1)
+                } else {
+                    (mem[OBP0], &palettes.obj0)
+                };
+                let (x, y) = (TILES_X + i % 16 * 8, OAM_Y + i / 16 * 16);
+                let addr = MEM_VRAM + tile as Word * 16;
+                let rows = if tall { 16 } else { 8 };
+                draw_tile(out, (x, y), addr, rows, Some(attr), mem, (pal, colors));
+            }
+
+            let map = if lcdc & LCDC_BIT_BG_TILE_MAP_SELECT != 0 {
+                0x9C00
+            } else {
+                0x9800
+            };
+            for i in 0..32 * 32 {
+                let index = mem[map + i as Word];
+                let addr = if lcdc & LCDC_BIT_BG_WINDOW_TILE_DATA_SELECT != 0 {
+                    MEM_VRAM + index as Word * 16
+                } else {
+                    0x9000u16.wrapping_add((index as i8 as i16 * 16) as Word)
+                };
+                let (x, y) = (MAP_X + i % 32 * 8, i / 32 * 8);
+                draw_tile(out, (x, y), addr, 8, None, mem, (mem[BGP], &palettes.bg));
+            }
+            // where the screen is, wrapping around the map like the ppu does
+            let (scx, scy) = (mem[SCX] as usize, mem[SCY] as usize);
+            let mut mark = |x: usize, y: usize| {
+                out[(y % 256) * LAYOUT_WIDTH + MAP_X + x % 256] = LAYOUT_VIEWPORT;
+            };
+            for x in 0..GB_SCREEN_WIDTH {
+                mark(scx + x, scy);
+                mark(scx + x, scy + GB_SCREEN_HEIGHT - 1);
+            }
+            for y in 0..GB_SCREEN_HEIGHT {
+                mark(scx, scy + y);
+                mark(scx + GB_SCREEN_WIDTH - 1, scy + y);
+            }
+        }
+
+        /// Fills out with the panels and a game frame (GB_SCREEN_WIDTH x GB_SCREEN_HEIGHT)
+        /// in its spot, LAYOUT_WIDTH x LAYOUT_HEIGHT in all. `status` is what the frontend
+        /// has to say (audio underruns, say), a few lines at the bottom under the registers
+        pub fn compose(&self, frame: &[u32], status: &[String], out: &mut Vec<u32>) {
+            out.clear();
+            out.extend_from_slice(&self.panels);
+            for (row, line) in frame.chunks_exact(GB_SCREEN_WIDTH).enumerate() {
+                out[row * LAYOUT_WIDTH..][..GB_SCREEN_WIDTH].copy_from_slice(line);
+            }
+            let lines = status.len().min(LAYOUT_STATUS_LINES);
+            for (i, line) in status[..lines].iter().enumerate() {
+                let y = LAYOUT_HEIGHT - (lines - i) * 7;
+                draw_text(out, LAYOUT_WIDTH, 1, y, line, LAYOUT_TEXT);
+            }
+        }
+    }
+
+    /// Draws a tile (8 or 16 rows) with its top left at (x, y) in the debug layout. Given
+    /// OAM attributes it's an object: color 0 is see through and the flips apply
+    fn draw_tile(
+        out: &mut [u32],
+        (x, y): (usize, usize),
+        addr: Word,
+        rows: usize,
+        attr: Option<Byte>,
+        mem: &Memory,
+        (pal, colors): (Byte, &[u32; 4]),
+    ) {
+        let flags = attr.unwrap_or(0);
+        for row in 0..rows {
+            let src = if flags & OAM_BIT_FLIP_Y != 0 {
+                rows - 1 - row
+            } else {
+                row
+            };
+            let line = addr + src as Word * 2;
+            let pixels = ppu_decode_tile_line(mem[line], mem[line + 1]);
+            for col in 0..PPU_TILE_WIDTH {
+                // bit 7 is the leftmost pixel
+                let bit = if flags & OAM_BIT_FLIP_X != 0 {
+                    col
+                } else {
+                    7 - col
+                };
+                let color = pixels[bit];
+                if color != 0 || attr.is_none() {
+                    out[(y + row) * LAYOUT_WIDTH + x + col] = palette_lookup(color, pal, colors);
+                }
+            }
+        }
+    }
+
+    /// The pandocs name of an IO register (or IE), if it has one
+    pub fn io_name(addr: Word) -> Option<&'static str> {
+        let name = match addr {
+            JOYP => "JOYP",
+            SB => "SB",
+            SC => "SC",
+            DIV => "DIV",
+            TIMA => "TIMA",
+            TMA => "TMA",
+            TAC => "TAC",
+            IF => "IF",
+            NR10 => "NR10",
+            NR11 => "NR11",
+            NR12 => "NR12",
+            NR13 => "NR13",
+            NR14 => "NR14",
+            NR21 => "NR21",
+            NR22 => "NR22",
+            0xFF18 => "NR23",
+            NR24 => "NR24",
+            NR30 => "NR30",
+            NR31 => "NR31",
+            NR32 => "NR32",
+            NR33 => "NR33",
+            NR34 => "NR34",
+            NR41 => "NR41",
+            NR42 => "NR42",
+            NR43 => "NR43",
+            NR44 => "NR44",
+            NR50 => "NR50",
+            NR51 => "NR51",
+            NR52 => "NR52",
+            0xFF30..=0xFF3F => "WAVE",
+            LCDC => "LCDC",
+            STAT => "STAT",
+            SCY => "SCY",
+            SCX => "SCX",
+            LY => "LY",
+            LYC => "LYC",
+            DMA => "DMA",
+            BGP => "BGP",
+            OBP0 => "OBP0",
+            OBP1 => "OBP1",
+            WY => "WY",
+            WX => "WX",
+            VBK => "VBK",
+            BCPS => "BCPS",
+            BCPD => "BCPD",
+            OCPS => "OCPS",
+            OCPD => "OCPD",
+            OPRI => "OPRI",
+            IE => "IE",
+            _ => return None,
+        };
+        Some(name)
+    }
+
+    fn str_bits(val: Byte, names: [&str; 8]) -> String {
+        let set: Vec<&str> = (0..8)
+            .rev()
+            .filter(|b| val & (1 << b) != 0 && !names[*b].is_empty())
+            .map(|b| names[b])
+            .collect();
+        set.join(" ")
+    }
+
+    /// What the bits of an IO register mean, for the registers where the raw value isn't enough
+    pub fn io_bits(addr: Word, val: Byte) -> Option<String> {
+        let shades = |p: Byte| format!("shades {} {} {} {}", p & 3, p >> 2 & 3, p >> 4 & 3, p >> 6);
+        let bits = match addr {
+            JOYP => {
+                let select = match (val >> 4) & 0b11 {
+                    0b00 => "dpad+buttons",
+                    0b01 => "buttons",
+                    0b10 => "dpad",
+                    _ => "none",
+                };
+                format!("select {}, pressed {:04b}", select, !val & 0x0F)
+            }
+            TAC => format!(
+                "{}, every {} cycles",
+                if val & 0b100 != 0 { "on" } else { "off" },
+                [1024, 16, 64, 256][(val & 0b11) as usize]
+            ),
+            IF | IE => str_bits(
+                val,
+                ["VBLANK", "STAT", "TIMER", "SERIAL", "JOYPAD", "", "", ""],
+            ),
+            LCDC => format!(
+                "{} bg_map {:04X} wnd_map {:04X} tiles {:04X} obj 8x{}",
+                str_bits(val, ["BG", "OBJ", "", "", "", "WND", "", "ON"]),
+                if val & LCDC_BIT_BG_TILE_MAP_SELECT != 0 {
+                    0x9C00
+                } else {
+                    0x9800
+                },
+                if val & LCDC_BIT_WINDOW_TILE_MAP_SELECT != 0 {
+                    0x9C00
+                } else {
+                    0x9800
+                },
+                if val & LCDC_BIT_BG_WINDOW_TILE_DATA_SELECT != 0 {
+                    0x8000
+                } else {
+                    0x8800
+                },
+                if val & LCDC_BIT_OBJ_SIZE != 0 { 16 } else { 8 },
+            ),
+            STAT => format!(
+                "mode {} {}",
+                val & STAT_MASK_PPU_MODE,
+                str_bits(
+                    val,
+                    ["", "", "LY=LYC", "INT_0", "INT_1", "INT_2", "INT_LYC", ""]
+                )
+            ),
+            BGP | OBP0 | OBP1 => shades(val),
+            NR50 => format!(
+                "left {}{} right {}{}",
+                (val >> 4 & 7) + 1,
+                if val & NR50_BIT_VIN_LEFT != 0 {
+                    " +VIN"
+                } else {
+                    ""
+                },
+                (val & 7) + 1,
+                if val & NR50_BIT_VIN_RIGHT != 0 {
+                    " +VIN"
+                } else {
+                    ""
+                },
+            ),
+            _ => return None,
+        };
+        let bits = bits.trim_end();
+        (!bits.is_empty()).then(|| bits.to_string())
+    }
+
+    /// One line of disassembly, given an opcode and the two bytes after it
+    pub fn disassemble(op: Byte, args: [Byte; 2]) -> String {
+        let inst = crate::decode::decode(op);
+        if inst.prefix() {
+            Instruction::from_cb(&crate::decode::decodeCB(args[0])).mnm
+        } else if inst.len > 1 {
+            inst.mnm_args(&args[..inst.len as usize - 1])
+        } else {
+            inst.mnm
+        }
+    }
+
+    /// Every named io register, one per line with its bits spelled out
+    pub fn io_summary(mem: &Memory) -> String {
+        let mut out = String::new();
+        for addr in (MEM_IO_PORTS..MEM_HRAM).chain(std::iter::once(IE)) {
+            let Some(name) = io_name(addr) else {
+                continue;
+            };
+            let val = mem.read(addr);
+            out += &match io_bits(addr, val) {
+                Some(bits) => format!("{:04X} {:<5} {:02X}  {}\n", addr, name, val, bits),
+                None => format!("{:04X} {:<5} {:02X}\n", addr, name, val),
+            };
+        }
+        out
+    }
+
+    /// The interrupts highest priority first, with what's holding each back and which one the
+    /// cpu takes next
+    pub fn interrupt_summary(mem: &Memory, ime: bool) -> String {
+        let (requested, enabled) = (mem.read(IF), mem.read(IE));
+        let pending = mem.enabled_interrupts();
+        let next = pending & pending.wrapping_neg();
+        let mut out = format!(
+            "IF {:02X} IE {:02X} mask {:02X} ime {}\n",
+            requested,
+            enabled,
+            mem.int_mask,
+            if ime { "on" } else { "off" }
+        );
+        for (i, name) in ["VBLANK", "STAT", "TIMER", "SERIAL", "JOYPAD"]
+            .iter()
+            .enumerate()
+        {
+            let bit = 1 << i;
+            let mut state = vec![];
+            if requested & bit != 0 {
+                state.push("requested");
+            }
+            if enabled & bit == 0 {
+                state.push("disabled");
+            }
+            if mem.int_mask & bit != 0 {
+                state.push("masked");
+            }
+            if bit == next {
+                state.push(if ime { "next" } else { "next once ime is on" });
+            }
+            out += &format!("{} {:<6} {}\n", i + 1, name, state.join(", "));
+        }
+        out
+    }
+
+    /// Loads a screenshot as 0RGB pixels, downscaling integer-scaled captures back to 160x144
+    pub fn load_reference_png(path: &str) -> std::io::Result<Vec<u32>> {
+        let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+        let mut decoder = png::Decoder::new(fs::File::open(path)?);
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let mut reader = decoder.read_info().map_err(|e| invalid(e.to_string()))?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader
+            .next_frame(&mut buf)
+            .map_err(|e| invalid(e.to_string()))?;
+        let (w, h) = (info.width as usize, info.height as usize);
+        let scale = w / GB_SCREEN_WIDTH;
+        if scale == 0 || w != GB_SCREEN_WIDTH * scale || h != GB_SCREEN_HEIGHT * scale {
+            return Err(invalid(format!(
+                "{}x{} isn't a (scaled) 160x144 screenshot",
+                w, h
+            )));
+        }
+        let channels = info.color_type.samples();
+        let mut pixels = Vec::with_capacity(GB_SCREEN_WIDTH * GB_SCREEN_HEIGHT);
+        for y in 0..GB_SCREEN_HEIGHT {
+            for x in 0..GB_SCREEN_WIDTH {
+                let p = &buf[(y * scale * w + x * scale) * channels..][..channels];
+                let (r, g, b) = match channels {
+                    1 | 2 => (p[0], p[0], p[0]), // grey (and alpha)
+                    _ => (p[0], p[1], p[2]),
+                };
+                pixels.push(u32::from_be_bytes([0, r, g, b]));
+            }
+        }
+        Ok(pixels)
+    }
+
+    // differing pixels are drawn in this while the overlay flashes on
+    const DIFF_HIGHLIGHT: u32 = 0xFF00FF;
+
+    /// Which of the 4 shades (0 white to 3 black) a color in a reference capture is. All
+    /// there is to go on is its brightness, whatever palette it was captured with
+    fn reference_shade(color: u32) -> Byte {
+        3 - (luma(color) * 3.0 / 255.0).round() as Byte
+    }
+
+    /// Compares frames against a reference capture (another emulator, real hardware) for ppu
+    /// accuracy work, by shade so the palettes either side don't matter
+    pub struct FrameDiff {
+        reference: Vec<Byte>, // shades
+    }
+
+    impl FrameDiff {
+        pub fn new(reference: &[u32]) -> FrameDiff {
+            FrameDiff {
+                reference: reference.iter().map(|c| reference_shade(*c)).collect(),
+            }
+        }
+
+        /// Copies `frame` into `out`, with the pixels whose `shades` (see Display::shades)
+        /// differ from the reference painted over when `flash` is set. Returns how many differ
+        pub fn overlay(
+            &self,
+            frame: &[u32],
+            shades: &[Byte],
+            flash: bool,
+            out: &mut Vec<u32>,
+        ) -> usize {
+            out.clear();
+            let mut differ = 0;
+            for ((pixel, shade), expected) in frame.iter().zip(shades).zip(&self.reference) {
+                let same = shade == expected;
+                differ += !same as usize;
+                out.push(if flash && !same {
+                    DIFF_HIGHLIGHT
+                } else {
+                    *pixel
+                });
+            }
+            differ
+        }
+    }
+
+    #[cfg(test)]
+    mod tests_dbg {
+        use super::*;
+
+        #[test]
+        fn test_debug_layout() {
+            let mut mem = Memory::new();
+            let mut cpu = CPUState::new();
+            cpu.pc = 0x1234;
+            mem.write(LCDC, 0x91); // tiles at 8000, map at 9800, 8x8 objects
+            mem.write(BGP, 0xE4);
+            mem.write(OBP0, 0xE4);
+            mem.write(SCX, 8);
+            mem.write(SCY, 0);
+            // tile 1 solid color 3, tile 2 color 1 down its left edge only
+            for i in 0..16 {
+                mem.write(0x8010 + i, 0xFF);
+                mem.write(0x8020 + i, if i % 2 == 0 { 0x80 } else { 0x00 });
+            }
+            mem.write(0x9800, 1);
+            mem.write(MEM_OAM + 2, 2);
+            mem.write(MEM_OAM + 3, OAM_BIT_FLIP_X);
+
+            let palettes = PALETTES_DEFAULT;
+            let mut layout = DebugLayout::default();
+            layout.update(&cpu, &mem, &palettes);
+            let frame = vec![0x123456; GB_SCREEN_WIDTH * GB_SCREEN_HEIGHT];
+            let mut out = vec![];
+            layout.compose(&frame, &[], &mut out);
+            assert_eq!(out.len(), LAYOUT_WIDTH * LAYOUT_HEIGHT);
+            let at = |x: usize, y: usize| out[y * LAYOUT_WIDTH + x];
+            assert_eq!(at(0, 0), 0x123456);
+            assert_eq!(at(GB_SCREEN_WIDTH - 1, GB_SCREEN_HEIGHT - 1), 0x123456);
+            assert_eq!(at(0, GB_SCREEN_HEIGHT), LAYOUT_BACKGROUND);
+
+            // tile 0 is blank, tile 1 black
+            assert_eq!(at(TILES_X, 0), palettes.bg[0]);
+            assert_eq!(at(TILES_X + 8, 0), palettes.bg[3]);
+            // object 0 is tile 2 flipped, the stripe ends up on the right over the background
+            assert_eq!(at(TILES_X + 7, OAM_Y), palettes.obj0[1]);
+            assert_eq!(at(TILES_X, OAM_Y), LAYOUT_BACKGROUND);
+            // the map's first tile is tile 1, with the screen's left edge 8 pixels in
+            assert_eq!(at(MAP_X + 1, 1), palettes.bg[3]);
+            assert_eq!(at(MAP_X + 8, 1), LAYOUT_VIEWPORT);
+            assert_eq!(at(MAP_X + 8 + GB_SCREEN_WIDTH - 1, 1), LAYOUT_VIEWPORT);
+            assert_eq!(at(MAP_X + 9, 0), LAYOUT_VIEWPORT);
+            assert_eq!(at(MAP_X + 9, 1), palettes.bg[0]);
+
+            // the registers go under the game
+            let text = (GB_SCREEN_HEIGHT + 2..LAYOUT_HEIGHT)
+                .any(|y| (0..GB_SCREEN_WIDTH).any(|x| at(x, y) == LAYOUT_TEXT));
+            assert!(text);
+
+            // the frontend's lines along the bottom, the last one last
+            assert_eq!(at(1, LAYOUT_HEIGHT - 7), LAYOUT_BACKGROUND);
+            layout.compose(&frame, &["L".to_string()], &mut out);
+            assert_eq!(out[(LAYOUT_HEIGHT - 7) * LAYOUT_WIDTH + 1], LAYOUT_TEXT);
+        }
+
+        #[test]
+        fn test_rom_usage_report() {
+            let mut usage = RomUsage::new(BANK_SIZE * 2);
+            for offset in 0x100..0x104 {
+                usage.mark(offset, ROM_USAGE_CODE | ROM_USAGE_DATA);
+            }
+            usage.mark(0x104, ROM_USAGE_DATA);
+            usage.mark(BANK_SIZE + 0x10, ROM_USAGE_DATA);
+            assert_eq!(
+                usage.ranges()[..3],
+                [
+                    (0, 0x100, 0),
+                    (0x100, 4, ROM_USAGE_CODE),
+                    (0x104, 1, ROM_USAGE_DATA)
+                ]
+            );
+            assert_eq!(usage.bank_counts(0), (4, 1, BANK_SIZE - 5));
+            let report = usage.report();
+            assert!(report.starts_with("bank 00:     4 code     1 data 16379 unused\n"));
+            assert!(report.contains("unused 00:0000-00:00FF (256 bytes)\n"));
+            assert!(report.contains("unused 01:4011-01:7FFF"));
+            // marks past the end (a header that undersells the rom) grow the map
+            usage.mark(BANK_SIZE * 2, ROM_USAGE_DATA);
+            assert_eq!(usage.flags().len(), BANK_SIZE * 2 + 1);
+        }
+
+        #[test]
+        fn test_draw_apu() {
+            use crate::apu::{Scope, NRX4_BIT_TRIGGER};
+            use crate::pacing::CPU_HZ;
+
+            let mut apu = Apu::new();
+            let mut mem = Memory::new();
+            apu.scope = Some(Scope::default());
+            mem.write(NR11, 0x80);
+            mem.write(NR12, 0xF0);
+            mem.write(NR14, NRX4_BIT_TRIGGER);
+            apu.set_muted(3, true);
+            apu.update(&mut mem, Cycles::t(CPU_HZ / 60));
+            let lines = apu_summary(&mem, &apu);
+            assert_eq!(lines[0], "CH1 ON  80 80 F0 00 80");
+            assert!(lines[2].starts_with("CH3 OFF") && lines[2].ends_with(" MUTED"));
+
+            let scope = apu.scope.as_ref().unwrap();
+            assert_eq!(scope.channels[0].len(), SCOPE_LEN);
+            assert!(scope.channels[0].iter().any(|s| *s > 0.0));
+            assert!(scope.channels[1].iter().all(|s| *s == 0.0));
+
+            let frame = vec![0x808080; GB_SCREEN_WIDTH * GB_SCREEN_HEIGHT];
+            let mut out = vec![];
+            draw_apu(&mem, &apu, &frame, &mut out);
+            assert_eq!(out.len(), frame.len());
+            assert_eq!(out[0], 0x202020);
+            assert_eq!(out[GB_SCREEN_WIDTH + 1], 0x202020); // C's top left is blank
+            assert_eq!(out[GB_SCREEN_WIDTH + 2], SCOPE_COLORS[0]);
+            let band = |ch: usize| &out[ch * GB_SCREEN_WIDTH * 36..(ch + 1) * GB_SCREEN_WIDTH * 36];
+            assert!(band(0).contains(&SCOPE_COLORS[0]));
+            // channel 2 sits flat along the middle
+            let middle = 36 + 8 + 13;
+            assert!((0..SCOPE_LEN).all(|x| out[middle * GB_SCREEN_WIDTH + x] == SCOPE_COLORS[1]));
+        }
+
+        #[test]
+        fn test_linter() {
+            let mut linter = Linter::default();
+            linter.add(Lint::UninitializedRead(0xC000), 1, 0x4000);
+            linter.add(Lint::UninitializedRead(0xC001), 1, 0x4000); // same site, another addr
+            linter.add(Lint::UninitializedRead(0xC000), 2, 0x4000);
+            linter.add(Lint::RomWrite(0x2000), 1, 0x4000);
+            let warnings = linter.take();
+            assert_eq!(warnings.len(), 3);
+            assert_eq!(
+                warnings[0].to_string(),
+                "warning @ 01:4000: read C000 before anything was written there"
+            );
+            assert!(linter.take().is_empty());
+            linter.add(Lint::RomWrite(0x2100), 1, 0x4000);
+            assert!(linter.take().is_empty());
+        }
+
+        #[test]
+        fn test_interrupt_summary() {
+            let mut mem = Memory::new();
+            mem.write(IE, FL_INT_VBLANK | FL_INT_TIMER);
+            mem.write(IF, FL_INT_VBLANK | FL_INT_TIMER | FL_INT_SERIAL);
+            mem.int_mask = FL_INT_VBLANK;
+            let summary = interrupt_summary(&mem, true);
+            let lines: Vec<&str> = summary.lines().collect();
+            assert_eq!(lines[0], "IF 0D IE 05 mask 01 ime on");
+            assert_eq!(lines[1].trim_end(), "1 VBLANK requested, masked");
+            assert_eq!(lines[2].trim_end(), "2 STAT   disabled");
+            assert_eq!(lines[3].trim_end(), "3 TIMER  requested, next");
+            assert_eq!(lines[4].trim_end(), "4 SERIAL requested, disabled");
+        }
+
+        #[test]
+        fn test_profile() {
+            let mut profiler = Profiler::new();
+            profiler.set_on(true);
+            let start = profiler.start();
+            std::thread::sleep(std::time::Duration::from_millis(5));
+            profiler.stop(Section::Cpu, start);
+            let profile = profiler.take();
+            if INSTRUMENT {
+                assert!(profile.share(Section::Cpu) > 0.5);
+            } else {
+                assert_eq!(profile.share(Section::Cpu), 0.0);
+            }
+            assert_eq!(profile.share(Section::Ppu), 0.0);
+            assert!(profile.to_string().starts_with("cpu "));
+
+            profiler.set_on(false);
+            assert!(profiler.start().is_none());
+        }
+
+        #[test]
+        fn test_step_diff() {
+            let (prev, prev_mem) = (CPUState::new(), Memory::new());
+            let (mut cpu, mut mem) = (prev, prev_mem.clone());
+            cpu.reg[REG_B] = 0x42;
+            cpu.reg[FLAGS] ^= FL_C;
+            cpu.pc += 1;
+            let diff = step_diff(&prev, &prev_mem, &cpu, &mem);
+            assert!(diff.contains("\x1b[7mB:42\x1b[0m C:13"));
+            assert!(diff.contains("F:Z-H\x1b[7m-\x1b[0m"));
+            assert!(diff.contains("\x1b[7mPC:0101\x1b[0m"));
+            assert!(!diff.contains("mem:"));
+
+            for addr in 0..20 {
+                mem.write(MEM_WRAM_0 + addr, 1);
+            }
+            let diff = step_diff(&prev, &prev_mem, &cpu, &mem);
+            assert!(diff.contains("\nmem: C000/E000 \x1b[7m00->01\x1b[0m, C001/E001"));
+            assert!(diff.ends_with("(4 more)"));
+        }
+
+        #[test]
+        fn test_io_bits() {
+            assert_eq!(io_name(LCDC), Some("LCDC"));
+            assert_eq!(io_name(0xFF03), None);
+            assert_eq!(io_bits(IF, 0x05), Some("TIMER VBLANK".to_string()));
+            assert_eq!(
+                io_bits(LCDC, 0x91),
+                Some("ON BG bg_map 9800 wnd_map 9800 tiles 8000 obj 8x8".to_string())
+            );
+            assert_eq!(io_bits(STAT, 0x85), Some("mode 1 LY=LYC".to_string()));
+            assert_eq!(io_bits(TAC, 0x05), Some("on, every 16 cycles".to_string()));
+            assert_eq!(io_bits(BGP, 0xE4), Some("shades 0 1 2 3".to_string()));
+            assert_eq!(io_bits(NR50, 0xF3), Some("left 8 +VIN right 4".to_string()));
+            assert_eq!(io_bits(SCX, 0x10), None);
+            assert_eq!(io_bits(IE, 0x00), None);
+        }
+
+        #[test]
+        fn test_frame_diff() {
+            let size = GB_SCREEN_WIDTH * GB_SCREEN_HEIGHT;
+            // the reference was captured with a grey palette
+            let mut reference = vec![0xFFFFFF; size];
+            reference[10] = 0x000000;
+            let diff = FrameDiff::new(&reference);
+
+            // and an inverted palette, the colors don't come into it
+            let palette = [0x000000, 0x555555, 0xAAAAAA, 0xFFFFFF];
+            let mut shades = vec![0; size];
+            shades[10] = 3;
+            let frame = |shades: &[Byte]| -> Vec<u32> {
+                shades.iter().map(|s| palette[*s as usize]).collect()
+            };
+            let mut out = vec![];
+            assert_eq!(diff.overlay(&frame(&shades), &shades, true, &mut out), 0);
+            assert_eq!(out, frame(&shades));
+
+            shades[11] = 2;
+            assert_eq!(diff.overlay(&frame(&shades), &shades, true, &mut out), 1);
+            assert_eq!(out[11], DIFF_HIGHLIGHT);
+            assert_eq!(diff.overlay(&frame(&shades), &shades, false, &mut out), 1);
+            assert_eq!(out, frame(&shades));
+        }
+
+        #[test]
+        fn test_load_reference_png() {
+            // a 2x scaled grey capture, black in the top left 2x2 block only
+            let (w, h) = (GB_SCREEN_WIDTH * 2, GB_SCREEN_HEIGHT * 2);
+            let mut grey = vec![0xFF; w * h];
+            grey[0] = 0x00;
+            grey[1] = 0x00;
+            grey[w] = 0x00;
+            grey[w + 1] = 0x00;
+            let path = std::env::temp_dir().join("cerboy_test_reference.png");
+            let mut encoder =
+                png::Encoder::new(fs::File::create(&path).unwrap(), w as u32, h as u32);
+            encoder.set_color(png::ColorType::Grayscale);
+            encoder
+                .write_header()
+                .unwrap()
+                .write_image_data(&grey)
+                .unwrap();
+
+            let pixels = load_reference_png(path.to_str().unwrap()).unwrap();
+            assert_eq!(pixels.len(), GB_SCREEN_WIDTH * GB_SCREEN_HEIGHT);
+            assert_eq!(pixels[0], 0x000000);
+            assert_eq!(pixels[1], 0xFFFFFF);
+            assert_eq!(pixels[GB_SCREEN_WIDTH], 0xFFFFFF);
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
+
+pub mod emu {
+    use std::collections::{HashMap, VecDeque};
+    use std::sync::Arc;
+
+    use crate::apu::Apu;
+    use crate::config::{Hack, SplitAction, Trigger};
+    use crate::cpu::*;
+    use crate::dbg::{
+        disassemble, io_summary, Lint, LintWarning, Linter, Profiler, Section, StackFault,
+        StateHash, WatchHit, INSTRUMENT, ROM_USAGE_CODE,
+    };
+    use crate::lcd::*;
+    use crate::memory::*;
+    use crate::sgb::Sgb;
+    use crate::types::*;
+
+    /// What happened during a step
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub struct StepInfo {
+        pub cycles: Cycles,
+        pub instructions: u64,
+        pub mode_changes: Vec<(Byte, Byte)>, // lcd mode (from, to), in order
+        pub interrupts_requested: Byte,      // FL_INT_* bits newly raised in IF
+        pub interrupts_serviced: Byte,       // FL_INT_* bits the cpu jumped to
+        pub frame: bool,                     // a frame was completed, see Display::frame
+        pub stack_fault: Option<StackFault>, // only with Memory::stack_guard
+        pub watch_hit: Option<WatchHit>,     // see Memory::watches
+    }
+
+    impl StepInfo {
+        /// Something wants the debugger: a stack fault or a watchpoint
+        pub fn stopped(&self) -> bool {
+            self.stack_fault.is_some() || self.watch_hit.is_some()
+        }
+        fn merge(&mut self, other: StepInfo) {
+            self.cycles += other.cycles;
+            self.instructions += other.instructions;
+            self.mode_changes.extend(other.mode_changes);
+            self.interrupts_requested |= other.interrupts_requested;
+            self.interrupts_serviced |= other.interrupts_serviced;
+            self.frame |= other.frame;
+            self.stack_fault = self.stack_fault.or(other.stack_fault);
+            self.watch_hit = self.watch_hit.or(other.watch_hit);
+        }
+    }
+
+    // backwards jumps further than this aren't considered for idle loop skipping
+    const IDLE_LOOP_MAX_LEN: Word = 16;
+
+    /// The last short backwards jump, a candidate idle loop
+    #[derive(Copy, Clone)]
+    struct IdleLoop {
+        cpu: CPUState,   // state on landing at the top of the loop
+        cpu_writes: u64, // Emulator::cpu_writes at that point
+        io: [Byte; 5],   // registers the hardware changes on its own, see io_snapshot()
+    }
+
+    // blocks end at a jump/call/return or after this many instructions
+    const BLOCK_MAX_LEN: usize = 32;
+
+    /// An instruction read ahead of time, see next_fetched()
+    #[derive(Copy, Clone)]
+    struct FetchedOp {
+        pc: Word,
+        op: Byte,
+        args: [Byte; 2],
+    }
+
+    /// Basic blocks of pre-fetched instructions keyed by (bank, pc)
+    ///
+    /// Running from the cache skips reading each instruction's bytes from memory again (and
+    /// the bank switching and io side effects that can come with it), they still go through
+    /// the interpreter's match.
+    /// Blocks only cover ROM, WRAM and HRAM; everything is dropped when an instruction writes
+    /// over a cached one (see Memory::code_written). Clones start out empty so rewind
+    /// snapshots stay cheap, the blocks just get fetched again.
+    #[derive(Default)]
+    struct BlockCache {
+        blocks: HashMap<(usize, Word), Arc<[FetchedOp]>>,
+        current: Option<(usize, Arc<[FetchedOp]>, usize)>, // bank, block, index of the next op
+    }
+
+    impl Clone for BlockCache {
+        fn clone(&self) -> Self {
+            BlockCache::default()
+        }
+    }
+
+    // does the op leave the straight line (or stop the cpu)?
+    const fn ends_block(op: Byte) -> bool {
+        matches!(
+            op,
+            0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 | 0x76 // stop, jr, halt
+            | 0xC0 | 0xC2 | 0xC3 | 0xC4 | 0xC7 | 0xC8 | 0xC9 | 0xCA | 0xCC | 0xCD | 0xCF
+            | 0xD0 | 0xD2 | 0xD4 | 0xD7 | 0xD8 | 0xD9 | 0xDA | 0xDC | 0xDF
+            | 0xE7 | 0xE9 | 0xEF | 0xF7 | 0xFF
+        )
+    }
+
+    // code is only cached where the bank + address says what's there, not cartridge RAM: a
+    // mapper write can disable it or swap in the RTC without writing over the code
+    fn cacheable(addr: Word) -> bool {
+        addr < MEM_VRAM || (MEM_WRAM_0..MEM_ECHO).contains(&addr) || (MEM_HRAM..IE).contains(&addr)
+    }
+
+    impl BlockCache {
+        fn clear(&mut self) {
+            self.blocks.clear();
+            self.current = None;
+        }
+
+        /// Reads instructions from pc up to the end of the block, marking them in memory
+        ///
+        /// Blocks stay inside an 8KB region (a single bank), an instruction straddling two
+        /// leaves the block empty and gets run uncached.
+        fn fetch_block(mem: &mut Memory, pc: Word) -> Arc<[FetchedOp]> {
+            let mut block = vec![];
+            let mut addr = pc;
+            while block.len() < BLOCK_MAX_LEN {
+                let op = mem.read_fetch(addr);
+                let inst = crate::decode::decode(op);
+                let len = if inst.prefix() { 2 } else { inst.len.max(1) } as Word;
+                let last = addr.wrapping_add(len - 1);
+                if last < addr || last >> 13 != pc >> 13 {
+                    break;
+                }
+                for a in addr..=last {
+                    mem.mark_code(a);
+                }
+                block.push(FetchedOp {
+                    pc: addr,
+                    op,
+                    args: [
+                        mem.read_fetch(addr.wrapping_add(1)),
+                        mem.read_fetch(addr.wrapping_add(2)),
+                    ],
+                });
+                addr = last.wrapping_add(1);
+                if ends_block(op) || !inst.valid() || addr >> 13 != pc >> 13 {
+                    break;
+                }
+            }
+            block.into()
+        }
+
+        /// The instruction at pc, None when it can't come from the cache
+        fn fetch(&mut self, mem: &mut Memory, pc: Word) -> Option<FetchedOp> {
+            mem.track_code();
+            if mem.code_written {
+                self.clear();
+                mem.clear_code();
+            }
+            let bank = mem.bank_at(pc);
+            if let Some((cur_bank, block, next)) = &mut self.current {
+                if let Some(&op) = block.get(*next) {
+                    if op.pc == pc && *cur_bank == bank {
+                        *next += 1;
+                        return Some(op);
+                    }
+                }
+            }
+            if !cacheable(pc) {
+                self.current = None;
+                return None;
+            }
+            let block = match self.blocks.get(&(bank, pc)) {
+                Some(block) => Arc::clone(block),
+                None => {
+                    let block = BlockCache::fetch_block(mem, pc);
+                    self.blocks.insert((bank, pc), Arc::clone(&block));
+                    block
+                }
+            };
+            let op = block.first().copied();
+            self.current = Some((bank, block, 1));
+            op
+        }
+    }
+
+    /// A trigger that fired, see TriggerWatcher
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct TriggerEvent {
+        pub name: String,
+        pub frame: u64, // completed frames when it fired
+        pub split: Option<SplitAction>,
+    }
+
+    /// Fires triggers (see config::Trigger) as their conditions go from not holding to holding
+    ///
+    /// Triggers can come from the game config or be added by whatever's built on top, the
+    /// events pile up until Emulator::take_events. A trigger that already holds when it's
+    /// added doesn't fire until it stops and starts holding again.
+    #[derive(Clone, Debug, Default)]
+    pub struct TriggerWatcher {
+        triggers: Vec<Trigger>,
+        held: Vec<bool>,
+        fired: Vec<bool>,
+        events: Vec<TriggerEvent>,
+    }
+
+    impl TriggerWatcher {
+        pub fn add(&mut self, trigger: Trigger, mem: &Memory) {
+            self.held.push(trigger.holds(mem));
+            self.fired.push(false);
+            self.triggers.push(trigger);
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.triggers.is_empty()
+        }
+
+        fn check(&mut self, mem: &Memory, frame: u64) {
+            for (i, trigger) in self.triggers.iter().enumerate() {
+                let holds = trigger.holds(mem);
+                if holds && !self.held[i] && (trigger.repeat || !self.fired[i]) {
+                    self.fired[i] = true;
+                    self.events.push(TriggerEvent {
+                        name: trigger.name.clone(),
+                        frame,
+                        split: trigger.split,
+                    });
+                }
+                self.held[i] = holds;
+            }
+        }
+    }
+
+    /// An executed instruction, as it looked when it ran
+    #[derive(Copy, Clone, Debug)]
+    pub struct Traced {
+        /// cpu.inst_count before it ran, where Emulator::run_until stops to get back here
+        pub inst_count: u64,
+        pub bank: usize,
+        pub pc: Word,
+        pub op: Byte,
+        pub args: [Byte; 2],
+    }
+
+    impl std::fmt::Display for Traced {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "{:02X}:{:04X}  {}",
+                self.bank,
+                self.pc,
+                disassemble(self.op, self.args)
+            )
+        }
+    }
+
+    /// The whole machine, everything needed to run a cartridge
+    #[derive(Clone)]
+    pub struct Emulator {
+        pub cpu: CPUState,
+        pub mem: Memory,
+        pub lcd: Display,
+        pub apu: Apu,
+        pub timers: HardwareTimers,
+        /// Fast forward through idle loops and halts, see skip_idle()
+        pub skip_idle: bool,
+        /// Run instructions out of a cache of pre-fetched basic blocks, see BlockCache
+        pub block_cache: bool,
+        /// Per-game workarounds from the config, see apply_hacks()
+        pub hacks: Vec<Hack>,
+        /// Checked at the end of every frame, see take_events()
+        pub triggers: TriggerWatcher,
+        /// How many of the last executed instructions to keep for crash_report(), 0 keeps none
+        pub trace_len: usize,
+        trace: VecDeque<Traced>,
+        cache: BlockCache,
+        idle: Option<IdleLoop>,
+        cpu_writes: u64, // memory writes made by instructions (not the ppu/timers)
+        frames: u64,     // completed since power on
+        linter: Linter,  // see Memory::lint
+        /// Colors the frames and draws the border for carts that ask for it, see Cartridge::sgb_mode
+        pub sgb: Option<Sgb>,
+        /// Where the time goes, off until turned on
+        pub profiler: Profiler,
+    }
+
+    impl Emulator {
+        pub fn new(cart: &Cartridge) -> Emulator {
+            let mut mem = Memory::new();
+            mem.load_rom(cart);
+            let mut lcd = Display::new();
+            let sgb = if cart.sgb_mode() {
+                lcd.keep_shades();
+                Some(Sgb::new())
+            } else {
+                None
+            };
+            Emulator {
+                cpu: if mem.cgb {
+                    CPUState::new_cgb()
+                } else {
+                    CPUState::new()
+                },
+                mem,
+                lcd,
+                apu: Apu::new(),
+                timers: HardwareTimers::new(),
+                skip_idle: true,
+                block_cache: false,
+                hacks: vec![],
+                triggers: TriggerWatcher::default(),
+                trace_len: 0,
+                trace: VecDeque::new(),
+                cache: BlockCache::default(),
+                idle: None,
+                cpu_writes: 0,
+                frames: 0,
+                linter: Linter::default(),
+                sgb,
+                profiler: Profiler::new(),
+            }
+        }
+
+        /// A canonical hash of the whole machine, for checking runs are deterministic
+        ///
+        /// Covers the cpu, memory, ppu, timers and sgb. Host side settings (skip_idle, the block
+        /// cache) must never change it.
+        pub fn state_hash(&self) -> u64 {
+            let mut h = StateHash::new();
+            let cpu = &self.cpu;
+            for val in [cpu.tsc.as_t(), cpu.inst_count, cpu.inst_ei] {
+                h.u64(val);
+            }
+            h.bytes(&cpu.reg);
+            h.bytes(&cpu.sp.to_le_bytes());
+            h.bytes(&cpu.pc.to_le_bytes());
+            h.bytes(&[cpu.ime as Byte, cpu.halt as Byte]);
+            self.mem.hash_state(&mut h);
+            self.lcd.hash_state(&mut h);
+            self.apu.hash_state(&mut h);
+            self.timers.hash_state(&mut h);
+            if let Some(sgb) = &self.sgb {
+                sgb.hash_state(&mut h);
+            }
+            h.finish()
+        }
+
+        /// Where the machine is and how it got there, for bug reports after a panic
+        ///
+        /// Registers with the bank being run, the last trace_len instructions and the io
+        /// registers.
+        pub fn crash_report(&self) -> String {
+            let cpu = &self.cpu;
+            let mut out = format!(
+                "pc {:02X}:{:04X} sp {:04X} af {:02X}{:02X} bc {:02X}{:02X} de {:02X}{:02X} hl {:02X}{:02X} ime {}\n",
+                self.mem.bank_at(cpu.pc),
+                cpu.pc,
+                cpu.sp,
+                cpu.reg[REG_A],
+                cpu.reg[FLAGS],
+                cpu.reg[REG_B],
+                cpu.reg[REG_C],
+                cpu.reg[REG_D],
+                cpu.reg[REG_E],
+                cpu.reg[REG_H],
+                cpu.reg[REG_L],
+                cpu.ime as u8
+            );
+            if !self.trace.is_empty() {
+                out += &format!("last {} instructions:\n", self.trace.len());
+                for t in &self.trace {
+                    out += &format!("  {}\n", t);
+                }
+            }
+            if !self.mem.unsupported().is_empty() {
+                out += "unsupported mapper features:\n";
+                for feature in self.mem.unsupported() {
+                    out += &format!("  {}\n", feature);
+                }
+            }
+            out += "io registers:\n";
+            out += &io_summary(&self.mem);
+            out
+        }
+
+        // flags the instruction at pc as code in the rom usage map
+        fn mark_code(&self, pc: Word) {
+            let inst = crate::decode::decode(self.mem[pc]);
+            let len = if inst.prefix() { 2 } else { inst.len.max(1) } as Word;
+            for addr in pc..pc.saturating_add(len).min(MEM_VRAM) {
+                self.mem.mark_rom_usage(addr, ROM_USAGE_CODE);
+            }
+        }
+
+        fn record_trace(&mut self, pc: Word) {
+            if self.trace.len() >= self.trace_len {
+                self.trace.pop_front();
+            }
+            self.trace.push_back(Traced {
+                inst_count: self.cpu.inst_count,
+                bank: self.mem.bank_at(pc),
+                pc,
+                op: self.mem.read_fetch(pc),
+                args: [
+                    self.mem.read_fetch(pc.wrapping_add(1)),
+                    self.mem.read_fetch(pc.wrapping_add(2)),
+                ],
+            });
+        }
+
+        /// The last trace_len instructions run, oldest first
+        pub fn trace(&self) -> impl DoubleEndedIterator<Item = &Traced> {
+            self.trace.iter()
+        }
+
+        /// Runs an instruction at a time until `inst_count` have run since reset, landing
+        /// right before a Traced one. Idle loops and halts aren't skipped on the way, skipping
+        /// would go straight past. See Rewind::rewind_to
+        pub fn run_until(&mut self, inst_count: u64) -> Result<(), UnknownInstructionError> {
+            let skip_idle = std::mem::replace(&mut self.skip_idle, false);
+            let mut ran = Ok(());
+            while self.cpu.inst_count < inst_count {
+                if let Err(e) = self.step_instruction() {
+                    ran = Err(e);
+                    break;
+                }
+            }
+            self.skip_idle = skip_idle;
+            ran
+        }
+
+        /// A hash of the pixels on screen, what `cerboy test --expect-screen` compares
+        pub fn screen_hash(&self) -> u64 {
+            let mut h = StateHash::new();
+            for px in self.lcd.frame() {
+                h.bytes(&px.to_le_bytes());
+            }
+            h.finish()
+        }
+
+        /// LY, STAT, IF, DIV, TIMA: if none of these moved, the hardware hasn't done anything a
+        /// loop could be waiting on
+        fn io_snapshot(&self) -> [Byte; 5] {
+            [LY, STAT, IF, DIV, TIMA].map(|addr| self.mem.read(addr))
+        }
+
+        /// Advances the hardware by some cycles without running any instructions
+        fn advance(&mut self, cycles: Cycles) {
+            self.timers = update_clocks(self.timers, &mut self.mem, cycles);
+            let start = self.profiler.start();
+            self.apu.update(&mut self.mem, cycles);
+            self.profiler.stop(Section::Apu, start);
+            self.mem.add_cartridge_cycles(cycles);
+            let start = self.profiler.start();
+            self.lcd.update(&mut self.mem, cycles);
+            self.profiler.stop(Section::Ppu, start);
+        }
+
+        /// Detects loops waiting on hardware (polling LY/STAT/IF, or halted) and jumps ahead
+        ///
+        /// A loop is idle when an iteration lands back at the top with identical registers, no
+        /// memory writes along the way and no hardware changes under it: until the ppu or the
+        /// timers change something it will keep doing exactly that. Whole iterations are skipped up
+        /// to (but not including) the next hardware event, so the loop notices the change on the
+        /// same cycle it would have otherwise. Returns the cycles skipped.
+        fn skip_idle(&mut self, cpu_prev: CPUState) -> Cycles {
+            let until_event = self
+                .lcd
+                .cycles_until_event(&self.mem)
+                .min(self.timers.cycles_until_event(&self.mem));
+
+            if self.cpu.halt {
+                if self.mem.enabled_interrupts() != 0 {
+                    return Cycles::ZERO; // about to wake up
+                }
+                // nothing happens until an interrupt, halted cycles are an M-cycle at a time
+                let skip = Cycles::m(until_event.saturating_sub(Cycles::t(1)).as_m());
+                self.cpu.tsc += skip;
+                self.cpu.inst_count += skip.as_m();
+                self.advance(skip);
+                return skip;
+            }
+
+            let pc = self.cpu.pc;
+            let jumped_back = pc < cpu_prev.pc && cpu_prev.pc - pc <= IDLE_LOOP_MAX_LEN;
+            if !jumped_back {
+                return Cycles::ZERO;
+            }
+
+            let mut skip = Cycles::ZERO;
+            if let Some(top) = self.idle {
+                let same = top.cpu.pc == pc
+                    && top.cpu.reg == self.cpu.reg
+                    && top.cpu.sp == self.cpu.sp
+                    && top.cpu.ime == self.cpu.ime
+                    && top.cpu_writes == self.cpu_writes
+                    && top.io == self.io_snapshot();
+                if same {
+                    let period = (self.cpu.tsc - top.cpu.tsc).as_t();
+                    let iterations = until_event.saturating_sub(Cycles::t(1)).as_t() / period;
+                    skip = Cycles::t(iterations * period);
+                    self.cpu.tsc += skip;
+                    self.cpu.inst_count += iterations * (self.cpu.inst_count - top.cpu.inst_count);
+                    self.advance(skip);
+                }
+            }
+            self.idle = Some(IdleLoop {
+                cpu: self.cpu,
+                cpu_writes: self.cpu_writes,
+                io: self.io_snapshot(),
+            });
+            skip
+        }
+
+        /// Skips routines the config asks to skip, returning as if they'd been called and
+        /// immediately hit a `ret`
+        fn skip_routines(&mut self) {
+            let pc = self.cpu.pc;
+            let skip = self.hacks.iter().any(|hack| match hack {
+                Hack::Skip { skip } => skip.addr == pc && skip.bank == self.mem.bank_at(pc),
+                _ => false,
+            });
+            if skip {
+                let (cpu, ret) = pop_d16(self.cpu, &self.mem);
+                self.cpu = CPUState { pc: ret, ..cpu };
+            }
+        }
+
+        /// Holds extra buttons whenever the configured interrupts are dispatched
+        fn press_buttons(&mut self, serviced: Byte) {
+            let mut buttons = 0;
+            for hack in &self.hacks {
+                if let Hack::Press { on, press, frames } = hack {
+                    if serviced & on.flag() != 0 && frames.is_none_or(|n| self.frames < n) {
+                        buttons |= press.iter().fold(0, |b, btn| b | btn.bit());
+                    }
+                }
+            }
+            if buttons != 0 {
+                self.mem.set_buttons(self.mem.buttons() | buttons);
+            }
+        }
+
+        /// Runs a single instruction (or interrupt dispatch, or halted tick) and lets the
+        /// rest of the hardware catch up
+        pub fn step_instruction(&mut self) -> Result<StepInfo, UnknownInstructionError> {
+            if !self.hacks.is_empty() {
+                self.skip_routines();
+            }
+            if self.trace_len > 0 {
+                self.record_trace(self.cpu.pc);
+            }
+            let cpu_prev = self.cpu;
+            let writes_prev = self.mem.writes;
+            self.mem.watch_hit = None;
+            if !self.mem.watches.is_empty() {
+                self.mem.watch_pc = cpu_prev.pc;
+            }
+            let mut mode_changes = vec![];
+            let mut frame = false;
+            let mut requested = 0;
+            let mut serviced = 0;
+            let mut caught_up = Cycles::ZERO;
+            let watch_hit;
+            if interrupt_pending(&cpu_prev, &self.mem) {
+                // the rest of the hardware keeps going while the vector is being decided, an
+                // interrupt raised in the meantime can still get in first
+                let cpu = dispatch_interrupt_begin(cpu_prev, &mut self.mem);
+                self.cpu_writes += self.mem.writes - writes_prev;
+                let hit = self.mem.watch_hit.take();
+                caught_up = cpu.tsc - cpu_prev.tsc;
+                let if_cpu = self.mem.read(IF);
+                frame = self.catch_up(caught_up, &mut mode_changes);
+                requested = self.mem.read(IF) & !if_cpu;
+
+                self.mem.watch_hit = None;
+                let writes_mid = self.mem.writes;
+                self.cpu = dispatch_interrupt_end(cpu, &mut self.mem);
+                self.cpu_writes += self.mem.writes - writes_mid;
+                watch_hit = hit.or(self.mem.watch_hit.take());
+                if self.cpu.pc != 0x0000 {
+                    serviced = 1 << ((self.cpu.pc - VEC_INT_VBLANK) / 8);
+                }
+                if serviced != 0 && !self.hacks.is_empty() {
+                    self.press_buttons(serviced);
+                }
+            } else {
+                let start = self.profiler.start();
+                if self.mem.tracks_rom_usage() && cpu_prev.pc < MEM_VRAM {
+                    self.mark_code(cpu_prev.pc);
+                }
+                let fetched = if self.block_cache {
+                    self.cache.fetch(&mut self.mem, cpu_prev.pc)
+                } else {
+                    None
+                };
+                let next = match fetched {
+                    Some(f) => next_fetched(cpu_prev, &mut self.mem, f.op, f.args),
+                    None => next(cpu_prev, &mut self.mem),
+                };
+                self.profiler.stop(Section::Cpu, start);
+                if next.is_err() && self.mem.lint {
+                    let op = self.mem[cpu_prev.pc];
+                    self.mem.lints.get_mut().push(Lint::InvalidOpcode(op));
+                    self.collect_lints(cpu_prev.pc);
+                }
+                self.cpu = next?;
+                self.cpu_writes += self.mem.writes - writes_prev;
+                // only the cpu's own writes count, not the hardware's (the ppu setting STAT...)
+                watch_hit = self.mem.watch_hit.take();
+            }
+            let if_cpu = self.mem.read(IF);
+            let dt_cyc = self.cpu.tsc - cpu_prev.tsc;
+
+            let mut stack_fault = None;
+            if INSTRUMENT && self.mem.stack_guard {
+                stack_fault = self.mem.stack_fault.take();
+                if stack_fault.is_none() && self.cpu.sp != cpu_prev.sp {
+                    stack_fault = StackFault::check_sp(cpu_prev.pc, self.cpu.sp);
+                }
+            }
+
+            frame |= self.catch_up(dt_cyc - caught_up, &mut mode_changes);
+            requested |= self.mem.read(IF) & !if_cpu;
+
+            if self.mem.lint {
+                self.collect_lints(cpu_prev.pc);
+            }
+
+            let mut skipped = Cycles::ZERO;
+            if self.skip_idle && !frame && !(INSTRUMENT && self.mem.doctor) {
+                skipped = self.skip_idle(cpu_prev);
+            }
+
+            Ok(StepInfo {
+                cycles: dt_cyc + skipped,
+                instructions: 1,
+                mode_changes,
+                interrupts_requested: requested,
+                interrupts_serviced: serviced,
+                frame,
+                stack_fault,
+                watch_hit,
+            })
+        }
+
+        /// Warnings for dubious things the rom did since the last call, with Memory::lint on
+        pub fn take_lints(&mut self) -> Vec<LintWarning> {
+            self.linter.take()
+        }
+
+        /// Sends a byte from the far end of the link cable, see Memory::serial_push
+        pub fn serial_push(&mut self, byte: Byte) {
+            self.mem.serial_push(byte);
+        }
+
+        /// What the game sent over the link cable since the last call
+        pub fn serial_take(&mut self) -> Vec<Byte> {
+            self.mem.serial_take()
+        }
+
+        /// Triggers that fired since the last call, oldest first
+        pub fn take_events(&mut self) -> Vec<TriggerEvent> {
+            std::mem::take(&mut self.triggers.events)
+        }
+
+        fn collect_lints(&mut self, pc: Word) {
+            let bank = self.mem.bank_at(pc);
+            for lint in self.mem.lints.get_mut().drain(..) {
+                // the two bytes after the opcode are fetched whether it takes arguments or not
+                if lint == Lint::UninitializedRead(pc.wrapping_add(1))
+                    || lint == Lint::UninitializedRead(pc.wrapping_add(2))
+                {
+                    continue;
+                }
+                self.linter.add(lint, bank, pc);
+            }
+        }
+
+        /// Lets the rest of the hardware run for the cycles the cpu just took, noting any lcd
+        /// mode change. Returns whether a frame was completed
+        fn catch_up(&mut self, cycles: Cycles, mode_changes: &mut Vec<(Byte, Byte)>) -> bool {
+            // update memory (e.g. handle any pending DMA transfers)
+            self.mem.update();
+
+            // update timers
+            self.timers = update_clocks(self.timers, &mut self.mem, cycles);
+            let start = self.profiler.start();
+            self.apu.update(&mut self.mem, cycles);
+            self.profiler.stop(Section::Apu, start);
+            self.mem.add_cartridge_cycles(cycles);
+            if let Some(sgb) = &mut self.sgb {
+                for select in std::mem::take(&mut self.mem.joyp_writes) {
+                    sgb.write_joyp(select, &mut self.mem);
+                }
+            }
+
+            // update display
+            let mode_prev = lcd_mode(&self.mem);
+            let start = self.profiler.start();
+            let frame = self.lcd.update(&mut self.mem, cycles);
+            self.profiler.stop(Section::Ppu, start);
+            let mode = lcd_mode(&self.mem);
+            if mode != mode_prev {
+                mode_changes.push((mode_prev, mode));
+            }
+
+            if frame {
+                self.frames += 1;
+                if let Some(sgb) = &mut self.sgb {
+                    sgb.frame_done(&mut self.lcd);
+                }
+                if !self.triggers.is_empty() {
+                    self.triggers.check(&self.mem, self.frames);
+                }
+            }
+            frame
+        }
+
+        /// Runs until the ppu moves on to the next scanline (or finishes the frame)
+        ///
+        /// Stops early on a stack fault or watchpoint.
+        pub fn step_scanline(&mut self) -> Result<StepInfo, UnknownInstructionError> {
+            let ly = self.lcd.ly(&self.mem);
+            let mut info = StepInfo::default();
+            while !info.frame && !info.stopped() && self.lcd.ly(&self.mem) == ly {
+                info.merge(self.step_instruction()?);
+            }
+            Ok(info)
+        }
+
+        /// Runs until the next frame is completed
+        ///
+        /// Stops early on a stack fault or watchpoint.
+        pub fn step_frame(&mut self) -> Result<StepInfo, UnknownInstructionError> {
+            let mut info = StepInfo::default();
+            while !info.frame && !info.stopped() {
+                info.merge(self.step_instruction()?);
+            }
+            Ok(info)
+        }
+    }
+
+    /// Ring of per-frame snapshots, newest at the back
+    ///
+    /// Only the newest is kept whole. The ones before it keep just the pages of memory and
+    /// of the frame sized buffers (the lcd's and the sgb's) that differ from the frame after
+    /// them, which is usually a few KB rather than a few hundred. Memory's write journal says
+    /// which memory pages can have changed, so there's no need to compare the rest.
+    pub struct Rewind {
+        head: Option<Emulator>,
+        undos: VecDeque<Undo>, // from the frame before head back to the oldest, oldest first
+        capacity: usize,
+    }
+
+    /// A frame before the head of the rewind buffer, as what it takes to get back there from
+    /// the frame after it
+    struct Undo {
+        emu: Emulator, // everything but the pages, see Memory::take_data() and buffers()
+        mem: Pages<Byte>,
+        pixels: Vec<Option<Pages<u32>>>,
+        bytes: Vec<Option<Pages<Byte>>>,
+        words: Vec<Option<Pages<Word>>>,
+    }
+
+    // (page, contents) with pages PAGE_SIZE long
+    type Pages<T> = Vec<(usize, Vec<T>)>;
+
+    // the buffers of an emulator Undo keeps as pages, by element type
+    type Buffers<'a> = (
+        Vec<&'a mut Vec<u32>>,
+        Vec<&'a mut Vec<Byte>>,
+        Vec<&'a mut Vec<Word>>,
+    );
+
+    fn buffers(emu: &mut Emulator) -> Buffers<'_> {
+        let lcd = &mut emu.lcd;
+        let mut pixels = vec![&mut lcd.buffer, &mut lcd.front, &mut lcd.last_raw];
+        let mut bytes = vec![
+            &mut lcd.sprite_history,
+            &mut lcd.buffer_shades,
+            &mut lcd.shades,
+        ];
+        let mut words = vec![];
+        if let Some(sgb) = &mut emu.sgb {
+            pixels.extend([&mut sgb.border, &mut sgb.screen]);
+            bytes.extend([&mut sgb.tiles, &mut sgb.map, &mut sgb.attr_files]);
+            words.push(&mut sgb.system_palettes);
+        }
+        (pixels, bytes, words)
+    }
+
+    /// Brings `old` up to date with `new` in the pages (out of `pages`) where they differ,
+    /// giving back what those held
+    fn update_pages<T: Copy + PartialEq>(
+        old: &mut [T],
+        new: &[T],
+        pages: impl Iterator<Item = usize>,
+    ) -> Pages<T> {
+        let mut held = vec![];
+        for page in pages {
+            let range = page * PAGE_SIZE..((page + 1) * PAGE_SIZE).min(old.len());
+            if old[range.clone()] != new[range.clone()] {
+                held.push((page, old[range.clone()].to_vec()));
+                old[range.clone()].copy_from_slice(&new[range]);
+            }
+        }
+        held
+    }
+
+    fn undo_pages<T: Copy>(data: &mut [T], pages: &Pages<T>) {
+        for (page, old) in pages {
+            data[page * PAGE_SIZE..][..old.len()].copy_from_slice(old);
+        }
+    }
+
+    /// Moves each of `prev` into the same one of `next`, brought up to date with `emu` on the
+    /// way, and gives back the pages it held that differed. Where there's no match of the
+    /// same size `next` gets a copy of emu's instead and prev keeps its own whole, as None
+    fn carry_buffers<T: Copy + PartialEq>(
+        prev: Vec<&mut Vec<T>>,
+        emu: Vec<&mut Vec<T>>,
+        next: Vec<&mut Vec<T>>,
+    ) -> Vec<Option<Pages<T>>> {
+        let mut prev = prev.into_iter();
+        emu.into_iter()
+            .zip(next)
+            .map(|(emu, next)| match prev.next() {
+                Some(prev) if prev.len() == emu.len() => {
+                    let pages = update_pages(prev, emu, 0..emu.len().div_ceil(PAGE_SIZE));
+                    *next = std::mem::take(prev);
+                    Some(pages)
+                }
+                _ => {
+                    *next = emu.to_vec();
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// A copy of `emu` with its memory and buffers() left empty, cheap next to a whole clone
+    fn clone_bare(emu: &mut Emulator) -> Emulator {
+        fn take_all<T>(buffers: Vec<&mut Vec<T>>) -> Vec<Vec<T>> {
+            buffers.into_iter().map(std::mem::take).collect()
+        }
+        fn put_all<T>(buffers: Vec<&mut Vec<T>>, taken: Vec<Vec<T>>) {
+            for (buffer, taken) in buffers.into_iter().zip(taken) {
+                *buffer = taken;
+            }
+        }
+        let data = std::mem::take(&mut emu.mem.data);
+        let (pixels, bytes, words) = buffers(emu);
+        let taken = (take_all(pixels), take_all(bytes), take_all(words));
+        let bare = emu.clone();
+        let (pixels, bytes, words) = buffers(emu);
+        put_all(pixels, taken.0);
+        put_all(bytes, taken.1);
+        put_all(words, taken.2);
+        emu.mem.data = data;
+        bare
+    }
+
+    /// Undoes carry_buffers(), moving each of `next` back into `prev`
+    fn undo_buffers<T: Copy>(
+        prev: Vec<&mut Vec<T>>,
+        next: Vec<&mut Vec<T>>,
+        pages: &[Option<Pages<T>>],
+    ) {
+        for ((prev, next), pages) in prev.into_iter().zip(next).zip(pages) {
+            if let Some(pages) = pages {
+                undo_pages(next, pages);
+                *prev = std::mem::take(next);
+            }
+        }
+    }
+
+    impl Undo {
+        /// `prev` as the changes from `emu`, given the memory pages written in between. Its
+        /// memory and buffers move into `next`, a clone_bare() of emu, caught up to emu's
+        fn new(
+            mut prev: Emulator,
+            emu: &mut Emulator,
+            next: &mut Emulator,
+            written: &[usize],
+        ) -> Undo {
+            let mem = update_pages(&mut prev.mem.data, &emu.mem.data, written.iter().copied());
+            next.mem.put_data(prev.mem.take_data());
+            let (old, now, new) = (buffers(&mut prev), buffers(emu), buffers(next));
+            let pixels = carry_buffers(old.0, now.0, new.0);
+            let bytes = carry_buffers(old.1, now.1, new.1);
+            let words = carry_buffers(old.2, now.2, new.2);
+            prev.apu.take_samples();
+            Undo {
+                emu: prev,
+                mem,
+                pixels,
+                bytes,
+                words,
+            }
+        }
+
+        /// The frame before `next`, made out of its pages
+        fn apply(self, mut next: Emulator) -> Emulator {
+            let mut prev = self.emu;
+            undo_pages(&mut next.mem.data, &self.mem);
+            prev.mem.put_data(next.mem.take_data());
+            let (old, new) = (buffers(&mut prev), buffers(&mut next));
+            undo_buffers(old.0, new.0, &self.pixels);
+            undo_buffers(old.1, new.1, &self.bytes);
+            undo_buffers(old.2, new.2, &self.words);
+            prev
+        }
+    }
+
+    impl Rewind {
+        pub fn new(capacity: usize) -> Rewind {
+            Rewind {
+                head: None,
+                undos: VecDeque::with_capacity(capacity),
+                capacity,
+            }
+        }
+
+        pub fn len(&self) -> usize {
+            self.head.is_some() as usize + self.undos.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.head.is_none()
+        }
+
+        /// Records the state at the end of a frame, dropping the oldest one when full
+        ///
+        /// Takes the memory write journal, so `emu` has to carry on from the last frame
+        /// pushed (or stepped back to).
+        pub fn push(&mut self, emu: &mut Emulator) {
+            let written = emu.mem.take_written();
+            let Some(prev) = self.head.take() else {
+                self.head = Some(emu.clone());
+                return;
+            };
+            // only what changed since the last frame is copied, into the last frame's buffers
+            let mut next = clone_bare(emu);
+            let undo = Undo::new(prev, emu, &mut next, &written);
+            self.undos.push_back(undo);
+            self.head = Some(next);
+            while self.len() > self.capacity {
+                self.undos.pop_front();
+            }
+        }
+
+        /// Steps back one frame
+        ///
+        /// The newest snapshot is the frame currently on screen, so it's dropped and the one
+        /// before it is returned. The oldest snapshot is never dropped.
+        pub fn step_back(&mut self) -> Option<Emulator> {
+            let undo = self.undos.pop_back()?;
+            let prev = undo.apply(self.head.take().unwrap());
+            self.head = Some(prev.clone());
+            Some(prev)
+        }
+
+        /// Steps back to the newest snapshot from before instruction `inst_count` (counted
+        /// since reset, see Traced), for Emulator::run_until to replay the rest of the way.
+        /// None, with nothing dropped, when that's further back than the oldest snapshot
+        pub fn rewind_to(&mut self, inst_count: u64) -> Option<Emulator> {
+            let oldest = match self.undos.front() {
+                Some(undo) => &undo.emu,
+                None => self.head.as_ref()?,
+            };
+            if oldest.cpu.inst_count > inst_count {
+                return None;
+            }
+            while self.head.as_ref().unwrap().cpu.inst_count > inst_count {
+                self.step_back();
+            }
+            self.head.clone()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests_emu {
+        use super::*;
+        use crate::dbg::{RomUsage, WatchPreset, ROM_USAGE_DATA};
+
+        // a cart full of nops, the cpu just slides through rom
+        fn emu() -> Emulator {
+            Emulator::new(&Cartridge::from(vec![0; BANK_SIZE * 2]))
+        }
+
+        #[test]
+        fn test_step_instruction() {
+            let mut emu = emu();
+            let info = emu.step_instruction().unwrap();
+            assert_eq!(info.instructions, 1);
+            assert_eq!(info.cycles, Cycles::m(1));
+            assert_eq!(emu.cpu.pc, ROM_ENTRY + 1);
+        }
+
+        #[test]
+        fn test_boot_model() {
+            let boot = |flag: Byte| {
+                let mut rom = vec![0; BANK_SIZE * 2];
+                rom[ROM_CGB as usize] = flag;
+                Emulator::new(&Cartridge::from(rom))
+            };
+            // dual mode carts see a dmg and take their dmg path
+            for flag in [0x00, 0x80] {
+                let emu = boot(flag);
+                assert!(!emu.mem.cgb);
+                assert_eq!(emu.cpu.reg[REG_A], 0x01);
+            }
+            let emu = boot(0xC0);
+            assert!(emu.mem.cgb);
+            assert_eq!(emu.cpu.reg[REG_A], 0x11);
+        }
+
+        #[test]
+        fn test_crash_report() {
+            let mut rom = vec![0; BANK_SIZE * 2];
+            rom[0x0101..0x0104].copy_from_slice(&[0x3E, 0x42, 0xCB]); // ld a, $42; swap a
+            rom[0x0104] = 0x37;
+            let mut emu = Emulator::new(&Cartridge::from(rom));
+            emu.step_instruction().unwrap();
+            assert!(!emu.crash_report().contains("instructions"));
+
+            emu.trace_len = 2;
+            for _ in 0..3 {
+                emu.step_instruction().unwrap();
+            }
+            let report = emu.crash_report();
+            assert!(report.starts_with("pc 00:0106 sp FFFE af 24"));
+            assert!(report.contains("last 2 instructions:\n  00:0103  SWAP, A\n  00:0105  NOP\n"));
+            assert!(report.contains("FF40 LCDC"));
+        }
+
+        #[test]
+        fn test_time_travel() {
+            let mut rom = vec![0; BANK_SIZE * 2];
+            // loop: inc a; ld ($C000), a; halt; jr loop, with the vblank interrupt waking it
+            let code = [0x3C, 0xEA, 0x00, 0xC0, 0x76, 0x18, 0xF9];
+            rom[0x0100..0x0100 + code.len()].copy_from_slice(&code);
+            rom[0x0040] = 0xD9; // reti
+            let mut emu = Emulator::new(&Cartridge::from(rom));
+            emu.mem.write(IE, FL_INT_VBLANK);
+            emu.cpu.ime = true;
+            emu.trace_len = 16;
+            let mut rewind = Rewind::new(4);
+            rewind.push(&mut emu);
+            let mut first_frame = None;
+            for _ in 0..3 {
+                emu.step_frame().unwrap();
+                rewind.push(&mut emu);
+                first_frame = first_frame.or_else(|| emu.trace().last().copied());
+            }
+            // partway into the next frame, keeping each state to check against
+            let mut states = vec![];
+            for _ in 0..6 {
+                states.push(emu.clone());
+                emu.step_instruction().unwrap();
+            }
+            let hash_at = |n| {
+                states
+                    .iter()
+                    .find(|e: &&Emulator| e.cpu.inst_count == n)
+                    .map(|e| e.state_hash())
+            };
+
+            // in the frame being run, the newest snapshot is the way back
+            let target = *emu.trace().nth(12).unwrap();
+            assert!(hash_at(target.inst_count).is_some());
+            let mut back = rewind.rewind_to(target.inst_count).unwrap();
+            assert_eq!(rewind.len(), 4);
+            back.run_until(target.inst_count).unwrap();
+            assert_eq!(back.cpu.pc, target.pc);
+            assert!(back.skip_idle);
+            assert_eq!(Some(back.state_hash()), hash_at(target.inst_count));
+            assert_eq!(
+                back.trace().last().unwrap().inst_count,
+                target.inst_count - 1
+            );
+
+            // frames back, the snapshots after it go
+            let earlier = first_frame.unwrap();
+            let mut back = rewind.rewind_to(earlier.inst_count).unwrap();
+            assert!(rewind.len() < 4);
+            back.run_until(earlier.inst_count).unwrap();
+            assert_eq!(back.cpu.pc, earlier.pc);
+
+            // older than anything kept
+            let mut short = Rewind::new(1);
+            assert!(short.rewind_to(0).is_none());
+            short.push(&mut emu.clone());
+            assert!(short.rewind_to(earlier.inst_count).is_none());
+            assert_eq!(short.len(), 1);
+        }
+
+        #[test]
+        fn test_rom_usage() {
+            let mut rom = vec![0; BANK_SIZE * 4];
+            rom[0x0147] = 0x01; // MBC1
+            rom[0x0148] = 0x01; // 4 banks
+
+            // ld a, $02; ld ($2000), a; ld a, ($4000); jr -2
+            let code = [0x3E, 0x02, 0xEA, 0x00, 0x20, 0xFA, 0x00, 0x40, 0x18, 0xFE];
+            rom[0x0100..0x0100 + code.len()].copy_from_slice(&code);
+            let mut emu = Emulator::new(&Cartridge::from(rom));
+            let usage = Arc::new(std::sync::Mutex::new(RomUsage::new(BANK_SIZE * 4)));
+            emu.mem.set_rom_usage(Some(usage.clone()));
+            for _ in 0..5 {
+                emu.step_instruction().unwrap();
+            }
+            let usage = usage.lock().unwrap();
+            let flags = usage.flags();
+            assert!(flags[0x0100..0x010A]
+                .iter()
+                .all(|f| f & ROM_USAGE_CODE != 0));
+            assert_eq!(flags[0x00FF], 0);
+            assert_eq!(flags[0x010A], 0);
+            // the read went to bank 2, where the mapper put it
+            assert_eq!(flags[2 * BANK_SIZE], ROM_USAGE_DATA);
+            assert_eq!(flags[BANK_SIZE], 0);
+            assert_eq!(usage.bank_counts(2), (0, 1, BANK_SIZE - 1));
+            assert_eq!(usage.bank_counts(1), (0, 0, BANK_SIZE));
+        }
+
+        #[test]
+        fn test_watchpoints() {
+            let mut rom = vec![0; BANK_SIZE * 2];
+            // ld a, $04; ldh ($0F), a; ld ($FE00), a
+            rom[0x0101..0x0108].copy_from_slice(&[0x3E, 0x04, 0xE0, 0x0F, 0xEA, 0x00, 0xFE]);
+            let mut writer = Emulator::new(&Cartridge::from(rom));
+            writer.mem.watches = "break-on-oam-write"
+                .parse::<WatchPreset>()
+                .unwrap()
+                .watchpoints();
+            let info = writer.step_frame().unwrap();
+            let hit = info.watch_hit.unwrap();
+            assert_eq!((hit.pc, hit.addr, hit.val), (0x0105, 0xFE00, 0x04));
+            assert_eq!(writer.cpu.pc, 0x0108);
+
+            // the ppu and timers raising interrupts isn't the cpu writing IF
+            let mut emu = emu();
+            emu.mem.watches = WatchPreset::IfWrite.watchpoints();
+            let info = emu.step_frame().unwrap();
+            assert!(info.frame);
+            assert_eq!(info.watch_hit, None);
+
+            // vram is only off limits while the ppu is drawing
+            let vram = WatchPreset::VramWriteDuringMode3.watchpoints()[0];
+            assert!(vram.hit(0x8000, 3));
+            assert!(!vram.hit(0x8000, 0));
+            assert!("break-on-everything".parse::<WatchPreset>().is_err());
+        }
+
+        #[test]
+        fn test_step_scanline() {
+            let mut emu = emu();
+            emu.step_frame().unwrap(); // sync up with the start of a frame
+            let info = emu.step_scanline().unwrap();
+            assert_eq!(emu.lcd.ly(&emu.mem), 1);
+            assert!(
+                info.cycles.as_t() >= TICKS_PER_SCANLINE
+                    && info.cycles.as_t() < TICKS_PER_SCANLINE + 8
+            );
+            assert_eq!(info.mode_changes, vec![(2, 3), (3, 0), (0, 2)]);
+            assert!(!info.frame);
+        }
+
+        #[test]
+        fn test_step_frame() {
+            let mut emu = emu();
+            emu.step_frame().unwrap();
+            emu.mem.write(IF, 0); // nothing services the last vblank
+            let info = emu.step_frame().unwrap();
+            assert!(info.frame);
+            assert!(
+                info.cycles.as_t() >= TICKS_PER_FRAME - 8
+                    && info.cycles.as_t() <= TICKS_PER_FRAME + 8
+            );
+            assert_eq!(info.interrupts_requested & FL_INT_VBLANK, FL_INT_VBLANK);
+            assert_eq!(info.interrupts_serviced, 0); // ime is off
+        }
+
+        #[test]
+        #[cfg(feature = "instrument")]
+        fn test_stack_guard() {
+            let mut rom = vec![0; BANK_SIZE * 2];
+            // ld sp, $8010 / push bc
+            rom[ROM_ENTRY as usize..][..4].copy_from_slice(&[0x31, 0x10, 0x80, 0xC5]);
+            let cart = Cartridge::from(rom);
+
+            let mut emu = Emulator::new(&cart);
+            let info = emu.step_instruction().unwrap();
+            assert_eq!(info.stack_fault, None); // guard is off
+
+            let mut emu = Emulator::new(&cart);
+            emu.mem.stack_guard = true;
+            let info = emu.step_instruction().unwrap();
+            assert_eq!(info.stack_fault, Some(StackFault::Sp { pc: 0x0100, sp: 0x8010 }));
+            let info = emu.step_instruction().unwrap();
+            assert_eq!(info.stack_fault, Some(StackFault::Push { pc: 0x0103, addr: 0x800E }));
+            let info = emu.step_instruction().unwrap();
+            assert_eq!(info.stack_fault, None);
+        }
+
+        #[test]
+        fn test_skip_idle() {
+            let mut rom = vec![0; BANK_SIZE * 2];
+            // wait for vblank, then spin
+            // loop: ldh a, (LY) / cp $90 / jr nz, loop / jr @
+            rom[ROM_ENTRY as usize..][..8]
+                .copy_from_slice(&[0xF0, 0x44, 0xFE, 0x90, 0x20, 0xFA, 0x18, 0xFE]);
+            let cart = Cartridge::from(rom);
+
+            let run = |skip_idle: bool| {
+                let mut emu = Emulator::new(&cart);
+                emu.skip_idle = skip_idle;
+                let mut steps = 0;
+                while emu.cpu.pc != ROM_ENTRY + 6 {
+                    emu.step_instruction().unwrap();
+                    steps += 1;
+                }
+                (emu, steps)
+            };
+            let (slow, slow_steps) = run(false);
+            let (fast, fast_steps) = run(true);
+            assert_eq!(fast.cpu.tsc, slow.cpu.tsc);
+            assert_eq!(fast.cpu.inst_count, slow.cpu.inst_count);
+            assert_eq!(fast.mem.read(LY), 0x90);
+            // DIV ticking every 256 cycles caps how far each skip can go
+            assert!(fast_steps * 3 < slow_steps * 2);
+        }
+
+        #[test]
+        fn test_block_cache() {
+            let mut rom = vec![0; BANK_SIZE * 2];
+            #[rustfmt::skip]
+            let code = [
+                0x21, 0x00, 0xC0, // ld hl, $C000
+                0x36, 0x3C,       // ld (hl), $3C (inc a)
+                0x23,             // inc hl
+                0x36, 0xC9,       // ld (hl), $C9 (ret)
+                0xCD, 0x00, 0xC0, // call $C000
+                0x21, 0x00, 0xC0, // ld hl, $C000
+                0x36, 0x04,       // ld (hl), $04 (inc b), overwrites cached code
+                0xCD, 0x00, 0xC0, // call $C000
+                0x18, 0xFE,       // jr @
+            ];
+            rom[ROM_ENTRY as usize..][..code.len()].copy_from_slice(&code);
+            let cart = Cartridge::from(rom);
+
+            let run = |block_cache: bool| {
+                let mut emu = Emulator::new(&cart);
+                emu.block_cache = block_cache;
+                while emu.cpu.pc != ROM_ENTRY + code.len() as Word - 2 {
+                    emu.step_instruction().unwrap();
+                }
+                emu
+            };
+            let slow = run(false);
+            let fast = run(true);
+            assert_eq!(fast.cpu.reg, slow.cpu.reg);
+            assert_eq!(fast.cpu.tsc, slow.cpu.tsc);
+            assert_eq!(fast.cpu.reg[REG_A], CPUState::new().reg[REG_A] + 1);
+            assert_eq!(fast.cpu.reg[REG_B], CPUState::new().reg[REG_B] + 1);
+        }
+
+        #[test]
+        fn test_state_hash() {
+            let mut a = emu();
+            let mut b = emu();
+            b.skip_idle = false;
+            b.block_cache = true;
+            assert_eq!(a.state_hash(), b.state_hash());
+            a.step_frame().unwrap();
+            assert_ne!(a.state_hash(), b.state_hash());
+            b.step_frame().unwrap();
+            assert_eq!(a.state_hash(), b.state_hash());
+
+            b.mem.write(MEM_WRAM_0, 1);
+            assert_ne!(a.state_hash(), b.state_hash());
+        }
+
+        #[test]
+        fn test_rewind() {
+            let mut emu = emu();
+            let mut rewind = Rewind::new(3);
+            let mut tsc = vec![];
+            for _ in 0..4 {
+                emu.step_frame().unwrap();
+                rewind.push(&mut emu);
+                tsc.push(emu.cpu.tsc);
+            }
+            assert_eq!(rewind.len(), 3);
+            assert_eq!(rewind.step_back().unwrap().cpu.tsc, tsc[2]);
+            assert_eq!(rewind.step_back().unwrap().cpu.tsc, tsc[1]);
+            assert!(rewind.step_back().is_none()); // first frame fell off the end
+        }
+
+        #[test]
+        fn test_rewind_deltas() {
+            // ld hl, $8000 / inc (hl) / jr -3: the first tile (and so the screen) keeps changing
+            let mut rom = vec![0; BANK_SIZE * 2];
+            rom[ROM_ENTRY as usize..][..6].copy_from_slice(&[0x21, 0x00, 0x80, 0x34, 0x18, 0xFD]);
+            // on an sgb, so its buffers and the shades get kept as pages too
+            rom[ROM_SGB as usize] = 0x03;
+            let mut emu = Emulator::new(&Cartridge::from(rom));
+            let mut rewind = Rewind::new(10);
+            let mut frames = vec![];
+            for _ in 0..5 {
+                emu.step_frame().unwrap();
+                rewind.push(&mut emu);
+                frames.push((
+                    emu.state_hash(),
+                    emu.lcd.frame().to_vec(),
+                    emu.lcd.shades().to_vec(),
+                ));
+            }
+            assert_ne!(frames[1].1, frames[2].1);
+            let undo = &rewind.undos[0];
+            assert!(
+                undo.emu.lcd.shades.is_empty() && undo.emu.sgb.as_ref().unwrap().border.is_empty()
+            );
+
+            for (hash, frame, shades) in frames[..4].iter().rev() {
+                emu = rewind.step_back().unwrap();
+                assert_eq!(emu.state_hash(), *hash);
+                assert_eq!(emu.lcd.frame(), &frame[..]);
+                assert_eq!(emu.lcd.shades(), &shades[..]);
+            }
+            // and carries on from there
+            emu.step_frame().unwrap();
+            rewind.push(&mut emu);
+            assert_eq!(emu.state_hash(), frames[1].0);
+            assert_eq!(rewind.step_back().unwrap().state_hash(), frames[0].0);
+        }
+
+        #[test]
+        fn test_hacks() {
+            use crate::config::{Button, CodeAddr, Interrupt};
+            let mut rom = vec![0; BANK_SIZE * 2];
+            rom[VEC_INT_VBLANK as usize] = 0xD9; // reti
+
+            // call $0200 / ei / jr -2, with a halt at $0200 that never gets run
+            rom[ROM_ENTRY as usize..][..6].copy_from_slice(&[0xCD, 0x00, 0x02, 0xFB, 0x18, 0xFE]);
+            rom[0x0200] = 0x76;
+            let mut emu = Emulator::new(&Cartridge::from(rom));
+            emu.hacks = vec![
+                Hack::Skip {
+                    skip: CodeAddr {
+                        bank: 0,
+                        addr: 0x0200,
+                    },
+                },
+                Hack::Press {
+                    on: Interrupt::Vblank,
+                    press: vec![Button::Start],
+                    frames: Some(2),
+                },
+            ];
+            emu.mem.write(IE, FL_INT_VBLANK);
+
+            emu.step_instruction().unwrap();
+            assert_eq!(emu.cpu.pc, 0x0200);
+            emu.step_instruction().unwrap(); // returns, then runs the ei
+            assert_eq!(emu.cpu.pc, ROM_ENTRY + 4);
+            assert!(!emu.cpu.halt);
+
+            emu.step_frame().unwrap();
+            emu.step_frame().unwrap();
+            assert_eq!(emu.mem.buttons(), BTN_START);
+
+            // past the first 2 frames the game's on its own again
+            emu.mem.set_buttons(0);
+            emu.step_frame().unwrap();
+            emu.step_frame().unwrap();
+            assert_eq!(emu.mem.buttons(), 0);
+        }
+
+        #[test]
+        fn test_triggers() {
+            use crate::config::Condition;
+            use std::convert::TryFrom;
+            let trigger = |text: &str, repeat| Trigger {
+                name: text.to_string(),
+                when: vec![Condition::try_from(text.to_string()).unwrap()],
+                repeat,
+                split: None,
+            };
+            let mut emu = emu();
+            emu.mem.write(0xC001, 0x01);
+            emu.triggers.add(trigger("C000 == 01", false), &emu.mem);
+            emu.triggers.add(trigger("C001 & 01", true), &emu.mem);
+            emu.step_frame().unwrap();
+            assert_eq!(emu.take_events(), vec![]); // already held when added
+
+            emu.mem.write(0xC000, 0x01);
+            emu.step_frame().unwrap();
+            let events = emu.take_events();
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].name, "C000 == 01");
+            assert_eq!(events[0].frame, 2);
+
+            // only the repeating one fires again
+            for val in [0x00, 0x01] {
+                emu.mem.write(0xC000, val);
+                emu.mem.write(0xC001, val);
+                emu.step_frame().unwrap();
+            }
+            let names: Vec<String> = emu.take_events().into_iter().map(|e| e.name).collect();
+            assert_eq!(names, vec!["C001 & 01"]);
+        }
+
+        // from $0150: fills the tiles and both maps with a pattern, sets up 4 sprites and the
+        // window, then every frame scrolls the background diagonally, moves two sprites and
+        // slides the window across
+        #[rustfmt::skip]
+        const SCROLL_DEMO: &[Byte] = &[
+            0xF3,             // di
+            0x31, 0xFE, 0xFF, // ld sp, $FFFE
+            0xF0, 0x44,       // .vblank: ldh a, (LY)
+            0xFE, 0x90,       // cp 144
+            0x20, 0xFA,       // jr nz, .vblank
+            0xAF,             // xor a
+            0xE0, 0x40,       // ldh (LCDC), a
+            0x21, 0x00, 0x80, // ld hl, $8000
+            0x7D,             // .vram: ld a, l
+            0x84,             // add a, h
+            0x22,             // ld (hl+), a
+            0x7C,             // ld a, h
+            0xFE, 0xA0,       // cp $A0
+            0x20, 0xF8,       // jr nz, .vram
+            0x21, 0x00, 0xFE, // ld hl, $FE00
+            0x7D,             // .oam: ld a, l
+            0xC6, 0x30,       // add a, $30
+            0x22,             // ld (hl+), a
+            0x7D,             // ld a, l
+            0xFE, 0x10,       // cp $10
+            0x20, 0xF7,       // jr nz, .oam
+            0x3E, 0xE4,       // ld a, $E4
+            0xE0, 0x47,       // ldh (BGP), a
+            0xE0, 0x48,       // ldh (OBP0), a
+            0x3E, 0xD2,       // ld a, $D2
+            0xE0, 0x49,       // ldh (OBP1), a
+            0x3E, 0x60,       // ld a, 96
+            0xE0, 0x4A,       // ldh (WY), a
+            0x3E, 0x50,       // ld a, 80
+            0xE0, 0x4B,       // ldh (WX), a
+            0x3E, 0xF3,       // ld a, $F3 (on, window at 9C00, tiles at 8000, objects, bg)
+            0xE0, 0x40,       // ldh (LCDC), a
+            0xF0, 0x44,       // .frame: ldh a, (LY)
+            0xFE, 0x90,       // cp 144
+            0x20, 0xFA,       // jr nz, .frame
+            0xF0, 0x43,       // ldh a, (SCX)
+            0x3C,             // inc a
+            0xE0, 0x43,       // ldh (SCX), a
+            0xF0, 0x42,       // ldh a, (SCY)
+            0x3D,             // dec a
+            0xE0, 0x42,       // ldh (SCY), a
+            0x21, 0x01, 0xFE, // ld hl, $FE01
+            0x34,             // inc (hl) (sprite 0 x)
+            0x21, 0x04, 0xFE, // ld hl, $FE04
+            0x35,             // dec (hl) (sprite 1 y)
+            0xF0, 0x4B,       // ldh a, (WX)
+            0x3C,             // inc a
+            0xE0, 0x4B,       // ldh (WX), a
+            0xF0, 0x44,       // .wait: ldh a, (LY)
+            0xFE, 0x90,       // cp 144
+            0x28, 0xFA,       // jr z, .wait
+            0x18, 0xDB,       // jr .frame
+        ];
+
+        // a hash of each frame the scroll demo draws, one per line
+        const SCROLL_DEMO_HASHES: &str = include_str!("../rom/test/scroll_demo.hashes");
+
+        fn frame_hash(frame: &[u32]) -> u64 {
+            let mut h = StateHash::new();
+            for px in frame {
+                h.bytes(&px.to_le_bytes());
+            }
+            h.finish()
+        }
+
+        /// Locks down scrolling, window and sprite timing frame by frame, run with
+        /// CERBOY_BLESS=1 to take a change that's meant to alter what's drawn
+        #[test]
+        fn test_scroll_demo() {
+            let mut rom = vec![0; BANK_SIZE * 2];
+            rom[0x0101..0x0104].copy_from_slice(&[0xC3, 0x50, 0x01]); // jp $0150
+            rom[0x0150..][..SCROLL_DEMO.len()].copy_from_slice(SCROLL_DEMO);
+            let mut emu = Emulator::new(&Cartridge::from(rom));
+            let hashes: Vec<String> = (0..300)
+                .map(|_| {
+                    emu.step_frame().unwrap();
+                    format!("{:016x}", frame_hash(emu.lcd.frame()))
+                })
+                .collect();
+
+            if std::env::var_os("CERBOY_BLESS").is_some() {
+                let path = concat!(env!("CARGO_MANIFEST_DIR"), "/rom/test/scroll_demo.hashes");
+                std::fs::write(path, hashes.join("\n") + "\n").unwrap();
+                return;
+            }
+            let expected: Vec<&str> = SCROLL_DEMO_HASHES.lines().collect();
+            assert_eq!(expected.len(), hashes.len());
+            for (frame, (hash, expected)) in hashes.iter().zip(expected).enumerate() {
+                assert_eq!(hash, expected, "frame {} was drawn differently", frame);
+            }
+        }
+    }
+}
+
+pub mod config {
+    use crate::lcd::PaletteSet;
+    use crate::memory::{Cartridge, Memory};
+    use crate::pacing::FrameRate;
+    use crate::types::{Byte, Word};
+    use serde::{Deserialize, Serialize};
+    use std::convert::TryFrom;
+    use std::path::PathBuf;
+
+    /// Per-game settings, read from a toml file next to the rom (e.g. tetris.gb -> tetris.toml)
+    /// or named after its checksum, see GameConfig::paths_for
+    ///
+    /// ```toml
+    /// frame_rate = "60hz" # or "native"
+    /// save_path = "/home/me/saves/tetris.sav"
+    ///
+    /// [palettes]
+    /// bg   = [0xFFFFFF, 0x7BFF31, 0x0063C5, 0x000000]
+    /// obj0 = [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000]
+    /// obj1 = [0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000]
+    ///
+    /// [[hacks]] # see Hack
+    /// on = "vblank"
+    /// press = ["start"]
+    ///
+    /// [[triggers]] # see Trigger
+    /// name = "Level 2"
+    /// when = ["C0A0 == 02"]
+    /// ```
+    #[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+    #[serde(default)]
+    pub struct GameConfig {
+        /// Overrides both the default and the cgb boot rom palettes
+        pub palettes: Option<PaletteSet>,
+        pub frame_rate: FrameRate,
+        pub hacks: Vec<Hack>,
+        pub triggers: Vec<Trigger>,
+        /// Where to keep the .sav, instead of next to the rom (or in the saves directory)
+        pub save_path: Option<PathBuf>,
+    }
+
+    /// Quick workarounds for games that don't get through their init yet, run by the emulator
+    /// as it goes (see Emulator::hacks) until the real fix lands
+    ///
+    /// ```toml
+    /// [[hacks]]
+    /// on = "vblank"     # every time this interrupt is dispatched...
+    /// press = ["start"] # ...also hold these buttons
+    /// frames = 120      # (only for the first 120 frames)
+    ///
+    /// [[hacks]]
+    /// skip = "01:4A2B"  # return straight out of the routine at bank:addr
+    /// ```
+    #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+    #[serde(untagged)]
+    pub enum Hack {
+        Press {
+            on: Interrupt,
+            press: Vec<Button>,
+            frames: Option<u64>,
+        },
+        Skip {
+            skip: CodeAddr,
+        },
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Interrupt {
+        Vblank,
+        Stat,
+        Timer,
+        Serial,
+        Joypad,
+    }
+
+    impl Interrupt {
+        /// The FL_INT_* bit
+        pub fn flag(&self) -> Byte {
+            1 << *self as Byte
+        }
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Button {
+        Right,
+        Left,
+        Up,
+        Down,
+        A,
+        B,
+        Select,
+        Start,
+    }
+
+    impl Button {
+        /// The BTN_* bit
+        pub fn bit(&self) -> Byte {
+            1 << *self as Byte
+        }
+    }
+
+    /// A rom address in a particular bank, written "bank:addr" in hex (e.g. "01:4A2B")
+    #[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+    #[serde(try_from = "String", into = "String")]
+    pub struct CodeAddr {
+        pub bank: usize,
+        pub addr: Word,
+    }
+
+    impl TryFrom<String> for CodeAddr {
+        type Error = String;
+        fn try_from(s: String) -> Result<Self, Self::Error> {
+            let err = || format!("expected bank:addr in hex (e.g. 01:4A2B), not {}", s);
+            let (bank, addr) = s.split_once(':').ok_or_else(err)?;
+            Ok(CodeAddr {
+                bank: usize::from_str_radix(bank, 16).map_err(|_| err())?,
+                addr: Word::from_str_radix(addr, 16).map_err(|_| err())?,
+            })
+        }
+    }
+
+    impl From<CodeAddr> for String {
+        fn from(a: CodeAddr) -> String {
+            format!("{:02X}:{:04X}", a.bank, a.addr)
+        }
+    }
+
+    /// Something to announce when the game gets somewhere (an achievement, a speedrun split),
+    /// checked at the end of every frame by emu::TriggerWatcher
+    ///
+    /// ```toml
+    /// [[triggers]]
+    /// name = "Level 2"
+    /// when = ["C0A0 == 02", "FF80 & 80"] # all of these, see Condition
+    /// repeat = true                      # every time they start holding, not just the first
+    /// split = "split"                    # and tell the speedrun timer, see SplitAction
+    /// ```
+    #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+    pub struct Trigger {
+        pub name: String,
+        pub when: Vec<Condition>,
+        #[serde(default)]
+        pub repeat: bool,
+        #[serde(default)]
+        pub split: Option<SplitAction>,
+    }
+
+    /// What a trigger firing does to a speedrun timer, see io::LiveSplit
+    #[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum SplitAction {
+        Start,
+        Split,
+        Reset,
+    }
+
+    impl Trigger {
+        pub fn holds(&self, mem: &Memory) -> bool {
+            self.when.iter().all(|c| c.holds(mem))
+        }
+    }
+
+    /// A byte of memory compared to a value, written "addr op value" in hex (e.g. "C0A0 >= 05")
+    ///
+    /// `&` holds when any of the value's bits are set.
+    #[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+    #[serde(try_from = "String", into = "String")]
+    pub struct Condition {
+        pub addr: Word,
+        pub op: CmpOp,
+        pub val: Byte,
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum CmpOp {
+        Eq,
+        Ne,
+        Lt,
+        Le,
+        Gt,
+        Ge,
+        And,
+    }
+
+    const CMP_OPS: [(&str, CmpOp); 7] = [
+        ("==", CmpOp::Eq),
+        ("!=", CmpOp::Ne),
+        ("<=", CmpOp::Le),
+        (">=", CmpOp::Ge),
+        ("<", CmpOp::Lt),
+        (">", CmpOp::Gt),
+        ("&", CmpOp::And),
+    ];
+
+    impl CmpOp {
+        pub fn apply(&self, a: Byte, b: Byte) -> bool {
+            match self {
+                CmpOp::Eq => a == b,
+                CmpOp::Ne => a != b,
+                CmpOp::Lt => a < b,
+                CmpOp::Le => a <= b,
+                CmpOp::Gt => a > b,
+                CmpOp::Ge => a >= b,
+                CmpOp::And => a & b != 0,
+            }
+        }
+
+        pub fn symbol(&self) -> &'static str {
+            CMP_OPS.iter().find(|(_, op)| op == self).unwrap().0
+        }
+    }
+
+    impl Condition {
+        /// Looks without touching, a trigger shouldn't trip a linter or a read side effect
+        pub fn holds(&self, mem: &Memory) -> bool {
+            self.op.apply(mem[self.addr], self.val)
+        }
+    }
+
+    impl TryFrom<String> for Condition {
+        type Error = String;
+        fn try_from(s: String) -> Result<Self, Self::Error> {
+            let err = || format!("expected addr op value in hex (e.g. C0A0 >= 05), not {}", s);
+            let parts: Vec<&str> = s.split_whitespace().collect();
+            let [addr, op, val] = parts[..] else {
+                return Err(err());
+            };
+            Ok(Condition {
+                addr: Word::from_str_radix(addr, 16).map_err(|_| err())?,
+                op: CMP_OPS
+                    .iter()
+                    .find(|(sym, _)| *sym == op)
+                    .ok_or_else(err)?
+                    .1,
+                val: Byte::from_str_radix(val, 16).map_err(|_| err())?,
+            })
+        }
+    }
+
+    impl From<Condition> for String {
+        fn from(c: Condition) -> String {
+            format!("{:04X} {} {:02X}", c.addr, c.op.symbol(), c.val)
+        }
+    }
+
+    impl GameConfig {
+        pub fn path_for(rom_path: &str) -> PathBuf {
+            crate::io::config_path_for(rom_path)
+        }
+
+        pub fn parse(text: &str) -> Result<GameConfig, toml::de::Error> {
+            toml::from_str(text)
+        }
+
+        /// Where configs named after a rom's checksum live, e.g. ~/.config/cerboy/games
+        pub fn games_dir() -> Option<PathBuf> {
+            dirs::config_dir().map(|dir| dir.join("cerboy").join("games"))
+        }
+
+        /// Config files that could belong to a rom, most specific first: the one next to it,
+        /// then `<sha1>.toml` and `<crc32>.toml` in games_dir, which follow the game wherever
+        /// it's kept and whatever it's called
+        pub fn paths_for(rom_path: &str, cart: &Cartridge) -> Vec<PathBuf> {
+            let mut paths = vec![GameConfig::path_for(rom_path)];
+            if let Some(dir) = GameConfig::games_dir() {
+                paths.push(dir.join(format!("{}.toml", cart.sha1())));
+                paths.push(dir.join(format!("{:08x}.toml", cart.crc32())));
+            }
+            paths
+        }
+
+        /// Loads the config for a rom, a missing or broken file just means defaults
+        pub fn load(rom_path: &str, cart: &Cartridge) -> GameConfig {
+            GameConfig::load_first(&GameConfig::paths_for(rom_path, cart))
+        }
+
+        /// The first of these files that exists, defaults if none do
+        pub fn load_first(paths: &[PathBuf]) -> GameConfig {
+            let Some((path, text)) = paths
+                .iter()
+                .find_map(|path| Some((path, std::fs::read_to_string(path).ok()?)))
+            else {
+                return GameConfig::default();
+            };
+            match GameConfig::parse(&text) {
+                Ok(config) => config,
+                Err(e) => {
+                    log::warn!("ignoring {}: {}", path.display(), e);
+                    GameConfig::default()
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests_config {
+        use super::*;
+
+        #[test]
+        fn test_load_first() {
+            let dir = std::env::temp_dir().join("cerboy_test_load_first");
+            std::fs::create_dir_all(&dir).unwrap();
+            let by_name = dir.join("tetris.toml");
+            let by_sha1 = dir.join("da39a3ee5e6b4b0d3255bfef95601890afd80709.toml");
+            let _ = std::fs::remove_file(&by_name);
+            std::fs::write(&by_sha1, "save_path = \"elsewhere/tetris.sav\"").unwrap();
+            let paths = [by_name.clone(), by_sha1];
+
+            let config = GameConfig::load_first(&paths);
+            assert_eq!(
+                config.save_path,
+                Some(PathBuf::from("elsewhere/tetris.sav"))
+            );
+
+            // the one next to the rom wins
+            std::fs::write(&by_name, "frame_rate = \"60hz\"").unwrap();
+            let config = GameConfig::load_first(&paths);
+            assert_eq!(config.frame_rate, FrameRate::Vsync60);
+            assert_eq!(config.save_path, None);
+
+            assert_eq!(GameConfig::load_first(&[]), GameConfig::default());
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn test_parse_palettes() {
+            let config = GameConfig::parse(
+                "[palettes]\n\
+                 bg   = [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000]\n\
+                 obj0 = [0xFF0000, 0xAA0000, 0x550000, 0x000000]\n\
+                 obj1 = [0x0000FF, 0x0000AA, 0x000055, 0x000000]\n",
+            )
+            .unwrap();
+            let palettes = config.palettes.unwrap();
+            assert_eq!(palettes.bg[1], 0xAAAAAA);
+            assert_eq!(palettes.obj1[0], 0x0000FF);
+
+            assert_eq!(config.frame_rate, FrameRate::Native);
+            assert_eq!(GameConfig::parse("frame_rate = \"60hz\"").unwrap().frame_rate, FrameRate::Vsync60);
+
+            assert_eq!(GameConfig::parse("").unwrap(), GameConfig::default());
+            assert!(GameConfig::parse("[palettes]\nbg = [1, 2]").is_err());
+        }
+
+        #[test]
+        fn test_parse_hacks() {
+            let config = GameConfig::parse(
+                "[[hacks]]\n\
+                 on = \"vblank\"\n\
+                 press = [\"start\", \"a\"]\n\
+                 frames = 120\n\
+                 [[hacks]]\n\
+                 skip = \"01:4a2B\"\n",
+            )
+            .unwrap();
+            assert_eq!(
+                config.hacks,
+                vec![
+                    Hack::Press {
+                        on: Interrupt::Vblank,
+                        press: vec![Button::Start, Button::A],
+                        frames: Some(120),
+                    },
+                    Hack::Skip {
+                        skip: CodeAddr {
+                            bank: 1,
+                            addr: 0x4A2B
+                        }
+                    },
+                ]
+            );
+            assert_eq!(Button::Start.bit(), crate::memory::BTN_START);
+            assert_eq!(Interrupt::Timer.flag(), crate::cpu::FL_INT_TIMER);
+            assert!(GameConfig::parse("[[hacks]]\nskip = \"4A2B\"").is_err());
+            assert!(GameConfig::parse("[[hacks]]\non = \"vblank\"").is_err());
+        }
+
+        #[test]
+        fn test_parse_triggers() {
+            let config = GameConfig::parse(
+                "[[triggers]]\n\
+                 name = \"Level 2\"\n\
+                 when = [\"c0a0 == 2\", \"FF80 & 80\"]\n\
+                 split = \"split\"\n",
+            )
+            .unwrap();
+            let trigger = &config.triggers[0];
+            assert!(!trigger.repeat);
+            assert_eq!(trigger.split, Some(SplitAction::Split));
+            assert_eq!(
+                trigger.when[0],
+                Condition {
+                    addr: 0xC0A0,
+                    op: CmpOp::Eq,
+                    val: 0x02
+                }
+            );
+            assert_eq!(String::from(trigger.when[1]), "FF80 & 80");
+
+            let mut mem = Memory::new();
+            assert!(!trigger.holds(&mem));
+            mem.write(0xC0A0, 0x02);
+            assert!(!trigger.holds(&mem));
+            mem.write(0xFF80, 0x81);
+            assert!(trigger.holds(&mem));
+
+            for bad in ["C0A0 = 02", "C0A0 >= 100", "C0A0 <"] {
+                let text = format!("[[triggers]]\nname = \"x\"\nwhen = [\"{}\"]", bad);
+                assert!(GameConfig::parse(&text).is_err(), "{}", bad);
+            }
+        }
+
+        #[test]
+        fn test_path_for() {
+            assert_eq!(GameConfig::path_for("rom/tetris.gb"), PathBuf::from("rom/tetris.toml"));
+        }
+    }
+}
+
+pub mod pacing {
+    use crate::cpu::TICKS_PER_FRAME;
+    use serde::{Deserialize, Serialize};
+    use std::time::{Duration, Instant};
+
+    pub const CPU_HZ: u64 = 4_194_304;
+
+    /// How fast frames are presented
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+    pub enum FrameRate {
+        /// What the hardware does, 4194304 / 70224 = ~59.7275Hz
+        #[default]
+        #[serde(rename = "native")]
+        Native,
+        /// Runs ~0.46% fast so every frame lines up with a 60Hz vsync
+        #[serde(rename = "60hz")]
+        Vsync60,
+    }
+
+    impl FrameRate {
+        pub fn hz(&self) -> f64 {
+            match self {
+                FrameRate::Native => CPU_HZ as f64 / TICKS_PER_FRAME as f64,
+                FrameRate::Vsync60 => 60.0,
+            }
+        }
+
+        pub fn frame_duration(&self) -> Duration {
+            Duration::from_secs_f64(1.0 / self.hz())
+        }
+
+        /// Emulated seconds per real second
+        pub fn speed(&self) -> f64 {
+            self.hz() / FrameRate::Native.hz()
+        }
+
+        /// Sample rate to generate audio at so it plays back at the right pitch
+        ///
+        /// Running fast produces more emulated time per second, so each emulated second has to
+        /// produce proportionally fewer samples to keep the output device fed at `output_hz`.
+        pub fn audio_source_rate(&self, output_hz: u32) -> f64 {
+            output_hz as f64 / self.speed()
+        }
+    }
+
+    /// Slow motion, applied on top of the frame rate
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+    pub enum Speed {
+        #[default]
+        #[serde(rename = "100%")]
+        Full,
+        #[serde(rename = "50%")]
+        Half,
+        #[serde(rename = "25%")]
+        Quarter,
+    }
+
+    impl Speed {
+        pub fn factor(&self) -> f64 {
+            match self {
+                Speed::Full => 1.0,
+                Speed::Half => 0.5,
+                Speed::Quarter => 0.25,
+            }
+        }
+
+        /// Full -> Half -> Quarter -> Full, so a single hotkey can go through them
+        pub fn next(&self) -> Speed {
+            match self {
+                Speed::Full => Speed::Half,
+                Speed::Half => Speed::Quarter,
+                Speed::Quarter => Speed::Full,
+            }
+        }
+
+        pub fn percent(&self) -> u32 {
+            (self.factor() * 100.0) as u32
+        }
+    }
+
+    /// What audio does in slow motion
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum SlowAudio {
+        /// Silent until back to full speed
+        #[default]
+        Mute,
+        /// Keeps playing, pitched down with the speed like a slowed tape so it stays in step
+        /// with the picture
+        Pitched,
+    }
+
+    impl SlowAudio {
+        pub fn muted_at(&self, speed: Speed) -> bool {
+            speed != Speed::Full && *self == SlowAudio::Mute
+        }
+    }
+
+    /// Which clock decides how fast emulation runs
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum SyncMode {
+        /// Frames wait on the host timer (see Pacer), audio gets dropped or runs dry whenever
+        /// the sound card's clock disagrees with it
+        #[default]
+        Video,
+        /// Each frame waits until the sound card has played the queue down to the latency
+        /// target, so its clock sets the speed and the queue never over or underflows. Falls
+        /// back to the timer while no audio is playing (paused, muted, no device)
+        Audio,
+    }
+
+    /// How long until an output queue holding `queued` samples is down to `target`
+    pub fn audio_wait(queued: usize, target: usize, output_hz: u32) -> Duration {
+        Duration::from_secs_f64(queued.saturating_sub(target) as f64 / output_hz as f64)
+    }
+
+    /// Sleeps the frontend so frames come out at a steady rate
+    pub struct Pacer {
+        rate: FrameRate,
+        period: Duration,
+        next: Instant,
+    }
+
+    impl Pacer {
+        pub fn rate(&self) -> FrameRate {
+            self.rate
+        }
+
+        pub fn new(rate: FrameRate) -> Pacer {
+            Pacer {
+                rate,
+                period: rate.frame_duration(),
+                next: Instant::now(),
+            }
+        }
+
+        /// Stretches the time between frames for slow motion
+        pub fn set_speed(&mut self, speed: Speed) {
+            self.period = self.rate.frame_duration().div_f64(speed.factor());
+        }
+
+        /// Blocks until the next frame is due
+        pub fn wait(&mut self) {
+            let now = Instant::now();
+            if now < self.next {
+                std::thread::sleep(self.next - now);
+            } else if now - self.next > self.period {
+                // fell behind (breakpoint, slow host), don't try to catch up
+                self.next = now;
+            }
+            self.next += self.period;
+        }
+
+        /// Blocks until the sound card is down to `target` queued samples instead of waiting
+        /// on the timer (SyncMode::Audio)
+        pub fn wait_for_audio(&mut self, queued: usize, target: usize, output_hz: u32) {
+            std::thread::sleep(audio_wait(queued, target, output_hz));
+            // going back to the timer carries on from here rather than catching up
+            self.next = Instant::now() + self.period;
+        }
+    }
+
+    // largest nudge AvSync makes to the resample ratio, small enough not to be heard as pitch
+    const MAX_RATE_ADJUST: f64 = 0.005;
+    // seconds of drift that push the adjustment all the way to MAX_RATE_ADJUST
+    const DRIFT_FULL_ADJUST: f64 = 0.05;
+
+    /// Keeps audio and video from slowly drifting apart over long sessions
+    ///
+    /// audio_source_rate() is exact on paper, but the output device clock and the host timer
+    /// never quite agree with the emulated clock. AvSync counts cycles emulated, samples played
+    /// and frames presented, and tweaks the resample ratio so audio converges back onto the
+    /// emulated clock instead of wandering off.
+    #[derive(Clone, Debug)]
+    pub struct AvSync {
+        rate: FrameRate,
+        speed: Speed,
+        slow_audio: SlowAudio,
+        output_hz: u32,
+        cycles: u64,
+        samples: u64,
+        frames: u64,
+    }
+
+    impl AvSync {
+        pub fn new(rate: FrameRate, output_hz: u32) -> AvSync {
+            AvSync {
+                rate,
+                speed: Speed::Full,
+                slow_audio: SlowAudio::Mute,
+                output_hz,
+                cycles: 0,
+                samples: 0,
+                frames: 0,
+            }
+        }
+
+        /// Changes speed, counting starts over since the old totals were made at another one
+        pub fn set_speed(&mut self, speed: Speed, slow_audio: SlowAudio) {
+            self.speed = speed;
+            self.slow_audio = slow_audio;
+            self.reset();
+        }
+
+        /// Audio should be silenced (slowed down with SlowAudio::Mute)
+        pub fn muted(&self) -> bool {
+            self.slow_audio.muted_at(self.speed)
+        }
+
+        /// Emulated seconds per real second, frame rate and slow motion together
+        fn speed(&self) -> f64 {
+            self.rate.speed() * self.speed.factor()
+        }
+
+        /// Start counting again, e.g. after a pause or a rewind
+        pub fn reset(&mut self) {
+            self.cycles = 0;
+            self.samples = 0;
+            self.frames = 0;
+        }
+
+        pub fn add_cycles(&mut self, cycles: u64) {
+            self.cycles += cycles;
+        }
+
+        /// Samples the output device has consumed
+        pub fn add_samples(&mut self, samples: u64) {
+            self.samples += samples;
+        }
+
+        pub fn add_frame(&mut self) {
+            self.frames += 1;
+        }
+
+        /// Real seconds the emulated cycles should have taken at this frame rate
+        fn emulated_secs(&self) -> f64 {
+            self.cycles as f64 / CPU_HZ as f64 / self.speed()
+        }
+
+        /// Seconds the audio is ahead of the emulated clock (negative when behind)
+        pub fn audio_drift(&self) -> f64 {
+            self.samples as f64 / self.output_hz as f64 - self.emulated_secs()
+        }
+
+        /// Seconds the presented frames are ahead of the emulated clock (negative when behind)
+        pub fn video_drift(&self) -> f64 {
+            self.frames as f64 / (self.rate.hz() * self.speed.factor()) - self.emulated_secs()
+        }
+
+        /// audio_source_rate() with the drift correction applied
+        ///
+        /// Audio running ahead means the device eats samples faster than we make them, so make
+        /// a few more per emulated second; behind, a few less.
+        pub fn source_rate(&self) -> f64 {
+            let adjust =
+                (self.audio_drift() / DRIFT_FULL_ADJUST).clamp(-1.0, 1.0) * MAX_RATE_ADJUST;
+            self.rate.audio_source_rate(self.output_hz) / self.speed.factor() * (1.0 + adjust)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests_pacing {
+        use super::*;
+
+        #[test]
+        fn test_frame_rate() {
+            assert!((FrameRate::Native.hz() - 59.7275).abs() < 0.0001);
+            assert_eq!(FrameRate::Vsync60.hz(), 60.0);
+            assert!(FrameRate::Vsync60.speed() > 1.0);
+            assert_eq!(FrameRate::Native.audio_source_rate(48000), 48000.0);
+            assert!(FrameRate::Vsync60.audio_source_rate(48000) < 48000.0);
+        }
+
+        #[test]
+        fn test_audio_wait() {
+            assert_eq!(audio_wait(2400, 4800, 48000), Duration::ZERO);
+            assert_eq!(audio_wait(4800, 4800, 48000), Duration::ZERO);
+            assert_eq!(audio_wait(7200, 4800, 48000), Duration::from_millis(50));
+        }
+
+        #[test]
+        fn test_av_sync() {
+            let mut sync = AvSync::new(FrameRate::Native, 48000);
+            for _ in 0..60 {
+                sync.add_cycles(TICKS_PER_FRAME);
+                sync.add_frame();
+            }
+            sync.add_samples(48000 * 60 * TICKS_PER_FRAME / CPU_HZ);
+            assert!(sync.video_drift().abs() < 1e-9);
+            assert!(sync.audio_drift().abs() < 0.001);
+            assert!((sync.source_rate() - 48000.0).abs() < 1.0);
+
+            // the device runs a little fast, a correction kicks in but stays subtle
+            sync.add_samples(4800);
+            assert!(sync.audio_drift() > 0.09);
+            assert!(sync.source_rate() > 48000.0);
+            assert!(sync.source_rate() <= 48000.0 * (1.0 + MAX_RATE_ADJUST));
+
+            sync.reset();
+            assert_eq!(sync.audio_drift(), 0.0);
+            assert_eq!(sync.source_rate(), 48000.0);
+        }
+
+        #[test]
+        fn test_slow_motion() {
+            assert_eq!(Speed::Full.next().next(), Speed::Quarter);
+            assert_eq!(Speed::Quarter.next(), Speed::Full);
+            let mut pacer = Pacer::new(FrameRate::Native);
+            pacer.set_speed(Speed::Quarter);
+            assert_eq!(pacer.period, FrameRate::Native.frame_duration() * 4);
+
+            // half speed: a frame's worth of cycles takes two frame periods of real time
+            let mut sync = AvSync::new(FrameRate::Native, 48000);
+            sync.set_speed(Speed::Half, SlowAudio::Pitched);
+            assert!(!sync.muted());
+            for _ in 0..60 {
+                sync.add_cycles(TICKS_PER_FRAME);
+                sync.add_frame();
+            }
+            sync.add_samples(2 * 48000 * 60 * TICKS_PER_FRAME / CPU_HZ);
+            assert!(sync.video_drift().abs() < 1e-9);
+            assert!(sync.audio_drift().abs() < 0.001);
+            assert!((sync.source_rate() - 96000.0).abs() < 1.0);
+
+            sync.set_speed(Speed::Half, SlowAudio::Mute);
+            assert!(sync.muted());
+            sync.set_speed(Speed::Full, SlowAudio::Mute);
+            assert!(!sync.muted());
+        }
+    }
+}
+
+pub mod settings {
+    use crate::apu::HighPass;
+    use crate::filter::FilterKind;
+    use crate::lcd::PaletteSet;
+    use crate::pacing::{SlowAudio, SyncMode};
+    use serde::{Deserialize, Serialize};
+    use std::convert::TryFrom;
+    use std::path::PathBuf;
+
+    pub const RECENT_ROMS_MAX: usize = 10;
+
+    /// Speed vs accuracy tradeoffs, for the shortcuts that have one
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Accuracy {
+        Fast,
+        #[default]
+        Balanced,
+        Accurate,
+    }
+
+    /// Samples the sound card takes at a time, a power of 2 like the ring buffer it fills
+    #[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+    #[serde(try_from = "u16", into = "u16")]
+    pub struct AudioBuffer(u16);
+
+    impl AudioBuffer {
+        pub fn samples(self) -> u16 {
+            self.0
+        }
+    }
+
+    impl TryFrom<u16> for AudioBuffer {
+        type Error = String;
+        fn try_from(samples: u16) -> Result<Self, Self::Error> {
+            if !samples.is_power_of_two() {
+                return Err(format!(
+                    "audio_buffer has to be a power of 2, not {}",
+                    samples
+                ));
+            }
+            Ok(AudioBuffer(samples))
+        }
+    }
+
+    impl From<AudioBuffer> for u16 {
+        fn from(buffer: AudioBuffer) -> u16 {
+            buffer.0
+        }
+    }
+
+    /// For the command line
+    impl std::str::FromStr for AudioBuffer {
+        type Err = String;
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let samples: u16 = s.parse().map_err(|e| format!("{}", e))?;
+            AudioBuffer::try_from(samples)
+        }
+    }
+
+    /// Key names as the frontend spells them (e.g. "Up", "Z", "Enter")
+    #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+    #[serde(default)]
+    pub struct KeyBindings {
+        pub up: String,
+        pub down: String,
+        pub left: String,
+        pub right: String,
+        pub a: String,
+        pub b: String,
+        pub select: String,
+        pub start: String,
+    }
+
+    impl Default for KeyBindings {
+        fn default() -> Self {
+            KeyBindings {
+                up: "Up".to_string(),
+                down: "Down".to_string(),
+                left: "Left".to_string(),
+                right: "Right".to_string(),
+                a: "X".to_string(),
+                b: "Z".to_string(),
+                select: "Backspace".to_string(),
+                start: "Enter".to_string(),
+            }
+        }
+    }
+
+    /// Emulator-wide settings, kept in the platform config dir (e.g. ~/.config/cerboy/settings.toml)
+    #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+    #[serde(default)]
+    pub struct Settings {
+        /// Default palettes, a game's own config still wins. Colors, or a built in set by name
+        /// (e.g. "deuteranopia", see PaletteSet::named)
+        pub palettes: Option<PaletteSet>,
+        /// Spreads the shades of whatever palettes are in use further apart
+        pub high_contrast: bool,
+        /// Show sprites a game flickers on and off (to get around the 10 per line limit)
+        /// steadily instead, F11 toggles it while playing
+        pub flicker_reduction: bool,
+        /// How much of the last frame shows through the next, in percent (0 is off). Like a
+        /// DMG's slow LCD, which games drawing things every other frame for transparency
+        /// count on
+        pub ghosting: u32,
+        /// Window size as a multiple of 160x144
+        pub scale: usize,
+        /// Run over every frame before it's shown, in order. The window stretches whatever
+        /// comes out to fit, so an empty list is a plain (blurry or blocky) scale
+        pub filters: Vec<FilterKind>,
+        /// Audio to keep queued for the sound card, more rides out hitches but lags the picture
+        pub audio_latency_ms: u32,
+        /// Samples the sound card takes at a time, smaller is snappier but underruns
+        /// (crackles) sooner on a busy or slow machine
+        pub audio_buffer: AudioBuffer,
+        /// Sample rate to open the sound card at (e.g. 44100), the device's own when unset.
+        /// Sound is resampled to whatever it ends up at
+        pub audio_rate: Option<u32>,
+        /// Mute or pitch down audio in slow motion
+        pub slow_audio: SlowAudio,
+        /// Master volume in percent, 0..=100
+        pub volume: u32,
+        /// "dmg", "cgb" or "off", see HighPass
+        pub high_pass: HighPass,
+        /// Pace emulation off the timer or the sound card, "audio" avoids crackle and drift
+        /// when there's a sound device
+        pub sync: SyncMode,
+        pub accuracy: Accuracy,
+        /// Keep every .sav here instead of next to its rom, e.g. a folder a sync tool watches
+        pub saves_dir: Option<PathBuf>,
+        /// Instructions to show in the report printed when the emulator crashes (and to travel
+        /// back through while paused), 0 skips recording them (slightly faster)
+        pub crash_trace: usize,
+        /// Earlier .sav files to keep beside each save (`zelda.sav.<unix time>.bak`), in case
+        /// a game corrupts its own save. 0 keeps none
+        pub sav_backups: usize,
+        /// Gzip the backups (`.bak.gz`), gunzip one and rename it to restore it
+        pub compress_sav_backups: bool,
+        pub last_rom_dir: Option<PathBuf>,
+        /// Most recently played first
+        pub recent_roms: Vec<PathBuf>,
+        pub keys: KeyBindings,
+    }
+
+    impl Default for Settings {
+        fn default() -> Self {
+            Settings {
+                palettes: None,
+                high_contrast: false,
+                flicker_reduction: false,
+                ghosting: 0,
+                scale: 4,
+                filters: vec![],
+                audio_latency_ms: 50,
+                audio_buffer: AudioBuffer(1024),
+                audio_rate: None,
+                slow_audio: SlowAudio::default(),
+                volume: 100,
+                high_pass: HighPass::default(),
+                sync: SyncMode::default(),
+                accuracy: Accuracy::default(),
+                saves_dir: None,
+                crash_trace: 32,
+                sav_backups: 5,
+                compress_sav_backups: false,
+                last_rom_dir: None,
+                recent_roms: vec![],
+                keys: KeyBindings::default(),
+            }
+        }
+    }
+
+    impl Settings {
+        pub fn path() -> Option<PathBuf> {
+            dirs::config_dir().map(|dir| dir.join("cerboy").join("settings.toml"))
+        }
+
+        pub fn parse(text: &str) -> Result<Settings, toml::de::Error> {
+            toml::from_str(text)
+        }
+
+        /// Moves a rom to the top of the recent list (and remembers its directory)
+        pub fn add_recent(&mut self, rom: PathBuf) {
+            self.last_rom_dir = rom.parent().map(|dir| dir.to_path_buf());
+            self.recent_roms.retain(|r| *r != rom);
+            self.recent_roms.insert(0, rom);
+            self.recent_roms.truncate(RECENT_ROMS_MAX);
+        }
+
+        /// Loads the settings file, a missing or broken file just means defaults
+        pub fn load() -> Settings {
+            let Some(path) = Settings::path() else {
+                return Settings::default();
+            };
+            let text = match std::fs::read_to_string(&path) {
+                Ok(text) => text,
+                Err(_) => return Settings::default(),
+            };
+            match Settings::parse(&text) {
+                Ok(settings) => settings,
+                Err(e) => {
+                    log::warn!("ignoring {}: {}", path.display(), e);
+                    Settings::default()
+                }
+            }
+        }
+
+        pub fn save(&self) -> std::io::Result<()> {
+            let Some(path) = Settings::path() else {
+                return Ok(());
+            };
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            let text = toml::to_string_pretty(self)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            std::fs::write(path, text)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests_settings {
+        use super::*;
+        use crate::lcd::PALETTES_PROTANOPIA;
+
+        #[test]
+        fn test_round_trip() {
+            let settings = Settings {
+                scale: 2,
+                accuracy: Accuracy::Accurate,
+                filters: vec![FilterKind::Scale2x, FilterKind::Nearest(2)],
+                slow_audio: SlowAudio::Pitched,
+                volume: 40,
+                audio_rate: Some(44100),
+                audio_buffer: AudioBuffer(512),
+                high_pass: HighPass::Cgb,
+                sync: SyncMode::Audio,
+                last_rom_dir: Some(PathBuf::from("/roms")),
+                ..Settings::default()
+            };
+            let text = toml::to_string_pretty(&settings).unwrap();
+            assert_eq!(Settings::parse(&text).unwrap(), settings);
+        }
+
+        #[test]
+        fn test_audio_buffer() {
+            let parse = |samples| Settings::parse(&format!("audio_buffer = {}", samples));
+            assert_eq!(parse(256).unwrap().audio_buffer.samples(), 256);
+            assert!(parse(1000).is_err());
+            assert!(parse(0).is_err());
+            assert!("1000".parse::<AudioBuffer>().is_err());
+            assert_eq!("2048".parse::<AudioBuffer>(), Ok(AudioBuffer(2048)));
+        }
+
+        #[test]
+        fn test_recent_roms() {
+            let mut settings = Settings::default();
+            for i in 0..RECENT_ROMS_MAX + 2 {
+                settings.add_recent(PathBuf::from(format!("/roms/{i}.gb")));
+            }
+            assert_eq!(settings.recent_roms.len(), RECENT_ROMS_MAX);
+            assert_eq!(settings.recent_roms[0], PathBuf::from("/roms/11.gb"));
+
+            settings.add_recent(PathBuf::from("/roms/5.gb"));
+            assert_eq!(settings.recent_roms[0], PathBuf::from("/roms/5.gb"));
+            assert_eq!(settings.recent_roms[1], PathBuf::from("/roms/11.gb"));
+            assert_eq!(settings.recent_roms.len(), RECENT_ROMS_MAX);
+            assert_eq!(settings.last_rom_dir, Some(PathBuf::from("/roms")));
+        }
+
+        #[test]
+        fn test_partial() {
+            let settings = Settings::parse("scale = 3\n[keys]\na = \"J\"\n").unwrap();
+            assert_eq!(settings.scale, 3);
+            assert_eq!(settings.keys.a, "J");
+            assert_eq!(settings.keys.b, KeyBindings::default().b);
+            assert_eq!(settings.accuracy, Accuracy::Balanced);
+
+            let settings =
+                Settings::parse("palettes = \"protanopia\"\nhigh_contrast = true").unwrap();
+            assert_eq!(settings.palettes, Some(PALETTES_PROTANOPIA));
+            assert!(settings.high_contrast);
+            assert!(Settings::parse("palettes = \"sepia\"").is_err());
+
+            let settings = Settings::parse("filters = [\"scale3x\", { nearest = 2 }]\n").unwrap();
+            assert_eq!(
+                settings.filters,
+                vec![FilterKind::Scale3x, FilterKind::Nearest(2)]
+            );
+        }
+    }
+}
+
+pub mod filter {
+    use serde::{Deserialize, Serialize};
+
+    /// Turns a frame into another (usually bigger) one, e.g. for scaling up to the window
+    ///
+    /// Frames are row major 0RGB pixels, like Display::frame().
+    pub trait VideoFilter {
+        /// Dimensions of the output for an input of width x height
+        fn output_size(&self, width: usize, height: usize) -> (usize, usize);
+        /// Fills `output` (already output_size() pixels) from `input`
+        fn apply(&self, input: &[u32], width: usize, height: usize, output: &mut [u32]);
+    }
+
+    /// Plain integer scaling, every pixel becomes a factor x factor block
+    pub struct Nearest(pub usize);
+
+    impl VideoFilter for Nearest {
+        fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+            (width * self.0, height * self.0)
+        }
+        fn apply(&self, input: &[u32], width: usize, _height: usize, output: &mut [u32]) {
+            let out_width = width * self.0;
+            for (y, row) in output.chunks_exact_mut(out_width).enumerate() {
+                let src = &input[y / self.0 * width..][..width];
+                for (x, px) in row.iter_mut().enumerate() {
+                    *px = src[x / self.0];
+                }
+            }
+        }
+    }
+
+    // neighbours of (x, y), clamped at the edges: up, left, right, down
+    fn cross(input: &[u32], width: usize, height: usize, x: usize, y: usize) -> [u32; 4] {
+        [
+            input[y.saturating_sub(1) * width + x],
+            input[y * width + x.saturating_sub(1)],
+            input[y * width + (x + 1).min(width - 1)],
+            input[(y + 1).min(height - 1) * width + x],
+        ]
+    }
+
+    /// Scale2x (aka AdvMAME2x): 2x that rounds off diagonal edges instead of making stairs
+    ///
+    /// Same idea as hq2x without the blending, so the palette stays exactly the game's.
+    pub struct Scale2x;
+
+    impl VideoFilter for Scale2x {
+        fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+            (width * 2, height * 2)
+        }
+        fn apply(&self, input: &[u32], width: usize, height: usize, output: &mut [u32]) {
+            for y in 0..height {
+                for x in 0..width {
+                    let p = input[y * width + x];
+                    let [a, c, b, d] = cross(input, width, height, x, y);
+                    let block = if c != b && a != d {
+                        [
+                            if c == a { a } else { p },
+                            if a == b { b } else { p },
+                            if c == d { c } else { p },
+                            if b == d { d } else { p },
+                        ]
+                    } else {
+                        [p; 4]
+                    };
+                    let out = (y * 2) * width * 2 + x * 2;
+                    output[out..][..2].copy_from_slice(&block[..2]);
+                    output[out + width * 2..][..2].copy_from_slice(&block[2..]);
+                }
+            }
+        }
+    }
+
+    /// Scale3x (aka AdvMAME3x), Scale2x's rules for a 3x3 block
+    pub struct Scale3x;
+
+    impl VideoFilter for Scale3x {
+        fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+            (width * 3, height * 3)
+        }
+        fn apply(&self, input: &[u32], width: usize, height: usize, output: &mut [u32]) {
+            let at = |x: usize, y: usize| input[y * width + x];
+            for y in 0..height {
+                let (up, down) = (y.saturating_sub(1), (y + 1).min(height - 1));
+                for x in 0..width {
+                    let (left, right) = (x.saturating_sub(1), (x + 1).min(width - 1));
+                    // A B C
+                    // D E F
+                    // G H I
+                    let (a, b, c) = (at(left, up), at(x, up), at(right, up));
+                    let (d, e, f) = (at(left, y), at(x, y), at(right, y));
+                    let (g, h, i) = (at(left, down), at(x, down), at(right, down));
+                    let block = if b != h && d != f {
+                        [
+                            if d == b { d } else { e },
+                            if (d == b && e != c) || (b == f && e != a) {
+                                b
+                            } else {
+                                e
+                            },
+                            if b == f { f } else { e },
+                            if (d == b && e != g) || (d == h && e != a) {
+                                d
+                            } else {
+                                e
+                            },
+                            e,
+                            if (b == f && e != i) || (h == f && e != c) {
+                                f
+                            } else {
+                                e
+                            },
+                            if d == h { d } else { e },
+                            if (d == h && e != i) || (h == f && e != g) {
+                                h
+                            } else {
+                                e
+                            },
+                            if h == f { f } else { e },
+                        ]
+                    } else {
+                        [e; 9]
+                    };
+                    for (row, pixels) in block.chunks_exact(3).enumerate() {
+                        let out = (y * 3 + row) * width * 3 + x * 3;
+                        output[out..][..3].copy_from_slice(pixels);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The built-in filters, as spelled in settings.toml: `filters = ["scale2x", { nearest = 2 }]`
+    #[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum FilterKind {
+        Nearest(usize),
+        Scale2x,
+        Scale3x,
+    }
+
+    impl FilterKind {
+        pub fn build(&self) -> Box<dyn VideoFilter> {
+            match *self {
+                FilterKind::Nearest(factor) => Box::new(Nearest(factor.max(1))),
+                FilterKind::Scale2x => Box::new(Scale2x),
+                FilterKind::Scale3x => Box::new(Scale3x),
+            }
+        }
+    }
+
+    /// Filters run one after the other, each on the output of the last
+    #[derive(Default)]
+    pub struct FilterChain {
+        filters: Vec<Box<dyn VideoFilter>>,
+        buffers: [Vec<u32>; 2],
+    }
+
+    impl FilterChain {
+        pub fn new(kinds: &[FilterKind]) -> FilterChain {
+            let mut chain = FilterChain::default();
+            for kind in kinds {
+                chain.push(kind.build());
+            }
+            chain
+        }
+
+        pub fn push(&mut self, filter: Box<dyn VideoFilter>) {
+            self.filters.push(filter);
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.filters.is_empty()
+        }
+
+        /// Runs the chain, returns the final frame and its width and height
+        ///
+        /// An empty chain hands the input straight back.
+        pub fn run<'a>(
+            &'a mut self,
+            input: &'a [u32],
+            width: usize,
+            height: usize,
+        ) -> (&'a [u32], usize, usize) {
+            let (mut width, mut height) = (width, height);
+            let [front, back] = &mut self.buffers;
+            for (n, filter) in self.filters.iter().enumerate() {
+                let (out_width, out_height) = filter.output_size(width, height);
+                back.resize(out_width * out_height, 0);
+                let src: &[u32] = if n == 0 { input } else { front };
+                filter.apply(src, width, height, back);
+                std::mem::swap(front, back);
+                width = out_width;
+                height = out_height;
+            }
+            if self.filters.is_empty() {
+                (input, width, height)
+            } else {
+                (&self.buffers[0], width, height)
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests_filter {
+        use super::*;
+
+        const X: u32 = 0xFFFFFF;
+
+        #[test]
+        fn test_nearest() {
+            let mut chain = FilterChain::new(&[FilterKind::Nearest(2)]);
+            let (out, w, h) = chain.run(&[1, 2, 3, 4], 2, 2);
+            assert_eq!((w, h), (4, 4));
+            assert_eq!(out, &[1, 1, 2, 2, 1, 1, 2, 2, 3, 3, 4, 4, 3, 3, 4, 4]);
+        }
+
+        #[test]
+        fn test_scale2x() {
+            // the corners along a diagonal get filled in, flat areas stay flat
+            #[rustfmt::skip]
+            let input = [
+                0, 0, 0, 0,
+                0, X, 0, 0,
+                0, 0, X, 0,
+                0, 0, 0, 0,
+            ];
+            let mut chain = FilterChain::new(&[FilterKind::Scale2x]);
+            let (out, w, h) = chain.run(&input, 4, 4);
+            assert_eq!((w, h), (8, 8));
+            #[rustfmt::skip]
+            assert_eq!(out, &[
+                0, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, X, X, 0, 0, 0, 0,
+                0, 0, X, X, X, 0, 0, 0,
+                0, 0, 0, X, X, X, 0, 0,
+                0, 0, 0, 0, X, X, 0, 0,
+                0, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0, 0, 0, 0, 0, 0,
+            ]);
+        }
+
+        #[test]
+        fn test_scale3x_flat() {
+            let mut chain = FilterChain::new(&[FilterKind::Scale3x]);
+            let (out, w, h) = chain.run(&[7; 4], 2, 2);
+            assert_eq!((w, h), (6, 6));
+            assert_eq!(out, &[7; 36]);
+        }
+
+        #[test]
+        fn test_chain() {
+            let mut chain = FilterChain::new(&[FilterKind::Scale2x, FilterKind::Nearest(3)]);
+            let (out, w, h) = chain.run(&[5; 160 * 144], 160, 144);
+            assert_eq!((w, h), (960, 864));
+            assert!(out.iter().all(|&px| px == 5));
+
+            let mut empty = FilterChain::new(&[]);
+            assert!(empty.is_empty());
+            assert_eq!(empty.run(&[1, 2], 2, 1), (&[1, 2][..], 2, 1));
+        }
+    }
+}
+
+pub mod sav {
+    //! .sav files: battery backed cartridge RAM, plus the clock for carts that have one
+    //!
+    //! The RAM is stored raw. Clock carts (MBC3+TIMER) append a footer after it, which comes
+    //! in two common flavours: 48 bytes (BGB, SameBoy, mGBA, VBA-M) and an older 44 byte one
+    //! with a 32 bit timestamp.
+    use crate::types::Byte;
+    use std::str::FromStr;
+
+    pub const RTC_FOOTER_LEN: usize = 48;
+    pub const RTC_FOOTER_LEN_SHORT: usize = 44;
+
+    /// How a .sav is laid out
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum SavLayout {
+        /// RAM only
+        Raw,
+        /// RAM + 48 byte clock footer
+        Rtc,
+        /// RAM + 44 byte clock footer (32 bit timestamp)
+        RtcShort,
+    }
+
+    impl SavLayout {
+        /// Guesses from the file size, RAM is always a multiple of 512 bytes
+        pub fn detect(len: usize) -> SavLayout {
+            match len % 0x200 {
+                RTC_FOOTER_LEN => SavLayout::Rtc,
+                RTC_FOOTER_LEN_SHORT => SavLayout::RtcShort,
+                _ => SavLayout::Raw,
+            }
+        }
+
+        fn footer_len(&self) -> usize {
+            match self {
+                SavLayout::Raw => 0,
+                SavLayout::Rtc => RTC_FOOTER_LEN,
+                SavLayout::RtcShort => RTC_FOOTER_LEN_SHORT,
+            }
+        }
+    }
+
+    impl FromStr for SavLayout {
+        type Err = String;
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "raw" => Ok(SavLayout::Raw),
+                "rtc" | "bgb" => Ok(SavLayout::Rtc),
+                "rtc-short" | "vba" => Ok(SavLayout::RtcShort),
+                _ => Err(format!("unknown .sav layout {} (raw, rtc, rtc-short)", s)),
+            }
+        }
+    }
+
+    /// MBC3 clock registers as saved: seconds, minutes, hours, day low, day high (flags)
+    #[derive(Copy, Clone, Debug, Default, PartialEq)]
+    pub struct RtcFooter {
+        pub regs: [Byte; 5],
+        pub latched: [Byte; 5],
+        /// Unix time the registers were saved at, the clock keeps running while off
+        pub timestamp: u64,
+    }
+
+    impl RtcFooter {
+        fn parse(bytes: &[Byte]) -> RtcFooter {
+            // every register takes up 4 bytes, little endian
+            let reg = |i: usize| bytes[i * 4];
+            let mut timestamp = [0; 8];
+            let ts = &bytes[40..];
+            timestamp[..ts.len()].copy_from_slice(ts);
+            RtcFooter {
+                regs: [reg(0), reg(1), reg(2), reg(3), reg(4)],
+                latched: [reg(5), reg(6), reg(7), reg(8), reg(9)],
+                timestamp: u64::from_le_bytes(timestamp),
+            }
+        }
+
+        fn write(&self, layout: SavLayout, out: &mut Vec<Byte>) {
+            for reg in self.regs.iter().chain(self.latched.iter()) {
+                out.extend_from_slice(&(*reg as u32).to_le_bytes());
+            }
+            let timestamp = self.timestamp.to_le_bytes();
+            out.extend_from_slice(&timestamp[..layout.footer_len() - 40]);
+        }
+    }
+
+    /// The contents of a .sav
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub struct SaveFile {
+        pub ram: Vec<Byte>,
+        pub rtc: Option<RtcFooter>,
+    }
+
+    impl SaveFile {
+        pub fn parse(bytes: &[Byte]) -> SaveFile {
+            let layout = SavLayout::detect(bytes.len());
+            let (ram, footer) = bytes.split_at(bytes.len() - layout.footer_len());
+            SaveFile {
+                ram: ram.to_vec(),
+                rtc: (layout != SavLayout::Raw).then(|| RtcFooter::parse(footer)),
+            }
+        }
+
+        /// Raw drops the clock, the other layouts write a zeroed one if there isn't any
+        pub fn to_bytes(&self, layout: SavLayout) -> Vec<Byte> {
+            let mut out = self.ram.clone();
+            if layout != SavLayout::Raw {
+                self.rtc.unwrap_or_default().write(layout, &mut out);
+            }
+            out
+        }
+
+        /// The footer on its own, as exported next to the raw RAM
+        pub fn rtc_bytes(&self) -> Option<Vec<Byte>> {
+            let mut out = vec![];
+            self.rtc?.write(SavLayout::Rtc, &mut out);
+            Some(out)
+        }
+
+        /// Takes a footer exported with rtc_bytes() (either length)
+        pub fn set_rtc_bytes(&mut self, bytes: &[Byte]) -> Result<(), String> {
+            match bytes.len() {
+                RTC_FOOTER_LEN | RTC_FOOTER_LEN_SHORT => {
+                    self.rtc = Some(RtcFooter::parse(bytes));
+                    Ok(())
+                }
+                len => Err(format!("a clock footer is 48 or 44 bytes, not {}", len)),
+            }
         }
     }
 
-    #[allow(non_snake_case)]
-    pub fn decodeCB(op: Byte) -> InstructionCB {
-        let _ROT_y = ROT[y(op) as usize];
-        let _R_z = R_ID[z(op) as usize];
-        let _y = y(op);
-        match x(op) {
-            0 => InstructionCB {
-                // mnm: format!("{_ROT_y} {_R_z}"),
-                opcode: _ROT_y,
-                bit: 0xFF,
-                reg: _R_z,
-            },
-            1 => InstructionCB {
-                // mnm: format!("BIT {_y}, {_R_z}"),
-                opcode: "BIT",
-                bit: _y,
-                reg: _R_z,
-            },
-            2 => InstructionCB {
-                // mnm: format!("RES {_y}, {_R_z}"),
-                opcode: "RES",
-                bit: _y,
-                reg: _R_z,
-            },
-            3 => InstructionCB {
-                // mnm: format!("SET {_y}, {_R_z}"),
-                opcode: "SET",
-                bit: _y,
-                reg: _R_z,
-            },
-            _ => InstructionCB {
-                opcode: "INVALID",
-                bit: 0xFF,
-                reg: usize::max_value(),
-            },
+    #[cfg(test)]
+    mod tests_sav {
+        use super::*;
+
+        fn with_rtc() -> SaveFile {
+            SaveFile {
+                ram: vec![0x42; 0x8000],
+                rtc: Some(RtcFooter {
+                    regs: [1, 2, 3, 4, 0x81],
+                    latched: [5, 6, 7, 8, 0],
+                    timestamp: 1_700_000_000,
+                }),
+            }
+        }
+
+        #[test]
+        fn test_detect() {
+            assert_eq!(SavLayout::detect(0x2000), SavLayout::Raw);
+            assert_eq!(SavLayout::detect(0x200), SavLayout::Raw); // MBC2
+            assert_eq!(SavLayout::detect(0x8000 + 48), SavLayout::Rtc);
+            assert_eq!(SavLayout::detect(0x8000 + 44), SavLayout::RtcShort);
+            assert_eq!("vba".parse(), Ok(SavLayout::RtcShort));
+            assert!("zip".parse::<SavLayout>().is_err());
+        }
+
+        #[test]
+        fn test_round_trip() {
+            let sav = with_rtc();
+            for layout in [SavLayout::Rtc, SavLayout::RtcShort] {
+                let bytes = sav.to_bytes(layout);
+                assert_eq!(bytes.len(), 0x8000 + layout.footer_len());
+                assert_eq!(SaveFile::parse(&bytes), sav);
+            }
+            let raw = SaveFile::parse(&sav.to_bytes(SavLayout::Raw));
+            assert_eq!(raw.ram, sav.ram);
+            assert_eq!(raw.rtc, None);
+        }
+
+        #[test]
+        fn test_rtc_bytes() {
+            let sav = with_rtc();
+            let footer = sav.rtc_bytes().unwrap();
+            assert_eq!(footer.len(), RTC_FOOTER_LEN);
+
+            let mut imported = SaveFile {
+                ram: sav.ram.clone(),
+                rtc: None,
+            };
+            imported.set_rtc_bytes(&footer).unwrap();
+            assert_eq!(imported, sav);
+            assert!(imported.set_rtc_bytes(&footer[..10]).is_err());
+        }
+    }
+}
+
+pub mod patch {
+    //! Rom patches (romhacks, translations) applied in memory, the rom file is left alone
+    //!
+    //! IPS: "PATCH", then records of a 24 bit offset and 16 bit length followed by the bytes
+    //! (or a length of 0 meaning a run of one byte), then "EOF" and an optional 24 bit size
+    //! to truncate to.
+    //! BPS: "BPS1", then a stream of copy actions building the new rom out of the old one and
+    //! the patch, then CRC32s of the source, the target and the patch itself.
+    //! https://www.romhacking.net/documents/746/
+    use crate::checksum::crc32;
+    use crate::types::{Byte, Word};
+
+    /// Patches a rom, picking the format from the patch's header
+    pub fn apply(rom: &[Byte], patch: &[Byte]) -> Result<Vec<Byte>, String> {
+        if patch.starts_with(b"PATCH") {
+            ips(rom, patch)
+        } else if patch.starts_with(b"BPS1") {
+            bps(rom, patch)
+        } else {
+            Err("not an IPS or BPS patch".to_string())
+        }
+    }
+
+    fn ips(rom: &[Byte], patch: &[Byte]) -> Result<Vec<Byte>, String> {
+        let mut out = rom.to_vec();
+        let mut pos = 5;
+        let mut take = |len: usize| -> Result<&[Byte], String> {
+            let bytes = patch.get(pos..pos + len).ok_or("IPS patch is cut short")?;
+            pos += len;
+            Ok(bytes)
+        };
+        let be = |bytes: &[Byte]| bytes.iter().fold(0, |n, &b| n << 8 | b as usize);
+        loop {
+            let offset = take(3)?;
+            if offset == b"EOF" {
+                break;
+            }
+            let offset = be(offset);
+            let (len, fill) = match be(take(2)?) {
+                0 => (be(take(2)?), Some(take(1)?[0])),
+                len => (len, None),
+            };
+            if out.len() < offset + len {
+                out.resize(offset + len, 0);
+            }
+            match fill {
+                Some(b) => out[offset..offset + len].fill(b),
+                None => out[offset..offset + len].copy_from_slice(take(len)?),
+            }
+        }
+        if let Ok(size) = take(3) {
+            out.truncate(be(size));
+        }
+        Ok(out)
+    }
+
+    fn bps(rom: &[Byte], patch: &[Byte]) -> Result<Vec<Byte>, String> {
+        if patch.len() < 4 + 12 {
+            return Err("BPS patch is cut short".to_string());
+        }
+        let footer = patch.len() - 12;
+        let crc = |at: usize| {
+            u32::from_le_bytes([patch[at], patch[at + 1], patch[at + 2], patch[at + 3]])
+        };
+        if crc32(&patch[..footer + 8]) != crc(footer + 8) {
+            return Err("BPS patch is corrupt (checksum mismatch)".to_string());
+        }
+        if crc32(rom) != crc(footer) {
+            return Err("BPS patch is for a different rom".to_string());
+        }
+        let target_crc = crc(footer + 4);
+
+        let patch = &patch[..footer];
+        let mut pos = 4;
+        let source_size = varint(patch, &mut pos)?;
+        let target_size = varint(patch, &mut pos)?;
+        let metadata = varint(patch, &mut pos)?;
+        if source_size != rom.len() {
+            return Err("BPS patch is for a different rom".to_string());
+        }
+        pos += metadata;
+
+        let mut out = Vec::with_capacity(target_size);
+        let (mut source_rel, mut target_rel) = (0usize, 0usize);
+        let bad = || "BPS patch reads out of bounds".to_string();
+        // copies move relative to where the last one of their kind ended
+        let offset = |n: usize, rel: usize| {
+            let delta = n >> 1;
+            if n & 1 != 0 {
+                rel.checked_sub(delta)
+            } else {
+                rel.checked_add(delta)
+            }
+        };
+        while pos < footer {
+            let action = varint(patch, &mut pos)?;
+            let len = (action >> 2) + 1;
+            if out.len() + len > target_size {
+                return Err(bad());
+            }
+            match action & 0x03 {
+                // source read: the same bytes as the old rom at this position
+                0 => {
+                    let at = out.len();
+                    out.extend_from_slice(rom.get(at..at + len).ok_or_else(bad)?);
+                }
+                // target read: new bytes straight out of the patch
+                1 => {
+                    let bytes = patch.get(pos..pos + len).ok_or_else(bad)?;
+                    out.extend_from_slice(bytes);
+                    pos += len;
+                }
+                // source copy: bytes from anywhere in the old rom
+                2 => {
+                    source_rel = offset(varint(patch, &mut pos)?, source_rel).ok_or_else(bad)?;
+                    let bytes = rom.get(source_rel..source_rel.saturating_add(len));
+                    out.extend_from_slice(bytes.ok_or_else(bad)?);
+                    source_rel += len;
+                }
+                // target copy: bytes already written, one at a time since runs can overlap
+                _ => {
+                    target_rel = offset(varint(patch, &mut pos)?, target_rel).ok_or_else(bad)?;
+                    for _ in 0..len {
+                        let b = *out.get(target_rel).ok_or_else(bad)?;
+                        out.push(b);
+                        target_rel += 1;
+                    }
+                }
+            }
+        }
+        if out.len() != target_size || crc32(&out) != target_crc {
+            return Err("BPS patch produced the wrong rom (checksum mismatch)".to_string());
+        }
+        Ok(out)
+    }
+
+    /// BPS numbers: 7 bits at a time, low first, the last byte has its top bit set
+    fn varint(data: &[Byte], pos: &mut usize) -> Result<usize, String> {
+        let mut n = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let b = *data.get(*pos).ok_or("BPS patch is cut short")?;
+            *pos += 1;
+            n += (b & 0x7F) as usize * shift;
+            if b & 0x80 != 0 {
+                return Ok(n);
+            }
+            if shift > 1 << 48 {
+                return Err("BPS patch has a number too big to be real".to_string());
+            }
+            shift <<= 7;
+            n += shift;
+        }
+    }
+
+    /// One byte swapped out as the cpu reads ROM, the way a Game Genie does it, see
+    /// Memory::add_rom_patch
+    ///
+    /// With `old` the byte is only swapped while the bank mapped there holds it, so a patch
+    /// meant for one bank leaves the others alone. Written as a Game Genie code (ABC-DEF-GHI,
+    /// or ABC-DEF without the compare) or as addr:old:new / addr:new in hex.
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub struct RomPatch {
+        pub addr: Word,
+        pub old: Option<Byte>,
+        pub new: Byte,
+    }
+
+    impl RomPatch {
+        /// What the cpu sees at addr, given what the cartridge put out
+        pub fn apply(&self, addr: Word, val: Byte) -> Byte {
+            if addr == self.addr && self.old.is_none_or(|old| old == val) {
+                self.new
+            } else {
+                val
+            }
+        }
+
+        /// AB: new, FCDE: address with F inverted, GI: old rotated right 2 and XORed with BA
+        /// (H is a check digit, ignored)
+        fn from_genie(digits: &[u8]) -> Option<RomPatch> {
+            let d = |i: usize| digits[i] as Word;
+            let addr = ((d(5) ^ 0xF) << 12) | d(2) << 8 | d(3) << 4 | d(4);
+            let old =
+                (digits.len() == 9).then(|| (digits[6] << 4 | digits[8]).rotate_right(2) ^ 0xBA);
+            (addr < 0x8000).then_some(RomPatch {
+                addr,
+                old,
+                new: digits[0] << 4 | digits[1],
+            })
+        }
+    }
+
+    impl std::str::FromStr for RomPatch {
+        type Err = String;
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let err = || {
+                format!(
+                    "expected a Game Genie code (ABC-DEF-GHI) or addr:old:new in hex, not {}",
+                    s
+                )
+            };
+            if s.contains('-') {
+                let digits: Option<Vec<u8>> = s
+                    .chars()
+                    .filter(|c| *c != '-')
+                    .map(|c| c.to_digit(16).map(|d| d as u8))
+                    .collect();
+                return match digits {
+                    Some(d) if d.len() == 6 || d.len() == 9 => RomPatch::from_genie(&d),
+                    _ => None,
+                }
+                .ok_or_else(err);
+            }
+            let parts: Vec<&str> = s.split(':').collect();
+            let (addr, old, new) = match parts[..] {
+                [addr, new] => (addr, None, new),
+                [addr, old, new] => (addr, Some(old), new),
+                _ => return Err(err()),
+            };
+            let addr = Word::from_str_radix(addr, 16).map_err(|_| err())?;
+            if addr >= 0x8000 {
+                return Err(err());
+            }
+            Ok(RomPatch {
+                addr,
+                old: old
+                    .map(|old| Byte::from_str_radix(old, 16))
+                    .transpose()
+                    .map_err(|_| err())?,
+                new: Byte::from_str_radix(new, 16).map_err(|_| err())?,
+            })
+        }
+    }
+
+    impl std::fmt::Display for RomPatch {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self.old {
+                Some(old) => write!(f, "{:04X}:{:02X}:{:02X}", self.addr, old, self.new),
+                None => write!(f, "{:04X}:{:02X}", self.addr, self.new),
+            }
         }
     }
 
     #[cfg(test)]
-    mod tests_decode {
+    mod tests_patch {
         use super::*;
+
+        fn bps_number(out: &mut Vec<Byte>, mut n: usize) {
+            loop {
+                let b = (n & 0x7F) as Byte;
+                n >>= 7;
+                if n == 0 {
+                    out.push(b | 0x80);
+                    return;
+                }
+                out.push(b);
+                n -= 1;
+            }
+        }
+
+        // a BPS action: how many bytes, then which kind (0 source read, 1 target read, 2
+        // source copy, 3 target copy)
+        fn bps_cmd(len: usize, kind: usize) -> usize {
+            (len - 1) << 2 | kind
+        }
+
         #[test]
-        fn test_xyzpq() {
-            let t = 0b11_010_001;
-            assert_eq!(x(t), 0b11);
-            assert_eq!(y(t), 0b010);
-            assert_eq!(z(t), 0b001);
-            assert_eq!(p(t), 0b01);
-            assert_eq!(q(t), 0b0);
+        fn test_ips() {
+            let rom = vec![0u8; 8];
+            let mut patch = b"PATCH".to_vec();
+            patch.extend([0x00, 0x00, 0x02, 0x00, 0x02, 0xAA, 0xBB]); // 2 bytes at 2
+            patch.extend([0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x04, 0xCC]); // 4 x CC at 6
+            patch.extend(b"EOF");
+            assert_eq!(
+                apply(&rom, &patch).unwrap(),
+                vec![0, 0, 0xAA, 0xBB, 0, 0, 0xCC, 0xCC, 0xCC, 0xCC]
+            );
+
+            // truncated to 3 bytes
+            patch.extend([0x00, 0x00, 0x03]);
+            assert_eq!(apply(&rom, &patch).unwrap(), vec![0, 0, 0xAA]);
+
+            assert!(apply(&rom, &patch[..9]).is_err());
+            assert!(apply(&rom, b"NOT A PATCH").is_err());
         }
 
         #[test]
-        #[rustfmt::skip]
-        fn test_reg_b() {
-            assert_eq!(decodeCB(0x00), InstructionCB{opcode:"RLC",  bit: 0xff, reg: REG_B});
-            assert_eq!(decodeCB(0x10), InstructionCB{opcode:"RL",   bit: 0xff, reg: REG_B});
-            assert_eq!(decodeCB(0x20), InstructionCB{opcode:"SLA",  bit: 0xff, reg: REG_B});
-            assert_eq!(decodeCB(0x30), InstructionCB{opcode:"SWAP", bit: 0xff, reg: REG_B});
-            assert_eq!(decodeCB(0x40), InstructionCB{opcode:"BIT",  bit: 0,    reg: REG_B});
-            assert_eq!(decodeCB(0x50), InstructionCB{opcode:"BIT",  bit: 2,    reg: REG_B});
-            assert_eq!(decodeCB(0x60), InstructionCB{opcode:"BIT",  bit: 4,    reg: REG_B});
-            assert_eq!(decodeCB(0x70), InstructionCB{opcode:"BIT",  bit: 6,    reg: REG_B});
-            assert_eq!(decodeCB(0x80), InstructionCB{opcode:"RES",  bit: 0,    reg: REG_B});
-            assert_eq!(decodeCB(0x90), InstructionCB{opcode:"RES",  bit: 2,    reg: REG_B});
-            assert_eq!(decodeCB(0xA0), InstructionCB{opcode:"RES",  bit: 4,    reg: REG_B});
-            assert_eq!(decodeCB(0xB0), InstructionCB{opcode:"RES",  bit: 6,    reg: REG_B});
-            assert_eq!(decodeCB(0xC0), InstructionCB{opcode:"SET",  bit: 0,    reg: REG_B});
-            assert_eq!(decodeCB(0xD0), InstructionCB{opcode:"SET",  bit: 2,    reg: REG_B});
-            assert_eq!(decodeCB(0xE0), InstructionCB{opcode:"SET",  bit: 4,    reg: REG_B});
-            assert_eq!(decodeCB(0xF0), InstructionCB{opcode:"SET",  bit: 6,    reg: REG_B});
-            
-            assert_eq!(decodeCB(0x08), InstructionCB{opcode:"RRC",  bit: 0xff, reg: REG_B});
-            assert_eq!(decodeCB(0x18), InstructionCB{opcode:"RR",   bit: 0xff, reg: REG_B});
-            assert_eq!(decodeCB(0x28), InstructionCB{opcode:"SRA",  bit: 0xff, reg: REG_B});
-            assert_eq!(decodeCB(0x38), InstructionCB{opcode:"SRL",  bit: 0xff, reg: REG_B});
-            assert_eq!(decodeCB(0x48), InstructionCB{opcode:"BIT",  bit: 1,    reg: REG_B});
-            assert_eq!(decodeCB(0x58), InstructionCB{opcode:"BIT",  bit: 3,    reg: REG_B});
-            assert_eq!(decodeCB(0x68), InstructionCB{opcode:"BIT",  bit: 5,    reg: REG_B});
-            assert_eq!(decodeCB(0x78), InstructionCB{opcode:"BIT",  bit: 7,    reg: REG_B});
-            assert_eq!(decodeCB(0x88), InstructionCB{opcode:"RES",  bit: 1,    reg: REG_B});
-            assert_eq!(decodeCB(0x98), InstructionCB{opcode:"RES",  bit: 3,    reg: REG_B});
-            assert_eq!(decodeCB(0xA8), InstructionCB{opcode:"RES",  bit: 5,    reg: REG_B});
-            assert_eq!(decodeCB(0xB8), InstructionCB{opcode:"RES",  bit: 7,    reg: REG_B});
-            assert_eq!(decodeCB(0xC8), InstructionCB{opcode:"SET",  bit: 1,    reg: REG_B});
-            assert_eq!(decodeCB(0xD8), InstructionCB{opcode:"SET",  bit: 3,    reg: REG_B});
-            assert_eq!(decodeCB(0xE8), InstructionCB{opcode:"SET",  bit: 5,    reg: REG_B});
-            assert_eq!(decodeCB(0xF8), InstructionCB{opcode:"SET",  bit: 7,    reg: REG_B});
+        fn test_bps() {
+            let rom = b"HELLO WORLD".to_vec();
+            let target = b"HELLO HELLO!!!!".to_vec();
+            let mut patch = b"BPS1".to_vec();
+            bps_number(&mut patch, rom.len());
+            bps_number(&mut patch, target.len());
+            bps_number(&mut patch, 0); // no metadata
+            bps_number(&mut patch, bps_cmd(6, 0)); // source read "HELLO "
+            bps_number(&mut patch, bps_cmd(5, 2)); // source copy "HELLO" from 0
+            bps_number(&mut patch, 0);
+            bps_number(&mut patch, bps_cmd(1, 1)); // target read "!"
+            patch.push(b'!');
+            bps_number(&mut patch, bps_cmd(3, 3)); // target copy the "!" 3 times
+            bps_number(&mut patch, 11 << 1);
+            patch.extend(crc32(&rom).to_le_bytes());
+            patch.extend(crc32(&target).to_le_bytes());
+            patch.extend(crc32(&patch).to_le_bytes());
+            assert_eq!(apply(&rom, &patch).unwrap(), target);
+
+            // made for some other rom
+            assert!(apply(b"HELLO THERE", &patch).is_err());
+            // damaged in transit
+            let mut broken = patch.clone();
+            broken[8] ^= 1;
+            assert!(apply(&rom, &broken).is_err());
+        }
+
+        #[test]
+        fn test_rom_patch() {
+            let patch = |s: &str| s.parse::<RomPatch>();
+            // 3E at 4A2B, but only over a 00: old 00 ^ BA = BA, rotated left 2 = EA
+            let genie = patch("3EA-2BB-E6A").unwrap();
+            assert_eq!(genie, patch("4A2B:00:3E").unwrap());
+            assert_eq!(genie.apply(0x4A2B, 0x00), 0x3E);
+            assert_eq!(genie.apply(0x4A2B, 0x01), 0x01); // some other bank
+            assert_eq!(genie.apply(0x4A2C, 0x00), 0x00);
+
+            let always = patch("3EA-2BB").unwrap();
+            assert_eq!(always.to_string(), "4A2B:3E");
+            assert_eq!(always.apply(0x4A2B, 0x01), 0x3E);
+            assert_eq!(patch(&genie.to_string()).unwrap(), genie);
+
+            for bad in [
+                "3EA-2B0",
+                "3EA-2BB-E6",
+                "C000:00",
+                "4A2B",
+                "4A2B:1:2:3",
+                "XYZ-123",
+            ] {
+                assert!(patch(bad).is_err(), "{}", bad);
+            }
         }
     }
 }
 
-pub mod io {
+pub mod checksum {
+    //! Hashes for identifying roms, the same ones rom databases (No-Intro) list
     use crate::types::Byte;
-    use std::io::Read;
 
-    pub fn read_bytes(path: &str) -> Vec<Byte> {
-        let mut file = match std::fs::File::open(&path) {
-            Ok(file) => file,
-            Err(file) => panic!("failed to open {}", file),
-        };
-        let info = file.metadata().expect("failed to read file info");
+    /// CRC-32 as used by zip and png (reflected, polynomial 0xEDB88320)
+    pub fn crc32(bytes: &[Byte]) -> u32 {
+        let mut crc = !0u32;
+        for &b in bytes {
+            crc ^= b as u32;
+            for _ in 0..8 {
+                crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+            }
+        }
+        !crc
+    }
 
-        // todo: not sure if I actually want this but it made clippy happy
-        // consider instead #[allow(clippy::unused_io_amount)]
-        let mut rom: Vec<Byte> = vec![0; info.len() as usize];
-        file.read_exact(&mut rom)
-            .expect("failed to read file into memory");
+    /// SHA-1, not for anything security related, just what rom databases key on
+    pub fn sha1(bytes: &[Byte]) -> [Byte; 20] {
+        let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+        let mut padded = bytes.to_vec();
+        padded.push(0x80);
+        while padded.len() % 64 != 56 {
+            padded.push(0);
+        }
+        padded.extend_from_slice(&(bytes.len() as u64 * 8).to_be_bytes());
+
+        for chunk in padded.chunks(64) {
+            let mut w = [0u32; 80];
+            for (i, word) in chunk.chunks(4).enumerate() {
+                w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+            }
+            for i in 16..80 {
+                w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+            }
+            let [mut a, mut b, mut c, mut d, mut e] = h;
+            for (i, &wi) in w.iter().enumerate() {
+                let (f, k) = match i {
+                    0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                    20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                    40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                    _ => (b ^ c ^ d, 0xCA62C1D6),
+                };
+                let t = a
+                    .rotate_left(5)
+                    .wrapping_add(f)
+                    .wrapping_add(e)
+                    .wrapping_add(k)
+                    .wrapping_add(wi);
+                e = d;
+                d = c;
+                c = b.rotate_left(30);
+                b = a;
+                a = t;
+            }
+            for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+                *h = h.wrapping_add(v);
+            }
+        }
+        let mut out = [0; 20];
+        for (i, v) in h.iter().enumerate() {
+            out[i * 4..i * 4 + 4].copy_from_slice(&v.to_be_bytes());
+        }
+        out
+    }
+
+    /// Lower case hex, how hashes are usually written down
+    pub fn hex(bytes: &[Byte]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[cfg(test)]
+    mod tests_checksum {
+        use super::*;
+
+        #[test]
+        fn test_crc32() {
+            assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        }
+
+        #[test]
+        fn test_sha1() {
+            assert_eq!(
+                hex(&sha1(b"abc")),
+                "a9993e364706816aba3e25717850c26c9cd0d89d"
+            );
+            assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+            // more than one block
+            let long = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+            assert_eq!(hex(&sha1(long)), "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+        }
+    }
+}
+
+pub mod osd {
+    //! Short messages drawn over the game (a trigger firing, say), in a tiny built in font
+    use crate::cpu::{GB_SCREEN_HEIGHT, GB_SCREEN_WIDTH};
+    use std::collections::VecDeque;
+
+    /// How long a message stays up, about 3 seconds
+    pub const OSD_FRAMES: u32 = 180;
+    /// Messages shown at once, a new one pushes out the oldest
+    pub const OSD_LINES: usize = 3;
+
+    const GLYPH_W: usize = 3;
+    const GLYPH_H: usize = 5;
+    const LINE_H: usize = GLYPH_H + 2;
+    const MAX_CHARS: usize = (GB_SCREEN_WIDTH - 1) / (GLYPH_W + 1);
+    const TEXT_COLOR: u32 = 0xFFFFFF;
+
+    // rows top to bottom, 3 pixels each with the leftmost in bit 2
+    #[rustfmt::skip]
+    const FONT: [(char, [u8; GLYPH_H]); 45] = [
+        ('A', [2, 5, 7, 5, 5]), ('B', [6, 5, 6, 5, 6]), ('C', [3, 4, 4, 4, 3]),
+        ('D', [6, 5, 5, 5, 6]), ('E', [7, 4, 6, 4, 7]), ('F', [7, 4, 6, 4, 4]),
+        ('G', [3, 4, 5, 5, 3]), ('H', [5, 5, 7, 5, 5]), ('I', [7, 2, 2, 2, 7]),
+        ('J', [1, 1, 1, 5, 2]), ('K', [5, 5, 6, 5, 5]), ('L', [4, 4, 4, 4, 7]),
+        ('M', [5, 7, 7, 5, 5]), ('N', [6, 5, 5, 5, 5]), ('O', [2, 5, 5, 5, 2]),
+        ('P', [6, 5, 6, 4, 4]), ('Q', [2, 5, 5, 6, 3]), ('R', [6, 5, 6, 5, 5]),
+        ('S', [3, 4, 2, 1, 6]), ('T', [7, 2, 2, 2, 2]), ('U', [5, 5, 5, 5, 7]),
+        ('V', [5, 5, 5, 5, 2]), ('W', [5, 5, 7, 7, 5]), ('X', [5, 5, 2, 5, 5]),
+        ('Y', [5, 5, 2, 2, 2]), ('Z', [7, 1, 2, 4, 7]), ('0', [7, 5, 5, 5, 7]),
+        ('1', [2, 6, 2, 2, 7]), ('2', [6, 1, 2, 4, 7]), ('3', [6, 1, 2, 1, 6]),
+        ('4', [5, 5, 7, 1, 1]), ('5', [7, 4, 6, 1, 6]), ('6', [3, 4, 7, 5, 7]),
+        ('7', [7, 1, 2, 2, 2]), ('8', [7, 5, 7, 5, 7]), ('9', [7, 5, 7, 1, 6]),
+        (' ', [0, 0, 0, 0, 0]), ('!', [2, 2, 2, 0, 2]), ('?', [6, 1, 2, 0, 2]),
+        ('.', [0, 0, 0, 0, 2]), (':', [0, 2, 0, 2, 0]), ('-', [0, 0, 7, 0, 0]),
+        ('\'', [2, 2, 0, 0, 0]), ('/', [1, 1, 2, 4, 4]), ('%', [5, 1, 2, 4, 5]),
+    ];
+
+    /// Lowercase is drawn as uppercase, anything else the font doesn't have as '?'
+    fn glyph(c: char) -> [u8; GLYPH_H] {
+        let c = c.to_ascii_uppercase();
+        let find = |c| FONT.iter().find(|(g, _)| *g == c).map(|(_, rows)| *rows);
+        find(c).or_else(|| find('?')).unwrap()
+    }
+
+    /// Writes text into a frame `width` pixels wide with its top left at (x, y), whatever
+    /// runs off the right edge is left out
+    pub fn draw_text(out: &mut [u32], width: usize, x: usize, y: usize, text: &str, color: u32) {
+        for (i, c) in text.chars().enumerate() {
+            let x0 = x + i * (GLYPH_W + 1);
+            if x0 + GLYPH_W > width {
+                break;
+            }
+            for (dy, row) in glyph(c).iter().enumerate() {
+                for dx in 0..GLYPH_W {
+                    if row & (4 >> dx) != 0 {
+                        out[(y + dy) * width + x0 + dx] = color;
+                    }
+                }
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, Default)]
+    pub struct Osd {
+        messages: VecDeque<(String, u32)>, // text, frames left
+        stats: Vec<String>,                // lines, see set_stats()
+    }
+
+    impl Osd {
+        /// Shows a message for OSD_FRAMES, cut short to fit the width of the screen
+        pub fn notify(&mut self, text: &str) {
+            if self.messages.len() == OSD_LINES {
+                self.messages.pop_front();
+            }
+            let text: String = text.chars().take(MAX_CHARS).collect();
+            self.messages.push_back((text, OSD_FRAMES));
+        }
+
+        /// Shows text along the top until it's set again, wrapped at spaces to fit the width of
+        /// the screen. Empty text takes it down
+        pub fn set_stats(&mut self, text: &str) {
+            self.stats.clear();
+            let mut line = String::new();
+            for word in text.split_whitespace() {
+                if !line.is_empty() && line.len() + 1 + word.len() > MAX_CHARS {
+                    self.stats.push(std::mem::take(&mut line));
+                }
+                if !line.is_empty() {
+                    line.push(' ');
+                }
+                line.extend(word.chars().take(MAX_CHARS));
+            }
+            if !line.is_empty() {
+                self.stats.push(line);
+            }
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.messages.is_empty() && self.stats.is_empty()
+        }
+
+        /// Copies a GB_SCREEN_WIDTH x GB_SCREEN_HEIGHT frame to out with the stats along the top
+        /// and the messages along the bottom (newest last) on darkened bands, and counts the
+        /// messages down a frame
+        ///
+        /// Returns false and leaves out alone when there's nothing to show.
+        pub fn draw(&mut self, frame: &[u32], out: &mut Vec<u32>) -> bool {
+            if self.is_empty() {
+                return false;
+            }
+            out.clear();
+            out.extend_from_slice(frame);
+            let darken = |band: &mut [u32]| {
+                for p in band {
+                    *p = (*p >> 1) & 0x7F7F7F;
+                }
+            };
+            darken(&mut out[..LINE_H * self.stats.len() * GB_SCREEN_WIDTH]);
+            for (line, text) in self.stats.iter().enumerate() {
+                draw_text(out, GB_SCREEN_WIDTH, 1, line * LINE_H + 1, text, TEXT_COLOR);
+            }
+            let top = GB_SCREEN_HEIGHT - LINE_H * self.messages.len();
+            darken(&mut out[top * GB_SCREEN_WIDTH..]);
+            for (line, (text, _)) in self.messages.iter().enumerate() {
+                draw_text(
+                    out,
+                    GB_SCREEN_WIDTH,
+                    1,
+                    top + line * LINE_H + 1,
+                    text,
+                    TEXT_COLOR,
+                );
+            }
+            for (_, left) in self.messages.iter_mut() {
+                *left -= 1;
+            }
+            self.messages.retain(|(_, left)| *left > 0);
+            true
+        }
+    }
+
+    #[cfg(test)]
+    mod tests_osd {
+        use super::*;
+
+        #[test]
+        fn test_osd() {
+            let frame = vec![0x808080; GB_SCREEN_WIDTH * GB_SCREEN_HEIGHT];
+            let mut out = vec![];
+            let mut osd = Osd::default();
+            assert!(!osd.draw(&frame, &mut out));
+            assert!(out.is_empty());
+
+            osd.notify("hi");
+            assert!(osd.draw(&frame, &mut out));
+            let at = |x, y| out[y * GB_SCREEN_WIDTH + x];
+            let y0 = GB_SCREEN_HEIGHT - LINE_H + 1;
+            assert_eq!(at(1, y0), TEXT_COLOR); // H's top left
+            assert_eq!(at(2, y0), 0x404040); // between H's posts, darkened
+            assert_eq!(at(0, 0), 0x808080); // above the band
+            assert_eq!(glyph('h'), glyph('H'));
+            assert_eq!(glyph('~'), glyph('?'));
+
+            for n in 0..OSD_LINES {
+                osd.notify(&format!("message {}", n));
+            }
+            assert_eq!(osd.messages.len(), OSD_LINES);
+            assert_eq!(osd.messages[0].0, "message 0");
+            osd.notify(&"x".repeat(100));
+            assert_eq!(osd.messages.back().unwrap().0.len(), MAX_CHARS);
 
-        rom
+            for _ in 0..OSD_FRAMES {
+                osd.draw(&frame, &mut out);
+            }
+            assert!(osd.is_empty());
+
+            osd.set_stats("cpu 41.0% ppu 30.2% apu 4.1% present 9.5% wait 12.0% other 3.2%");
+            assert_eq!(
+                osd.stats,
+                [
+                    "cpu 41.0% ppu 30.2% apu 4.1% present",
+                    "9.5% wait 12.0% other 3.2%"
+                ]
+            );
+            assert!(osd.draw(&frame, &mut out));
+            assert_eq!(out[GB_SCREEN_WIDTH + 2], TEXT_COLOR); // the top of C
+            assert_eq!(out[LINE_H * 2 * GB_SCREEN_WIDTH], 0x808080); // under the stats
+            osd.draw(&frame, &mut out);
+            assert_eq!(osd.stats.len(), 2); // stays up
+            osd.set_stats("");
+            assert!(osd.is_empty());
+        }
     }
 }
 
-pub mod bits {
-    use crate::types::{Byte, SByte, Word};
+pub mod sgb {
+    //! The Super Game Boy: the commands a game sends it through JOYP, the palettes and
+    //! attribute map they set up to color the screen, and the border drawn around it
+    // https://gbdev.io/pandocs/SGB_Functions.html
+    use crate::bits::combine;
+    use crate::cpu::{GB_SCREEN_HEIGHT, GB_SCREEN_WIDTH};
+    use crate::dbg::StateHash;
+    use crate::lcd::{cgb_rgb, Display};
+    use crate::memory::Memory;
+    use crate::types::*;
 
-    // bit masks
-    pub const BIT_0: Byte = 1 << 0;
-    pub const BIT_1: Byte = 1 << 1;
-    pub const BIT_2: Byte = 1 << 2;
-    pub const BIT_3: Byte = 1 << 3;
-    pub const BIT_4: Byte = 1 << 4;
-    pub const BIT_5: Byte = 1 << 5;
-    pub const BIT_6: Byte = 1 << 6;
-    pub const BIT_7: Byte = 1 << 7;
+    /// The picture with the border, the game's screen sits in the middle of it
+    pub const SGB_SCREEN_WIDTH: usize = 256;
+    pub const SGB_SCREEN_HEIGHT: usize = 224;
+    const SCREEN_X: usize = (SGB_SCREEN_WIDTH - GB_SCREEN_WIDTH) / 2;
+    const SCREEN_Y: usize = (SGB_SCREEN_HEIGHT - GB_SCREEN_HEIGHT) / 2;
+
+    // commands, the top 5 bits of a packet's first byte (the low 3 are how many packets)
+    pub const PAL01: Byte = 0x00;
+    pub const PAL23: Byte = 0x01;
+    pub const PAL03: Byte = 0x02;
+    pub const PAL12: Byte = 0x03;
+    pub const ATTR_BLK: Byte = 0x04;
+    pub const ATTR_LIN: Byte = 0x05;
+    pub const ATTR_DIV: Byte = 0x06;
+    pub const ATTR_CHR: Byte = 0x07;
+    pub const PAL_SET: Byte = 0x0A;
+    pub const PAL_TRN: Byte = 0x0B;
+    pub const MLT_REQ: Byte = 0x11;
+    pub const CHR_TRN: Byte = 0x13;
+    pub const PCT_TRN: Byte = 0x14;
+    pub const ATTR_TRN: Byte = 0x15;
+    pub const ATTR_SET: Byte = 0x16;
+    pub const MASK_EN: Byte = 0x17;
+
+    const PACKET_SIZE: usize = 16;
+    const TRANSFER_SIZE: usize = 0x1000;
+    // the screen is colored in 8x8 cells
+    const ATTR_COLUMNS: usize = GB_SCREEN_WIDTH / 8;
+    const ATTR_ROWS: usize = GB_SCREEN_HEIGHT / 8;
+    const ATTR_FILE_SIZE: usize = ATTR_COLUMNS * ATTR_ROWS / 4;
+    const ATTR_FILES: usize = 45;
+    const SYSTEM_PALETTES: usize = 512;
+    // the border is 32x28 snes tiles, 4 bits per pixel, in 4 palettes of 16 colors
+    const BORDER_COLUMNS: usize = SGB_SCREEN_WIDTH / 8;
+    const BORDER_ROWS: usize = SGB_SCREEN_HEIGHT / 8;
+    const BORDER_TILES: usize = 256;
+    const BORDER_TILE_SIZE: usize = 32;
+    // where PCT_TRN's palettes start
+    const BORDER_PALETTES: usize = 0x800;
+    // color 0 of any border palette, the backdrop shows
+    const BORDER_CLEAR: u32 = 0xFF00_0000;
+    // what the sgb shows before a game sets any palettes
+    const GRAYS: [Word; 4] = [0x7FFF, 0x56B5, 0x294A, 0x0000];
+    const BLACK: u32 = 0;
+
+    #[derive(Clone)]
+    pub struct Sgb {
+        // packets coming in, a bit at a time
+        select: Byte,    // JOYP select bits last written
+        receiving: bool, // since a reset pulse, until the packet's stop bit
+        bits: usize,
+        packet: [Byte; PACKET_SIZE],
+        command: Vec<Byte>, // the packets received of a command sent in several
+        transfer: Option<(Byte, Byte)>, // command (and its first parameter) waiting on the next frame
+        // what the commands set up
+        palettes: [[Word; 4]; 4],                // bgr555, as on the cgb
+        pub(crate) system_palettes: Vec<Word>,   // see PAL_TRN, 4 colors each
+        attrs: [Byte; ATTR_COLUMNS * ATTR_ROWS], // palette of each cell
+        pub(crate) attr_files: Vec<Byte>,        // see ATTR_TRN, 2 bits per cell
+        mask: Byte,                              // see MASK_EN
+        pub(crate) tiles: Vec<Byte>,             // see CHR_TRN
+        pub(crate) map: Vec<Byte>,               // see PCT_TRN
+        pub(crate) border: Vec<u32>, // rendered from tiles and map, SGB_SCREEN_WIDTH x SGB_SCREEN_HEIGHT
+        pub(crate) screen: Vec<u32>, // the last frame colored, held while the mask freezes the screen
+    }
+
+    impl Default for Sgb {
+        fn default() -> Sgb {
+            Sgb::new()
+        }
+    }
 
-    pub const HIGH_MASK: Word = 0xFF00;
-    pub const LOW_MASK: Word = 0x00FF;
-    pub const HIGH_MASK_NIB: Byte = 0xF0;
-    pub const LOW_MASK_NIB: Byte = 0x0F;
+    impl Sgb {
+        pub fn new() -> Sgb {
+            Sgb {
+                select: 0x30,
+                receiving: false,
+                bits: 0,
+                packet: [0; PACKET_SIZE],
+                command: vec![],
+                transfer: None,
+                palettes: [GRAYS; 4],
+                system_palettes: vec![0; SYSTEM_PALETTES * 4],
+                attrs: [0; ATTR_COLUMNS * ATTR_ROWS],
+                attr_files: vec![0; ATTR_FILES * ATTR_FILE_SIZE],
+                mask: 0,
+                tiles: vec![0; BORDER_TILES * BORDER_TILE_SIZE],
+                map: vec![0; TRANSFER_SIZE],
+                border: vec![BORDER_CLEAR; SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT],
+                screen: vec![BLACK; GB_SCREEN_WIDTH * GB_SCREEN_HEIGHT],
+            }
+        }
 
-    pub const fn hi(reg: Word) -> Byte {
-        (reg >> Byte::BITS) as Byte
-    }
+        /// Takes in a write of JOYP's select bits, which is how packets are sent
+        ///
+        /// Both bits low is a reset pulse that starts a packet, then each bit (low bit of the
+        /// first byte first) is P14 low for a 0 or P15 low for a 1, with both high in between.
+        /// 128 bits and a 0 stop bit make a packet.
+        pub fn write_joyp(&mut self, select: Byte, mem: &mut Memory) {
+            if select == self.select {
+                return;
+            }
+            self.select = select;
+            match select {
+                0x00 => {
+                    self.receiving = true;
+                    self.bits = 0;
+                    self.packet = [0; PACKET_SIZE];
+                }
+                0x30 => {}
+                _ if !self.receiving => {}
+                _ if self.bits == PACKET_SIZE * 8 => {
+                    self.receiving = false;
+                    self.packet_done(mem);
+                }
+                _ => {
+                    if select == 0x10 {
+                        self.packet[self.bits / 8] |= 1 << (self.bits % 8);
+                    }
+                    self.bits += 1;
+                }
+            }
+        }
 
-    pub const fn lo(reg: Word) -> Byte {
-        (reg & LOW_MASK) as Byte
-    }
+        fn packet_done(&mut self, mem: &mut Memory) {
+            self.command.extend_from_slice(&self.packet);
+            let packets = (self.command[0] & 0x07).max(1) as usize;
+            if self.command.len() >= packets * PACKET_SIZE {
+                let command = std::mem::take(&mut self.command);
+                self.run(&command, mem);
+            }
+        }
 
-    pub const fn combine(high: Byte, low: Byte) -> Word {
-        (high as Word) << Byte::BITS | (low as Word)
-    }
+        fn run(&mut self, data: &[Byte], mem: &mut Memory) {
+            let color = |i: usize| combine(data[i + 1], data[i]) & 0x7FFF;
+            match data[0] >> 3 {
+                cmd @ PAL01..=PAL12 => {
+                    let (a, b) = [(0, 1), (2, 3), (0, 3), (1, 2)][cmd as usize];
+                    // color 0 is the same in every palette
+                    for palette in self.palettes.iter_mut() {
+                        palette[0] = color(1);
+                    }
+                    for i in 0..3 {
+                        self.palettes[a][i + 1] = color(3 + i * 2);
+                        self.palettes[b][i + 1] = color(9 + i * 2);
+                    }
+                }
+                ATTR_BLK => self.attr_blocks(data),
+                ATTR_LIN => {
+                    for &line in data[2..].iter().take(data[1] as usize) {
+                        let n = (line & 0x1F) as usize;
+                        let palette = (line >> 5) & 0x03;
+                        for (i, attr) in self.attrs.iter_mut().enumerate() {
+                            let (x, y) = (i % ATTR_COLUMNS, i / ATTR_COLUMNS);
+                            // bit 7 set is a row, clear a column
+                            if (line & 0x80 != 0 && y == n) || (line & 0x80 == 0 && x == n) {
+                                *attr = palette;
+                            }
+                        }
+                    }
+                }
+                ATTR_DIV => {
+                    let at = data[2] as usize;
+                    for (i, attr) in self.attrs.iter_mut().enumerate() {
+                        let (x, y) = (i % ATTR_COLUMNS, i / ATTR_COLUMNS);
+                        let n = if data[1] & 0x40 != 0 { y } else { x };
+                        let shift = match n.cmp(&at) {
+                            std::cmp::Ordering::Less => 2, // left of or above the line
+                            std::cmp::Ordering::Equal => 4,
+                            std::cmp::Ordering::Greater => 0,
+                        };
+                        *attr = (data[1] >> shift) & 0x03;
+                    }
+                }
+                ATTR_CHR => {
+                    let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+                    let count = combine(data[4], data[3]) as usize;
+                    for i in 0..count.min(ATTR_COLUMNS * ATTR_ROWS) {
+                        let byte = match data.get(6 + i / 4) {
+                            Some(byte) => byte,
+                            None => break,
+                        };
+                        if x < ATTR_COLUMNS && y < ATTR_ROWS {
+                            self.attrs[y * ATTR_COLUMNS + x] = (byte >> (6 - i % 4 * 2)) & 0x03;
+                        }
+                        if data[5] & 1 == 0 {
+                            x += 1;
+                            if x == ATTR_COLUMNS {
+                                x = 0;
+                                y += 1;
+                            }
+                        } else {
+                            y += 1;
+                            if y == ATTR_ROWS {
+                                y = 0;
+                                x += 1;
+                            }
+                        }
+                    }
+                }
+                PAL_SET => {
+                    for (p, palette) in self.palettes.iter_mut().enumerate() {
+                        let n = (combine(data[2 + p * 2], data[1 + p * 2]) & 0x1FF) as usize;
+                        palette.copy_from_slice(&self.system_palettes[n * 4..n * 4 + 4]);
+                    }
+                    let color0 = self.palettes[0][0];
+                    for palette in self.palettes.iter_mut() {
+                        palette[0] = color0;
+                    }
+                    if data[9] & 0x80 != 0 {
+                        self.apply_attr_file(data[9]);
+                    }
+                    if data[9] & 0x40 != 0 {
+                        self.mask = 0;
+                    }
+                }
+                MLT_REQ => {
+                    let players = match data[1] & 0x03 {
+                        1 => 2,
+                        3 => 4,
+                        _ => 1,
+                    };
+                    mem.set_sgb_players(players);
+                }
+                cmd @ PAL_TRN | cmd @ CHR_TRN | cmd @ PCT_TRN | cmd @ ATTR_TRN => {
+                    self.transfer = Some((cmd, data[1]));
+                }
+                ATTR_SET => {
+                    self.apply_attr_file(data[1]);
+                    if data[1] & 0x40 != 0 {
+                        self.mask = 0;
+                    }
+                }
+                MASK_EN => self.mask = data[1] & 0x03,
+                _ => {} // sound, the snes side of things
+            }
+        }
 
-    pub const fn fl_set(flag: Byte, set: bool) -> Byte {
-        (set as u8) * flag
-    }
+        /// ATTR_BLK: rectangles, each with a palette inside, on its edge and outside
+        fn attr_blocks(&mut self, data: &[Byte]) {
+            for set in data[2..].chunks_exact(6).take(data[1] as usize) {
+                let (control, palettes) = (set[0], set[1]);
+                let (x1, y1) = (set[2] as usize, set[3] as usize);
+                let (x2, y2) = (set[4] as usize, set[5] as usize);
+                let inside = palettes & 0x03;
+                let outside = (palettes >> 4) & 0x03;
+                // with only the inside or the outside given, the edge goes along with it
+                let edge = match control & 0x07 {
+                    0x01 => Some(inside),
+                    0x04 => Some(outside),
+                    c if c & 0x02 != 0 => Some((palettes >> 2) & 0x03),
+                    _ => None,
+                };
+                for (i, attr) in self.attrs.iter_mut().enumerate() {
+                    let (x, y) = (i % ATTR_COLUMNS, i / ATTR_COLUMNS);
+                    let within = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let palette = if within && (x == x1 || x == x2 || y == y1 || y == y2) {
+                        edge
+                    } else if within {
+                        (control & 0x01 != 0).then_some(inside)
+                    } else {
+                        (control & 0x04 != 0).then_some(outside)
+                    };
+                    if let Some(palette) = palette {
+                        *attr = palette;
+                    }
+                }
+            }
+        }
 
-    pub const fn fl_z(val: Byte) -> Byte {
-        fl_set(crate::cpu::FL_Z, val == 0)
-    }
+        fn apply_attr_file(&mut self, n: Byte) {
+            let n = (n & 0x3F) as usize;
+            if n >= ATTR_FILES {
+                return;
+            }
+            let file = &self.attr_files[n * ATTR_FILE_SIZE..(n + 1) * ATTR_FILE_SIZE];
+            for (i, attr) in self.attrs.iter_mut().enumerate() {
+                *attr = (file[i / 4] >> (6 - i % 4 * 2)) & 0x03;
+            }
+        }
 
-    pub const fn bit(idx: Byte, val: Byte) -> Byte {
-        (val >> idx) & 1
-    }
+        /// The 4KB a *_TRN command sends: the sgb reads it off the screen, as the first 256
+        /// tiles' worth of the picture left to right, top to bottom. Games show the data
+        /// as tiles with BGP at E4, so the shades are the tiles' color indices
+        fn vram_data(shades: &[Byte]) -> Vec<Byte> {
+            let mut data = vec![0; TRANSFER_SIZE];
+            for (tile, bytes) in data.chunks_exact_mut(16).enumerate() {
+                let (tx, ty) = (tile % ATTR_COLUMNS * 8, tile / ATTR_COLUMNS * 8);
+                for row in 0..8 {
+                    for col in 0..8 {
+                        let shade = shades[(ty + row) * GB_SCREEN_WIDTH + tx + col];
+                        bytes[row * 2] |= (shade & 1) << (7 - col);
+                        bytes[row * 2 + 1] |= ((shade >> 1) & 1) << (7 - col);
+                    }
+                }
+            }
+            data
+        }
 
-    pub const fn bit_test(idx: Byte, val: Byte) -> bool {
-        bit(idx, val) != 0
-    }
+        fn finish_transfer(&mut self, cmd: Byte, param: Byte, data: &[Byte]) {
+            match cmd {
+                PAL_TRN => {
+                    for (i, color) in self.system_palettes.iter_mut().enumerate() {
+                        *color = combine(data[i * 2 + 1], data[i * 2]) & 0x7FFF;
+                    }
+                }
+                CHR_TRN => {
+                    // the low or the high half of the border's tiles
+                    let at = (param & 1) as usize * TRANSFER_SIZE;
+                    self.tiles[at..at + TRANSFER_SIZE].copy_from_slice(data);
+                    self.render_border();
+                }
+                PCT_TRN => {
+                    self.map.copy_from_slice(data);
+                    self.render_border();
+                }
+                _ => {
+                    let files = ATTR_FILES * ATTR_FILE_SIZE;
+                    self.attr_files.copy_from_slice(&data[..files]);
+                }
+            }
+        }
 
-    pub const fn bit_set(idx: Byte, val: Byte, set: bool) -> Byte {
-        if set {
-            val | idx
-        } else {
-            val & !idx
+        fn render_border(&mut self) {
+            for i in 0..BORDER_COLUMNS * BORDER_ROWS {
+                // tile, palette 4-7 in bits 10-12, then flip x and flip y on top
+                let entry = combine(self.map[i * 2 + 1], self.map[i * 2]);
+                let tile = &self.tiles[(entry & 0xFF) as usize * BORDER_TILE_SIZE..];
+                let palette = ((entry >> 10) & 0x03) as usize;
+                for row in 0..8 {
+                    for col in 0..8 {
+                        let r = if entry & 0x8000 != 0 { 7 - row } else { row };
+                        let bit = if entry & 0x4000 != 0 { col } else { 7 - col };
+                        // bitplanes 0 and 1 interleaved, then 2 and 3
+                        let planes = [
+                            tile[r * 2],
+                            tile[r * 2 + 1],
+                            tile[16 + r * 2],
+                            tile[17 + r * 2],
+                        ];
+                        let color = planes
+                            .iter()
+                            .enumerate()
+                            .fold(0, |c, (n, plane)| c | ((plane >> bit) & 1) << n)
+                            as usize;
+                        let x = i % BORDER_COLUMNS * 8 + col;
+                        let y = i / BORDER_COLUMNS * 8 + row;
+                        self.border[y * SGB_SCREEN_WIDTH + x] = if color == 0 {
+                            BORDER_CLEAR
+                        } else {
+                            let at = BORDER_PALETTES + (palette * 16 + color) * 2;
+                            cgb_rgb(combine(self.map[at + 1], self.map[at]))
+                        };
+                    }
+                }
+            }
         }
-    }
 
-    #[test]
-    fn test_bit_test() {
-        let x: Byte = 0b00000101;
-        assert_eq!(bit_test(7, x), false);
-        assert_eq!(bit_test(6, x), false);
-        assert_eq!(bit_test(5, x), false);
-        assert_eq!(bit_test(4, x), false);
-        assert_eq!(bit_test(3, x), false);
-        assert_eq!(bit_test(2, x), true);
-        assert_eq!(bit_test(1, x), false);
-        assert_eq!(bit_test(0, x), true);
-    }
+        /// Colors a completed frame (and takes any transfer off it), call once frame() has
+        /// a new one
+        ///
+        /// The ppu's shades are looked up in the palette of each pixel's cell, unless the
+        /// mask is freezing or blanking the screen.
+        pub fn frame_done(&mut self, lcd: &mut Display) {
+            let shades = lcd.shades();
+            if shades.is_empty() {
+                return;
+            }
+            if let Some((cmd, param)) = self.transfer.take() {
+                let data = Sgb::vram_data(shades);
+                self.finish_transfer(cmd, param, &data);
+            }
+            match self.mask {
+                0 => {
+                    for (i, px) in self.screen.iter_mut().enumerate() {
+                        let (x, y) = (i % GB_SCREEN_WIDTH, i / GB_SCREEN_WIDTH);
+                        let palette = self.attrs[y / 8 * ATTR_COLUMNS + x / 8] as usize;
+                        *px = cgb_rgb(self.palettes[palette][shades[i] as usize]);
+                    }
+                }
+                1 => {} // frozen
+                2 => self.screen.iter_mut().for_each(|px| *px = BLACK),
+                _ => {
+                    let color0 = cgb_rgb(self.palettes[0][0]);
+                    self.screen.iter_mut().for_each(|px| *px = color0);
+                }
+            }
+            lcd.front.copy_from_slice(&self.screen);
+        }
 
-    // can't be const for some reason https://github.com/rust-lang/rust/issues/53605
-    pub fn signed(val: Byte) -> SByte {
-        unsafe { std::mem::transmute(val) }
+        /// Puts a frame in the middle of the border, SGB_SCREEN_WIDTH x SGB_SCREEN_HEIGHT
+        /// pixels with the backdrop (color 0) wherever the border is see through
+        pub fn compose(&self, frame: &[u32], out: &mut Vec<u32>) {
+            out.clear();
+            out.resize(
+                SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT,
+                cgb_rgb(self.palettes[0][0]),
+            );
+            for (row, line) in frame.chunks_exact(GB_SCREEN_WIDTH).enumerate() {
+                let at = (SCREEN_Y + row) * SGB_SCREEN_WIDTH + SCREEN_X;
+                out[at..at + GB_SCREEN_WIDTH].copy_from_slice(line);
+            }
+            for (px, &border) in out.iter_mut().zip(self.border.iter()) {
+                if border != BORDER_CLEAR {
+                    *px = border;
+                }
+            }
+        }
+
+        /// The packet receiver and what the commands set up, the border is only output
+        pub fn hash_state(&self, h: &mut StateHash) {
+            h.bytes(&[self.select, self.receiving as Byte, self.mask]);
+            h.u64(self.bits as u64);
+            h.bytes(&self.packet);
+            h.bytes(&self.command);
+            if let Some((cmd, param)) = self.transfer {
+                h.bytes(&[cmd, param]);
+            }
+            for color in self.palettes.iter().flatten() {
+                h.bytes(&color.to_le_bytes());
+            }
+            h.bytes(&self.attrs);
+            for color in &self.system_palettes {
+                h.bytes(&color.to_le_bytes());
+            }
+            h.bytes(&self.attr_files);
+        }
     }
-}
 
-pub mod dbg {
-    use std::fs;
-    use std::fs::File;
-    use std::io::{BufWriter, Write};
+    #[cfg(test)]
+    mod tests_sgb {
+        use super::*;
+        use crate::memory::{Cartridge, JOYP, ROM_SGB};
 
-    use crate::cpu::*;
-    use crate::lcd::*;
-    use crate::memory::*;
-    use crate::types::*;
+        fn sgb_mem() -> Memory {
+            let mut rom = vec![0; 0x8000];
+            rom[ROM_SGB as usize] = 0x03;
+            let mut mem = Memory::new();
+            mem.load_rom(&Cartridge::from(rom));
+            mem
+        }
 
-    pub struct CPULog {
-        cpu: CPUState,
-        mem_next: [Byte; 4],
-    }
+        /// Sends a command the way games do, a bit at a time through JOYP
+        fn send(sgb: &mut Sgb, mem: &mut Memory, packets: &[Byte]) {
+            for packet in packets.chunks(PACKET_SIZE) {
+                let mut writes = vec![0x00, 0x30];
+                for i in 0..PACKET_SIZE * 8 {
+                    let one = packet.get(i / 8).is_some_and(|b| b & (1 << (i % 8)) != 0);
+                    writes.extend_from_slice(&[if one { 0x10 } else { 0x20 }, 0x30]);
+                }
+                writes.extend_from_slice(&[0x20, 0x30]); // stop bit
+                for val in writes {
+                    mem.write(JOYP, val);
+                }
+                for select in std::mem::take(&mut mem.joyp_writes) {
+                    sgb.write_joyp(select, mem);
+                }
+            }
+        }
 
-    impl std::fmt::Display for CPULog {
-        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-            write!(f, "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
-                self.cpu.reg[REG_A],
-                self.cpu.reg[FLAGS],
-                self.cpu.reg[REG_B],
-                self.cpu.reg[REG_C],
-                self.cpu.reg[REG_D],
-                self.cpu.reg[REG_E],
-                self.cpu.reg[REG_H],
-                self.cpu.reg[REG_L],
-                self.cpu.sp,
-                self.cpu.pc,
-                self.mem_next[0],
-                self.mem_next[1],
-                self.mem_next[2],
-                self.mem_next[3]
-            )
+        fn lcd_with_shades(shade: impl Fn(usize, usize) -> Byte) -> Display {
+            let mut lcd = Display::new();
+            lcd.keep_shades();
+            for (i, px) in lcd.shades.iter_mut().enumerate() {
+                *px = shade(i % GB_SCREEN_WIDTH, i / GB_SCREEN_WIDTH);
+            }
+            lcd
         }
-    }
 
-    pub fn log_cpu(buffer: &mut Vec<CPULog>, cpu: &CPUState, mem: &Memory) {
-        buffer.push(CPULog {
-            cpu: cpu.clone(),
-            mem_next: [
-                mem.read(cpu.pc + 0),
-                mem.read(cpu.pc + 1),
-                mem.read(cpu.pc + 2),
-                mem.read(cpu.pc + 3),
-            ],
-        });
-    }
+        /// A frame showing the data as gb tiles, for a *_TRN to read
+        fn showing(data: &[Byte]) -> Display {
+            lcd_with_shades(|x, y| {
+                let (tile, row, col) = (y / 8 * ATTR_COLUMNS + x / 8, y % 8, x % 8);
+                let lo = data.get(tile * 16 + row * 2).copied().unwrap_or(0);
+                let hi = data.get(tile * 16 + row * 2 + 1).copied().unwrap_or(0);
+                ((lo >> (7 - col)) & 1) | ((hi >> (7 - col)) & 1) << 1
+            })
+        }
 
-    pub fn write_cpu_logs(logs: &Vec<CPULog>) -> std::io::Result<()> {
-        let f = File::create("cpu.log")?;
-        let mut writer = BufWriter::with_capacity(1 << 16, f);
-        for log in logs {
-            writeln!(writer, "{}", log)?;
+        #[test]
+        fn test_palettes() {
+            let mut mem = sgb_mem();
+            let mut sgb = Sgb::new();
+            // PAL12: red backdrop, palette 1 blues and palette 2 greens
+            let mut packet = vec![PAL12 << 3 | 1, 0x1F, 0x00];
+            packet.extend_from_slice(&[0x00, 0x7C, 0x00, 0x3C, 0x00, 0x1C]);
+            packet.extend_from_slice(&[0xE0, 0x03, 0xE0, 0x01, 0xE0, 0x00]);
+            send(&mut sgb, &mut mem, &packet);
+            assert_eq!(sgb.palettes[0], [0x001F, GRAYS[1], GRAYS[2], GRAYS[3]]);
+            assert_eq!(sgb.palettes[1], [0x001F, 0x7C00, 0x3C00, 0x1C00]);
+            assert_eq!(sgb.palettes[2], [0x001F, 0x03E0, 0x01E0, 0x00E0]);
+
+            // the right half of the screen in palette 1
+            let mut packet = vec![ATTR_BLK << 3 | 1, 1, 0x01, 0x01, 10, 0, 19, 17];
+            packet.resize(PACKET_SIZE, 0);
+            send(&mut sgb, &mut mem, &packet);
+            let mut lcd = lcd_with_shades(|_, _| 1);
+            sgb.frame_done(&mut lcd);
+            assert_eq!(lcd.frame()[0], cgb_rgb(GRAYS[1]));
+            assert_eq!(lcd.frame()[80], cgb_rgb(0x7C00));
+            assert_eq!(lcd.frame()[GB_SCREEN_WIDTH * 143 + 159], cgb_rgb(0x7C00));
+
+            // blanked to black, the frame isn't looked at
+            send(&mut sgb, &mut mem, &[MASK_EN << 3 | 1, 2]);
+            let mut lcd = lcd_with_shades(|_, _| 0);
+            sgb.frame_done(&mut lcd);
+            assert!(lcd.frame().iter().all(|&px| px == BLACK));
         }
-        writer.flush()?;
-        Ok(())
-    }
 
-    pub fn dump(path: &str, mem: &Memory) -> std::io::Result<()> {
-        fs::write(path, mem.data)?;
-        Ok(())
-    }
+        #[test]
+        fn test_attr_commands() {
+            let mut mem = sgb_mem();
+            let mut sgb = Sgb::new();
+            let at = |sgb: &Sgb, x: usize, y: usize| sgb.attrs[y * ATTR_COLUMNS + x];
+
+            // a box: 1 inside, 2 on the edge, 3 outside
+            let mut packet = vec![ATTR_BLK << 3 | 1, 1, 0x07, 0b11_10_01, 2, 2, 6, 6];
+            packet.resize(PACKET_SIZE, 0);
+            send(&mut sgb, &mut mem, &packet);
+            assert_eq!([at(&sgb, 4, 4), at(&sgb, 2, 4), at(&sgb, 0, 0)], [1, 2, 3]);
+
+            // row 0 in palette 2, column 1 in palette 1
+            send(
+                &mut sgb,
+                &mut mem,
+                &[ATTR_LIN << 3 | 1, 2, 0x80 | 2 << 5, 1 << 5 | 1],
+            );
+            assert_eq!([at(&sgb, 5, 0), at(&sgb, 1, 9), at(&sgb, 0, 0)], [2, 1, 2]);
 
-    const VEC_NAMES: [&str; 5] = ["VBLANK", "STAT", "TIMER", "SERIAL", "JOYPAD"];
+            // split at row 9: 1 above, 2 on it, 3 below
+            send(
+                &mut sgb,
+                &mut mem,
+                &[ATTR_DIV << 3 | 1, 0x40 | 0b10_01_11, 9],
+            );
+            assert_eq!(
+                [at(&sgb, 0, 8), at(&sgb, 0, 9), at(&sgb, 19, 10)],
+                [1, 2, 3]
+            );
 
-    pub const fn str_interrupt(i: Word) -> &'static str {
-        let idx = (i - VEC_INT_VBLANK) / 0x08;
-        VEC_NAMES[idx as usize]
-    }
+            // cells one at a time from 18,0 left to right, wrapping onto the next row
+            send(
+                &mut sgb,
+                &mut mem,
+                &[ATTR_CHR << 3 | 1, 18, 0, 3, 0, 0, 0b00_01_10_00],
+            );
+            assert_eq!(
+                [at(&sgb, 18, 0), at(&sgb, 19, 0), at(&sgb, 0, 1)],
+                [0, 1, 2]
+            );
+        }
 
-    pub fn str_flags(flags: Byte) -> String {
-        format!(
-            "{}{}{}{}",
-            if flags & FL_C != 0 { "C" } else { "—" },
-            if flags & FL_H != 0 { "H" } else { "—" },
-            if flags & FL_N != 0 { "N" } else { "—" },
-            if flags & FL_Z != 0 { "Z" } else { "—" },
-        )
-    }
+        #[test]
+        fn test_players() {
+            let mut mem = sgb_mem();
+            let mut sgb = Sgb::new();
+            assert_eq!(mem.read(JOYP) & 0x0F, 0x0F);
+            send(&mut sgb, &mut mem, &[MLT_REQ << 3 | 1, 1]);
+            // deselecting the buttons moves on to the next player
+            mem.write(JOYP, 0x30);
+            let first = mem.read(JOYP) & 0x0F;
+            mem.write(JOYP, 0x10);
+            mem.write(JOYP, 0x30);
+            let second = mem.read(JOYP) & 0x0F;
+            assert_ne!(first, second);
+            assert!([first, second].contains(&0x0F) && [first, second].contains(&0x0E));
+        }
 
-    #[rustfmt::skip]
-    pub fn print_lcdc(mem: &Memory) {
-        // print LCDC diagnostics
-        let lcdc_v = mem.read(LCDC);
-        let lcdc_7 = if lcdc_v & LCDC_BIT_ENABLE != 0                     { " on" }    else { "off" };
-        let lcdc_6 = if lcdc_v & LCDC_BIT_WINDOW_TILE_MAP_SELECT != 0     { "0x9C00" } else { "0x9800" };
-        let lcdc_5 = if lcdc_v & LCDC_BIT_WINDOW_ENABLE != 0              { " on" }    else { "off" };
-        let lcdc_4 = if lcdc_v & LCDC_BIT_BG_WINDOW_TILE_DATA_SELECT != 0 { "0x8000" } else { "0x8800" };
-        let lcdc_3 = if lcdc_v & LCDC_BIT_BG_TILE_MAP_SELECT != 0         { "0x9C00" } else { "0x9800" };
-        let lcdc_2 = if lcdc_v & LCDC_BIT_OBJ_SIZE != 0                   { "16" }     else { " 8" };
-        let lcdc_1 = if lcdc_v & LCDC_BIT_OBJ_ENABLE != 0                 { " on" }    else { "off" };
-        let lcdc_0 = if lcdc_v & LCDC_BIT_BG_WINDOW_ENABLE != 0           { " on" }    else { "off" };
-        println!("{lcdc_v:#10b} LCDC [scr: {lcdc_7}, wnd_map: {lcdc_6}, wnd: {lcdc_5}, bg/wnd_dat: {lcdc_4}, bg_map: {lcdc_3}, obj_sz: {lcdc_2}, obj: {lcdc_1}, bg: {lcdc_0}]");
+        #[test]
+        fn test_border() {
+            let mut mem = sgb_mem();
+            let mut sgb = Sgb::new();
+            // tile 1 is solid color 1, the data shown on screen as gb tiles
+            send(&mut sgb, &mut mem, &[CHR_TRN << 3 | 1, 0]);
+            let mut tiles = vec![0; TRANSFER_SIZE];
+            for row in 0..8 {
+                tiles[BORDER_TILE_SIZE + row * 2] = 0xFF;
+            }
+            sgb.frame_done(&mut showing(&tiles));
+            assert_eq!(&sgb.tiles[..TRANSFER_SIZE], &tiles[..]);
+
+            // the top left corner is tile 1 in palette 4, color 1 white
+            send(&mut sgb, &mut mem, &[PCT_TRN << 3 | 1]);
+            let mut map = vec![0; TRANSFER_SIZE];
+            map[0] = 1;
+            map[1] = 4 << 2;
+            map[BORDER_PALETTES + 2] = 0xFF;
+            map[BORDER_PALETTES + 3] = 0x7F;
+            sgb.frame_done(&mut showing(&map));
+
+            let frame = vec![0x123456; GB_SCREEN_WIDTH * GB_SCREEN_HEIGHT];
+            let mut out = vec![];
+            sgb.compose(&frame, &mut out);
+            assert_eq!(out.len(), SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT);
+            assert_eq!(out[0], 0xFFFFFF);
+            assert_eq!(out[8], cgb_rgb(sgb.palettes[0][0])); // see through, the backdrop
+            assert_eq!(out[SCREEN_Y * SGB_SCREEN_WIDTH + SCREEN_X], 0x123456);
+        }
     }
 }
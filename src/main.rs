@@ -3,104 +3,1747 @@
 #![allow(clippy::identity_op)]
 #![feature(const_trait_impl)]
 
-extern crate minifb;
-use minifb::{Key, Window, WindowOptions};
-
 extern crate env_logger;
 
+use std::collections::VecDeque;
+use std::io::Write;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+
+use cerboy::apu::{Resampler, Scope, SAMPLE_RATE};
+use cerboy::config::GameConfig;
 use cerboy::cpu::*;
-use cerboy::lcd::*;
+use cerboy::dbg::{
+    draw_apu, interrupt_summary, load_reference_png, step_diff, DebugLayout, FrameDiff, Profiler,
+    RomUsage, Section, WatchPreset, LAYOUT_HEIGHT, LAYOUT_WIDTH,
+};
+use cerboy::emu::{Emulator, Rewind, Traced};
+use cerboy::filter::FilterChain;
+use cerboy::io::{sav_path_for, write_with_backups, LiveSplit, WavWriter, LIVESPLIT_ADDR};
+use cerboy::lcd::cgb_compat_palettes;
 use cerboy::memory::*;
+use cerboy::osd::Osd;
+use cerboy::pacing::{AvSync, FrameRate, Pacer, SlowAudio, Speed, SyncMode};
+use cerboy::patch::RomPatch;
+use cerboy::sav::{SavLayout, SaveFile};
+use cerboy::settings::{Accuracy, AudioBuffer, KeyBindings, Settings};
+use cerboy::sgb::{Sgb, SGB_SCREEN_HEIGHT, SGB_SCREEN_WIDTH};
+use cerboy::types::Byte;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Path to ROM
+    /// Path to ROM, pick from recent ROMs if not given
+    #[arg(short, long)]
+    rom: Option<String>,
+
+    /// Print the cartridge header summary and exit
+    #[arg(short, long, default_value_t = false)]
+    info: bool,
+
+    /// Print every header field, the checksums and the mapper as JSON and exit
+    #[arg(long, default_value_t = false)]
+    header_json: bool,
+
+    /// Window scale (remembered for next time)
     #[arg(short, long)]
-    rom: String,
+    scale: Option<usize>,
+
+    /// Master volume in percent, 0-100 (remembered for next time)
+    #[arg(long)]
+    volume: Option<u32>,
+
+    /// Milliseconds of audio to keep queued, raise it if sound crackles (remembered for next
+    /// time)
+    #[arg(long)]
+    audio_latency: Option<u32>,
+
+    /// Samples the sound card takes at a time, a power of 2 (remembered for next time)
+    #[arg(long)]
+    audio_buffer: Option<AudioBuffer>,
+
+    /// Boot as a CGB would, colorizing dmg games with the boot rom's palettes
+    #[arg(long, default_value_t = false)]
+    cgb: bool,
+
+    /// Print a hash of the whole machine state every N frames (H prints it on demand)
+    #[arg(long)]
+    hash_every: Option<u64>,
+
+    /// Apply an IPS or BPS patch (a romhack or translation) to the rom before running it
+    #[arg(long)]
+    patch: Option<String>,
+
+    /// Patch a ROM byte as it's read, as a Game Genie code (ABC-DEF-GHI) or addr:old:new in
+    /// hex (repeatable, alt+1-9 toggle them while playing)
+    #[arg(long)]
+    genie: Vec<RomPatch>,
+
+    /// Refuse to run a rom whose header or global checksum doesn't match (a bad dump)
+    #[arg(long, default_value_t = false)]
+    strict: bool,
+
+    /// Flash the pixels that differ from this screenshot (a png from another emulator or
+    /// real hardware, 160x144 or a multiple)
+    #[arg(long)]
+    reference: Option<String>,
+
+    /// Show the tile data, background map, OAM and registers beside the game, all in the
+    /// one window
+    #[arg(long, default_value_t = false)]
+    debug_layout: bool,
+
+    /// Write everything the sound hardware plays to a .wav, for as long as the game runs
+    #[arg(long)]
+    record_audio: Option<String>,
+
+    /// Note which ROM bytes run as code or get read as data, writing the map (a byte of
+    /// flags per ROM byte) here on exit and printing a per-bank summary
+    #[arg(long)]
+    rom_usage: Option<String>,
+
+    /// Send the splits of the game config's triggers to LiveSplit Server at host:port
+    /// (127.0.0.1:16834 if not given)
+    #[arg(long, num_args = 0..=1, default_missing_value = LIVESPLIT_ADDR)]
+    livesplit: Option<String>,
+
+    /// Pause when the cpu writes somewhere suspicious: oam-write, vram-write-during-mode3,
+    /// if-write, hram-write or io-write (repeatable)
+    #[arg(long)]
+    break_on: Vec<WatchPreset>,
+
+    /// Warn (once per instruction) about things homebrew probably didn't mean to do: reading
+    /// RAM before writing it, writing to ROM without a mapper, invalid opcodes, turning the
+    /// window on off screen
+    #[arg(long, default_value_t = false)]
+    lint: bool,
 
     /// Run in gameboy-doctor mode
+    #[cfg(feature = "instrument")]
     #[arg(short, long, default_value_t = false)]
     doctor: bool,
+
+    /// Pause when the stack pointer strays outside WRAM/HRAM
+    #[cfg(feature = "instrument")]
+    #[arg(long, default_value_t = false)]
+    stack_guard: bool,
+
+    #[command(subcommand)]
+    tool: Option<Tool>,
+}
+
+/// Utilities that run and exit without opening a window
+#[derive(Subcommand, Debug)]
+enum Tool {
+    /// Split a .sav into its raw RAM and (optionally) its clock footer
+    ExportSav {
+        sav: String,
+        ram: String,
+        /// Where to write the 48 byte clock footer, if the save has one
+        #[arg(long)]
+        rtc: Option<String>,
+    },
+    /// Build a .sav from raw RAM and an optional clock footer
+    ImportSav {
+        ram: String,
+        sav: String,
+        #[arg(long)]
+        rtc: Option<String>,
+        /// raw, rtc (BGB/SameBoy) or rtc-short (older VBA)
+        #[arg(long, default_value = "rtc")]
+        layout: SavLayout,
+    },
+    /// Rewrite a .sav with a different clock footer layout
+    ConvertSav {
+        input: String,
+        output: String,
+        #[arg(long, default_value = "rtc")]
+        layout: SavLayout,
+    },
+    /// Run a test rom without a window, exiting non-zero unless it produces the expected
+    /// output before the time runs out
+    Test {
+        rom: String,
+        /// Pass once the rom has sent this over the serial port (Blargg's roms print "Passed")
+        #[arg(long)]
+        expect_serial: Option<String>,
+        /// Pass once the screen hashes to this, the hash is printed when a run ends
+        #[arg(long)]
+        expect_screen: Option<String>,
+        /// Give up after this many frames
+        #[arg(long, default_value_t = 60 * 60)]
+        frames: u64,
+    },
+}
+
+/// Passes through the result of some emulation, or if it panicked prints the machine state
+/// under the panic message and carries on panicking, so bug reports come with some context
+fn report_panic<T>(ran: std::thread::Result<T>, emu: &Emulator) -> T {
+    ran.unwrap_or_else(|panic| {
+        eprintln!("\n{}", emu.crash_report());
+        std::panic::resume_unwind(panic)
+    })
+}
+
+fn run_tool(tool: Tool) -> std::io::Result<()> {
+    let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+    match tool {
+        Tool::ExportSav { sav, ram, rtc } => {
+            let save = SaveFile::parse(&std::fs::read(sav)?);
+            std::fs::write(ram, &save.ram)?;
+            match (rtc, save.rtc_bytes()) {
+                (Some(path), Some(footer)) => std::fs::write(path, footer)?,
+                (Some(_), None) => eprintln!("save has no clock data, skipping --rtc"),
+                _ => {}
+            }
+        }
+        Tool::ImportSav {
+            ram,
+            sav,
+            rtc,
+            layout,
+        } => {
+            let mut save = SaveFile {
+                ram: std::fs::read(ram)?,
+                rtc: None,
+            };
+            if let Some(path) = rtc {
+                save.set_rtc_bytes(&std::fs::read(path)?).map_err(invalid)?;
+            }
+            // without a clock there's nothing to put in the footer
+            let layout = if save.rtc.is_some() {
+                layout
+            } else {
+                SavLayout::Raw
+            };
+            std::fs::write(sav, save.to_bytes(layout))?;
+        }
+        Tool::ConvertSav {
+            input,
+            output,
+            layout,
+        } => {
+            let save = SaveFile::parse(&std::fs::read(input)?);
+            std::fs::write(output, save.to_bytes(layout))?;
+        }
+        Tool::Test {
+            rom,
+            expect_serial,
+            expect_screen,
+            frames,
+        } => {
+            let expect_screen = match expect_screen {
+                Some(hash) => Some(
+                    u64::from_str_radix(hash.trim_start_matches("0x"), 16)
+                        .map_err(|e| invalid(format!("bad screen hash {}: {}", hash, e)))?,
+                ),
+                None => None,
+            };
+            let expecting = expect_serial.is_some() || expect_screen.is_some();
+            let passed = |emu: &Emulator| {
+                let serial = String::from_utf8_lossy(emu.mem.serial_output());
+                expect_serial
+                    .as_ref()
+                    .is_none_or(|s| serial.contains(s.as_str()))
+                    && expect_screen.is_none_or(|h| emu.screen_hash() == h)
+            };
+            let cart = Cartridge::new(rom.as_str()).map_err(|e| invalid(e.to_string()))?;
+            let mut emu = Emulator::new(&cart);
+            // the user's settings.toml stays out of it, a test runs the same everywhere
+            emu.trace_len = Settings::default().crash_trace;
+            let mut frame = 0;
+            while frame < frames && !(expecting && passed(&emu)) {
+                let ran = std::panic::catch_unwind(AssertUnwindSafe(|| emu.step_frame()));
+                if let Err(e) = report_panic(ran, &emu) {
+                    eprintln!("{}", emu.crash_report());
+                    return Err(invalid(e.to_string()));
+                }
+                frame += 1;
+            }
+            let serial = String::from_utf8_lossy(emu.mem.serial_output());
+            if !serial.is_empty() {
+                println!("{}", serial.trim_end());
+            }
+            println!("screen {:016x} after {} frames", emu.screen_hash(), frame);
+            if expecting && !passed(&emu) {
+                return Err(std::io::Error::other(
+                    "test failed: expected output never showed up",
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// a few seconds of per-frame snapshots for stepping backwards while paused
+const REWIND_FRAMES: usize = 300;
+// about a second between profiler updates
+const PROFILE_FRAMES: u64 = 60;
+
+/// Everything the frontend can ask of the emulator, queued up and applied between frames
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Command {
+    Quit,
+    TogglePause,
+    /// while paused
+    StepFrame,
+    /// while paused, prints what the instruction changed
+    StepInstruction,
+    /// while paused, uses the rewind buffer
+    StepBack,
+    /// while paused, back to the nth most recent instruction in the trace (1 is the last
+    /// one run), replayed from the rewind buffer
+    TravelBack(usize),
+    /// see Emulator::trace
+    PrintTrace,
+    /// BTN_* held
+    SetButtons(Byte),
+    /// accelerometer (x, y) in g, for MBC7 carts
+    SetTilt(f32, f32),
+    /// see Emulator::state_hash
+    PrintHash,
+    /// pull the cartridge out (or push it back in) while running
+    ToggleCartridge,
+    /// see Display::flicker_reduction
+    ToggleFlickerReduction,
+    /// shows where the time goes along the top, updated every PROFILE_FRAMES, see Profiler
+    ToggleProfiler,
+    /// FL_INT_* bit to set in IF
+    RaiseInterrupt(Byte),
+    /// FL_INT_* bit to clear in IF
+    ClearInterrupt(Byte),
+    /// FL_INT_* bit to flip in Memory::int_mask
+    ToggleInterruptMask(Byte),
+    /// see dbg::interrupt_summary
+    PrintInterrupts,
+    /// index into Memory::rom_patches
+    ToggleRomPatch(usize),
+    /// sound channel 1-4, see Apu::set_muted
+    ToggleChannelMute(usize),
+    /// sound channel 1-4, see Apu::solo
+    SoloChannel(usize),
+    /// shows the sound channels instead of the game, see dbg::draw_apu
+    ToggleApuView,
+}
+
+/// A window to play in, one per backend (minifb or sdl, picked with cargo features)
+trait Frontend {
+    fn is_open(&self) -> bool;
+    /// Turns input since the last poll into commands
+    fn poll(&mut self, commands: &mut VecDeque<Command>);
+    /// Shows an emulated frame, GB_SCREEN_WIDTH x GB_SCREEN_HEIGHT 0RGB pixels, with the
+    /// debug panels beside it if given, or else the sgb's border around it. The time it takes
+    /// goes to the profiler
+    fn present(
+        &mut self,
+        frame: &[u32],
+        layout: Option<&DebugLayout>,
+        sgb: Option<&Sgb>,
+        profiler: &mut Profiler,
+    );
+    /// Queues up samples for playback, interleaved left and right (see Apu::take_samples),
+    /// and the cycles they took to emulate
+    fn push_audio(&mut self, samples: &[f32], cycles: u64);
+    /// Shows a message over the game for a few seconds, see Osd
+    fn notify(&mut self, text: &str);
+    /// Shows text over the top of the game until it's replaced, empty takes it down
+    fn show_stats(&mut self, text: &str);
+}
+
+/// What a backend knows about the keyboard, keys by the names used in settings.toml
+trait Keyboard {
+    fn down(&self, name: &str) -> bool;
+    /// Pressed since the last poll, with `repeat` held keys count again as the OS repeats them
+    fn pressed(&self, name: &str, repeat: bool) -> bool;
+}
+
+/// Every key a backend has to know (see Keyboard): its name, minifb's Key and sdl's Keycode.
+/// Gives KEY_NAMES, and key_from_name/key_name for the backends to go between them
+macro_rules! keys {
+    ($($name:literal $minifb:ident $sdl:ident,)*) => {
+        const KEY_NAMES: &[&str] = &[$($name),*];
+
+        #[cfg(feature = "minifb")]
+        fn key_from_name(name: &str) -> Option<minifb::Key> {
+            match name {
+                $($name => Some(minifb::Key::$minifb),)*
+                _ => None,
+            }
+        }
+
+        #[cfg(feature = "sdl")]
+        fn key_name(key: sdl2::keyboard::Keycode) -> Option<&'static str> {
+            match key {
+                $(sdl2::keyboard::Keycode::$sdl => Some($name),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+keys! {
+    "A" A A, "B" B B, "C" C C, "D" D D, "E" E E, "F" F F, "G" G G, "H" H H, "I" I I,
+    "J" J J, "K" K K, "L" L L, "M" M M, "N" N N, "O" O O, "P" P P, "Q" Q Q, "R" R R,
+    "S" S S, "T" T T, "U" U U, "V" V V, "W" W W, "X" X X, "Y" Y Y, "Z" Z Z,
+    "0" Key0 NUM_0, "1" Key1 NUM_1, "2" Key2 NUM_2, "3" Key3 NUM_3, "4" Key4 NUM_4,
+    "5" Key5 NUM_5, "6" Key6 NUM_6, "7" Key7 NUM_7, "8" Key8 NUM_8, "9" Key9 NUM_9,
+    "Up" Up UP, "Down" Down DOWN, "Left" Left LEFT, "Right" Right RIGHT,
+    "Enter" Enter RETURN, "Space" Space SPACE, "Backspace" Backspace BACKSPACE, "Tab" Tab TAB,
+    "LeftShift" LeftShift LSHIFT, "RightShift" RightShift RSHIFT,
+    "LeftCtrl" LeftCtrl LCTRL, "RightCtrl" RightCtrl RCTRL,
+    "LeftAlt" LeftAlt LALT, "RightAlt" RightAlt RALT,
+    "Escape" Escape ESCAPE, "Minus" Minus MINUS, "Period" Period PERIOD,
+    "Comma" Comma COMMA, "Slash" Slash SLASH,
+    "F9" F9 F9, "F10" F10 F10, "F11" F11 F11, "F12" F12 F12,
+}
+
+/// The part of a frontend that doesn't care about the backend: pacing, slow motion, filters,
+/// on screen messages and the debug layout
+struct Presenter {
+    bindings: Vec<(String, Byte)>,
+    pacer: Pacer,
+    sync: SyncMode,
+    // audio to keep queued with SyncMode::Audio
+    audio_latency_ms: u32,
+    // what the sound card plays at, see set_output_rate
+    output_hz: u32,
+    // times the sound card ran dry, None without one. Shown in the debug layout
+    underruns: Option<u64>,
+    // apu samples to the sound card, see audio()
+    av_sync: AvSync,
+    resampler: Resampler,
+    resampled: Vec<f32>,
+    queued: usize, // frames (left and right) the sound card had after the last audio()
+    // instructions to travel back, being typed in after shift+, (see poll)
+    travel_count: Option<usize>,
+    speed: Speed,
+    slow_audio: SlowAudio,
+    filters: FilterChain,
+    osd: Osd,
+    osd_frame: Vec<u32>,
+    // the window leaves room for the debug panels, see DebugLayout
+    debug_layout: bool,
+    layout_frame: Vec<u32>,
+    // the window leaves room for the sgb's border, see Cartridge::sgb_mode
+    sgb_border: bool,
+    border_frame: Vec<u32>,
+}
+
+impl Presenter {
+    fn new(settings: &Settings, rate: FrameRate) -> Presenter {
+        Presenter {
+            bindings: joypad_bindings(&settings.keys),
+            pacer: Pacer::new(rate),
+            sync: settings.sync,
+            audio_latency_ms: settings.audio_latency_ms,
+            output_hz: SAMPLE_RATE as u32,
+            underruns: None,
+            av_sync: AvSync::new(rate, SAMPLE_RATE as u32),
+            resampler: Resampler::new(SAMPLE_RATE, SAMPLE_RATE),
+            resampled: vec![],
+            queued: 0,
+            travel_count: None,
+            speed: Speed::Full,
+            slow_audio: settings.slow_audio,
+            filters: FilterChain::new(&settings.filters),
+            osd: Osd::default(),
+            osd_frame: vec![],
+            debug_layout: false,
+            layout_frame: vec![],
+            sgb_border: false,
+            border_frame: vec![],
+        }
+    }
+
+    /// What the window shows before scaling, the game alone or with the debug panels
+    fn size(&self) -> (usize, usize) {
+        if self.debug_layout {
+            (LAYOUT_WIDTH, LAYOUT_HEIGHT)
+        } else if self.sgb_border {
+            (SGB_SCREEN_WIDTH, SGB_SCREEN_HEIGHT)
+        } else {
+            (GB_SCREEN_WIDTH, GB_SCREEN_HEIGHT)
+        }
+    }
+
+    /// Audio should be silenced (slowed down with SlowAudio::Mute)
+    fn muted(&self) -> bool {
+        self.av_sync.muted()
+    }
+
+    /// For when the sound card runs at another rate than the apu's, queued audio is counted
+    /// in its samples
+    fn set_output_rate(&mut self, hz: u32) {
+        self.output_hz = hz;
+        self.av_sync = AvSync::new(self.pacer.rate(), hz);
+        self.av_sync.set_speed(self.speed, self.slow_audio);
+        self.resampler = Resampler::new(SAMPLE_RATE, hz as f64);
+    }
+
+    /// Turns a frame's worth of apu samples into what the sound card should play next
+    ///
+    /// `cycles` is how long they took to emulate, `queued` how many frames (left and right)
+    /// the card still has to play. They go through AvSync, which nudges the resample rate so
+    /// the sound card's clock doesn't drift away from the emulated one. Nothing comes back
+    /// while muted or with more than `max_queued` waiting, that audio counts as played so
+    /// the two clocks still agree.
+    fn audio(&mut self, samples: &[f32], cycles: u64, queued: usize, max_queued: usize) -> &[f32] {
+        self.resampled.clear();
+        if samples.is_empty() {
+            // paused, counting starts over when it's running again
+            self.av_sync.reset();
+            self.queued = queued;
+            return &self.resampled;
+        }
+        self.av_sync.add_cycles(cycles);
+        self.av_sync
+            .add_samples(self.queued.saturating_sub(queued) as u64);
+        self.resampler
+            .set_rates(SAMPLE_RATE, self.av_sync.source_rate());
+        self.resampler.process(samples, &mut self.resampled);
+        let frames = self.resampled.len() / 2;
+        if self.muted() || queued > max_queued {
+            self.av_sync.add_samples(frames as u64);
+            self.resampled.clear();
+            self.queued = queued;
+        } else {
+            self.queued = queued + frames;
+        }
+        &self.resampled
+    }
+
+    /// P pauses/resumes, while paused . steps a frame forward and , steps a frame back,
+    /// / steps an instruction and shift+, travels back through the trace: type how many
+    /// instructions (the digits go to the count until then) and press Enter. T prints the
+    /// recent instructions, H prints the state hash. IJKL tilt the cartridge, as does `stick`
+    /// (a gamepad's, -1.0..=1.0 each way), - cycles through slow motion speeds, F11 toggles
+    /// flicker reduction, F9 the profiler.
+    /// 1-5 raise an interrupt in priority order (vblank first), shift+1-5 clear it and
+    /// ctrl+1-5 mask it, 0 prints where the interrupts stand. alt+1-9 toggle the rom patches.
+    /// 6-9 mute sound channels 1-4 and shift+6-9 solo them, V shows what they're playing
+    fn poll(&mut self, keys: &impl Keyboard, stick: (f32, f32), commands: &mut VecDeque<Command>) {
+        if keys.pressed("Minus", false) {
+            self.speed = self.speed.next();
+            self.pacer.set_speed(self.speed);
+            // SlowAudio::Pitched stretches the audio to match, see audio()
+            self.av_sync.set_speed(self.speed, self.slow_audio);
+            println!(
+                "speed {}%{}",
+                self.speed.percent(),
+                if self.muted() { ", audio muted" } else { "" }
+            );
+        }
+
+        if keys.down("Escape") {
+            commands.push_back(Command::Quit);
+        }
+        let shift = keys.down("LeftShift") || keys.down("RightShift");
+        let ctrl = keys.down("LeftCtrl") || keys.down("RightCtrl");
+        let alt = keys.down("LeftAlt") || keys.down("RightAlt");
+        let hotkeys = [
+            ("P", false, Command::TogglePause),
+            ("Period", true, Command::StepFrame),
+            ("Slash", true, Command::StepInstruction),
+            ("T", false, Command::PrintTrace),
+            ("H", false, Command::PrintHash),
+            ("F12", false, Command::ToggleCartridge),
+            ("F11", false, Command::ToggleFlickerReduction),
+            ("F9", false, Command::ToggleProfiler),
+            ("V", false, Command::ToggleApuView),
+        ];
+        for (name, repeat, command) in hotkeys {
+            if keys.pressed(name, repeat) {
+                commands.push_back(command);
+            }
+        }
+
+        if keys.pressed("Comma", true) {
+            if !shift {
+                commands.push_back(Command::StepBack);
+            } else if self.travel_count.is_none() {
+                println!("travel back how many instructions? (then enter, none is 1)");
+                self.travel_count = Some(0);
+            }
+        }
+
+        let digits = ["1", "2", "3", "4", "5", "6", "7", "8", "9"];
+        let entering = self.travel_count.is_some();
+        if let Some(count) = self.travel_count {
+            let mut count = count;
+            for (i, name) in digits.iter().enumerate() {
+                if keys.pressed(name, true) {
+                    count = count.saturating_mul(10).saturating_add(i + 1);
+                }
+            }
+            if keys.pressed("0", true) {
+                count = count.saturating_mul(10);
+            }
+            if keys.pressed("Backspace", true) {
+                count /= 10;
+            }
+            if Some(count) != self.travel_count {
+                println!("{}", count);
+            }
+            if keys.pressed("Enter", false) {
+                commands.push_back(Command::TravelBack(count.max(1)));
+                self.travel_count = None;
+            } else {
+                self.travel_count = Some(count);
+            }
+        }
+        if !entering {
+            for (i, name) in digits.iter().enumerate() {
+                if alt && keys.pressed(name, false) {
+                    commands.push_back(Command::ToggleRomPatch(i));
+                } else if i >= 5 && keys.pressed(name, false) {
+                    let channel = i - 4;
+                    commands.push_back(if shift {
+                        Command::SoloChannel(channel)
+                    } else {
+                        Command::ToggleChannelMute(channel)
+                    });
+                } else if keys.pressed(name, false) {
+                    let flag = 1 << i;
+                    commands.push_back(if ctrl {
+                        Command::ToggleInterruptMask(flag)
+                    } else if shift {
+                        Command::ClearInterrupt(flag)
+                    } else {
+                        Command::RaiseInterrupt(flag)
+                    });
+                }
+            }
+            if keys.pressed("0", false) {
+                commands.push_back(Command::PrintInterrupts);
+            }
+        }
+
+        let mut buttons = 0;
+        for (name, btn) in self.bindings.iter() {
+            if keys.down(name) {
+                buttons |= btn;
+            }
+        }
+        commands.push_back(Command::SetButtons(buttons));
+
+        let axis = |neg: &str, pos: &str, stick: f32| {
+            (stick + (keys.down(pos) as i8 - keys.down(neg) as i8) as f32).clamp(-1.0, 1.0)
+        };
+        commands.push_back(Command::SetTilt(
+            axis("J", "L", stick.0),
+            axis("I", "K", stick.1),
+        ));
+    }
+
+    /// Runs the filters over a frame (put among the debug panels, or in the sgb's
+    /// border, if any) and waits until it's time to show it, gives back the pixels to show
+    /// and their size.
+    /// `queued_audio` is how many samples the sound card has left to play, None when nothing
+    /// is playing
+    fn prepare<'a>(
+        &'a mut self,
+        frame: &'a [u32],
+        layout: Option<&DebugLayout>,
+        sgb: Option<&Sgb>,
+        queued_audio: Option<usize>,
+        profiler: &mut Profiler,
+    ) -> (&'a [u32], usize, usize) {
+        let mut frame = frame;
+        if self.osd.draw(frame, &mut self.osd_frame) {
+            frame = &self.osd_frame;
+        }
+        let (mut width, mut height) = (GB_SCREEN_WIDTH, GB_SCREEN_HEIGHT);
+        if let Some(layout) = layout {
+            let status: Vec<String> = self
+                .underruns
+                .map(|underruns| {
+                    let queued = queued_audio.unwrap_or(0) as u64 * 1000 / self.output_hz as u64;
+                    format!(
+                        "AUDIO {}MS/{}MS {} UNDERRUNS",
+                        queued, self.audio_latency_ms, underruns
+                    )
+                })
+                .into_iter()
+                .collect();
+            layout.compose(frame, &status, &mut self.layout_frame);
+            frame = &self.layout_frame;
+            (width, height) = (LAYOUT_WIDTH, LAYOUT_HEIGHT);
+        } else if let Some(sgb) = sgb.filter(|_| self.sgb_border) {
+            sgb.compose(frame, &mut self.border_frame);
+            frame = &self.border_frame;
+            (width, height) = (SGB_SCREEN_WIDTH, SGB_SCREEN_HEIGHT);
+        }
+        self.av_sync.add_frame();
+        let start = profiler.start();
+        let frame = self.filters.run(frame, width, height);
+        profiler.stop(Section::Present, start);
+        let start = profiler.start();
+        match queued_audio {
+            // slow motion with SlowAudio::Mute plays nothing to wait on
+            Some(queued) if self.sync == SyncMode::Audio && !self.av_sync.muted() => {
+                let target = (self.output_hz * self.audio_latency_ms / 1000) as usize;
+                self.pacer.wait_for_audio(queued, target, self.output_hz)
+            }
+            _ => self.pacer.wait(),
+        }
+        profiler.stop(Section::Wait, start);
+        frame
+    }
+}
+
+#[cfg(feature = "minifb")]
+mod minifb_frontend {
+    use super::*;
+    use minifb::{KeyRepeat, Window, WindowOptions};
+
+    /// minifb: works about everywhere, but no audio or gamepads
+    pub struct MinifbFrontend {
+        window: Window,
+        presenter: Presenter,
+    }
+
+    impl MinifbFrontend {
+        pub fn new(title: &str, settings: &Settings, presenter: Presenter) -> MinifbFrontend {
+            let (width, height) = presenter.size();
+            let mut window = Window::new(
+                title,
+                width * settings.scale.max(1),
+                height * settings.scale.max(1),
+                WindowOptions::default(),
+            )
+            .unwrap_or_else(|e| panic!("{}", e));
+            // frames are paced by Presenter, see Pacer
+            window.limit_update_rate(None);
+            MinifbFrontend { window, presenter }
+        }
+    }
+
+    impl Keyboard for Window {
+        fn down(&self, name: &str) -> bool {
+            key_from_name(name).is_some_and(|key| self.is_key_down(key))
+        }
+
+        fn pressed(&self, name: &str, repeat: bool) -> bool {
+            let repeat = if repeat {
+                KeyRepeat::Yes
+            } else {
+                KeyRepeat::No
+            };
+            key_from_name(name).is_some_and(|key| self.is_key_pressed(key, repeat))
+        }
+    }
+
+    impl Frontend for MinifbFrontend {
+        fn is_open(&self) -> bool {
+            self.window.is_open()
+        }
+
+        fn poll(&mut self, commands: &mut VecDeque<Command>) {
+            self.presenter.poll(&self.window, (0.0, 0.0), commands);
+        }
+
+        fn present(
+            &mut self,
+            frame: &[u32],
+            layout: Option<&DebugLayout>,
+            sgb: Option<&Sgb>,
+            profiler: &mut Profiler,
+        ) {
+            let (frame, width, height) = self.presenter.prepare(frame, layout, sgb, None, profiler);
+            let start = profiler.start();
+            self.window
+                .update_with_buffer(frame, width, height)
+                .unwrap();
+            profiler.stop(Section::Present, start);
+        }
+
+        fn push_audio(&mut self, _samples: &[f32], _cycles: u64) {
+            // minifb has no audio output
+        }
+
+        fn notify(&mut self, text: &str) {
+            self.presenter.osd.notify(text);
+        }
+
+        fn show_stats(&mut self, text: &str) {
+            self.presenter.osd.set_stats(text);
+        }
+    }
+}
+
+#[cfg(feature = "sdl")]
+mod sdl_frontend {
+    use super::*;
+    use sdl2::audio::{AudioQueue, AudioSpecDesired};
+    use sdl2::controller::{Axis, GameController};
+    use sdl2::event::Event;
+    use sdl2::pixels::PixelFormatEnum;
+    use sdl2::render::{Canvas, Texture, TextureCreator};
+    use sdl2::video::{FullscreenType, Window, WindowContext};
+    use sdl2::{EventPump, GameControllerSubsystem};
+    use std::collections::HashSet;
+
+    // how far a stick has to lean before it counts, they rarely rest right at the middle
+    const STICK_DEAD_ZONE: f32 = 0.15;
+
+    /// SDL2: sound, fullscreen (F10), keys that don't drop presses between frames, and
+    /// gamepads, whose left stick tilts the cartridge
+    pub struct SdlFrontend {
+        canvas: Canvas<Window>,
+        // leaked, there's one for the life of the program and the texture can't outlive it
+        textures: &'static TextureCreator<WindowContext>,
+        // what the frame is copied into, made again when the size changes, see present()
+        texture: Option<Texture<'static>>,
+        events: EventPump,
+        audio: Option<AudioQueue<f32>>,
+        // keep about this many bytes queued, anything past it is dropped to catch up
+        audio_max_bytes: u32,
+        // samples went into the queue last frame, so it's worth pacing off
+        playing: bool,
+        keys: SdlKeys,
+        // None without the subsystem, then there are no gamepads
+        controllers: Option<GameControllerSubsystem>,
+        gamepads: Vec<GameController>,
+        open: bool,
+        bytes: Vec<u8>,
+        presenter: Presenter,
+    }
+
+    /// Key state as of the last poll, from the event queue
+    #[derive(Default)]
+    struct SdlKeys {
+        down: HashSet<&'static str>,
+        // (key, repeated) for every key down event since the last poll
+        pressed: Vec<(&'static str, bool)>,
+    }
+
+    impl Keyboard for SdlKeys {
+        fn down(&self, name: &str) -> bool {
+            self.down.contains(name)
+        }
+
+        fn pressed(&self, name: &str, repeat: bool) -> bool {
+            self.pressed
+                .iter()
+                .any(|&(key, repeated)| key == name && (repeat || !repeated))
+        }
+    }
+
+    impl SdlFrontend {
+        pub fn new(title: &str, settings: &Settings, mut presenter: Presenter) -> SdlFrontend {
+            let (width, height) = presenter.size();
+            let init = || -> Result<SdlFrontend, String> {
+                let sdl = sdl2::init()?;
+                let window = sdl
+                    .video()?
+                    .window(
+                        title,
+                        (width * settings.scale.max(1)) as u32,
+                        (height * settings.scale.max(1)) as u32,
+                    )
+                    .position_centered()
+                    .resizable()
+                    .build()
+                    .map_err(|e| e.to_string())?;
+                // frames are paced by Presenter, see Pacer
+                let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+                let textures = Box::leak(Box::new(canvas.texture_creator()));
+                // no sound is better than no game
+                let audio = sdl.audio().and_then(|audio| {
+                    let spec = AudioSpecDesired {
+                        freq: settings.audio_rate.map(|hz| hz as i32),
+                        channels: Some(2),
+                        samples: Some(settings.audio_buffer.samples()),
+                    };
+                    let queue = audio.open_queue::<f32, _>(None, &spec)?;
+                    queue.resume();
+                    Ok(queue)
+                });
+                let audio = audio.map_err(|e| eprintln!("no audio: {}", e)).ok();
+                // the device may well not take the rate asked for
+                let output_hz = audio
+                    .as_ref()
+                    .map_or(SAMPLE_RATE as u32, |queue| queue.spec().freq as u32);
+                presenter.set_output_rate(output_hz);
+                if audio.is_some() {
+                    presenter.underruns = Some(0);
+                }
+                let controllers = sdl
+                    .game_controller()
+                    .map_err(|e| eprintln!("no gamepads: {}", e))
+                    .ok();
+                let audio_max_bytes = (output_hz * settings.audio_latency_ms / 1000)
+                    * 2
+                    * std::mem::size_of::<f32>() as u32;
+                Ok(SdlFrontend {
+                    canvas,
+                    textures,
+                    texture: None,
+                    events: sdl.event_pump()?,
+                    audio,
+                    audio_max_bytes,
+                    playing: false,
+                    keys: SdlKeys::default(),
+                    controllers,
+                    gamepads: vec![],
+                    open: true,
+                    bytes: vec![],
+                    presenter,
+                })
+            };
+            init().unwrap_or_else(|e| panic!("{}", e))
+        }
+
+        fn toggle_fullscreen(&mut self) {
+            let window = self.canvas.window_mut();
+            let mode = match window.fullscreen_state() {
+                FullscreenType::Off => FullscreenType::Desktop,
+                _ => FullscreenType::Off,
+            };
+            if let Err(e) = window.set_fullscreen(mode) {
+                eprintln!("fullscreen: {}", e);
+            }
+        }
+
+        /// Where the first gamepad's left stick leans, -1.0..=1.0 each way with right/down
+        /// positive
+        fn stick(&self) -> (f32, f32) {
+            let Some(pad) = self.gamepads.first() else {
+                return (0.0, 0.0);
+            };
+            let axis = |axis| {
+                let lean = pad.axis(axis) as f32 / i16::MAX as f32;
+                if lean.abs() < STICK_DEAD_ZONE {
+                    0.0
+                } else {
+                    lean.clamp(-1.0, 1.0)
+                }
+            };
+            (axis(Axis::LeftX), axis(Axis::LeftY))
+        }
+    }
+
+    impl Frontend for SdlFrontend {
+        fn is_open(&self) -> bool {
+            self.open
+        }
+
+        fn poll(&mut self, commands: &mut VecDeque<Command>) {
+            self.keys.pressed.clear();
+            for event in self.events.poll_iter() {
+                match event {
+                    Event::Quit { .. } => self.open = false,
+                    Event::KeyDown {
+                        keycode: Some(key),
+                        repeat,
+                        ..
+                    } => {
+                        if let Some(name) = key_name(key) {
+                            self.keys.down.insert(name);
+                            self.keys.pressed.push((name, repeat));
+                        }
+                    }
+                    Event::KeyUp {
+                        keycode: Some(key), ..
+                    } => {
+                        if let Some(name) = key_name(key) {
+                            self.keys.down.remove(name);
+                        }
+                    }
+                    // sent for those already plugged in at startup too
+                    Event::ControllerDeviceAdded { which, .. } => {
+                        if let Some(controllers) = &self.controllers {
+                            match controllers.open(which) {
+                                Ok(pad) => self.gamepads.push(pad),
+                                Err(e) => eprintln!("gamepad: {}", e),
+                            }
+                        }
+                    }
+                    Event::ControllerDeviceRemoved { which, .. } => {
+                        self.gamepads.retain(|pad| pad.instance_id() != which);
+                    }
+                    _ => {}
+                }
+            }
+            if self.keys.pressed("F10", false) {
+                self.toggle_fullscreen();
+            }
+            let stick = self.stick();
+            self.presenter.poll(&self.keys, stick, commands);
+        }
+
+        fn present(
+            &mut self,
+            frame: &[u32],
+            layout: Option<&DebugLayout>,
+            sgb: Option<&Sgb>,
+            profiler: &mut Profiler,
+        ) {
+            let queued = self
+                .audio
+                .as_ref()
+                .filter(|_| self.playing)
+                .map(|queue| queue.size() as usize / (2 * std::mem::size_of::<f32>()));
+            let (frame, width, height) =
+                self.presenter.prepare(frame, layout, sgb, queued, profiler);
+            let start = profiler.start();
+            self.bytes.clear();
+            self.bytes
+                .extend(frame.iter().flat_map(|p| p.to_le_bytes()));
+            let size = (width as u32, height as u32);
+            let texture = match &mut self.texture {
+                Some(texture) if (texture.query().width, texture.query().height) == size => texture,
+                texture => {
+                    // RGB888 is 0RGB packed in a u32, the same as the frame
+                    let new = self
+                        .textures
+                        .create_texture_streaming(PixelFormatEnum::RGB888, size.0, size.1)
+                        .unwrap();
+                    texture.insert(new)
+                }
+            };
+            texture
+                .update(None, &self.bytes, width * std::mem::size_of::<u32>())
+                .unwrap();
+            // stretched to the window, fullscreen or not
+            self.canvas.clear();
+            self.canvas.copy(texture, None, None).unwrap();
+            self.canvas.present();
+            profiler.stop(Section::Present, start);
+        }
+
+        fn push_audio(&mut self, samples: &[f32], cycles: u64) {
+            let Some(queue) = &self.audio else {
+                return;
+            };
+            // last frame's samples have all been played and this frame's aren't in yet
+            if self.playing && queue.size() == 0 {
+                if let Some(underruns) = &mut self.presenter.underruns {
+                    *underruns += 1;
+                }
+            }
+            self.playing = false;
+            let frame_bytes = 2 * std::mem::size_of::<f32>();
+            let queued = queue.size() as usize / frame_bytes;
+            let max_queued = self.audio_max_bytes as usize / frame_bytes;
+            let out = self.presenter.audio(samples, cycles, queued, max_queued);
+            if out.is_empty() {
+                return;
+            }
+            if let Err(e) = queue.queue_audio(out) {
+                eprintln!("audio: {}", e);
+                return;
+            }
+            self.playing = true;
+        }
+
+        fn notify(&mut self, text: &str) {
+            self.presenter.osd.notify(text);
+        }
+
+        fn show_stats(&mut self, text: &str) {
+            self.presenter.osd.set_stats(text);
+        }
+    }
+}
+
+#[cfg(not(any(feature = "minifb", feature = "sdl")))]
+compile_error!("cerboy needs a window, enable the minifb or sdl feature");
+
+/// Opens the window for whichever backend was built in, sdl when there's a choice
+fn open_frontend(title: &str, settings: &Settings, presenter: Presenter) -> Box<dyn Frontend> {
+    #[cfg(feature = "sdl")]
+    return Box::new(sdl_frontend::SdlFrontend::new(title, settings, presenter));
+    #[cfg(all(feature = "minifb", not(feature = "sdl")))]
+    return Box::new(minifb_frontend::MinifbFrontend::new(
+        title, settings, presenter,
+    ));
+}
+
+/// The running game: the emulator plus the state around it (pause, rewind)
+struct Session {
+    emu: Emulator,
+    rewind: Rewind,
+    paused: bool,
+    doctor: bool,
+    frames: u64, // completed since boot
+    hash_every: Option<u64>,
+    apu_view: Option<Vec<u32>>,
+    layout: Option<DebugLayout>,
+    // for the frontend to show next, see Command::ToggleProfiler
+    stats: Option<String>,
+    // flashes the pixels that differ over the frame, see frame()
+    reference: Option<FrameDiff>,
+    diff_frame: Vec<u32>,
+    differ: usize,
+    shown: u64, // frames handed to the frontend
+}
+
+// the reference overlay toggles on and off every this many frames
+const DIFF_FLASH_FRAMES: u64 = 16;
+
+impl Session {
+    fn new(emu: Emulator) -> Session {
+        Session {
+            emu,
+            rewind: Rewind::new(REWIND_FRAMES),
+            paused: false,
+            doctor: false,
+            frames: 0,
+            hash_every: None,
+            apu_view: None,
+            layout: None,
+            stats: None,
+            reference: None,
+            diff_frame: vec![],
+            differ: 0,
+            shown: 0,
+        }
+    }
+
+    fn apply(&mut self, command: Command) {
+        match command {
+            Command::Quit => {}
+            Command::TogglePause => self.paused = !self.paused,
+            Command::StepFrame => {
+                if self.paused {
+                    self.run_frame();
+                }
+            }
+            Command::StepInstruction => {
+                if self.paused {
+                    let prev = self.emu.clone();
+                    if let Err(e) = self.emu.step_instruction() {
+                        eprintln!("{}", e);
+                        return;
+                    }
+                    let (cpu, mem) = (&self.emu.cpu, &self.emu.mem);
+                    println!("{}", step_diff(&prev.cpu, &prev.mem, cpu, mem));
+                }
+            }
+            Command::StepBack => {
+                if self.paused {
+                    if let Some(prev) = self.rewind.step_back() {
+                        self.emu = prev;
+                        self.frames = self.frames.saturating_sub(1);
+                    }
+                }
+            }
+            Command::TravelBack(n) => {
+                if self.paused {
+                    self.travel_back(n);
+                }
+            }
+            Command::PrintTrace => self.print_trace(),
+            Command::SetButtons(buttons) => self.emu.mem.set_buttons(buttons),
+            Command::SetTilt(x, y) => self.emu.mem.set_tilt(x, y),
+            Command::PrintHash => self.print_hash(),
+            Command::ToggleCartridge => {
+                let mem = &mut self.emu.mem;
+                if mem.cartridge_ejected() {
+                    mem.insert_cartridge();
+                    println!("cartridge inserted");
+                } else {
+                    mem.eject_cartridge();
+                    println!("cartridge ejected");
+                }
+            }
+            Command::ToggleFlickerReduction => {
+                let lcd = &mut self.emu.lcd;
+                lcd.flicker_reduction = !lcd.flicker_reduction;
+                println!(
+                    "flicker reduction {}",
+                    if lcd.flicker_reduction { "on" } else { "off" }
+                );
+            }
+            Command::ToggleProfiler => {
+                if cfg!(feature = "instrument") {
+                    let profiler = &mut self.emu.profiler;
+                    profiler.set_on(!profiler.on());
+                    self.stats = Some(if profiler.on() { "profiling..." } else { "" }.into());
+                } else {
+                    println!("the profiler needs the instrument feature");
+                }
+            }
+            Command::RaiseInterrupt(flag) => {
+                request_interrupt(&mut self.emu.mem, flag);
+                self.print_interrupts();
+            }
+            Command::ClearInterrupt(flag) => {
+                let mem = &mut self.emu.mem;
+                mem.write(IF, mem.read(IF) & !flag);
+                self.print_interrupts();
+            }
+            Command::ToggleInterruptMask(flag) => {
+                self.emu.mem.int_mask ^= flag;
+                self.print_interrupts();
+            }
+            Command::PrintInterrupts => self.print_interrupts(),
+            Command::ToggleRomPatch(i) => {
+                let Some(on) = self.emu.mem.toggle_rom_patch(i) else {
+                    return;
+                };
+                let (patch, _) = self.emu.mem.rom_patches()[i];
+                println!(
+                    "rom patch {} ({}) {}",
+                    i + 1,
+                    patch,
+                    if on { "on" } else { "off" }
+                );
+            }
+            Command::ToggleChannelMute(ch) => {
+                let apu = &mut self.emu.apu;
+                apu.set_muted(ch, !apu.muted(ch));
+                self.print_channels();
+            }
+            Command::SoloChannel(ch) => {
+                self.emu.apu.solo(ch);
+                self.print_channels();
+            }
+            Command::ToggleApuView => {
+                if self.apu_view.take().is_some() {
+                    self.emu.apu.scope = None;
+                } else {
+                    self.apu_view = Some(vec![]);
+                    self.emu.apu.scope = Some(Scope::default());
+                }
+            }
+        }
+    }
+
+    /// The game's frame (flashing where it differs from the reference if there is one, and
+    /// with the sound channels drawn over it while the apu view is up), the debug panels to go beside it brought up to date if they're on, and the sgb if any
+    fn frame(&mut self) -> (&[u32], Option<&DebugLayout>, Option<&Sgb>, &mut Profiler) {
+        let emu = &mut self.emu;
+        if let Some(layout) = &mut self.layout {
+            layout.update(&emu.cpu, &emu.mem, &emu.lcd.palettes);
+        }
+        let mut frame = emu.lcd.frame();
+        if let Some(diff) = &self.reference {
+            let flash = (self.shown / DIFF_FLASH_FRAMES).is_multiple_of(2);
+            let differ = diff.overlay(frame, emu.lcd.shades(), flash, &mut self.diff_frame);
+            if differ != self.differ {
+                println!("{} pixels differ from the reference", differ);
+                self.differ = differ;
+            }
+            frame = &self.diff_frame;
+        }
+        self.shown += 1;
+        let frame = match &mut self.apu_view {
+            Some(view) => {
+                draw_apu(&emu.mem, &emu.apu, frame, view);
+                view
+            }
+            None => frame,
+        };
+        (
+            frame,
+            self.layout.as_ref(),
+            emu.sgb.as_ref(),
+            &mut emu.profiler,
+        )
+    }
+
+    fn print_channels(&self) {
+        let channels: Vec<String> = (1..=4)
+            .map(|ch| {
+                let on = if self.emu.apu.muted(ch) {
+                    "muted"
+                } else {
+                    "on"
+                };
+                format!("ch{} {}", ch, on)
+            })
+            .collect();
+        println!("{}", channels.join(", "));
+    }
+
+    fn print_interrupts(&self) {
+        print!("{}", interrupt_summary(&self.emu.mem, self.emu.cpu.ime));
+    }
+
+    fn print_trace(&self) {
+        if self.emu.trace_len == 0 {
+            println!("no trace kept, crash_trace in the settings sets its length");
+            return;
+        }
+        let trace: Vec<&Traced> = self.emu.trace().collect();
+        for (i, t) in trace.iter().enumerate() {
+            println!("{:3}  {}", trace.len() - i, t);
+        }
+    }
+
+    /// Puts the machine back to right before the nth most recent traced instruction, from
+    /// the newest snapshot before it. Input is replayed as it is now, not as it was
+    fn travel_back(&mut self, n: usize) {
+        let Some(&target) = self.emu.trace().rev().nth(n.saturating_sub(1)) else {
+            println!("the trace doesn't go back that far");
+            return;
+        };
+        let snapshots = self.rewind.len();
+        let Some(mut emu) = self.rewind.rewind_to(target.inst_count) else {
+            println!("{} is from before the oldest rewind snapshot", target);
+            return;
+        };
+        self.frames -= (snapshots - self.rewind.len()) as u64;
+        if let Err(e) = emu.run_until(target.inst_count) {
+            panic!("{}", e);
+        }
+        if emu.cpu.pc == target.pc {
+            println!("back to {}", target);
+        } else {
+            println!(
+                "the replay went another way (different input?), now at {:04X}",
+                emu.cpu.pc
+            );
+        }
+        self.emu = emu;
+    }
+
+    fn print_hash(&self) {
+        println!("frame {}: {:016x}", self.frames, self.emu.state_hash());
+    }
+
+    /// Runs until the end of the frame, or until something breaks into the debugger
+    fn run_frame(&mut self) {
+        loop {
+            #[cfg(feature = "instrument")]
+            if self.doctor {
+                print_doctor(&self.emu);
+            }
+            let step = self.emu.step_instruction();
+            for warning in self.emu.take_lints() {
+                eprintln!("{}", warning);
+            }
+            let info = match step {
+                Ok(info) => info,
+                Err(e) => {
+                    panic!("{}", e.to_string());
+                }
+            };
+            if let Some(fault) = info.stack_fault {
+                eprintln!("{}", fault);
+                self.paused = true;
+                return;
+            }
+            if let Some(hit) = info.watch_hit {
+                println!("{}", hit);
+                self.paused = true;
+                return;
+            }
+            if info.frame {
+                self.rewind.push(&mut self.emu);
+                self.frames += 1;
+                if let Some(n) = self.hash_every {
+                    if n > 0 && self.frames.is_multiple_of(n) {
+                        self.print_hash();
+                    }
+                }
+                let profiler = &mut self.emu.profiler;
+                if profiler.on() && self.frames.is_multiple_of(PROFILE_FRAMES) {
+                    self.stats = Some(profiler.take().to_string());
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// https://robertheaton.com/gameboy-doctor/
+#[cfg(feature = "instrument")]
+fn print_doctor(emu: &Emulator) {
+    let cpu = emu.cpu;
+    let mem = &emu.mem;
+    println!("A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+        cpu.reg[REG_A],
+        cpu.reg[FLAGS],
+        cpu.reg[REG_B],
+        cpu.reg[REG_C],
+        cpu.reg[REG_D],
+        cpu.reg[REG_E],
+        cpu.reg[REG_H],
+        cpu.reg[REG_L],
+        cpu.sp,
+        cpu.pc,
+        mem[cpu.pc+0],
+        mem[cpu.pc+1],
+        mem[cpu.pc+2],
+        mem[cpu.pc+3]
+    )
+}
+
+/// e.g. "cerboy - TETRIS [ROM ONLY, 32KB ROM, Japanese]"
+fn window_title(cart: &Cartridge) -> String {
+    let mut ram = String::new();
+    if cart.size_ram() > 0 {
+        ram = format!(", {}KB RAM", cart.size_ram() / KB);
+    }
+    format!(
+        "cerboy - {} [{}, {}KB ROM{}, {}]",
+        cart.title(),
+        cart.hardware_type(),
+        cart.size() / KB,
+        ram,
+        cart.destination_code()
+    )
+}
+
+/// (key name, BTN_*) pairs, unknown key names are reported and left unbound
+fn joypad_bindings(keys: &KeyBindings) -> Vec<(String, Byte)> {
+    let names = [
+        (&keys.up, BTN_UP),
+        (&keys.down, BTN_DOWN),
+        (&keys.left, BTN_LEFT),
+        (&keys.right, BTN_RIGHT),
+        (&keys.a, BTN_A),
+        (&keys.b, BTN_B),
+        (&keys.select, BTN_SELECT),
+        (&keys.start, BTN_START),
+    ];
+    let mut bindings = vec![];
+    for (name, btn) in names {
+        if KEY_NAMES.contains(&name.as_str()) {
+            bindings.push((name.clone(), btn));
+        } else {
+            eprintln!("unknown key \"{}\" in settings", name);
+        }
+    }
+    bindings
+}
+
+/// Asks for a rom on the terminal (or with a file dialog, with the file-dialog feature)
+fn choose_rom(settings: &Settings) -> Option<String> {
+    #[cfg(feature = "file-dialog")]
+    let open_dialog = || {
+        let mut dialog =
+            rfd::FileDialog::new().add_filter("Game Boy ROM", &["gb", "gbc", "bin", "zip", "gz"]);
+        if let Some(dir) = &settings.last_rom_dir {
+            dialog = dialog.set_directory(dir);
+        }
+        dialog.pick_file().map(|p| p.display().to_string())
+    };
+
+    #[cfg(feature = "file-dialog")]
+    if settings.recent_roms.is_empty() {
+        return open_dialog();
+    }
+
+    println!("recent roms:");
+    for (i, rom) in settings.recent_roms.iter().enumerate() {
+        println!("  {}) {}", i + 1, rom.display());
+    }
+    #[cfg(feature = "file-dialog")]
+    println!("  o) open...");
+    print!("number or path> ");
+    std::io::stdout().flush().ok()?;
+
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).ok()?;
+    let line = line.trim();
+    #[cfg(feature = "file-dialog")]
+    if line == "o" {
+        return open_dialog();
+    }
+    if let Ok(n) = line.parse::<usize>() {
+        return settings
+            .recent_roms
+            .get(n.wrapping_sub(1))
+            .map(|p| p.display().to_string());
+    }
+    if line.is_empty() {
+        None
+    } else {
+        Some(line.to_string())
+    }
+}
+
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
 }
 
 fn main() {
-    let args = Args::parse();
+    let mut args = Args::parse();
     env_logger::init();
 
+    if let Some(tool) = args.tool.take() {
+        if let Err(e) = run_tool(tool) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut settings = Settings::load();
+    if let Some(scale) = args.scale {
+        settings.scale = scale;
+    }
+    if let Some(volume) = args.volume {
+        settings.volume = volume.min(100);
+    }
+    if let Some(ms) = args.audio_latency {
+        settings.audio_latency_ms = ms;
+    }
+    if let Some(samples) = args.audio_buffer {
+        settings.audio_buffer = samples;
+    }
+
+    let rom = match args.rom.clone().or_else(|| choose_rom(&settings)) {
+        Some(rom) => rom,
+        None => {
+            eprintln!("no rom selected");
+            return;
+        }
+    };
+    let mut cart = match Cartridge::new(rom.as_str()) {
+        Ok(cart) => cart,
+        Err(e) => {
+            eprintln!("{}: {}", rom, e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(path) = &args.patch {
+        let patched = std::fs::read(path)
+            .map_err(CartridgeError::Io)
+            .and_then(|patch| cart.apply_patch(&patch));
+        if let Err(e) = patched {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+    let config = GameConfig::load(rom.as_str(), &cart);
+    if args.header_json {
+        match serde_json::to_string_pretty(&cart.header_info()) {
+            Ok(json) => println!("{}", json),
+            Err(e) => eprintln!("{}", e),
+        }
+        return;
+    }
+    if args.info {
+        println!("{}", cart.summary());
+        // what to name a config in GameConfig::games_dir
+        println!("crc32: {:08x} | sha1: {}", cart.crc32(), cart.sha1());
+        return;
+    }
+    let errors = cart.checksum_errors();
+    for e in &errors {
+        eprintln!("{}: {}", if args.strict { "error" } else { "warning" }, e);
+    }
+    if args.strict && !errors.is_empty() {
+        std::process::exit(1);
+    }
+    if let Ok(path) = std::path::Path::new(&rom).canonicalize() {
+        settings.add_recent(path);
+    }
+
     // window management
     // -----------------
-    let mut window = Window::new(
-        "cerboy",
-        GB_SCREEN_WIDTH * 4,
-        GB_SCREEN_HEIGHT * 4,
-        WindowOptions::default(),
-    )
-    .unwrap_or_else(|e| panic!("{}", e));
-    // todo: acc: changed timing here to make it more closely match the hardware
-    // but I'm not sure why it's not running at the correct speed normally
-    // (frame time should be longer, 16600)
-    window.limit_update_rate(Some(std::time::Duration::from_micros(12600)));
+    let mut presenter = Presenter::new(&settings, config.frame_rate);
+    presenter.debug_layout = args.debug_layout;
+    presenter.sgb_border = cart.sgb_mode();
+    let mut frontend = open_frontend(&window_title(&cart), &settings, presenter);
 
     // init system
     // ------------
-    let cart = Cartridge::new(args.rom.as_str());
-    let mut cpu = CPUState::new();
-    let mut mem: Memory = Memory::new();
-    let mut lcd: Display = Display::new();
-    mem.doctor = args.doctor;
-    lcd.doctor = args.doctor;
-    mem.load_rom(&cart); // load cartridge
+    let mut emu = Emulator::new(&cart);
+    emu.skip_idle = settings.accuracy != Accuracy::Accurate;
+    emu.block_cache = settings.accuracy == Accuracy::Fast;
+    emu.trace_len = settings.crash_trace;
+    for preset in &args.break_on {
+        emu.mem.watches.extend(preset.watchpoints());
+    }
+    emu.mem.lint = args.lint;
+    let rom_usage = args.rom_usage.as_ref().map(|_| {
+        let usage = Arc::new(Mutex::new(RomUsage::new(cart.size())));
+        emu.mem.set_rom_usage(Some(Arc::clone(&usage)));
+        usage
+    });
+    if let Some(palettes) = settings.palettes {
+        emu.lcd.palettes = palettes;
+    }
+    if args.cgb && !cart.cgb_flag() {
+        emu.lcd.palettes = cgb_compat_palettes(&cart);
+    }
+    if let Some(palettes) = config.palettes {
+        emu.lcd.palettes = palettes;
+    }
+    if settings.high_contrast {
+        emu.lcd.palettes = emu.lcd.palettes.high_contrast();
+    }
+    emu.lcd.flicker_reduction = settings.flicker_reduction;
+    emu.lcd.ghosting = settings.ghosting;
+    emu.apu.high_pass = settings.high_pass;
+    emu.apu.volume = settings.volume.min(100) as f32 / 100.0;
+    emu.hacks = config.hacks.clone();
+    for patch in &args.genie {
+        emu.mem.add_rom_patch(*patch);
+    }
+    for trigger in &config.triggers {
+        emu.triggers.add(trigger.clone(), &emu.mem);
+    }
+    let sav_path = config
+        .save_path
+        .clone()
+        .unwrap_or_else(|| sav_path_for(rom.as_str(), settings.saves_dir.as_deref()));
+    if cart.has_battery() {
+        // a save from before the saves directory was set up is still next to the rom
+        let sav = std::fs::read(&sav_path).or_else(|_| std::fs::read(sav_path_for(&rom, None)));
+        if let Ok(sav) = sav {
+            let sav = SaveFile::parse(&sav);
+            emu.mem.load_ext_ram(&sav.ram);
+            if let Some(rtc) = &sav.rtc {
+                emu.mem.load_rtc(rtc, unix_time());
+            }
+        }
+    }
+    #[cfg(feature = "instrument")]
+    {
+        emu.mem.doctor = args.doctor;
+        emu.lcd.doctor = args.doctor;
+        emu.mem.stack_guard = args.stack_guard;
+    }
 
     // todo: boot doesn't work anymore with the new cartridge setup
     // let boot = init_rom("./rom/boot/DMG_ROM.bin");
     // load_rom(&mut mem, &boot);
 
-    let mut timers = HardwareTimers::new();
+    let mut recording = args.record_audio.as_ref().map(|path| {
+        WavWriter::create(std::path::Path::new(path), SAMPLE_RATE as u32).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        })
+    });
+
+    let mut livesplit = args
+        .livesplit
+        .as_ref()
+        .and_then(|addr| match LiveSplit::connect(addr) {
+            Ok(livesplit) => Some(livesplit),
+            Err(e) => {
+                eprintln!("couldn't reach LiveSplit at {}: {}", addr, e);
+                None
+            }
+        });
+
+    let mut session = Session::new(emu);
+    session.hash_every = args.hash_every;
+    if args.debug_layout {
+        session.layout = Some(DebugLayout::default());
+    }
+    #[cfg(feature = "instrument")]
+    {
+        session.doctor = args.doctor;
+    }
+    if let Some(path) = &args.reference {
+        match load_reference_png(path) {
+            Ok(pixels) => {
+                // compared by shade, see FrameDiff
+                session.emu.lcd.keep_shades();
+                session.reference = Some(FrameDiff::new(&pixels));
+            }
+            Err(e) => eprintln!("failed to load {}: {}", path, e),
+        }
+    }
 
     // loop
     // ------------
-    while window.is_open() && !window.is_key_down(Key::Escape) {
-        // update
-        // ------------------------------------------------
-        if args.doctor {
-            println!("A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
-                cpu.reg[REG_A],
-                cpu.reg[FLAGS],
-                cpu.reg[REG_B],
-                cpu.reg[REG_C],
-                cpu.reg[REG_D],
-                cpu.reg[REG_E],
-                cpu.reg[REG_H],
-                cpu.reg[REG_L],
-                cpu.sp,
-                cpu.pc,
-                mem[cpu.pc+0],
-                mem[cpu.pc+1],
-                mem[cpu.pc+2],
-                mem[cpu.pc+3]
-            )
+    let mut commands = VecDeque::new();
+    // a frame of everything, so whatever panics (a command, the frontend, the emulator) the
+    // crash report comes with it. Returns false to quit
+    let mut step = |session: &mut Session| {
+        if !frontend.is_open() {
+            return false;
         }
-        let cpu_prev = cpu;
-        cpu = match next(cpu_prev, &mut mem) {
-            Ok(cpu) => cpu,
-            Err(e) => {
-                panic!("{}", e.to_string());
-            }
-        };
-        let dt_cyc = cpu.tsc - cpu_prev.tsc;
+        let tsc = session.emu.cpu.tsc.as_t();
 
-        // update memory (e.g. handle any pending DMA transfers)
+        // input
         // ------------------------------------------------
-        mem.update();
+        frontend.poll(&mut commands);
+        while let Some(command) = commands.pop_front() {
+            if command == Command::Quit {
+                return false;
+            }
+            session.apply(command);
+        }
 
-        // update timers
+        // update
         // ------------------------------------------------
-        timers = update_clocks(timers, &mut mem, dt_cyc);
+        if !session.paused {
+            session.run_frame();
+        }
+        for event in session.emu.take_events() {
+            println!("frame {}: {}", event.frame, event.name);
+            frontend.notify(&event.name);
+            if let (Some(action), Some(ls)) = (event.split, &mut livesplit) {
+                if let Err(e) = ls.send(action) {
+                    eprintln!("lost LiveSplit: {}", e);
+                    livesplit = None;
+                }
+            }
+        }
 
-        // update display
+        // present
         // ------------------------------------------------
-        lcd.update(&mut mem, &mut window, dt_cyc);
+        if let Some(stats) = session.stats.take() {
+            frontend.show_stats(&stats);
+        }
+        let (frame, layout, sgb, profiler) = session.frame();
+        frontend.present(frame, layout, sgb, profiler);
+        let samples = session.emu.apu.take_samples();
+        let cycles = session.emu.cpu.tsc.as_t().saturating_sub(tsc);
+        frontend.push_audio(&samples, cycles);
+        if let Some(wav) = &mut recording {
+            if let Err(e) = wav.write(&samples) {
+                eprintln!("stopped recording audio: {}", e);
+                recording = None;
+            }
+        }
+        true
+    };
+    loop {
+        let ran = std::panic::catch_unwind(AssertUnwindSafe(|| step(&mut session)));
+        if !report_panic(ran, &session.emu) {
+            break;
+        }
+    }
+    if let Some(mut wav) = recording {
+        if let Err(e) = wav.finish() {
+            eprintln!("failed to finish the audio recording: {}", e);
+        }
+    }
+    if let (Some(path), Some(usage)) = (&args.rom_usage, &rom_usage) {
+        let usage = usage.lock().unwrap();
+        print!("{}", usage.report());
+        if let Err(e) = std::fs::write(path, usage.flags()) {
+            eprintln!("failed to write {}: {}", path, e);
+        }
+    }
+
+    let sav = SaveFile {
+        ram: session.emu.mem.ext_ram().to_vec(),
+        rtc: session.emu.mem.rtc(unix_time()),
+    };
+    if cart.has_battery() && (!sav.ram.is_empty() || sav.rtc.is_some()) {
+        // the clock goes in the same footer BGB and SameBoy use, so saves move between them
+        let layout = if sav.rtc.is_some() {
+            SavLayout::Rtc
+        } else {
+            SavLayout::Raw
+        };
+        let written = write_with_backups(
+            &sav_path,
+            &sav.to_bytes(layout),
+            settings.sav_backups,
+            settings.compress_sav_backups,
+            unix_time(),
+        );
+        if let Err(e) = written {
+            eprintln!("failed to write {}: {}", sav_path.display(), e);
+        }
+    }
+    if let Err(e) = settings.save() {
+        eprintln!("failed to save settings: {}", e);
     }
 }
@@ -0,0 +1,126 @@
+//! Runs blargg's dmg_sound roms without a window, so APU regressions show up in `cargo test`
+//!
+//! cargo test --features test-roms --test dmg_sound
+//!
+//! The roms come from CERBOY_DMG_SOUND (a directory of them, e.g. dmg_sound/rom_singles, or a
+//! single .gb), defaulting to the copy under rom/test/blargg. Singles in KNOWN_FAILURES are
+//! allowed to fail, so the combined rom won't pass until that list is empty.
+#![cfg(feature = "test-roms")]
+
+use cerboy::emu::Emulator;
+use cerboy::memory::Cartridge;
+use std::path::{Path, PathBuf};
+
+// the longest single rom takes a few seconds, the combined one about half a minute
+const MAX_FRAMES: u64 = 60 * 60;
+// what the roms write to $A001-$A003 once $A000 and the text after it are worth reading
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+// $A000 while the rom is still going
+const RUNNING: u8 = 0x80;
+// how much of a failing rom's output to show, some print whole tables of register values
+const OUTPUT_LINES: usize = 8;
+// the singles the APU doesn't get right yet, take them off as they start passing
+const KNOWN_FAILURES: [&str; 8] = [
+    "02-len ctr.gb",
+    "03-trigger.gb",
+    "07-len sweep period sync.gb",
+    "08-len ctr during power.gb",
+    "09-wave read while on.gb",
+    "10-wave trigger while on.gb",
+    "11-regs after power.gb",
+    "12-wave write while on.gb",
+];
+
+fn roms() -> Vec<PathBuf> {
+    let path = std::env::var_os("CERBOY_DMG_SOUND")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("rom/test/blargg/dmg_sound/rom_singles")
+        });
+    if path.is_file() {
+        return vec![path];
+    }
+    let mut roms: Vec<PathBuf> = std::fs::read_dir(&path)
+        .unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "gb"))
+        .collect();
+    roms.sort();
+    roms
+}
+
+/// The result code in $A000 once the rom's done, None while it's running
+fn result(emu: &Emulator) -> Option<u8> {
+    let ram = emu.mem.ext_ram();
+    if ram.len() < 4 || ram[1..4] != SIGNATURE || ram[0] == RUNNING {
+        return None;
+    }
+    Some(ram[0])
+}
+
+/// The end of what the rom has printed, from $A004 or failing that the serial port
+fn output(emu: &Emulator) -> String {
+    let ram = emu.mem.ext_ram();
+    let text = if ram.len() > 4 && ram[1..4] == SIGNATURE {
+        let text = &ram[4..];
+        let end = text.iter().position(|&b| b == 0).unwrap_or(text.len());
+        String::from_utf8_lossy(&text[..end]).into_owned()
+    } else {
+        String::from_utf8_lossy(emu.mem.serial_output()).into_owned()
+    };
+    let lines: Vec<&str> = text.trim_end().lines().collect();
+    lines[lines.len().saturating_sub(OUTPUT_LINES)..].join("\n")
+}
+
+/// None if the rom passed, otherwise why not
+fn run(rom: &Path) -> Option<String> {
+    let cart = match Cartridge::new(rom.to_str().unwrap()) {
+        Ok(cart) => cart,
+        Err(e) => return Some(e.to_string()),
+    };
+    let mut emu = Emulator::new(&cart);
+    for _ in 0..MAX_FRAMES {
+        if let Err(e) = emu.step_frame() {
+            return Some(format!("{}\n{}", e, emu.crash_report()));
+        }
+        match result(&emu) {
+            Some(0) => return None,
+            Some(code) => return Some(format!("code {}\n{}", code, output(&emu))),
+            None => {}
+        }
+    }
+    Some(format!(
+        "still running after {} frames\n{}",
+        MAX_FRAMES,
+        output(&emu)
+    ))
+}
+
+#[test]
+fn dmg_sound() {
+    let roms = roms();
+    assert!(!roms.is_empty(), "no roms to run");
+    let mut failures = vec![];
+    let mut fixed = vec![];
+    for rom in &roms {
+        let name = rom.file_name().unwrap().to_string_lossy().into_owned();
+        let known = KNOWN_FAILURES.contains(&name.as_str());
+        match run(rom) {
+            Some(why) if !known => failures.push(format!("{}: {}", name, why)),
+            None if known => fixed.push(name),
+            _ => {}
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "{} of {} failed\n\n{}",
+        failures.len(),
+        roms.len(),
+        failures.join("\n\n")
+    );
+    assert!(
+        fixed.is_empty(),
+        "passing now, take them out of KNOWN_FAILURES: {}",
+        fixed.join(", ")
+    );
+}